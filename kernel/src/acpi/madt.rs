@@ -48,7 +48,8 @@ pub struct IOAPIC {
 pub struct InterruptSourceOverride {
     apic_struct_header: APICStructureHeader,
     bus: u8,
-    source: u8,
+    // The ISA IRQ being overridden
+    pub source: u8,
     pub global_system_interrupt: u32,
     pub mps_inti_flags: u16,
 }
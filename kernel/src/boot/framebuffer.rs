@@ -3,15 +3,53 @@ use bootloader_api::info::{
     FrameBufferInfo,
 };
 use bootloader_x86_64_common::logger::LockedLogger;
+use conquer_once::spin::OnceCell;
 use log;
 
+use crate::mmu::address::VirtualAddress;
+use crate::mmu::vmm::cache::{
+    set_cache_mode,
+    CacheMode,
+};
+use crate::mmu::vmm::direct_map::set_direct_map_cache_mode;
+use crate::mmu::vmm::page_table::{
+    MappedPageTable,
+    PageTable,
+};
 use crate::LOGGER;
 
+// Start address and size of the framebuffer's mapping
+static FRAMEBUFFER_REGION: OnceCell<(VirtualAddress, usize)> = OnceCell::uninit();
+
 pub fn init_kernel_logging(framebuffer: FrameBuffer) {
     let framebuffer_info: FrameBufferInfo = framebuffer.info().clone();
     let raw_char_buffer: &'static mut [u8] = framebuffer.into_buffer();
-    let logger = LOGGER.get_or_init(move || LockedLogger::new(raw_char_buffer, framebuffer_info, true, false));
+    FRAMEBUFFER_REGION.init_once(|| {
+        (
+            VirtualAddress::new(raw_char_buffer.as_ptr() as usize),
+            raw_char_buffer.len(),
+        )
+    });
+    // The log goes to the first serial port as well, which the QEMU runner writes to serial_output.log
+    let logger = LOGGER.get_or_init(move || LockedLogger::new(raw_char_buffer, framebuffer_info, true, true));
     log::set_logger(logger).expect("Logger already set");
     log::set_max_level(log::LevelFilter::Trace);
     log::info!("RUNIX kernel logging enabled");
 }
+
+// The logger only ever writes to the framebuffer, so letting the writes be combined is much faster than the
+// bootloader's default caching. The framebuffer is physically contiguous, and its frames are also reachable
+// through the direct map, which has to use the same memory type. Needs the PAT and the direct map to be set up.
+pub fn enable_framebuffer_write_combining() {
+    let (start, size) = *FRAMEBUFFER_REGION.get().unwrap();
+    let active_pml4 = MappedPageTable::new(VirtualAddress::kernel_base(), unsafe { PageTable::get_active_pml4() });
+    let physical_start = active_pml4
+        .translate_virtual_address(start)
+        .expect("Framebuffer is not mapped!");
+    let result = set_direct_map_cache_mode(physical_start, size, CacheMode::WriteCombining)
+        .and_then(|()| set_cache_mode(start, size, CacheMode::WriteCombining));
+    match result {
+        Ok(()) => log::info!("Framebuffer mapped write combining"),
+        Err(flag_update_error) => log::warn!("Failed to make the framebuffer write combining: {}", flag_update_error),
+    }
+}
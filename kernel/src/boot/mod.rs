@@ -2,9 +2,39 @@ use bootloader_api::info::Optional;
 use bootloader_api::BootInfo;
 
 use crate::acpi::read_acpi_tables;
-use crate::boot::framebuffer::init_kernel_logging;
+use crate::boot::framebuffer::{
+    enable_framebuffer_write_combining,
+    init_kernel_logging,
+};
+use crate::cpu::control::init_protection_features;
+use crate::cpu::fpu::init_fpu;
+use crate::cpu::ioapic::init_ioapic_from_acpi;
+use crate::cpu::pic::{
+    init_pic,
+    init_pic_fallback,
+};
+use crate::cpu::smp::init_smp;
+use crate::cpu::{
+    init_cpu_index,
+    init_cpu_info,
+};
 use crate::interrupts::init_idt;
+use crate::interrupts::softirq::init_softirqs;
+use crate::mmu::alloc::frame::accounting::{
+    init_memory_accounting,
+    log_memory_info,
+};
+use crate::mmu::alloc::frame::init_frame_allocator;
 use crate::mmu::alloc::init_kheap;
+use crate::mmu::alloc::pressure::init_shrinkers;
+use crate::mmu::vmm::address_space::init_kernel_address_space;
+use crate::mmu::vmm::cache::{
+    init_pat,
+    report_mtrrs,
+};
+use crate::mmu::vmm::direct_map::init_direct_map;
+use crate::mmu::vmm::kernel_image::protect_kernel_image;
+use crate::mmu::vmm::tlb::init_tlb;
 use crate::segmentation::init_gdt;
 
 pub mod framebuffer;
@@ -19,6 +49,27 @@ pub fn init(boot_info: &'static mut BootInfo) {
         .unwrap() as usize;
     read_acpi_tables(rsdp_addr);
     init_gdt();
+    init_pic();
     init_idt();
-    init_kheap(boot_info);
+    init_softirqs();
+    init_cpu_info();
+    // The boot CPU is always index 0
+    init_cpu_index(0);
+    init_protection_features();
+    init_fpu();
+    init_tlb();
+    init_pat();
+    report_mtrrs();
+    init_memory_accounting(&boot_info.memory_regions);
+    init_frame_allocator(&boot_info.memory_regions);
+    init_direct_map(&boot_info.memory_regions);
+    protect_kernel_image();
+    enable_framebuffer_write_combining();
+    init_kheap();
+    init_shrinkers();
+    init_kernel_address_space();
+    init_ioapic_from_acpi();
+    init_pic_fallback();
+    init_smp();
+    log_memory_info();
 }
@@ -0,0 +1,155 @@
+use core::arch::asm;
+use core::sync::atomic::{
+    AtomicBool,
+    Ordering,
+};
+
+use crate::cpu::msr::{
+    read_msr_value,
+    write_msr_value,
+    IA32_EFER,
+};
+use crate::cpu::CPU_INFO;
+
+// Set once the matching feature has been switched on. Page table code checks NX_ENABLED before using bit 63,
+// which is reserved while EFER.NXE is clear. stac/clac are undefined instructions without SMAP.
+static NX_ENABLED: AtomicBool = AtomicBool::new(false);
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub enum Cr0Flags {}
+
+impl Cr0Flags {
+    pub const PROTECTION_ENABLE: usize = 1 << 0;
+    // WAIT/FWAIT honor the task switched flag
+    pub const MONITOR_COPROCESSOR: usize = 1 << 1;
+    // x87 instructions raise #NM, as if there was no FPU
+    pub const EMULATION: usize = 1 << 2;
+    // The next x87 or SIMD instruction raises #NM, so the FPU state can be switched lazily
+    pub const TASK_SWITCHED: usize = 1 << 3;
+    // x87 errors are reported through #MF instead of the legacy external interrupt
+    pub const NUMERIC_ERROR: usize = 1 << 5;
+    // Supervisor writes to read-only pages fault
+    pub const WRITE_PROTECT: usize = 1 << 16;
+    pub const PAGING: usize = 1 << 31;
+}
+
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub enum Cr4Flags {}
+
+impl Cr4Flags {
+    // Required for long mode
+    pub const PHYSICAL_ADDRESS_EXTENSION: usize = 1 << 5;
+    // Entries marked global survive CR3 writes
+    pub const PAGE_GLOBAL_ENABLE: usize = 1 << 7;
+    // FXSAVE/FXRSTOR include the SSE registers and SSE instructions are allowed
+    pub const OSFXSR: usize = 1 << 9;
+    // Unmasked SIMD floating-point errors raise #XM instead of #UD
+    pub const OSXMMEXCPT: usize = 1 << 10;
+    // The low 12 bits of CR3 select the PCID TLB entries are tagged with
+    pub const PCID_ENABLE: usize = 1 << 17;
+    // XGETBV/XSETBV and the XSAVE family are allowed
+    pub const OSXSAVE: usize = 1 << 18;
+    // Supervisor mode can't execute code on user pages
+    pub const SMEP: usize = 1 << 20;
+    // Supervisor mode can't access user pages unless RFLAGS.AC is set
+    pub const SMAP: usize = 1 << 21;
+}
+
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub enum EferFlags {}
+
+impl EferFlags {
+    pub const LONG_MODE_ENABLE: usize = 1 << 8;
+    pub const NO_EXECUTE_ENABLE: usize = 1 << 11;
+}
+
+#[inline]
+pub unsafe fn read_cr0() -> usize {
+    let cr0: usize;
+    asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack, preserves_flags));
+    cr0
+}
+
+#[inline]
+pub unsafe fn write_cr0(cr0: usize) {
+    asm!("mov cr0, {}", in(reg) cr0, options(nostack, preserves_flags));
+}
+
+#[inline]
+pub unsafe fn read_cr4() -> usize {
+    let cr4: usize;
+    asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags));
+    cr4
+}
+
+#[inline]
+pub unsafe fn write_cr4(cr4: usize) {
+    asm!("mov cr4, {}", in(reg) cr4, options(nostack, preserves_flags));
+}
+
+// Turns on every protection feature CPUInfo reports. Has to run before any mapping uses the NX bit.
+pub fn init_protection_features() {
+    let cpu_info = CPU_INFO.get().unwrap();
+    unsafe {
+        if cpu_info.nx_enabled {
+            write_msr_value(IA32_EFER, read_msr_value(IA32_EFER) | EferFlags::NO_EXECUTE_ENABLE);
+            NX_ENABLED.store(true, Ordering::Release);
+        }
+        write_cr0(read_cr0() | Cr0Flags::WRITE_PROTECT);
+        let mut cr4 = read_cr4();
+        if cpu_info.smep_enabled {
+            cr4 |= Cr4Flags::SMEP;
+        }
+        if cpu_info.smap_enabled {
+            cr4 |= Cr4Flags::SMAP;
+        }
+        write_cr4(cr4);
+        SMAP_ENABLED.store(cpu_info.smap_enabled, Ordering::Release);
+    }
+    log::info!(
+        "Protection features enabled: NX - {} WP - true SMEP - {} SMAP - {}",
+        cpu_info.nx_enabled,
+        cpu_info.smep_enabled,
+        cpu_info.smap_enabled
+    );
+}
+
+#[inline]
+pub fn is_nx_enabled() -> bool {
+    NX_ENABLED.load(Ordering::Acquire)
+}
+
+#[inline]
+pub fn is_smap_enabled() -> bool {
+    SMAP_ENABLED.load(Ordering::Acquire)
+}
+
+// Sets RFLAGS.AC, allowing supervisor accesses to user pages
+#[inline]
+pub unsafe fn stac() {
+    asm!("stac", options(nomem, nostack));
+}
+
+// Clears RFLAGS.AC
+#[inline]
+pub unsafe fn clac() {
+    asm!("clac", options(nomem, nostack));
+}
+
+// The only sanctioned way for the kernel to touch user memory. Access is opened for the duration of the closure
+// and closed again right after.
+pub fn with_user_access<R>(f: impl FnOnce() -> R) -> R {
+    let smap_enabled = is_smap_enabled();
+    if smap_enabled {
+        unsafe { stac() };
+    }
+    let result = f();
+    if smap_enabled {
+        unsafe { clac() };
+    }
+    result
+}
@@ -21,22 +21,56 @@ pub struct CPUInfo {
     pub cpu_vendor: Option<CpuVendor>,
     pub acpi_enabled: bool,
     pub msr_present: bool,
+    pub fxsr_enabled: bool,
+    pub sse_enabled: bool,
+    pub sse2_enabled: bool,
     pub sse3_enabled: bool,
+    pub xsave_enabled: bool,
+    pub avx_enabled: bool,
     pub apic_enabled: bool,
     pub x2apic_enabled: bool,
+    pub gib_pages_enabled: bool,
+    pub nx_enabled: bool,
+    pub smep_enabled: bool,
+    pub smap_enabled: bool,
+    pub pcid_enabled: bool,
+    pub invpcid_enabled: bool,
+    pub rdtscp_enabled: bool,
+    pub rdpid_enabled: bool,
+    pub pat_enabled: bool,
+    pub mtrr_enabled: bool,
+    pub physical_address_bits: u8,
+    pub cache_line_size: usize,
     pub apic_id: Option<u8>,
 }
 
 impl core::fmt::Display for CPUInfo {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.write_fmt(format_args!(
-            "CPU INFO:\nVendor: {}\nFeature Enabled: ACPI Thermal Control MSRs - {}\nFeature Enabled: MSR Instructions - {}\nFeature Enabled: SSE3 - {}\nFeature Enabled: APIC - {}\nFeature Enabled: X2APIC - {}\nInitial APIC ID: {}",
+            "CPU INFO:\nVendor: {}\nFeature Enabled: ACPI Thermal Control MSRs - {}\nFeature Enabled: MSR Instructions - {}\nFeature Enabled: FXSR - {}\nFeature Enabled: SSE - {}\nFeature Enabled: SSE2 - {}\nFeature Enabled: SSE3 - {}\nFeature Enabled: XSAVE - {}\nFeature Enabled: AVX - {}\nFeature Enabled: APIC - {}\nFeature Enabled: X2APIC - {}\nFeature Enabled: 1 GiB Pages - {}\nFeature Enabled: NX - {}\nFeature Enabled: SMEP - {}\nFeature Enabled: SMAP - {}\nFeature Enabled: PCID - {}\nFeature Enabled: INVPCID - {}\nFeature Enabled: RDTSCP - {}\nFeature Enabled: RDPID - {}\nFeature Enabled: PAT - {}\nFeature Enabled: MTRR - {}\nPhysical Address Bits: {}\nCache Line Size: {}\nInitial APIC ID: {}",
             self.cpu_vendor.as_ref().unwrap().to_str(),
             self.acpi_enabled,
             self.msr_present,
+            self.fxsr_enabled,
+            self.sse_enabled,
+            self.sse2_enabled,
             self.sse3_enabled,
+            self.xsave_enabled,
+            self.avx_enabled,
             self.apic_enabled,
             self.x2apic_enabled,
+            self.gib_pages_enabled,
+            self.nx_enabled,
+            self.smep_enabled,
+            self.smap_enabled,
+            self.pcid_enabled,
+            self.invpcid_enabled,
+            self.rdtscp_enabled,
+            self.rdpid_enabled,
+            self.pat_enabled,
+            self.mtrr_enabled,
+            self.physical_address_bits,
+            self.cache_line_size,
             self.apic_id.unwrap(),
         ))
     }
@@ -48,9 +82,27 @@ impl CPUInfo {
             cpu_vendor: None,
             acpi_enabled: false,
             msr_present: false,
+            fxsr_enabled: false,
+            sse_enabled: false,
+            sse2_enabled: false,
             sse3_enabled: false,
+            xsave_enabled: false,
+            avx_enabled: false,
             apic_enabled: false,
             x2apic_enabled: false,
+            gib_pages_enabled: false,
+            nx_enabled: false,
+            smep_enabled: false,
+            smap_enabled: false,
+            pcid_enabled: false,
+            invpcid_enabled: false,
+            rdtscp_enabled: false,
+            rdpid_enabled: false,
+            pat_enabled: false,
+            mtrr_enabled: false,
+            // The architectural minimum when CPUID doesn't report it
+            physical_address_bits: 36,
+            cache_line_size: 64,
             apic_id: None,
         }
     }
@@ -70,9 +122,35 @@ impl CPUInfo {
         }
         cpu_info.acpi_enabled = cpu_features.has_acpi();
         cpu_info.msr_present = cpu_features.has_msr();
+        cpu_info.fxsr_enabled = cpu_features.has_fxsave_fxstor();
+        cpu_info.sse_enabled = cpu_features.has_sse();
+        cpu_info.sse2_enabled = cpu_features.has_sse2();
         cpu_info.sse3_enabled = cpu_features.has_sse3();
+        cpu_info.xsave_enabled = cpu_features.has_xsave();
+        cpu_info.avx_enabled = cpu_features.has_avx();
         cpu_info.apic_enabled = cpu_features.has_apic();
         cpu_info.x2apic_enabled = cpu_features.has_x2apic();
+        cpu_info.pcid_enabled = cpu_features.has_pcid();
+        cpu_info.pat_enabled = cpu_features.has_pat();
+        cpu_info.mtrr_enabled = cpu_features.has_mtrr();
+        // Reported in units of 8 bytes
+        if cpu_features.has_clflush() {
+            cpu_info.cache_line_size = cpu_features.cflush_cache_line_size() as usize * 8;
+        }
+        if let Some(extended_features) = raw_cpuid.get_extended_processor_and_feature_identifiers() {
+            cpu_info.gib_pages_enabled = extended_features.has_1gib_pages();
+            cpu_info.nx_enabled = extended_features.has_execute_disable();
+            cpu_info.rdtscp_enabled = extended_features.has_rdtscp();
+        }
+        if let Some(structured_extended_features) = raw_cpuid.get_extended_feature_info() {
+            cpu_info.smep_enabled = structured_extended_features.has_smep();
+            cpu_info.smap_enabled = structured_extended_features.has_smap();
+            cpu_info.invpcid_enabled = structured_extended_features.has_invpcid();
+            cpu_info.rdpid_enabled = structured_extended_features.has_rdpid();
+        }
+        if let Some(capacity_info) = raw_cpuid.get_processor_capacity_feature_info() {
+            cpu_info.physical_address_bits = capacity_info.physical_address_bits();
+        }
         if cpu_info.apic_enabled {
             cpu_info.apic_id = Some(cpu_features.initial_local_apic_id());
         }
@@ -0,0 +1,85 @@
+use core::fmt;
+
+use crate::cpu::MAX_CPUS;
+
+const _: () = assert!(MAX_CPUS <= usize::BITS as usize, "A CpuSet has one bit per CPU index");
+
+// A set of CPU indices, one bit each
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CpuSet {
+    bits: usize,
+}
+
+impl CpuSet {
+    pub const fn empty() -> Self {
+        CpuSet { bits: 0 }
+    }
+
+    pub const fn single(cpu_index: usize) -> Self {
+        CpuSet { bits: 1 << cpu_index }
+    }
+
+    // Indices past MAX_CPUS are dropped
+    pub const fn from_bits(bits: usize) -> Self {
+        CpuSet {
+            bits: bits & CpuSet::all_bits(),
+        }
+    }
+
+    const fn all_bits() -> usize {
+        match MAX_CPUS == usize::BITS as usize {
+            true => usize::MAX,
+            false => (1 << MAX_CPUS) - 1,
+        }
+    }
+
+    #[inline]
+    pub fn bits(&self) -> usize {
+        self.bits
+    }
+
+    pub fn insert(&mut self, cpu_index: usize) {
+        assert!(cpu_index < MAX_CPUS, "CPU index {} is out of range!", cpu_index);
+        self.bits |= 1 << cpu_index;
+    }
+
+    pub fn remove(&mut self, cpu_index: usize) {
+        if cpu_index < MAX_CPUS {
+            self.bits &= !(1 << cpu_index);
+        }
+    }
+
+    #[inline]
+    pub fn contains(&self, cpu_index: usize) -> bool {
+        cpu_index < MAX_CPUS && self.bits & (1 << cpu_index) != 0
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.bits.count_ones() as usize
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.bits == 0
+    }
+
+    // In ascending order
+    pub fn iter(&self) -> impl Iterator<Item = usize> {
+        let bits = self.bits;
+        (0..MAX_CPUS).filter(move |&cpu_index| bits & (1 << cpu_index) != 0)
+    }
+}
+
+impl fmt::Display for CpuSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("{")?;
+        for (position, cpu_index) in self.iter().enumerate() {
+            if position > 0 {
+                f.write_str(", ")?;
+            }
+            f.write_fmt(format_args!("{}", cpu_index))?;
+        }
+        f.write_str("}")
+    }
+}
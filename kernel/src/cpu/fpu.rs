@@ -0,0 +1,403 @@
+use alloc::alloc::{
+    alloc_zeroed,
+    dealloc,
+    handle_alloc_error,
+    Layout,
+};
+use core::arch::asm;
+use core::ptr::{
+    self,
+    NonNull,
+};
+use core::sync::atomic::{
+    AtomicBool,
+    AtomicPtr,
+    AtomicUsize,
+    Ordering,
+};
+
+use crate::cpu::control::{
+    read_cr0,
+    read_cr4,
+    write_cr0,
+    write_cr4,
+    Cr0Flags,
+    Cr4Flags,
+};
+use crate::cpu::{
+    current_cpu_index,
+    CPU_INFO,
+    MAX_CPUS,
+};
+use crate::interrupts::asm::without_interrupts;
+
+// The kernel is built soft-float, none of its own code touches the x87 or SIMD registers. They only hold user
+// state, and whatever runs inside `with_kernel_fpu`.
+
+// The FXSAVE area, which is also the legacy region at the start of every XSAVE area
+const FXSAVE_AREA_SIZE: usize = 512;
+// XSAVE needs 64 bytes, FXSAVE 16
+const FPU_AREA_ALIGNMENT: usize = 64;
+const FCW_OFFSET: usize = 0;
+const MXCSR_OFFSET: usize = 24;
+// Every x87 exception masked, double extended precision, round to nearest
+const DEFAULT_FCW: u16 = 0x037F;
+// Every SIMD exception masked, round to nearest
+const DEFAULT_MXCSR: u32 = 0x1F80;
+// XSAVE leaf of CPUID, subleaf 0 lists the supported components and the area size for the enabled ones
+const CPUID_XSAVE_LEAF: u32 = 0xD;
+
+static XSAVE_ENABLED: AtomicBool = AtomicBool::new(false);
+static FPU_AREA_SIZE: AtomicUsize = AtomicUsize::new(FXSAVE_AREA_SIZE);
+// The state currently in the registers of each CPU, saved to its area before anything else is loaded
+static LOADED_AREA: [AtomicPtr<u8>; MAX_CPUS] = [const { AtomicPtr::new(ptr::null_mut()) }; MAX_CPUS];
+// The state of the thread running on each CPU, loaded on its first x87 or SIMD instruction under lazy switching
+static ACTIVE_AREA: [AtomicPtr<u8>; MAX_CPUS] = [const { AtomicPtr::new(ptr::null_mut()) }; MAX_CPUS];
+// Allocated through #NM for a thread that ran without a state, handed over to it on the next switch
+static FIRST_USE_AREA: [AtomicPtr<u8>; MAX_CPUS] = [const { AtomicPtr::new(ptr::null_mut()) }; MAX_CPUS];
+
+// State components of XCR0
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub enum XsaveComponent {}
+
+impl XsaveComponent {
+    pub const X87: u64 = 1 << 0;
+    pub const SSE: u64 = 1 << 1;
+    pub const AVX: u64 = 1 << 2;
+    // Opmask, ZMM_Hi256 and Hi16_ZMM, which can only be enabled together
+    pub const AVX512: u64 = 0b111 << 5;
+}
+
+// Exception flags of MXCSR, set by the instruction that raised #XM
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub enum MxcsrFlags {}
+
+impl MxcsrFlags {
+    pub const INVALID_OPERATION: u32 = 1 << 0;
+    pub const DENORMAL: u32 = 1 << 1;
+    pub const DIVIDE_BY_ZERO: u32 = 1 << 2;
+    pub const OVERFLOW: u32 = 1 << 3;
+    pub const UNDERFLOW: u32 = 1 << 4;
+    pub const PRECISION: u32 = 1 << 5;
+}
+
+// The exception flags of an MXCSR value, by name
+#[derive(Debug, Clone, Copy)]
+pub struct SimdExceptions(pub u32);
+
+impl core::fmt::Display for SimdExceptions {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let names = [
+            (MxcsrFlags::INVALID_OPERATION, "Invalid Operation"),
+            (MxcsrFlags::DENORMAL, "Denormal"),
+            (MxcsrFlags::DIVIDE_BY_ZERO, "Divide By Zero"),
+            (MxcsrFlags::OVERFLOW, "Overflow"),
+            (MxcsrFlags::UNDERFLOW, "Underflow"),
+            (MxcsrFlags::PRECISION, "Precision"),
+        ];
+        f.write_fmt(format_args!("MXCSR: {:#X}", self.0))?;
+        for (flag, name) in names {
+            if self.0 & flag != 0 {
+                f.write_fmt(format_args!(" {}", name))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FpuSwitching {
+    // The state is loaded on the first x87 or SIMD instruction after a switch, through #NM. Cheap for threads
+    // that rarely use the FPU.
+    Lazy,
+    // The state is loaded on every switch, which saves the #NM round trip for threads that use it all the time
+    Eager,
+}
+
+// The x87, SSE and AVX registers of a thread, in an area sized for the components this CPU enabled
+#[derive(Debug)]
+pub struct FpuState {
+    area: NonNull<u8>,
+    layout: Layout,
+    switching: FpuSwitching,
+}
+
+// The area is only touched by the CPU the thread runs on, with interrupts disabled
+unsafe impl Send for FpuState {}
+unsafe impl Sync for FpuState {}
+
+impl FpuState {
+    // Starts out in the state fninit and the default MXCSR leave behind. The zeroed XSAVE header marks every
+    // other component as being in its initial state.
+    pub fn new(switching: FpuSwitching) -> Self {
+        Self::try_new(switching).unwrap_or_else(|| handle_alloc_error(fpu_area_layout()))
+    }
+
+    // None if the heap is out of memory
+    pub fn try_new(switching: FpuSwitching) -> Option<Self> {
+        let layout = fpu_area_layout();
+        let area = NonNull::new(unsafe { alloc_zeroed(layout) })?;
+        unsafe {
+            area.as_ptr().add(FCW_OFFSET).cast::<u16>().write(DEFAULT_FCW);
+            area.as_ptr().add(MXCSR_OFFSET).cast::<u32>().write(DEFAULT_MXCSR);
+        }
+        Some(FpuState {
+            area,
+            layout,
+            switching,
+        })
+    }
+
+    // Takes back an area handed out by `into_area`
+    unsafe fn from_area(area: NonNull<u8>, switching: FpuSwitching) -> Self {
+        FpuState {
+            area,
+            layout: fpu_area_layout(),
+            switching,
+        }
+    }
+
+    // Gives up ownership of the area without freeing it
+    fn into_area(self) -> NonNull<u8> {
+        let area = self.area;
+        core::mem::forget(self);
+        area
+    }
+
+    #[inline]
+    pub fn switching(&self) -> FpuSwitching {
+        self.switching
+    }
+
+    #[inline]
+    pub fn set_switching(&mut self, switching: FpuSwitching) {
+        self.switching = switching;
+    }
+
+    #[inline]
+    fn area_ptr(&self) -> *mut u8 {
+        self.area.as_ptr()
+    }
+}
+
+impl Drop for FpuState {
+    fn drop(&mut self) {
+        let area = self.area_ptr();
+        without_interrupts(|| {
+            for cpu_index in 0..MAX_CPUS {
+                let _ =
+                    LOADED_AREA[cpu_index].compare_exchange(area, ptr::null_mut(), Ordering::AcqRel, Ordering::Relaxed);
+                let _ =
+                    ACTIVE_AREA[cpu_index].compare_exchange(area, ptr::null_mut(), Ordering::AcqRel, Ordering::Relaxed);
+            }
+        });
+        unsafe { dealloc(area, self.layout) };
+    }
+}
+
+#[inline]
+fn fpu_area_layout() -> Layout {
+    Layout::from_size_align(FPU_AREA_SIZE.load(Ordering::Acquire), FPU_AREA_ALIGNMENT).unwrap()
+}
+
+// Size of the save area of every FpuState, known once init_fpu ran
+#[inline]
+pub fn fpu_area_size() -> usize {
+    FPU_AREA_SIZE.load(Ordering::Acquire)
+}
+
+#[inline]
+pub fn is_xsave_enabled() -> bool {
+    XSAVE_ENABLED.load(Ordering::Acquire)
+}
+
+#[inline]
+unsafe fn xsetbv(xcr: u32, value: u64) {
+    asm!("xsetbv", in("ecx") xcr, in("eax") value as u32, in("edx") (value >> 32) as u32, options(nomem, nostack));
+}
+
+#[inline]
+pub unsafe fn clts() {
+    asm!("clts", options(nomem, nostack, preserves_flags));
+}
+
+// The next x87 or SIMD instruction raises #NM
+#[inline]
+unsafe fn set_task_switched() {
+    write_cr0(read_cr0() | Cr0Flags::TASK_SWITCHED);
+}
+
+#[inline]
+pub fn read_mxcsr() -> u32 {
+    let mut mxcsr: u32 = 0;
+    unsafe { asm!("stmxcsr [{}]", in(reg) &mut mxcsr, options(nostack, preserves_flags)) };
+    mxcsr
+}
+
+// Resets the registers to the state a new FpuState starts out in
+unsafe fn reset_fpu_registers() {
+    let mxcsr = DEFAULT_MXCSR;
+    asm!("fninit", "ldmxcsr [{}]", in(reg) &mxcsr, options(nostack, preserves_flags));
+}
+
+// Every enabled component is saved and restored, the mask in edx:eax is all ones
+unsafe fn save_area(area: *mut u8) {
+    match is_xsave_enabled() {
+        true => {
+            asm!("xsave64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack, preserves_flags))
+        }
+        false => asm!("fxsave64 [{}]", in(reg) area, options(nostack, preserves_flags)),
+    }
+}
+
+unsafe fn restore_area(area: *const u8) {
+    match is_xsave_enabled() {
+        true => {
+            asm!("xrstor64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack, preserves_flags))
+        }
+        false => asm!("fxrstor64 [{}]", in(reg) area, options(nostack, preserves_flags)),
+    }
+}
+
+// Saves whatever the registers of this CPU hold to its owner's area. CR0.TS has to be clear.
+unsafe fn save_loaded_area(cpu_index: usize) {
+    let loaded_area = LOADED_AREA[cpu_index].swap(ptr::null_mut(), Ordering::AcqRel);
+    if !loaded_area.is_null() {
+        save_area(loaded_area);
+    }
+}
+
+// Loads the active state into the registers of this CPU, unless it's there already
+unsafe fn load_active_area(cpu_index: usize) -> bool {
+    let active_area = ACTIVE_AREA[cpu_index].load(Ordering::Acquire);
+    if active_area.is_null() {
+        return false;
+    }
+    clts();
+    if LOADED_AREA[cpu_index].load(Ordering::Acquire) != active_area {
+        save_loaded_area(cpu_index);
+        restore_area(active_area);
+        LOADED_AREA[cpu_index].store(active_area, Ordering::Release);
+    }
+    true
+}
+
+// Called by the scheduler with interrupts disabled, when `next` is about to run on this CPU. The state of `prev`
+// is saved right away if it's loaded, so the thread can be picked up by another CPU. A state allocated through #NM
+// while `prev` ran is moved into it, `prev` is None if that thread exited. Threads without an FpuState pass None
+// as `next`, they get one on their first x87 or SIMD instruction.
+pub fn switch_fpu(prev: Option<&mut Option<FpuState>>, next: Option<&FpuState>) {
+    let cpu_index = current_cpu_index();
+    unsafe {
+        clts();
+        let first_use_area = NonNull::new(FIRST_USE_AREA[cpu_index].swap(ptr::null_mut(), Ordering::AcqRel))
+            .map(|area| FpuState::from_area(area, FpuSwitching::Lazy));
+        let prev = match (prev, first_use_area) {
+            (Some(prev), Some(first_use_area)) => {
+                assert!(
+                    prev.is_none(),
+                    "FPU Error: State allocated for a thread that already had one!"
+                );
+                Some(&*prev.insert(first_use_area))
+            }
+            (Some(prev), None) => prev.as_ref(),
+            // Dropping it unloads it as well
+            (None, Some(first_use_area)) => {
+                drop(first_use_area);
+                None
+            }
+            (None, None) => None,
+        };
+        if let Some(prev) = prev {
+            if LOADED_AREA[cpu_index].load(Ordering::Acquire) == prev.area_ptr() {
+                save_loaded_area(cpu_index);
+            }
+        }
+        let next_area = next.map_or(ptr::null_mut(), |next| next.area_ptr());
+        ACTIVE_AREA[cpu_index].store(next_area, Ordering::Release);
+        match next.map(|next| next.switching()) {
+            Some(FpuSwitching::Eager) => {
+                load_active_area(cpu_index);
+            }
+            Some(FpuSwitching::Lazy) | None => set_task_switched(),
+        }
+    }
+}
+
+// #NM with CR0.TS set means the running thread wants its state back. A thread without one gets a fresh state,
+// which switch_fpu hands over to it. Returns false only if there's no memory left for it.
+pub fn handle_device_not_available() -> bool {
+    let cpu_index = current_cpu_index();
+    if ACTIVE_AREA[cpu_index].load(Ordering::Acquire).is_null() {
+        let fpu_state = match FpuState::try_new(FpuSwitching::Lazy) {
+            Some(fpu_state) => fpu_state,
+            None => return false,
+        };
+        let area = fpu_state.into_area().as_ptr();
+        FIRST_USE_AREA[cpu_index].store(area, Ordering::Release);
+        ACTIVE_AREA[cpu_index].store(area, Ordering::Release);
+    }
+    unsafe { load_active_area(cpu_index) }
+}
+
+// Lets the kernel use the x87 and SIMD registers for the duration of the closure, on a clean state. The state of
+// the running thread is saved first and loaded again through #NM once it needs it.
+pub fn with_kernel_fpu<R>(f: impl FnOnce() -> R) -> R {
+    without_interrupts(|| unsafe {
+        clts();
+        save_loaded_area(current_cpu_index());
+        reset_fpu_registers();
+        let result = f();
+        set_task_switched();
+        result
+    })
+}
+
+// Enables the x87, SSE and, where CPUID reports them, XSAVE and AVX on the running CPU. Every CPU has to run it
+// before its first thread does.
+pub fn init_fpu() {
+    let cpu_info = CPU_INFO.get().unwrap();
+    assert!(
+        cpu_info.fxsr_enabled && cpu_info.sse_enabled && cpu_info.sse2_enabled,
+        "FPU Error: FXSR and SSE2 are architectural on x86_64!"
+    );
+    let mut xcr0 = XsaveComponent::X87 | XsaveComponent::SSE;
+    unsafe {
+        write_cr0(
+            (read_cr0() & !(Cr0Flags::EMULATION | Cr0Flags::TASK_SWITCHED))
+                | Cr0Flags::MONITOR_COPROCESSOR
+                | Cr0Flags::NUMERIC_ERROR,
+        );
+        let mut cr4 = read_cr4() | Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT;
+        if cpu_info.xsave_enabled {
+            cr4 |= Cr4Flags::OSXSAVE;
+        }
+        write_cr4(cr4);
+        if cpu_info.xsave_enabled {
+            let xsave_info = raw_cpuid::cpuid!(CPUID_XSAVE_LEAF, 0);
+            let supported = ((xsave_info.edx as u64) << 32) | xsave_info.eax as u64;
+            if cpu_info.avx_enabled && supported & XsaveComponent::AVX != 0 {
+                xcr0 |= XsaveComponent::AVX;
+                if supported & XsaveComponent::AVX512 == XsaveComponent::AVX512 {
+                    xcr0 |= XsaveComponent::AVX512;
+                }
+            }
+            xsetbv(0, xcr0);
+            // EBX is the size needed for the components enabled in XCR0 right now
+            let area_size = raw_cpuid::cpuid!(CPUID_XSAVE_LEAF, 0).ebx as usize;
+            FPU_AREA_SIZE.fetch_max(area_size, Ordering::AcqRel);
+            XSAVE_ENABLED.store(true, Ordering::Release);
+        }
+        reset_fpu_registers();
+        // Nothing is loaded until the first thread runs
+        set_task_switched();
+    }
+    log::info!(
+        "FPU initialized: XSAVE - {} XCR0 - {:#X} Save Area Size - {}",
+        cpu_info.xsave_enabled,
+        xcr0,
+        fpu_area_size()
+    );
+}
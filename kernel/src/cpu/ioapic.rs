@@ -1,25 +1,385 @@
+use alloc::vec::Vec;
+use core::fmt;
+
 use conquer_once::spin::OnceCell;
+use spin::Mutex;
+
+use crate::acpi::madt::InterruptSourceOverride;
+use crate::acpi::ACPI_TABLES;
+use crate::cpu::{
+    CPU_INFO,
+    LOCAL_APIC,
+};
+use crate::interrupts::irq::{
+    set_interrupt_controller,
+    InterruptController,
+    IrqError,
+};
+use crate::mmu::address::PhysicalAddress;
+use crate::mmu::vmm::cache::CacheMode;
+use crate::mmu::vmm::vmalloc::{
+    ioremap,
+    IoMapping,
+    VmapError,
+};
+
+// IOREGSEL and IOWIN, each padded out to 16 bytes
+const IOAPIC_REGISTERS_SIZE: usize = 0x20;
+// Offsets of the only two memory mapped registers. Every other register is reached by writing its index to
+// IOREGSEL and then accessing IOWIN.
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+
+// The first 16 GSIs are wired to the ISA IRQs of the same number unless an override says otherwise
+const ISA_IRQ_COUNT: u32 = 16;
+
+pub static IOAPICS: OnceCell<Vec<IOAPIC>> = OnceCell::uninit();
+static IOAPIC_CONTROLLER: IOAPICController = IOAPICController;
+
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub enum IOAPICRegister {}
+
+impl IOAPICRegister {
+    // Intel 82093AA datasheet - Section 3.2
+    pub const IOAPIC_ID: u32 = 0x00;
+    pub const IOAPIC_VERSION: u32 = 0x01;
+    // Each redirection entry takes two registers, the low half comes first
+    pub const REDIRECTION_TABLE_BASE: u32 = 0x10;
+}
 
-use crate::mmu::address::VirtualAddress;
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub enum RedirectionEntryFlags {}
 
-pub static IOAPIC: OnceCell<IOAPIC> = OnceCell::uninit();
+impl RedirectionEntryFlags {
+    pub const VECTOR_MASK: u64 = 0xFF;
+    // Delivery mode bits are left at 0, fixed delivery to the destination
+    // Destination mode bit is left at 0, the destination is a physical APIC ID
+    pub const ACTIVE_LOW: u64 = 1 << 13;
+    pub const LEVEL_TRIGGERED: u64 = 1 << 15;
+    pub const MASKED: u64 = 1 << 16;
+    pub const DESTINATION_SHIFT: u64 = 56;
+}
 
+// The ISA IRQs of legacy devices
 #[derive(Debug, Clone, Copy)]
-#[repr(transparent)]
+#[non_exhaustive]
+pub enum LegacyIrq {}
+
+impl LegacyIrq {
+    pub const PIT: u8 = 0;
+    pub const KEYBOARD: u8 = 1;
+    pub const COM2: u8 = 3;
+    pub const COM1: u8 = 4;
+    pub const RTC: u8 = 8;
+    pub const MOUSE: u8 = 12;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+// How a GSI is wired up, and the ISA IRQ it belongs to if it's a legacy one
+#[derive(Debug, Clone, Copy)]
+pub struct GsiWiring {
+    pub gsi: u32,
+    pub isa_irq: Option<u8>,
+    pub polarity: Polarity,
+    pub trigger_mode: TriggerMode,
+}
+
+impl InterruptSourceOverride {
+    // ACPI Specification - Section 5.2.12.5, MPS INTI flags
+    // Both fields are 2 bits wide, 0b01 and 0b11 pick one of the two settings explicitly and 0b00 conforms to the
+    // bus, which means ISA here: active high and edge triggered
+    const INTI_FLAG_MASK: u16 = 0b11;
+    const POLARITY_ACTIVE_LOW: u16 = 0b11;
+    const TRIGGER_MODE_SHIFT: u16 = 2;
+    const TRIGGER_MODE_LEVEL: u16 = 0b11;
+
+    fn polarity(&self) -> Polarity {
+        match self.mps_inti_flags & Self::INTI_FLAG_MASK {
+            Self::POLARITY_ACTIVE_LOW => Polarity::ActiveLow,
+            _ => Polarity::ActiveHigh,
+        }
+    }
+
+    fn trigger_mode(&self) -> TriggerMode {
+        match (self.mps_inti_flags >> Self::TRIGGER_MODE_SHIFT) & Self::INTI_FLAG_MASK {
+            Self::TRIGGER_MODE_LEVEL => TriggerMode::Level,
+            _ => TriggerMode::Edge,
+        }
+    }
+}
+
+fn interrupt_source_overrides() -> impl Iterator<Item = InterruptSourceOverride> {
+    let apic_structures = &ACPI_TABLES.get().unwrap().madt.apic_structures;
+    let interrupt_source_override_records = apic_structures.interrupt_source_override_records;
+    interrupt_source_override_records.into_iter().flatten()
+}
+
+// The GSI an ISA IRQ arrives on, with the polarity and trigger mode the MADT overrides it with
+pub fn legacy_irq_wiring(isa_irq: u8) -> GsiWiring {
+    match interrupt_source_overrides().find(|iso| iso.source == isa_irq) {
+        Some(iso) => GsiWiring {
+            gsi: iso.global_system_interrupt,
+            isa_irq: Some(isa_irq),
+            polarity: iso.polarity(),
+            trigger_mode: iso.trigger_mode(),
+        },
+        None => GsiWiring {
+            gsi: isa_irq as u32,
+            isa_irq: Some(isa_irq),
+            polarity: Polarity::ActiveHigh,
+            trigger_mode: TriggerMode::Edge,
+        },
+    }
+}
+
+#[inline]
+pub fn legacy_irq_gsi(isa_irq: u8) -> u32 {
+    legacy_irq_wiring(isa_irq).gsi
+}
+
+pub fn gsi_wiring(gsi: u32) -> GsiWiring {
+    if let Some(iso) = interrupt_source_overrides().find(|iso| iso.global_system_interrupt == gsi) {
+        return legacy_irq_wiring(iso.source);
+    }
+    // An ISA IRQ overridden to a different GSI no longer arrives on its identity mapped one
+    let isa_irq_moved = interrupt_source_overrides().any(|iso| iso.source as u32 == gsi);
+    match gsi < ISA_IRQ_COUNT && !isa_irq_moved {
+        true => legacy_irq_wiring(gsi as u8),
+        // Anything else is a PCI interrupt line, which are shared, active low and level triggered
+        false => GsiWiring {
+            gsi,
+            isa_irq: None,
+            polarity: Polarity::ActiveLow,
+            trigger_mode: TriggerMode::Level,
+        },
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum IOAPICError {
+    NoIOAPIC,
+    Mapping(VmapError),
+}
+
+impl fmt::Display for IOAPICError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoIOAPIC => f.write_str("IOAPIC Error: The MADT doesn't list any IOAPIC"),
+            Self::Mapping(vmap_error) => f.write_fmt(format_args!("IOAPIC Error: {}", vmap_error)),
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct IOAPIC {
-    address: VirtualAddress,
+    registers: IoMapping,
+    // IOREGSEL and IOWIN have to be accessed as a pair
+    register_select: Mutex<()>,
+    id: u8,
+    gsi_base: u32,
+    redirection_entry_count: u32,
 }
 
 impl IOAPIC {
-    pub fn read_register(&self, register_offset: u32) -> u32 {
-        let register_address = VirtualAddress::with_offset(self.address.inner, register_offset as usize);
-        unsafe { core::ptr::read_volatile(register_address.inner as *const u32) }
+    pub fn new(physical_address: PhysicalAddress, gsi_base: u32) -> Result<Self, VmapError> {
+        let registers = ioremap(physical_address, IOAPIC_REGISTERS_SIZE, CacheMode::Uncached)?;
+        let mut ioapic = IOAPIC {
+            registers,
+            register_select: Mutex::new(()),
+            id: 0,
+            gsi_base,
+            redirection_entry_count: 0,
+        };
+        ioapic.id = ((ioapic.read_register(IOAPICRegister::IOAPIC_ID) >> 24) & 0xF) as u8;
+        // Bits 16-23 of the version register hold the index of the last redirection entry
+        ioapic.redirection_entry_count = ((ioapic.read_register(IOAPICRegister::IOAPIC_VERSION) >> 16) & 0xFF) + 1;
+        Ok(ioapic)
+    }
+
+    pub fn read_register(&self, register_index: u32) -> u32 {
+        let _register_select = self.register_select.lock();
+        self.registers.write(IOREGSEL, register_index);
+        self.registers.read(IOWIN)
+    }
+
+    pub fn write_to_register(&self, register_index: u32, value: u32) {
+        let _register_select = self.register_select.lock();
+        self.registers.write(IOREGSEL, register_index);
+        self.registers.write(IOWIN, value)
+    }
+
+    #[inline]
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    #[inline]
+    pub fn gsi_base(&self) -> u32 {
+        self.gsi_base
+    }
+
+    #[inline]
+    pub fn redirection_entry_count(&self) -> u32 {
+        self.redirection_entry_count
+    }
+
+    #[inline]
+    pub fn handles_gsi(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.redirection_entry_count).contains(&gsi)
+    }
+
+    fn redirection_register(&self, gsi: u32) -> u32 {
+        IOAPICRegister::REDIRECTION_TABLE_BASE + (gsi - self.gsi_base) * 2
+    }
+
+    pub fn read_redirection_entry(&self, gsi: u32) -> u64 {
+        let register = self.redirection_register(gsi);
+        let low = self.read_register(register) as u64;
+        let high = self.read_register(register + 1) as u64;
+        (high << 32) | low
+    }
+
+    // The high half goes first, so the entry never points at a stale destination while it's unmasked
+    pub fn write_redirection_entry(&self, gsi: u32, redirection_entry: u64) {
+        let register = self.redirection_register(gsi);
+        self.write_to_register(
+            register,
+            (redirection_entry as u32) | RedirectionEntryFlags::MASKED as u32,
+        );
+        self.write_to_register(register + 1, (redirection_entry >> 32) as u32);
+        self.write_to_register(register, redirection_entry as u32);
+    }
+
+    // Delivers the GSI to `vector` on the CPU with the given APIC ID, wired up the way the MADT says
+    pub fn route(&self, gsi: u32, vector: usize, apic_id: u8) {
+        let gsi_wiring = gsi_wiring(gsi);
+        let mut redirection_entry = vector as u64 & RedirectionEntryFlags::VECTOR_MASK;
+        if gsi_wiring.polarity == Polarity::ActiveLow {
+            redirection_entry |= RedirectionEntryFlags::ACTIVE_LOW;
+        }
+        if gsi_wiring.trigger_mode == TriggerMode::Level {
+            redirection_entry |= RedirectionEntryFlags::LEVEL_TRIGGERED;
+        }
+        redirection_entry |= (apic_id as u64) << RedirectionEntryFlags::DESTINATION_SHIFT;
+        self.write_redirection_entry(gsi, redirection_entry);
+    }
+
+    pub fn mask(&self, gsi: u32) {
+        let redirection_entry = self.read_redirection_entry(gsi);
+        self.write_redirection_entry(gsi, redirection_entry | RedirectionEntryFlags::MASKED);
+    }
+
+    pub fn unmask(&self, gsi: u32) {
+        let redirection_entry = self.read_redirection_entry(gsi);
+        self.write_redirection_entry(gsi, redirection_entry & !RedirectionEntryFlags::MASKED);
     }
 
-    pub fn write_to_register(&self, register_offset: u32, value: u32) {
-        let register_address = VirtualAddress::with_offset(self.address.inner, register_offset as usize);
-        unsafe { core::ptr::write_volatile(register_address.inner as *mut u32, value) }
+    // Moves an already routed GSI over to another CPU
+    pub fn set_destination(&self, gsi: u32, apic_id: u8) {
+        let redirection_entry = self.read_redirection_entry(gsi) & !(0xFF << RedirectionEntryFlags::DESTINATION_SHIFT);
+        self.write_redirection_entry(
+            gsi,
+            redirection_entry | (apic_id as u64) << RedirectionEntryFlags::DESTINATION_SHIFT,
+        );
+    }
+
+    fn mask_all(&self) {
+        for gsi in self.gsi_base..self.gsi_base + self.redirection_entry_count {
+            self.write_redirection_entry(gsi, RedirectionEntryFlags::MASKED);
+        }
+    }
+}
+
+// The IOAPIC a GSI is wired to
+pub fn gsi_ioapic(gsi: u32) -> Option<&'static IOAPIC> {
+    IOAPICS.get()?.iter().find(|ioapic| ioapic.handles_gsi(gsi))
+}
+
+// Sends a routed GSI to the CPU with the given APIC ID from now on
+pub fn route_gsi_to_cpu(gsi: u32, apic_id: u8) -> Result<(), IrqError> {
+    let ioapic = gsi_ioapic(gsi).ok_or(IrqError::UnroutableGsi)?;
+    ioapic.set_destination(gsi, apic_id);
+    Ok(())
+}
+
+// Routes GSIs for the IRQ manager. Everything goes to the boot CPU at first.
+#[derive(Debug)]
+pub struct IOAPICController;
+
+impl InterruptController for IOAPICController {
+    fn name(&self) -> &'static str {
+        "IOAPIC"
+    }
+
+    fn route(&self, gsi: u32, vector: usize) -> Result<(), IrqError> {
+        let ioapic = gsi_ioapic(gsi).ok_or(IrqError::UnroutableGsi)?;
+        let boot_apic_id = CPU_INFO.get().unwrap().apic_id.unwrap_or(0);
+        ioapic.route(gsi, vector, boot_apic_id);
+        Ok(())
+    }
+
+    fn mask(&self, gsi: u32) {
+        if let Some(ioapic) = gsi_ioapic(gsi) {
+            ioapic.mask(gsi);
+        }
+    }
+
+    // The IOAPIC forwards interrupts to the LAPICs and is told about the EOI by them, level triggered entries
+    // included
+    fn end_of_interrupt(&self, _gsi: u32, _vector: usize) {
+        if let Some(local_apic) = LOCAL_APIC.get() {
+            local_apic.signal_end_of_interrupt();
+        }
     }
 }
 
-pub fn init_ioapic_from_acpi() {}
+fn read_ioapics() -> Result<Vec<IOAPIC>, IOAPICError> {
+    let apic_structures = &ACPI_TABLES.get().unwrap().madt.apic_structures;
+    let io_apic_records = apic_structures.io_apic_records;
+    let mut ioapics = Vec::new();
+    for io_apic_record in io_apic_records.into_iter().flatten() {
+        let ioapic = IOAPIC::new(
+            PhysicalAddress::new(io_apic_record.io_apic_physical_address as usize),
+            io_apic_record.global_system_interrupt_base,
+        )
+        .map_err(IOAPICError::Mapping)?;
+        ioapic.mask_all();
+        log::info!(
+            "IOAPIC {} at {:#X}: GSIs {}-{}",
+            ioapic.id(),
+            { io_apic_record.io_apic_physical_address },
+            ioapic.gsi_base(),
+            ioapic.gsi_base() + ioapic.redirection_entry_count() - 1
+        );
+        ioapics.push(ioapic);
+    }
+    match ioapics.is_empty() {
+        true => Err(IOAPICError::NoIOAPIC),
+        false => Ok(ioapics),
+    }
+}
+
+// Maps every IOAPIC the MADT lists, masks all of their entries and makes them the interrupt controller of the
+// IRQ manager
+pub fn init_ioapic_from_acpi() {
+    match read_ioapics() {
+        Ok(ioapics) => {
+            IOAPICS.init_once(move || ioapics);
+            set_interrupt_controller(&IOAPIC_CONTROLLER);
+        }
+        Err(ioapic_error) => log::error!("{}", ioapic_error),
+    }
+}
@@ -8,12 +8,23 @@ use crate::cpu::pit::{
     PIT_FREQUENCY,
 };
 use crate::cpu::CPU_INFO;
+use crate::interrupts::asm::without_interrupts;
 use crate::interrupts::InterruptVector;
-use crate::mmu::address::VirtualAddress;
+use crate::mmu::address::PhysicalAddress;
+use crate::mmu::vmm::cache::CacheMode;
+use crate::mmu::vmm::vmalloc::{
+    ioremap,
+    IoMapping,
+};
 
 // TODO: Check if there is an MSR, Read the MSR value
 const IA32_APIC_BASE_MSR: usize = 0x1B;
 
+// The low bits of IA32_APIC_BASE hold flags, not the address
+const LAPIC_BASE_ADDRESS_MASK: usize = 0x000F_FFFF_FFFF_F000;
+// The registers take up the first 1 KiB of the 4 KiB LAPIC page
+const LAPIC_REGISTERS_SIZE: usize = 0x400;
+
 // If this bit is set in a LAPIC register, the corresponding interrupt is masked
 const LAPIC_INTERRUPT_MASK: u32 = 1 << 16;
 const LAPIC_TIMER_MODE_PERIODIC: u32 = 1 << 17;
@@ -31,6 +42,8 @@ impl LAPICRegister {
     pub const TASK_PRIORITY: u32 = 0x080;
     pub const END_OF_INTERRUPT: u32 = 0x0B0;
     pub const SPURIOUS_INTERRUPT_VECTOR: u32 = 0x0F0;
+    pub const INTERRUPT_COMMAND_LOW: u32 = 0x300;
+    pub const INTERRUPT_COMMAND_HIGH: u32 = 0x310;
     pub const TIMER_LOCAL_VECTOR_TABLE_ENTRY: u32 = 0x320;
     pub const ERROR_LOCAL_VECTOR_TABLE_ENTRY: u32 = 0x370;
     pub const TIMER_INITIAL_COUNT: u32 = 0x380;
@@ -38,24 +51,38 @@ impl LAPICRegister {
     pub const TIMER_DIVIDE_CONFIGURATION: u32 = 0x3E0;
 }
 
+// Fields of the low half of the Interrupt Command Register. The destination APIC ID goes into the high half.
 #[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub enum IpiFlags {}
+
+impl IpiFlags {
+    // Delivery modes. Fixed IPIs carry their vector in the low byte, startup IPIs the page the AP starts at.
+    pub const FIXED: u32 = 0b000 << 8;
+    pub const INIT: u32 = 0b101 << 8;
+    pub const STARTUP: u32 = 0b110 << 8;
+    // Set while the LAPIC hasn't sent the previous IPI yet
+    pub const DELIVERY_PENDING: u32 = 1 << 12;
+    pub const LEVEL_ASSERT: u32 = 1 << 14;
+    pub const DESTINATION_SHIFT: u32 = 24;
+}
+
+#[derive(Debug)]
 pub struct LocalAPIC {
-    virtual_address: VirtualAddress,
+    registers: IoMapping,
 }
 
 impl LocalAPIC {
     pub fn read_register(&self, register_offset: u32) -> u32 {
-        let register_address = VirtualAddress::with_offset(self.virtual_address.inner, register_offset as usize);
-        unsafe { core::ptr::read_volatile(register_address.inner as *const u32) }
+        self.registers.read(register_offset as usize)
     }
 
     pub fn write_to_register(&self, register_offset: u32, value: u32) {
-        let register_address = VirtualAddress::with_offset(self.virtual_address.inner, register_offset as usize);
-        unsafe { core::ptr::write_volatile(register_address.inner as *mut u32, value) }
+        self.registers.write(register_offset as usize, value)
     }
 
     pub fn read_id(&self) -> u32 {
-        self.read_register(LAPICRegister::LAPIC_ID) << 24
+        self.read_register(LAPICRegister::LAPIC_ID) >> 24
     }
 
     pub fn clear_task_priority_register(&self) {
@@ -71,6 +98,30 @@ impl LocalAPIC {
         );
     }
 
+    // Every CPU sees its own LAPIC at the same address, so one mapping serves all of them. This has to run on each.
+    pub fn enable(&self) {
+        self.clear_task_priority_register();
+        self.enable_interrupts();
+    }
+
+    // The ICR of this CPU's LAPIC is written in two halves, an IRQ sending an IPI of its own in between would mix
+    // them up
+    pub fn send_ipi(&self, apic_id: u32, command: u32) {
+        without_interrupts(|| {
+            while self.read_register(LAPICRegister::INTERRUPT_COMMAND_LOW) & IpiFlags::DELIVERY_PENDING != 0 {
+                core::hint::spin_loop();
+            }
+            self.write_to_register(
+                LAPICRegister::INTERRUPT_COMMAND_HIGH,
+                apic_id << IpiFlags::DESTINATION_SHIFT,
+            );
+            self.write_to_register(LAPICRegister::INTERRUPT_COMMAND_LOW, command);
+            while self.read_register(LAPICRegister::INTERRUPT_COMMAND_LOW) & IpiFlags::DELIVERY_PENDING != 0 {
+                core::hint::spin_loop();
+            }
+        });
+    }
+
     pub fn signal_end_of_interrupt(&self) {
         self.write_to_register(LAPICRegister::END_OF_INTERRUPT, 0);
     }
@@ -120,7 +171,7 @@ impl LocalAPIC {
             match cpu_info.msr_present {
                 true => {
                     let apic_msr_read_value = unsafe { read_msr_value(IA32_APIC_MSR_BASE) };
-                    raw_physical_address_base = apic_msr_read_value;
+                    raw_physical_address_base = apic_msr_read_value & LAPIC_BASE_ADDRESS_MASK;
                 }
                 false => {
                     let apic_structures = &ACPI_TABLES.get().unwrap().madt.apic_structures;
@@ -133,24 +184,40 @@ impl LocalAPIC {
                     }
                 }
             }
-            let virtual_address = VirtualAddress::with_kernel_base_offset(raw_physical_address_base);
-            Some(LocalAPIC { virtual_address })
+            // Register accesses have side effects, so they must never be cached or combined
+            match ioremap(
+                PhysicalAddress::new(raw_physical_address_base),
+                LAPIC_REGISTERS_SIZE,
+                CacheMode::Uncached,
+            ) {
+                Ok(registers) => Some(LocalAPIC { registers }),
+                Err(vmap_error) => {
+                    log::error!("Failed to map the LAPIC registers: {}", vmap_error);
+                    None
+                }
+            }
         } else {
             log::error!("APIC not supported by CPU!");
             None
         }
     }
 
-    pub fn initialize_core_lapic() -> Self {
+    pub fn map() -> Self {
         let lapic = Self::try_read_and_init_from_madt().unwrap();
-        log::info!("LAPIC detected at address: {:#X}", lapic.virtual_address.inner);
+        log::info!(
+            "LAPIC detected at address: {:#X}, mapped at {:#X}",
+            lapic.registers.physical_address().inner,
+            lapic.registers.address().inner
+        );
+        lapic
+    }
+
+    pub fn initialize_core_lapic(&self) {
         unsafe { core::arch::asm!("cli", options(nomem, nostack)) }
-        lapic.clear_task_priority_register();
-        lapic.enable_interrupts();
+        self.enable();
         log::info!("LAPIC interrupts enabled");
-        lapic.calibrate_and_init_periodic_timer();
+        self.calibrate_and_init_periodic_timer();
         log::info!("LAPIC Timer calibrated and initialized");
         unsafe { core::arch::asm!("sti", options(nomem, nostack)) }
-        lapic
     }
 }
@@ -1,18 +1,143 @@
+use alloc::boxed::Box;
+use core::arch::asm;
+use core::sync::atomic::{
+    AtomicUsize,
+    Ordering,
+};
+
 use conquer_once::spin::OnceCell;
 
 use crate::cpu::cpu_info::CPUInfo;
 use crate::cpu::lapic::LocalAPIC;
+use crate::cpu::msr::{
+    write_msr_value,
+    IA32_TSC_AUX,
+};
+use crate::cpu::smp::cpu_index_of;
+use crate::interrupts::irq::{
+    request_irq,
+    IrqReturn,
+};
+use crate::interrupts::softirq::{
+    raise_softirq,
+    register_softirq,
+    SoftIrq,
+};
+use crate::interrupts::workqueue::{
+    schedule_work,
+    Work,
+};
+use crate::interrupts::InterruptVector;
 
+pub mod control;
 pub mod cpu_info;
+pub mod cpu_set;
+pub mod fpu;
 pub mod ioapic;
 pub mod lapic;
 pub mod msr;
+pub mod pic;
 pub mod pit;
+pub mod smp;
+
+// Size of the per-CPU tables, the MADT is only read for as many processors as well
+pub const MAX_CPUS: usize = 16;
 
 pub static LOCAL_APIC: OnceCell<LocalAPIC> = OnceCell::uninit();
 pub static CPU_INFO: OnceCell<CPUInfo> = OnceCell::uninit();
 
-pub fn init_cpu_intrinsics() {
+// Maps the LAPIC on first use. Only the mapping is shared, each CPU still has to enable its own LAPIC.
+pub fn local_apic() -> &'static LocalAPIC {
+    LOCAL_APIC.get_or_init(LocalAPIC::map)
+}
+
+#[inline]
+pub fn current_apic_id() -> u32 {
+    raw_cpuid::cpuid!(1).ebx >> 24
+}
+
+// Where the running CPU's index is read from
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub enum CpuIndexSource {}
+
+impl CpuIndexSource {
+    // Looked up by the initial APIC ID
+    pub const CPUID: usize = 0;
+    pub const RDPID: usize = 1;
+    pub const RDTSCP: usize = 2;
+}
+
+static CPU_INDEX_SOURCE: AtomicUsize = AtomicUsize::new(CpuIndexSource::CPUID);
+
+// Index of the running CPU into per-CPU tables. GS can't hold per-CPU data, the interrupt entry stubs reload it,
+// so every CPU keeps its index in IA32_TSC_AUX instead. CPUID is only the fallback, it's slow and traps to the
+// hypervisor in a VM, and this is read on every interrupt.
+#[inline]
+pub fn current_cpu_index() -> usize {
+    match CPU_INDEX_SOURCE.load(Ordering::Relaxed) {
+        CpuIndexSource::RDPID => {
+            let cpu_index: usize;
+            unsafe { asm!("rdpid {}", out(reg) cpu_index, options(nomem, nostack, preserves_flags)) };
+            cpu_index
+        }
+        CpuIndexSource::RDTSCP => {
+            let cpu_index: u32;
+            unsafe {
+                asm!("rdtscp", out("ecx") cpu_index, out("eax") _, out("edx") _, options(nomem, nostack, preserves_flags))
+            };
+            cpu_index as usize
+        }
+        _ => cpu_index_of(current_apic_id()),
+    }
+}
+
+// Stores the index of the running CPU in IA32_TSC_AUX. Has to run on every CPU before it takes any interrupts,
+// the first call picks the instruction all of them read it with.
+pub fn init_cpu_index(cpu_index: usize) {
+    let cpu_info = CPU_INFO.get().unwrap();
+    let source = match (cpu_info.rdpid_enabled, cpu_info.rdtscp_enabled) {
+        (true, _) => CpuIndexSource::RDPID,
+        (false, true) => CpuIndexSource::RDTSCP,
+        (false, false) => return,
+    };
+    unsafe { write_msr_value(IA32_TSC_AUX, cpu_index) };
+    CPU_INDEX_SOURCE.store(source, Ordering::Relaxed);
+}
+
+// Cycles since reset. Only good for measuring short intervals on one CPU, the counters of different CPUs aren't
+// guaranteed to be in sync.
+#[inline]
+pub fn read_tsc() -> u64 {
+    let (high_bytes, low_bytes): (u32, u32);
+    unsafe {
+        core::arch::asm!("rdtsc", out("edx") high_bytes, out("eax") low_bytes, options(nomem, nostack, preserves_flags))
+    };
+    ((high_bytes as u64) << 32) | (low_bytes as u64)
+}
+
+pub fn init_cpu_info() {
     CPU_INFO.get_or_init(move || unsafe { CPUInfo::parse_raw_cpuid() });
-    LOCAL_APIC.get_or_init(move || LocalAPIC::initialize_core_lapic());
+}
+
+static TIMER_TICK_WORK: Work = Work::new("timer-tick", |_| log::info!("LAPIC TIMER IRQ"), 0);
+
+pub fn init_cpu_intrinsics() {
+    init_cpu_info();
+    // The timer starts firing as soon as the LAPIC is initialized. Anything done on a tick is deferred to the timer
+    // softirq, which still runs in interrupt context, so logging the tick is left to the system work queue.
+    register_softirq(SoftIrq::TIMER, || {
+        schedule_work(&TIMER_TICK_WORK);
+    });
+    request_irq(
+        InterruptVector::APIC_TIMER,
+        "lapic-timer",
+        0,
+        Box::new(|_| {
+            raise_softirq(SoftIrq::TIMER);
+            IrqReturn::Handled
+        }),
+    )
+    .expect("Failed to register the LAPIC timer handler");
+    local_apic().initialize_core_lapic();
 }
@@ -2,9 +2,22 @@ use core::arch::asm;
 
 // MSR values: https://sandpile.org/x86/msr.html
 pub const IA32_APIC_MSR_BASE: u32 = 0x1B;
+pub const IA32_MTRRCAP: u32 = 0xFE;
+// Variable range MTRR n is made up of IA32_MTRR_PHYSBASE0 + 2n and IA32_MTRR_PHYSMASK0 + 2n
+pub const IA32_MTRR_PHYSBASE0: u32 = 0x200;
+pub const IA32_MTRR_PHYSMASK0: u32 = 0x201;
+pub const IA32_PAT: u32 = 0x277;
+pub const IA32_MTRR_DEF_TYPE: u32 = 0x2FF;
+pub const IA32_EFER: u32 = 0xC000_0080;
+pub const IA32_TSC_AUX: u32 = 0xC000_0103;
 
 pub unsafe fn read_msr_value(msr_base: u32) -> usize {
     let (high_bytes, low_bytes): (u32, u32);
     asm!("rdmsr", out("edx") high_bytes, out("eax") low_bytes, in("ecx") msr_base, options(nomem));
     ((high_bytes as usize) << 32) | (low_bytes as usize)
 }
+
+pub unsafe fn write_msr_value(msr_base: u32, value: usize) {
+    let (high_bytes, low_bytes) = ((value >> 32) as u32, value as u32);
+    asm!("wrmsr", in("edx") high_bytes, in("eax") low_bytes, in("ecx") msr_base, options(nostack, preserves_flags));
+}
@@ -0,0 +1,219 @@
+use spin::Mutex;
+
+use crate::cpu::ioapic::gsi_wiring;
+use crate::device::serial::Port;
+use crate::interrupts::irq::{
+    interrupt_controller,
+    set_interrupt_controller,
+    InterruptController,
+    IrqError,
+};
+use crate::interrupts::InterruptVector;
+
+pub const PIC_MASTER_COMMAND_PORT_NUMBER: u16 = 0x20;
+pub const PIC_MASTER_DATA_PORT_NUMBER: u16 = 0x21;
+pub const PIC_SLAVE_COMMAND_PORT_NUMBER: u16 = 0xA0;
+pub const PIC_SLAVE_DATA_PORT_NUMBER: u16 = 0xA1;
+// Writing to an unused port takes long enough for the PICs to settle between initialization words
+const IO_WAIT_PORT_NUMBER: u16 = 0x80;
+
+// Lines per PIC, the slave is cascaded into IRQ 2 of the master
+pub const PIC_IRQ_COUNT: u8 = 8;
+const CASCADE_IRQ: u8 = 2;
+// The lowest priority line of each PIC, where spurious interrupts show up
+const SPURIOUS_IRQ: u8 = 7;
+
+static PICS: Mutex<ChainedPICs> = Mutex::new(ChainedPICs::new());
+static PIC_CONTROLLER: PICController = PICController;
+
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub enum PICCommand {}
+
+impl PICCommand {
+    // Intel 8259A datasheet
+    // ICW1: start initialization, an ICW4 will follow
+    pub const INIT: u8 = 0x11;
+    // ICW4: 8086 mode, normal EOI
+    pub const MODE_8086: u8 = 0x01;
+    // OCW2: non-specific end of interrupt
+    pub const END_OF_INTERRUPT: u8 = 0x20;
+    // OCW3: the next read of the command port returns the In-Service Register
+    pub const READ_IN_SERVICE_REGISTER: u8 = 0x0B;
+}
+
+#[derive(Debug)]
+pub struct PIC {
+    command: Port,
+    data: Port,
+    vector_base: u8,
+}
+
+impl PIC {
+    const fn new(command_port_number: u16, data_port_number: u16, vector_base: u8) -> Self {
+        PIC {
+            command: Port::new(command_port_number, true),
+            data: Port::new(data_port_number, true),
+            vector_base,
+        }
+    }
+
+    fn read_mask(&self) -> u8 {
+        self.data.read_byte_from_port()
+    }
+
+    fn write_mask(&self, mask: u8) {
+        self.data.write_byte_to_port(mask)
+    }
+
+    fn in_service(&self) -> u8 {
+        self.command.write_byte_to_port(PICCommand::READ_IN_SERVICE_REGISTER);
+        self.command.read_byte_from_port()
+    }
+
+    fn end_of_interrupt(&self) {
+        self.command.write_byte_to_port(PICCommand::END_OF_INTERRUPT)
+    }
+}
+
+#[derive(Debug)]
+pub struct ChainedPICs {
+    master: PIC,
+    slave: PIC,
+}
+
+impl ChainedPICs {
+    const fn new() -> Self {
+        let vector_base = InterruptVector::LEGACY_PIC_BASE as u8;
+        ChainedPICs {
+            master: PIC::new(PIC_MASTER_COMMAND_PORT_NUMBER, PIC_MASTER_DATA_PORT_NUMBER, vector_base),
+            slave: PIC::new(
+                PIC_SLAVE_COMMAND_PORT_NUMBER,
+                PIC_SLAVE_DATA_PORT_NUMBER,
+                vector_base + PIC_IRQ_COUNT,
+            ),
+        }
+    }
+
+    // The PICs come up delivering IRQs 0-7 on the vectors of the CPU exceptions. Moves both of them to their
+    // vector base with every line masked.
+    fn remap(&self) {
+        let io_wait = Port::new(IO_WAIT_PORT_NUMBER, true);
+        for (pic, cascade) in [(&self.master, 1 << CASCADE_IRQ), (&self.slave, CASCADE_IRQ)] {
+            pic.command.write_byte_to_port(PICCommand::INIT);
+            io_wait.write_byte_to_port(0);
+            pic.data.write_byte_to_port(pic.vector_base);
+            io_wait.write_byte_to_port(0);
+            // The master is told which line the slave is on, the slave which line of the master it is
+            pic.data.write_byte_to_port(cascade);
+            io_wait.write_byte_to_port(0);
+            pic.data.write_byte_to_port(PICCommand::MODE_8086);
+            io_wait.write_byte_to_port(0);
+        }
+        self.mask_all();
+    }
+
+    fn mask_all(&self) {
+        self.master.write_mask(0xFF);
+        self.slave.write_mask(0xFF);
+    }
+
+    fn pic(&self, irq: u8) -> (&PIC, u8) {
+        match irq < PIC_IRQ_COUNT {
+            true => (&self.master, irq),
+            false => (&self.slave, irq - PIC_IRQ_COUNT),
+        }
+    }
+
+    pub fn mask(&self, irq: u8) {
+        let (pic, line) = self.pic(irq);
+        pic.write_mask(pic.read_mask() | (1 << line));
+    }
+
+    // Lines of the slave only get through if the cascade line is unmasked as well
+    pub fn unmask(&self, irq: u8) {
+        let (pic, line) = self.pic(irq);
+        pic.write_mask(pic.read_mask() & !(1 << line));
+        if irq >= PIC_IRQ_COUNT {
+            self.master.write_mask(self.master.read_mask() & !(1 << CASCADE_IRQ));
+        }
+    }
+
+    // A line that was deasserted before the CPU acknowledged it is delivered as the lowest priority IRQ of its PIC
+    // without being marked in service. Those must not be acknowledged, except to the master for the cascade.
+    pub fn end_of_interrupt(&self, irq: u8) {
+        let (pic, line) = self.pic(irq);
+        let spurious = line == SPURIOUS_IRQ && pic.in_service() & (1 << SPURIOUS_IRQ) == 0;
+        match (irq >= PIC_IRQ_COUNT, spurious) {
+            (false, true) => {}
+            (true, true) => self.master.end_of_interrupt(),
+            (true, false) => {
+                self.slave.end_of_interrupt();
+                self.master.end_of_interrupt();
+            }
+            (false, false) => self.master.end_of_interrupt(),
+        }
+    }
+}
+
+// The PIC line a GSI arrives on. The PICs only know the ISA IRQs, which the MADT may have moved to other GSIs.
+fn gsi_pic_irq(gsi: u32) -> Option<u8> {
+    gsi_wiring(gsi)
+        .isa_irq
+        .filter(|&isa_irq| isa_irq < 2 * PIC_IRQ_COUNT && isa_irq != CASCADE_IRQ)
+}
+
+// Serves legacy IRQs when there is no IOAPIC. Every ISA IRQ has its own fixed vector.
+#[derive(Debug)]
+pub struct PICController;
+
+impl InterruptController for PICController {
+    fn name(&self) -> &'static str {
+        "8259 PIC"
+    }
+
+    fn route(&self, gsi: u32, vector: usize) -> Result<(), IrqError> {
+        match gsi_pic_irq(gsi) {
+            Some(irq) if self.fixed_vector(gsi) == Some(vector) => {
+                PICS.lock().unmask(irq);
+                Ok(())
+            }
+            _ => Err(IrqError::UnroutableGsi),
+        }
+    }
+
+    fn fixed_vector(&self, gsi: u32) -> Option<usize> {
+        gsi_pic_irq(gsi).map(|irq| InterruptVector::LEGACY_PIC_BASE + irq as usize)
+    }
+
+    fn mask(&self, gsi: u32) {
+        if let Some(irq) = gsi_pic_irq(gsi) {
+            PICS.lock().mask(irq);
+        }
+    }
+
+    fn end_of_interrupt(&self, gsi: u32, _vector: usize) {
+        if let Some(irq) = gsi_pic_irq(gsi) {
+            PICS.lock().end_of_interrupt(irq);
+        }
+    }
+}
+
+// Has to run before interrupts are enabled, so a stray PIC interrupt can't land on an exception vector. The
+// PICs stay masked until they are made the interrupt controller.
+pub fn init_pic() {
+    PICS.lock().remap();
+    log::info!(
+        "Remapped the 8259 PICs to vectors {:#X}-{:#X} and masked them",
+        InterruptVector::LEGACY_PIC_BASE,
+        InterruptVector::LEGACY_PIC_BASE + 2 * PIC_IRQ_COUNT as usize - 1
+    );
+}
+
+// Falls back to the PICs for legacy IRQs if no IOAPIC took over
+pub fn init_pic_fallback() {
+    if interrupt_controller().is_none() {
+        log::warn!("No IOAPIC available, falling back to the 8259 PICs");
+        set_interrupt_controller(&PIC_CONTROLLER);
+    }
+}
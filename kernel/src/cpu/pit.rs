@@ -6,6 +6,8 @@ pub const PIT_CHANNEL_0_PORT_NUMBER: u16 = 0x40;
 pub const PIT_CHANNEL_1_PORT_NUMBER: u16 = 0x41;
 pub const PIT_CHANNEL_2_PORT_NUMBER: u16 = 0x42;
 pub const PIT_COMMAND_PORT_NUMBER: u16 = 0x43;
+// Channel 0, low byte then high byte, mode 2 (rate generator). Unlike the square wave mode, it counts down by one.
+const PIT_CHANNEL_0_RATE_GENERATOR: u8 = 0x34;
 
 #[derive(Debug)]
 pub struct PIT {
@@ -35,6 +37,20 @@ impl PIT {
         self.channel_0.write_byte_to_port(high_byte);
     }
 
+    // Spins for at least `microseconds`. Channel 0 is only used as a clock here, its IRQ stays masked. The count
+    // wraps around every 65536 ticks, which is fine as long as it's read more often than that.
+    pub fn busy_wait_microseconds(&self, microseconds: usize) {
+        let mut remaining_ticks = microseconds * PIT_FREQUENCY / 1_000_000 + 1;
+        self.command.write_byte_to_port(PIT_CHANNEL_0_RATE_GENERATOR);
+        self.set_count(0);
+        let mut last_count = self.read_count();
+        while remaining_ticks > 0 {
+            let count = self.read_count();
+            remaining_ticks = remaining_ticks.saturating_sub(last_count.wrapping_sub(count) as usize);
+            last_count = count;
+        }
+    }
+
     pub fn read_count(&self) -> u16 {
         self.command.write_byte_to_port(0x00);
         let low_byte = self.channel_0.read_byte_from_port() as u16;
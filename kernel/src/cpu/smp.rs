@@ -0,0 +1,599 @@
+use alloc::boxed::Box;
+use core::arch::{
+    asm,
+    global_asm,
+};
+use core::fmt;
+use core::mem::offset_of;
+use core::ptr;
+use core::sync::atomic::{
+    AtomicBool,
+    AtomicPtr,
+    AtomicU32,
+    AtomicU8,
+    AtomicUsize,
+    Ordering,
+};
+
+use spin::{
+    Mutex,
+    MutexGuard,
+};
+
+use crate::acpi::ACPI_TABLES;
+use crate::cpu::control::{
+    init_protection_features,
+    is_nx_enabled,
+    Cr0Flags,
+    Cr4Flags,
+    EferFlags,
+};
+use crate::cpu::cpu_set::CpuSet;
+use crate::cpu::fpu::init_fpu;
+use crate::cpu::lapic::{
+    IpiFlags,
+    LocalAPIC,
+};
+use crate::cpu::msr::IA32_EFER;
+use crate::cpu::pit::PIT;
+use crate::cpu::{
+    current_apic_id,
+    current_cpu_index,
+    init_cpu_index,
+    local_apic,
+    MAX_CPUS,
+};
+use crate::interrupts::exception::idle;
+use crate::interrupts::irq::{
+    request_irq,
+    IrqReturn,
+};
+use crate::interrupts::load_idt;
+use crate::interrupts::softirq::{
+    raise_softirq,
+    register_softirq,
+    SoftIrq,
+};
+use crate::interrupts::InterruptVector;
+use crate::mmu::address::{
+    PhysicalAddress,
+    VirtualAddress,
+};
+use crate::mmu::vmm::address_space::kernel_pml4_frame;
+use crate::mmu::vmm::cache::init_pat;
+use crate::mmu::vmm::dma::{
+    DmaBuffer,
+    DmaMapping,
+};
+use crate::mmu::vmm::page_table::PageTable;
+use crate::mmu::vmm::page_table_entry::{
+    PageTableEntry,
+    PageTableEntryFlags,
+};
+use crate::mmu::vmm::tlb::init_tlb;
+use crate::mmu::vmm::vmalloc::vmalloc;
+use crate::mmu::vmm::Size;
+use crate::segmentation::init_ap_gdt;
+
+// Startup IPIs can only send an AP to a page in the first MiB
+const TRAMPOLINE_ADDRESS_LIMIT: usize = 0x10_0000;
+// The trampoline code comes first, its real mode stack grows down towards it from the data
+const TRAMPOLINE_DATA_OFFSET: usize = 0xF00;
+const TRAMPOLINE_STACK_SIZE: usize = 0x100;
+// The page tables the trampoline enables paging with follow the code page
+const TRAMPOLINE_PML4_OFFSET: usize = Size::FOUR_KIB;
+const TRAMPOLINE_PDPT_OFFSET: usize = 2 * Size::FOUR_KIB;
+const TRAMPOLINE_PD_OFFSET: usize = 3 * Size::FOUR_KIB;
+const TRAMPOLINE_SIZE: usize = 4 * Size::FOUR_KIB;
+
+// Flat segments for the way up to long mode, the kernel's GDT is only loaded once the AP runs Rust code
+const TRAMPOLINE_GDT: [u64; 4] = [0, 0x00CF_9A00_0000_FFFF, 0x00CF_9200_0000_FFFF, 0x00AF_9A00_0000_FFFF];
+const TRAMPOLINE_CODE_32_SELECTOR: usize = 0x08;
+const TRAMPOLINE_DATA_32_SELECTOR: usize = 0x10;
+const TRAMPOLINE_CODE_64_SELECTOR: usize = 0x18;
+
+const AP_STACK_SIZE: usize = 16 * Size::FOUR_KIB;
+
+// Intel Manual - Section 8.4.4.1: INIT, wait 10 ms, startup IPI, wait 200 µs, and a second startup IPI if the AP
+// didn't come up
+const INIT_DELAY_MICROSECONDS: usize = 10_000;
+const STARTUP_DELAY_MICROSECONDS: usize = 200;
+const AP_BOOT_TIMEOUT_MILLISECONDS: usize = 100;
+
+// CPU index of every APIC ID. All zero until init_smp fills it in, which keeps the BSP at index 0 from the start.
+static CPU_INDEX_BY_APIC_ID: [AtomicU8; 256] = [const { AtomicU8::new(0) }; 256];
+static APIC_ID_BY_CPU_INDEX: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(0) }; MAX_CPUS];
+// CPUs the MADT lists, and the ones of them that are running
+static POSSIBLE_CPUS: AtomicUsize = AtomicUsize::new(1);
+static ONLINE_CPUS: AtomicUsize = AtomicUsize::new(1);
+// APs sent startup IPIs that haven't reached ap_main yet. Whichever of the AP and the BSP clears the bit first
+// decides whether the AP comes up or is given up on.
+static STARTING_CPUS: AtomicUsize = AtomicUsize::new(0);
+// Top of the stack of each AP, found by the AP itself through its APIC ID
+static AP_STACK_TOPS: [AtomicUsize; MAX_CPUS] = [const { AtomicUsize::new(0) }; MAX_CPUS];
+// Calls queued for each CPU by the others
+static CPU_CALLS: [AtomicPtr<CpuCall>; MAX_CPUS] = [const { AtomicPtr::new(ptr::null_mut()) }; MAX_CPUS];
+
+#[derive(Debug, Clone, Copy)]
+pub enum SmpError {
+    InvalidCpu,
+    CpuOffline,
+    OutOfMemory,
+    Timeout,
+}
+
+impl fmt::Display for SmpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidCpu => f.write_str("SMP Error: CPU index is out of range"),
+            Self::CpuOffline => f.write_str("SMP Error: CPU is not online"),
+            Self::OutOfMemory => f.write_str("SMP Error: Out of memory for the AP trampoline or stack"),
+            Self::Timeout => f.write_str("SMP Error: AP didn't come up after its startup IPIs"),
+        }
+    }
+}
+
+#[inline]
+pub fn cpu_index_of(apic_id: u32) -> usize {
+    CPU_INDEX_BY_APIC_ID[apic_id as usize % 256].load(Ordering::Relaxed) as usize
+}
+
+#[inline]
+pub fn apic_id_of(cpu_index: usize) -> u32 {
+    APIC_ID_BY_CPU_INDEX[cpu_index].load(Ordering::Relaxed)
+}
+
+#[inline]
+pub fn possible_cpus() -> CpuSet {
+    CpuSet::from_bits(POSSIBLE_CPUS.load(Ordering::Acquire))
+}
+
+#[inline]
+pub fn online_cpus() -> CpuSet {
+    CpuSet::from_bits(ONLINE_CPUS.load(Ordering::Acquire))
+}
+
+#[inline]
+pub fn is_cpu_online(cpu_index: usize) -> bool {
+    online_cpus().contains(cpu_index)
+}
+
+// Read by the trampoline at TRAMPOLINE_DATA_OFFSET
+#[derive(Debug)]
+#[repr(C)]
+struct TrampolineData {
+    gdt: [u64; 4],
+    // Puts the base of the GDT pointer on a 4 byte boundary
+    _padding: [u16; 3],
+    gdt_limit: u16,
+    gdt_base: u32,
+    // Ored into EFER before paging is enabled
+    efer: u32,
+    pml4: u32,
+    kernel_cr3: u64,
+    entry: u64,
+}
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+}
+
+// Copied to the start of the trampoline page, where the APs start in real mode with CS set to its page. It only
+// uses addresses relative to itself, and takes everything it needs from the data after it.
+global_asm!(
+    ".pushsection .rodata.ap_trampoline, \"a\"",
+    ".global ap_trampoline_start",
+    ".global ap_trampoline_end",
+    ".code16",
+    "ap_trampoline_start:",
+    "cli",
+    "cld",
+    "movw %cs, %ax",
+    "movw %ax, %ds",
+    "movw %ax, %ss",
+    "movw ${data}, %sp",
+    // EBX holds the physical address of the trampoline from here on
+    "xorl %ebx, %ebx",
+    "movw %ax, %bx",
+    "shll $4, %ebx",
+    "lgdtl {gdt_pointer}",
+    "movl %cr0, %eax",
+    "orl ${protection_enable}, %eax",
+    "movl %eax, %cr0",
+    "leal (.Lap_protected_mode - ap_trampoline_start)(%ebx), %eax",
+    "pushl ${code_32}",
+    "pushl %eax",
+    "lretl",
+    ".code32",
+    ".Lap_protected_mode:",
+    "movw ${data_32}, %ax",
+    "movw %ax, %ds",
+    "movw %ax, %es",
+    "movw %ax, %ss",
+    "leal {data}(%ebx), %esp",
+    "movl %cr4, %eax",
+    "orl ${physical_address_extension}, %eax",
+    "movl %eax, %cr4",
+    "movl {pml4}(%ebx), %eax",
+    "movl %eax, %cr3",
+    "movl ${efer_msr}, %ecx",
+    "rdmsr",
+    "orl {efer}(%ebx), %eax",
+    "wrmsr",
+    "movl %cr0, %eax",
+    "orl ${paging}, %eax",
+    "movl %eax, %cr0",
+    "leal (.Lap_long_mode - ap_trampoline_start)(%ebx), %eax",
+    "pushl ${code_64}",
+    "pushl %eax",
+    "lretl",
+    ".code64",
+    ".Lap_long_mode:",
+    // The upper half of RBX is undefined after the switch
+    "movl %ebx, %ebx",
+    "movq {kernel_cr3}(%rbx), %rdx",
+    "jmpq *{entry}(%rbx)",
+    "ap_trampoline_end:",
+    ".popsection",
+    data = const TRAMPOLINE_DATA_OFFSET,
+    gdt_pointer = const TRAMPOLINE_DATA_OFFSET + offset_of!(TrampolineData, gdt_limit),
+    efer = const TRAMPOLINE_DATA_OFFSET + offset_of!(TrampolineData, efer),
+    pml4 = const TRAMPOLINE_DATA_OFFSET + offset_of!(TrampolineData, pml4),
+    kernel_cr3 = const TRAMPOLINE_DATA_OFFSET + offset_of!(TrampolineData, kernel_cr3),
+    entry = const TRAMPOLINE_DATA_OFFSET + offset_of!(TrampolineData, entry),
+    code_32 = const TRAMPOLINE_CODE_32_SELECTOR,
+    data_32 = const TRAMPOLINE_DATA_32_SELECTOR,
+    code_64 = const TRAMPOLINE_CODE_64_SELECTOR,
+    protection_enable = const Cr0Flags::PROTECTION_ENABLE,
+    paging = const Cr0Flags::PAGING,
+    physical_address_extension = const Cr4Flags::PHYSICAL_ADDRESS_EXTENSION,
+    efer_msr = const IA32_EFER,
+    options(att_syntax)
+);
+
+// The trampoline jumps here still on its own page tables, with the kernel's PML4 in RDX. Nothing about the AP
+// itself goes through the trampoline, an AP that comes up late can't pick up the one started after it. The initial
+// APIC ID gives its CPU index, and the index its stack.
+#[naked]
+unsafe extern "C" fn ap_long_mode_entry() -> ! {
+    core::arch::asm!(
+        "mov cr3, rdx",
+        "mov eax, 1",
+        "cpuid",
+        "shr ebx, 24",
+        "lea rax, [rip + {cpu_index_by_apic_id}]",
+        "movzx edi, byte ptr [rax + rbx]",
+        "lea rax, [rip + {ap_stack_tops}]",
+        "mov rsp, [rax + rdi * 8]",
+        "xor ebp, ebp",
+        "call {ap_main}",
+        "ud2",
+        cpu_index_by_apic_id = sym CPU_INDEX_BY_APIC_ID,
+        ap_stack_tops = sym AP_STACK_TOPS,
+        ap_main = sym ap_main,
+        options(noreturn)
+    )
+}
+
+// Every CPU sets up its own control registers, GDT and LAPIC. It idles once it's online, until it's given work.
+extern "C" fn ap_main(cpu_index: usize) -> ! {
+    // The BSP gave up on this AP and sent it INIT, which may not have arrived yet
+    if STARTING_CPUS.fetch_and(!(1 << cpu_index), Ordering::AcqRel) & (1 << cpu_index) == 0 {
+        loop {
+            unsafe { asm!("cli", "hlt", options(nomem, nostack)) };
+        }
+    }
+    // Anything touching per-CPU state needs the index in place first
+    init_cpu_index(cpu_index);
+    init_ap_gdt();
+    load_idt();
+    init_protection_features();
+    init_fpu();
+    init_tlb();
+    init_pat();
+    local_apic().enable();
+    ONLINE_CPUS.fetch_or(1 << cpu_index, Ordering::AcqRel);
+    log::info!("CPU {} online, APIC ID {}", cpu_index, current_apic_id());
+    idle()
+}
+
+// Copies the trampoline to low memory, along with the page tables it enables paging with: the kernel's PML4 with
+// its first entry replaced by an identity mapping of the first 2 MiB, where the trampoline runs until it jumps to
+// the kernel
+fn setup_trampoline(trampoline: &mut DmaBuffer) {
+    let trampoline_address = trampoline.physical_address().inner;
+    let code_start = core::ptr::addr_of!(ap_trampoline_start);
+    let code_size = core::ptr::addr_of!(ap_trampoline_end) as usize - code_start as usize;
+    assert!(
+        code_size + TRAMPOLINE_STACK_SIZE <= TRAMPOLINE_DATA_OFFSET,
+        "AP trampoline overlaps its data!"
+    );
+    let entry = ap_long_mode_entry as usize;
+    assert_ne!(
+        VirtualAddress::new(entry).get_pml4_index(),
+        0,
+        "The AP entry is hidden by the trampoline's identity mapping!"
+    );
+    unsafe {
+        ptr::copy_nonoverlapping(code_start, trampoline.as_ptr(), code_size);
+        let table = |offset: usize| &mut *(trampoline.as_ptr().add(offset) as *mut PageTable);
+        let table_flags = PageTableEntryFlags::PRESENT | PageTableEntryFlags::WRITE_ACCESS;
+        let pml4 = table(TRAMPOLINE_PML4_OFFSET);
+        *pml4 = *kernel_pml4_frame().frame_to_page_table(VirtualAddress::kernel_base());
+        pml4[0] = PageTableEntry::new(
+            table_flags,
+            PhysicalAddress::new(trampoline_address + TRAMPOLINE_PDPT_OFFSET),
+        );
+        table(TRAMPOLINE_PDPT_OFFSET)[0] = PageTableEntry::new(
+            table_flags,
+            PhysicalAddress::new(trampoline_address + TRAMPOLINE_PD_OFFSET),
+        );
+        table(TRAMPOLINE_PD_OFFSET)[0] = PageTableEntry::new(
+            table_flags | PageTableEntryFlags::LARGE_PAGE_SIZE,
+            PhysicalAddress::new(0),
+        );
+    }
+    // Kernel mappings use the NX bit, which is reserved until EFER.NXE is set
+    let efer = match is_nx_enabled() {
+        true => EferFlags::LONG_MODE_ENABLE | EferFlags::NO_EXECUTE_ENABLE,
+        false => EferFlags::LONG_MODE_ENABLE,
+    };
+    let trampoline_data = TrampolineData {
+        gdt: TRAMPOLINE_GDT,
+        _padding: [0; 3],
+        gdt_limit: (core::mem::size_of_val(&TRAMPOLINE_GDT) - 1) as u16,
+        gdt_base: (trampoline_address + TRAMPOLINE_DATA_OFFSET + offset_of!(TrampolineData, gdt)) as u32,
+        efer: efer as u32,
+        pml4: (trampoline_address + TRAMPOLINE_PML4_OFFSET) as u32,
+        kernel_cr3: kernel_pml4_frame().start_address() as u64,
+        entry: entry as u64,
+    };
+    unsafe { trampoline_data_ptr(trampoline).write(trampoline_data) };
+}
+
+#[inline]
+fn trampoline_data_ptr(trampoline: &DmaBuffer) -> *mut TrampolineData {
+    unsafe { trampoline.as_ptr().add(TRAMPOLINE_DATA_OFFSET) as *mut TrampolineData }
+}
+
+// APs are started one at a time, they all run on the same real mode stack in the trampoline. One that doesn't
+// answer in time is sent INIT, which parks it until the next startup IPI.
+fn start_ap(local_apic: &LocalAPIC, trampoline: &DmaBuffer, cpu_index: usize) -> Result<(), SmpError> {
+    let stack = vmalloc(AP_STACK_SIZE).map_err(|_| SmpError::OutOfMemory)?;
+    AP_STACK_TOPS[cpu_index].store(stack.address().inner + AP_STACK_SIZE, Ordering::Release);
+    // The stack stays with the AP for good, whether or not it answers
+    core::mem::forget(stack);
+    STARTING_CPUS.fetch_or(1 << cpu_index, Ordering::AcqRel);
+
+    let apic_id = apic_id_of(cpu_index);
+    let startup_page = (trampoline.physical_address().inner / Size::FOUR_KIB) as u32;
+    let pit = PIT::new();
+    local_apic.send_ipi(apic_id, IpiFlags::INIT | IpiFlags::LEVEL_ASSERT);
+    pit.busy_wait_microseconds(INIT_DELAY_MICROSECONDS);
+    // An AP that already left the wait for startup state ignores the second one
+    for _ in 0..2 {
+        local_apic.send_ipi(apic_id, IpiFlags::STARTUP | startup_page);
+        pit.busy_wait_microseconds(STARTUP_DELAY_MICROSECONDS);
+        if is_cpu_online(cpu_index) {
+            return Ok(());
+        }
+    }
+    for _ in 0..AP_BOOT_TIMEOUT_MILLISECONDS {
+        if is_cpu_online(cpu_index) {
+            return Ok(());
+        }
+        pit.busy_wait_microseconds(1000);
+    }
+    // An AP that made it to ap_main first is about to come online
+    if STARTING_CPUS.fetch_and(!(1 << cpu_index), Ordering::AcqRel) & (1 << cpu_index) == 0 {
+        return Ok(());
+    }
+    local_apic.send_ipi(apic_id, IpiFlags::INIT | IpiFlags::LEVEL_ASSERT);
+    // Keeps the next AP off the trampoline stack until INIT took effect
+    pit.busy_wait_microseconds(INIT_DELAY_MICROSECONDS);
+    Err(SmpError::Timeout)
+}
+
+// Gives every enabled processor in the MADT a CPU index, the BSP keeps 0. Returns how many there are.
+fn assign_cpu_indices() -> usize {
+    let bsp_apic_id = current_apic_id();
+    APIC_ID_BY_CPU_INDEX[0].store(bsp_apic_id, Ordering::Relaxed);
+    let processor_local_apic_records = ACPI_TABLES
+        .get()
+        .unwrap()
+        .madt
+        .apic_structures
+        .processor_local_apic_records;
+    let mut cpu_count = 1;
+    for processor_local_apic in processor_local_apic_records.iter().flatten() {
+        let apic_id = processor_local_apic.lapic_id as u32;
+        if apic_id == bsp_apic_id {
+            continue;
+        }
+        if cpu_count == MAX_CPUS {
+            log::warn!("More than {} CPUs, APIC ID {} stays offline", MAX_CPUS, apic_id);
+            continue;
+        }
+        CPU_INDEX_BY_APIC_ID[apic_id as usize].store(cpu_count as u8, Ordering::Relaxed);
+        APIC_ID_BY_CPU_INDEX[cpu_count].store(apic_id, Ordering::Relaxed);
+        cpu_count += 1;
+    }
+    POSSIBLE_CPUS.store(usize::MAX >> (usize::BITS as usize - cpu_count), Ordering::Release);
+    cpu_count
+}
+
+// Starts every AP the MADT lists through INIT and startup IPIs. Needs the heap, vmalloc and the IRQ manager.
+pub fn init_smp() {
+    register_softirq(SoftIrq::CPU_CALL, run_cpu_calls);
+    request_irq(
+        InterruptVector::CPU_CALL,
+        "cpu-call",
+        0,
+        Box::new(|_| {
+            raise_softirq(SoftIrq::CPU_CALL);
+            IrqReturn::Handled
+        }),
+    )
+    .expect("Failed to register the CPU call handler");
+
+    let cpu_count = assign_cpu_indices();
+    if cpu_count == 1 {
+        log::info!("No APs to start");
+        return;
+    }
+    let mut trampoline = match DmaBuffer::new(
+        TRAMPOLINE_SIZE,
+        TRAMPOLINE_SIZE,
+        TRAMPOLINE_ADDRESS_LIMIT,
+        DmaMapping::Coherent,
+    ) {
+        Ok(trampoline) => trampoline,
+        Err(dma_error) => {
+            log::error!("No low memory for the AP trampoline, staying on one CPU: {}", dma_error);
+            return;
+        }
+    };
+    setup_trampoline(&mut trampoline);
+    let local_apic = local_apic();
+    local_apic.enable();
+    let mut timed_out = false;
+    for cpu_index in 1..cpu_count {
+        if let Err(smp_error) = start_ap(local_apic, &trampoline, cpu_index) {
+            log::error!(
+                "Failed to start CPU {} (APIC ID {}): {}",
+                cpu_index,
+                apic_id_of(cpu_index),
+                smp_error
+            );
+            timed_out |= matches!(smp_error, SmpError::Timeout);
+        }
+    }
+    // Every AP that came up is done with the trampoline. An AP that timed out could still be in it, INIT or not,
+    // so it's never given back then.
+    if timed_out {
+        core::mem::forget(trampoline);
+    }
+    log::info!(
+        "{} of {} CPUs online: {}",
+        online_cpus().len(),
+        cpu_count,
+        online_cpus()
+    );
+    // An AP that's online has to take calls from the others as well
+    for cpu_index in online_cpus().iter().filter(|&cpu_index| cpu_index != 0) {
+        match run_on_cpu(cpu_index, current_cpu_index) {
+            Ok(answering_cpu) => log::info!("CPU {} answered a call from CPU 0", answering_cpu),
+            Err(smp_error) => log::error!("CPU {} didn't answer a call: {}", cpu_index, smp_error),
+        }
+    }
+}
+
+// A function queued for another CPU. It lives on the stack of the caller, which waits for it to be done.
+struct CpuCall {
+    func: unsafe fn(*mut ()),
+    data: *mut (),
+    done: AtomicBool,
+    next: AtomicPtr<CpuCall>,
+}
+
+// `data` points at the closure and the slot for its result
+unsafe fn call_closure<F, R>(data: *mut ())
+where
+    F: FnOnce() -> R,
+{
+    let (func, result) = &mut *(data as *mut (Option<F>, Option<R>));
+    *result = func.take().map(|func| func());
+}
+
+// The CPU_CALL softirq, and whoever waits in run_on_cpu. Calls run in no particular order, usually with interrupts
+// enabled.
+fn run_cpu_calls() {
+    let mut call_ptr = CPU_CALLS[current_cpu_index()].swap(ptr::null_mut(), Ordering::AcqRel);
+    while !call_ptr.is_null() {
+        let call = unsafe { &*call_ptr };
+        // The caller's stack frame is gone once the call is done
+        call_ptr = call.next.load(Ordering::Relaxed);
+        unsafe { (call.func)(call.data) };
+        call.done.store(true, Ordering::Release);
+    }
+}
+
+// Runs `func` on the CPU and returns its result. On another CPU it runs from a softirq, so it must not sleep.
+// Calls queued for this CPU are run while it waits, so CPUs waiting on each other, or a caller inside the CPU_CALL
+// softirq, make progress even though the softirq can't run. The other CPU mustn't spin with interrupts disabled
+// on something this one holds.
+pub fn run_on_cpu<F, R>(cpu_index: usize, func: F) -> Result<R, SmpError>
+where
+    F: FnOnce() -> R + Send,
+    R: Send,
+{
+    if cpu_index >= MAX_CPUS {
+        return Err(SmpError::InvalidCpu);
+    }
+    if !is_cpu_online(cpu_index) {
+        return Err(SmpError::CpuOffline);
+    }
+    if cpu_index == current_cpu_index() {
+        return Ok(func());
+    }
+    let mut closure: (Option<F>, Option<R>) = (Some(func), None);
+    let call = CpuCall {
+        func: call_closure::<F, R>,
+        data: &mut closure as *mut (Option<F>, Option<R>) as *mut (),
+        done: AtomicBool::new(false),
+        next: AtomicPtr::new(ptr::null_mut()),
+    };
+    let call_ptr = &call as *const CpuCall as *mut CpuCall;
+    let calls = &CPU_CALLS[cpu_index];
+    let mut head = calls.load(Ordering::Acquire);
+    loop {
+        call.next.store(head, Ordering::Relaxed);
+        match calls.compare_exchange_weak(head, call_ptr, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => break,
+            Err(current_head) => head = current_head,
+        }
+    }
+    local_apic().send_ipi(
+        apic_id_of(cpu_index),
+        IpiFlags::FIXED | InterruptVector::CPU_CALL as u32,
+    );
+    let own_calls = &CPU_CALLS[current_cpu_index()];
+    while !call.done.load(Ordering::Acquire) {
+        match own_calls.load(Ordering::Acquire).is_null() {
+            true => core::hint::spin_loop(),
+            false => run_cpu_calls(),
+        }
+    }
+    Ok(closure.1.take().unwrap())
+}
+
+// Runs `func` on every CPU of the set, one after the other. Nothing runs if any of them is offline.
+pub fn run_on_cpus<F>(cpu_set: CpuSet, func: F) -> Result<(), SmpError>
+where
+    F: Fn(usize) + Sync,
+{
+    if cpu_set.iter().any(|cpu_index| !is_cpu_online(cpu_index)) {
+        return Err(SmpError::CpuOffline);
+    }
+    let func = &func;
+    for cpu_index in cpu_set.iter() {
+        run_on_cpu(cpu_index, move || func(cpu_index))?;
+    }
+    Ok(())
+}
+
+// Takes the lock, running the calls queued for this CPU while it spins. For locks taken with interrupts disabled
+// whose holder may be waiting for this CPU to answer a call, like an address space being shot down.
+pub fn lock_answering_calls<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    let own_calls = &CPU_CALLS[current_cpu_index()];
+    loop {
+        if let Some(guard) = mutex.try_lock() {
+            return guard;
+        }
+        match own_calls.load(Ordering::Acquire).is_null() {
+            true => core::hint::spin_loop(),
+            false => run_cpu_calls(),
+        }
+    }
+}
@@ -1,8 +1,12 @@
 use core::arch::asm;
 
+use crate::util::bits::is_bit_set;
+
+const RFLAGS_INTERRUPT_FLAG: usize = 1 << 9;
+
 #[macro_export]
 macro_rules! interrupt {
-    ($interrupt_name:ident, $rust_secondary_handler:ident) => {
+    ($interrupt_name:ident, $rust_secondary_handler:ident, $vector:expr) => {
         #[naked]
         pub unsafe extern "C" fn $interrupt_name() {
             core::arch::asm!(
@@ -28,8 +32,11 @@ macro_rules! interrupt {
                     "mov fs, ax",
                     "mov gs, ax",
                     "mov rdi, rsp",
-                    "sub rsp, 8",
-                    "call {}",
+                    "mov rsi, {vector}",
+                    "lea rdx, [rip + {secondary_handler}]",
+                    // The CPU aligns the stack to 16 bytes before pushing its 5 qword frame, so with the 15
+                    // registers on top it's aligned again for the call
+                    "call {trace}",
                     "pop r15",
                     "pop r14",
                     "pop r13",
@@ -46,7 +53,9 @@ macro_rules! interrupt {
                     "pop rbx",
                     "pop rax",
                     "iretq",
-                    sym $rust_secondary_handler,
+                    vector = const $vector,
+                    secondary_handler = sym $rust_secondary_handler,
+                    trace = sym $crate::interrupts::stats::trace_interrupt,
                 options(noreturn))
         }
     };
@@ -55,7 +64,7 @@ macro_rules! interrupt {
 
 #[macro_export]
 macro_rules! interrupt_with_error_code {
-    ($interrupt_name:ident, $rust_secondary_handler:ident) => {
+    ($interrupt_name:ident, $rust_secondary_handler:ident, $vector:expr) => {
         #[naked]
         pub unsafe extern "C" fn $interrupt_name() {
             core::arch::asm!(
@@ -81,8 +90,12 @@ macro_rules! interrupt_with_error_code {
                     "mov fs, ax",
                     "mov gs, ax",
                     "mov rdi, rsp",
+                    "mov rsi, {vector}",
+                    "lea rdx, [rip + {secondary_handler}]",
+                    // The error code leaves the stack 8 bytes off the alignment the call needs
                     "sub rsp, 8",
-                    "call {}",
+                    "call {trace}",
+                    "add rsp, 8",
                     "pop r15",
                     "pop r14",
                     "pop r13",
@@ -100,7 +113,9 @@ macro_rules! interrupt_with_error_code {
                     "pop rax",
                     "add rsp, 8",
                     "iretq",
-                    sym $rust_secondary_handler,
+                    vector = const $vector,
+                    secondary_handler = sym $rust_secondary_handler,
+                    trace = sym $crate::interrupts::stats::trace_interrupt_with_error_code,
                     options(noreturn),
                     )
         }
@@ -110,3 +125,27 @@ macro_rules! interrupt_with_error_code {
 pub unsafe fn enable_interrupts() {
     asm!("sti", options(nomem, nostack));
 }
+
+pub unsafe fn disable_interrupts() {
+    asm!("cli", options(nomem, nostack));
+}
+
+pub fn interrupts_enabled() -> bool {
+    let rflags: usize;
+    unsafe { asm!("pushfq", "pop {}", out(reg) rflags, options(nomem, preserves_flags)) };
+    is_bit_set(rflags, RFLAGS_INTERRUPT_FLAG)
+}
+
+// Runs `f` with interrupts disabled on this CPU, and restores them to how they were after. For locks that are
+// also taken by interrupt handlers.
+pub fn without_interrupts<T>(f: impl FnOnce() -> T) -> T {
+    let were_enabled = interrupts_enabled();
+    if were_enabled {
+        unsafe { disable_interrupts() };
+    }
+    let result = f();
+    if were_enabled {
+        unsafe { enable_interrupts() };
+    }
+    result
+}
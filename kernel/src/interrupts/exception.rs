@@ -0,0 +1,248 @@
+use core::arch::asm;
+use core::fmt;
+use core::sync::atomic::{
+    AtomicUsize,
+    Ordering,
+};
+
+use crate::cpu::{
+    current_cpu_index,
+    MAX_CPUS,
+};
+use crate::interrupts::asm::{
+    disable_interrupts,
+    enable_interrupts,
+};
+use crate::interrupts::workqueue::SYSTEM_WORK_QUEUE;
+use crate::interrupts::{
+    ExcRegisterState,
+    ExceptionStackFrame,
+    ExceptionStackFrameWithErrorCode,
+    InterruptVector,
+};
+use crate::mmu::vmm::address_space::switch_to_kernel;
+use crate::mmu::vmm::vmalloc::vmalloc;
+use crate::process::{
+    current_process_id,
+    kill_process,
+    RegisterState,
+};
+use crate::segmentation::tss::STACK_SIZE;
+use crate::util::bits::is_bit_set;
+
+// Top of the stack each CPU idles on after killing a faulting process. Allocated on first use
+static IDLE_STACKS: [AtomicUsize; MAX_CPUS] = [const { AtomicUsize::new(0) }; MAX_CPUS];
+
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub enum SelectorErrorCodeFlags {}
+
+impl SelectorErrorCodeFlags {
+    // Intel Manual - Section 6.13
+    // The exception was raised while delivering an event external to the program, like an interrupt
+    pub const EXTERNAL: usize = 1;
+    // The index refers to a gate in the IDT. The table indicator bit is meaningless then
+    pub const DESCRIPTOR_LOCATION_IDT: usize = 1 << 1;
+    // The index refers to the LDT instead of the GDT
+    pub const TABLE_INDICATOR_LDT: usize = 1 << 2;
+    pub const INDEX_SHIFT: usize = 3;
+    pub const INDEX_MASK: usize = 0x1FFF;
+}
+
+// The error code #TS, #NP, #SS and #GP push to point at the segment selector or IDT gate at fault
+#[derive(Debug, Clone, Copy)]
+pub struct SelectorErrorCode {
+    inner: usize,
+}
+
+impl SelectorErrorCode {
+    #[inline]
+    pub fn new(error_code: usize) -> Self {
+        SelectorErrorCode { inner: error_code }
+    }
+
+    #[inline]
+    pub fn index(&self) -> usize {
+        (self.inner >> SelectorErrorCodeFlags::INDEX_SHIFT) & SelectorErrorCodeFlags::INDEX_MASK
+    }
+
+    #[inline]
+    pub fn is_external(&self) -> bool {
+        is_bit_set(self.inner, SelectorErrorCodeFlags::EXTERNAL)
+    }
+
+    pub fn table(&self) -> &'static str {
+        match (
+            is_bit_set(self.inner, SelectorErrorCodeFlags::DESCRIPTOR_LOCATION_IDT),
+            is_bit_set(self.inner, SelectorErrorCodeFlags::TABLE_INDICATOR_LDT),
+        ) {
+            (true, _) => "IDT",
+            (false, true) => "LDT",
+            (false, false) => "GDT",
+        }
+    }
+}
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // #GP and #SS push 0 when the fault isn't caused by a selector at all
+        if self.inner == 0 {
+            return f.write_str("Not selector related");
+        }
+        f.write_fmt(format_args!(
+            "{} index {} External: {}",
+            self.table(),
+            self.index(),
+            self.is_external()
+        ))
+    }
+}
+
+// Everything known about an exception at the time it was raised
+#[derive(Debug, Clone, Copy)]
+pub struct FaultReport<'a> {
+    pub vector: usize,
+    pub error_code: Option<usize>,
+    pub interrupt_registers: &'a ExcRegisterState,
+    pub execution_state: &'a RegisterState,
+}
+
+impl<'a> FaultReport<'a> {
+    pub fn new(vector: usize, exception_stack_frame: &'a ExceptionStackFrame) -> Self {
+        FaultReport {
+            vector,
+            error_code: None,
+            interrupt_registers: &exception_stack_frame.interrupt_registers,
+            execution_state: &exception_stack_frame.execution_state,
+        }
+    }
+
+    pub fn with_error_code(vector: usize, exception_stack_frame: &'a ExceptionStackFrameWithErrorCode) -> Self {
+        FaultReport {
+            vector,
+            error_code: Some(exception_stack_frame.error_code),
+            interrupt_registers: &exception_stack_frame.interrupt_registers,
+            execution_state: &exception_stack_frame.execution_state,
+        }
+    }
+
+    // Whether the CPU was running user code, going by the privilege level of the interrupted code segment
+    #[inline]
+    pub fn is_user_mode(&self) -> bool {
+        self.interrupt_registers.cs & 0b11 == 3
+    }
+
+    fn uses_selector_error_code(&self) -> bool {
+        matches!(
+            self.vector,
+            InterruptVector::INVALID_TSS
+                | InterruptVector::SEGMENT_NOT_PRESENT
+                | InterruptVector::STACK_SEGMENT_FAULT
+                | InterruptVector::GENERAL_PROTECTION
+        )
+    }
+}
+
+impl fmt::Display for FaultReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_fmt(format_args!(
+            "{} EXCEPTION (vector {}) in {} mode\n",
+            InterruptVector::name(self.vector),
+            self.vector,
+            if self.is_user_mode() { "user" } else { "kernel" }
+        ))?;
+        match self.error_code {
+            Some(error_code) if self.uses_selector_error_code() => f.write_fmt(format_args!(
+                "ERROR CODE: {:#X} - {}\n",
+                error_code,
+                SelectorErrorCode::new(error_code)
+            ))?,
+            Some(error_code) => f.write_fmt(format_args!("ERROR CODE: {:#X}\n", error_code))?,
+            None => {}
+        }
+        f.write_fmt(format_args!(
+            "EXCEPTION REGISTERS: {}\nEXECUTION STATE: {}",
+            self.interrupt_registers, self.execution_state
+        ))
+    }
+}
+
+// Faults raised by user code only take down the process that caused them. Anything the kernel does wrong is fatal.
+pub fn handle_fault(fault_report: &FaultReport) -> ! {
+    match fault_report.is_user_mode() {
+        true => {
+            log::warn!("{}", fault_report);
+            terminate_faulting_process()
+        }
+        false => {
+            log::error!("{}", fault_report);
+            panic!(
+                "Unhandled {} in kernel mode",
+                InterruptVector::name(fault_report.vector)
+            );
+        }
+    }
+}
+
+// Kills the process whose address space is loaded. There is no scheduler to pick something else to run yet, so
+// the CPU is left idling instead.
+pub fn terminate_faulting_process() -> ! {
+    match current_process_id() {
+        Some(process_id) => {
+            log::warn!("Killing process {}", process_id);
+            kill_process(process_id);
+        }
+        None => log::warn!("Faulting user code doesn't belong to any process"),
+    }
+    // Nothing may return to the user code, whether or not it had a process
+    unsafe { switch_to_kernel() };
+    unsafe { idle_on_stack(idle_stack()) }
+}
+
+// The handler may be running on an IST stack, which the next exception using it would reset right under the idle
+// loop. Nothing that was running before is ever returned to, so the same stack can be reused every time.
+fn idle_stack() -> usize {
+    let idle_stack = &IDLE_STACKS[current_cpu_index()];
+    match idle_stack.load(Ordering::Acquire) {
+        0 => {
+            let stack = vmalloc(STACK_SIZE).expect("Failed to allocate an idle stack");
+            let stack_top = stack.as_ptr() as usize + STACK_SIZE;
+            core::mem::forget(stack);
+            idle_stack.store(stack_top, Ordering::Release);
+            stack_top
+        }
+        stack_top => stack_top,
+    }
+}
+
+unsafe fn idle_on_stack(stack_top: usize) -> ! {
+    asm!(
+        "mov rsp, {}",
+        "xor ebp, ebp",
+        "call {}",
+        "ud2",
+        in(reg) stack_top,
+        sym idle_entry,
+        options(noreturn)
+    );
+}
+
+extern "C" fn idle_entry() -> ! {
+    idle()
+}
+
+// Stands in for the worker threads of the system work queue until there is a scheduler. Where the APs end up
+// once they are online.
+pub fn idle() -> ! {
+    loop {
+        unsafe { enable_interrupts() };
+        SYSTEM_WORK_QUEUE.run_pending();
+        unsafe { disable_interrupts() };
+        match SYSTEM_WORK_QUEUE.has_pending_work() {
+            true => continue,
+            // sti only takes effect after the next instruction, so work queued by an IRQ can't slip in between the
+            // check and the hlt
+            false => unsafe { asm!("sti", "hlt", options(nomem, nostack)) },
+        }
+    }
+}
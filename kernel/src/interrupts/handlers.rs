@@ -1,58 +1,281 @@
+use core::sync::atomic::{
+    AtomicUsize,
+    Ordering,
+};
+
 use log;
 
-use crate::cpu::LOCAL_APIC;
+use crate::cpu::fpu::{
+    handle_device_not_available,
+    read_mxcsr,
+    SimdExceptions,
+};
+use crate::cpu::{
+    current_cpu_index,
+    MAX_CPUS,
+};
+use crate::interrupts::exception::{
+    handle_fault,
+    FaultReport,
+};
+use crate::interrupts::workqueue::{
+    schedule_work,
+    Work,
+};
 use crate::interrupts::{
     ExceptionStackFrame,
     ExceptionStackFrameWithErrorCode,
+    InterruptVector,
+};
+use crate::mmu::vmm::fault::{
+    handle_page_fault,
+    PageFault,
 };
 use crate::{
     interrupt,
     interrupt_with_error_code,
 };
 
+// Secondary handlers of the exceptions that are always faults: fatal in the kernel, the end of the process in user
+// code
+macro_rules! fault_handler {
+    ($secondary_handler:ident, $vector:expr) => {
+        #[no_mangle]
+        pub extern "C" fn $secondary_handler(exception_stack_frame: &mut ExceptionStackFrame) {
+            handle_fault(&FaultReport::new($vector, exception_stack_frame));
+        }
+    };
+    ($secondary_handler:ident, $vector:expr, error_code) => {
+        #[no_mangle]
+        pub extern "C" fn $secondary_handler(exception_stack_frame: &mut ExceptionStackFrameWithErrorCode) {
+            handle_fault(&FaultReport::with_error_code($vector, exception_stack_frame));
+        }
+    };
+}
+
+fault_handler!(divide_by_zero_secondary_handler, InterruptVector::DIVIDE_ERROR);
+fault_handler!(overflow_secondary_handler, InterruptVector::OVERFLOW);
+fault_handler!(
+    bound_range_exceeded_secondary_handler,
+    InterruptVector::BOUND_RANGE_EXCEEDED
+);
+fault_handler!(invalid_opcode_secondary_handler, InterruptVector::INVALID_OPCODE);
+fault_handler!(
+    coprocessor_segment_overrun_secondary_handler,
+    InterruptVector::COPROCESSOR_SEGMENT_OVERRUN
+);
+fault_handler!(invalid_tss_secondary_handler, InterruptVector::INVALID_TSS, error_code);
+fault_handler!(
+    segment_not_present_secondary_handler,
+    InterruptVector::SEGMENT_NOT_PRESENT,
+    error_code
+);
+fault_handler!(
+    stack_segment_fault_secondary_handler,
+    InterruptVector::STACK_SEGMENT_FAULT,
+    error_code
+);
+fault_handler!(
+    general_protection_secondary_handler,
+    InterruptVector::GENERAL_PROTECTION,
+    error_code
+);
+fault_handler!(
+    x87_floating_point_secondary_handler,
+    InterruptVector::X87_FLOATING_POINT_ERROR
+);
+fault_handler!(
+    alignment_check_secondary_handler,
+    InterruptVector::ALIGNMENT_CHECK,
+    error_code
+);
+fault_handler!(
+    virtualization_secondary_handler,
+    InterruptVector::VIRTUALIZATION_EXCEPTION
+);
+
+// Debug exceptions and breakpoints in the kernel are only reported, execution carries on after them
 #[no_mangle]
-pub extern "C" fn divide_by_zero_secondary_handler(exception_stack_frame: &mut ExceptionStackFrame) {
-    log::error!("DIVIDE BY ZERO EXCEPTION");
-    log::error!("EXCEPTION REGISTERS: {}", exception_stack_frame.interrupt_registers);
-    log::error!("EXECUTION STATE: {}", exception_stack_frame.execution_state);
-    panic!();
+pub extern "C" fn debug_exception_secondary_handler(exception_stack_frame: &mut ExceptionStackFrame) {
+    let fault_report = FaultReport::new(InterruptVector::DEBUG_EXCEPTION, exception_stack_frame);
+    match fault_report.is_user_mode() {
+        true => handle_fault(&fault_report),
+        false => log::warn!("{}", fault_report),
+    }
 }
 
 #[no_mangle]
-pub extern "C" fn double_fault_secondary_handler(exception_stack_frame: &mut ExceptionStackFrameWithErrorCode) {
-    log::error!("DOUBLE FAULT EXCEPTION");
-    log::error!("EXCEPTION REGISTERS: {}", exception_stack_frame.interrupt_registers);
-    log::error!("EXECUTION STATE: {}", exception_stack_frame.execution_state);
-    log::error!("ERROR CODE: {:#X}", exception_stack_frame.error_code);
-    panic!();
+pub extern "C" fn breakpoint_secondary_handler(exception_stack_frame: &mut ExceptionStackFrame) {
+    let fault_report = FaultReport::new(InterruptVector::BREAKPOINT, exception_stack_frame);
+    match fault_report.is_user_mode() {
+        true => handle_fault(&fault_report),
+        false => log::warn!("{}", fault_report),
+    }
 }
 
+// Raised by the first x87 or SIMD instruction after a lazy FPU switch. Only a fault if there's no memory left for
+// the state of a thread using the FPU for the first time.
 #[no_mangle]
-pub extern "C" fn page_fault_secondary_handler(exception_stack_frame: &mut ExceptionStackFrameWithErrorCode) {
-    log::error!("PAGE FAULT EXCEPTION");
-    log::error!("EXCEPTION REGISTERS: {}", exception_stack_frame.interrupt_registers);
-    log::error!("EXECUTION STATE: {}", exception_stack_frame.execution_state);
-    log::error!("ERROR CODE: {:#X}", exception_stack_frame.error_code);
-    panic!();
+pub extern "C" fn device_not_available_secondary_handler(exception_stack_frame: &mut ExceptionStackFrame) {
+    if !handle_device_not_available() {
+        handle_fault(&FaultReport::new(
+            InterruptVector::DEVICE_NOT_AVAILABLE,
+            exception_stack_frame,
+        ));
+    }
 }
 
+// The faulting instruction's state is still in the registers, MXCSR tells which exception it raised
 #[no_mangle]
-pub extern "C" fn timer_interrupt_secondary_handler(_exception_stack_frame: &mut ExceptionStackFrame) {
-    log::info!("LAPIC TIMER IRQ");
-    LOCAL_APIC.get().unwrap().signal_end_of_interrupt();
+pub extern "C" fn simd_floating_point_secondary_handler(exception_stack_frame: &mut ExceptionStackFrame) {
+    log::error!("SIMD Floating Point Exception: {}", SimdExceptions(read_mxcsr()));
+    handle_fault(&FaultReport::new(
+        InterruptVector::SIMD_FLOATING_POINT_EXCEPTION,
+        exception_stack_frame,
+    ));
 }
 
+// NMIs taken on each CPU since the last report, and where the latest one interrupted the CPU
+static UNREPORTED_NMIS: [AtomicUsize; MAX_CPUS] = [const { AtomicUsize::new(0) }; MAX_CPUS];
+static LAST_NMI_RIP: [AtomicUsize; MAX_CPUS] = [const { AtomicUsize::new(0) }; MAX_CPUS];
+static NMI_REPORT_WORK: Work = Work::new("nmi-report", report_nmis, 0);
+
+fn report_nmis(_: usize) {
+    for (cpu_index, unreported_nmis) in UNREPORTED_NMIS.iter().enumerate() {
+        let nmi_count = unreported_nmis.swap(0, Ordering::AcqRel);
+        if nmi_count > 0 {
+            log::error!(
+                "NMI on CPU {}: {} received, the last one at RIP {:#X}",
+                cpu_index,
+                nmi_count,
+                LAST_NMI_RIP[cpu_index].load(Ordering::Acquire)
+            );
+        }
+    }
+}
+
+// NMIs are raised by hardware for things like watchdogs and memory errors, not by the interrupted code. They can't
+// be masked, so the interrupted code may hold any lock, the logger's included. Only atomics are touched here, the
+// report is logged from the system work queue.
 #[no_mangle]
-pub extern "C" fn spurious_interrupt_secondary_handler(_exception_stack_frame: &mut ExceptionStackFrame) {
-    log::error!("SPURIOUS INTERRUPT RECIEVED");
-    LOCAL_APIC.get().unwrap().signal_end_of_interrupt();
+pub extern "C" fn nmi_secondary_handler(exception_stack_frame: &mut ExceptionStackFrame) {
+    let cpu_index = current_cpu_index();
+    LAST_NMI_RIP[cpu_index].store(exception_stack_frame.interrupt_registers.rip, Ordering::Release);
+    UNREPORTED_NMIS[cpu_index].fetch_add(1, Ordering::AcqRel);
+    schedule_work(&NMI_REPORT_WORK);
 }
 
-// Exceptions
-interrupt!(divide_by_zero, divide_by_zero_secondary_handler);
-interrupt_with_error_code!(double_fault, double_fault_secondary_handler);
-interrupt_with_error_code!(page_fault, page_fault_secondary_handler);
+// The CPU state can't be trusted after a double fault or a machine check, no matter which mode it was in
+#[no_mangle]
+pub extern "C" fn double_fault_secondary_handler(exception_stack_frame: &mut ExceptionStackFrameWithErrorCode) {
+    log::error!(
+        "{}",
+        FaultReport::with_error_code(InterruptVector::DOUBLE_FAULT, exception_stack_frame)
+    );
+    panic!("Double fault");
+}
 
-// IRQs
-interrupt!(lapic_timer_interrupt, timer_interrupt_secondary_handler);
-interrupt!(lapic_spurious_interrupt, spurious_interrupt_secondary_handler);
+#[no_mangle]
+pub extern "C" fn machine_check_secondary_handler(exception_stack_frame: &mut ExceptionStackFrame) {
+    log::error!(
+        "{}",
+        FaultReport::new(InterruptVector::MACHINE_CHECK, exception_stack_frame)
+    );
+    panic!("Machine check");
+}
+
+#[no_mangle]
+pub extern "C" fn page_fault_secondary_handler(exception_stack_frame: &mut ExceptionStackFrameWithErrorCode) {
+    let page_fault = PageFault::read(
+        exception_stack_frame.error_code,
+        exception_stack_frame.interrupt_registers.rip,
+    );
+    if let Err(page_fault_error) = handle_page_fault(&page_fault) {
+        let fault_report = FaultReport::with_error_code(InterruptVector::PAGE_FAULT, exception_stack_frame);
+        log::error!("{}", page_fault);
+        log::error!("{}", page_fault_error);
+        handle_fault(&fault_report);
+    }
+}
+
+// Exceptions
+interrupt!(
+    divide_by_zero,
+    divide_by_zero_secondary_handler,
+    InterruptVector::DIVIDE_ERROR
+);
+interrupt!(
+    debug_exception,
+    debug_exception_secondary_handler,
+    InterruptVector::DEBUG_EXCEPTION
+);
+interrupt!(nmi, nmi_secondary_handler, InterruptVector::NMI);
+interrupt!(breakpoint, breakpoint_secondary_handler, InterruptVector::BREAKPOINT);
+interrupt!(overflow, overflow_secondary_handler, InterruptVector::OVERFLOW);
+interrupt!(
+    bound_range_exceeded,
+    bound_range_exceeded_secondary_handler,
+    InterruptVector::BOUND_RANGE_EXCEEDED
+);
+interrupt!(
+    invalid_opcode,
+    invalid_opcode_secondary_handler,
+    InterruptVector::INVALID_OPCODE
+);
+interrupt!(
+    device_not_available,
+    device_not_available_secondary_handler,
+    InterruptVector::DEVICE_NOT_AVAILABLE
+);
+interrupt_with_error_code!(
+    double_fault,
+    double_fault_secondary_handler,
+    InterruptVector::DOUBLE_FAULT
+);
+interrupt!(
+    coprocessor_segment_overrun,
+    coprocessor_segment_overrun_secondary_handler,
+    InterruptVector::COPROCESSOR_SEGMENT_OVERRUN
+);
+interrupt_with_error_code!(invalid_tss, invalid_tss_secondary_handler, InterruptVector::INVALID_TSS);
+interrupt_with_error_code!(
+    segment_not_present,
+    segment_not_present_secondary_handler,
+    InterruptVector::SEGMENT_NOT_PRESENT
+);
+interrupt_with_error_code!(
+    stack_segment_fault,
+    stack_segment_fault_secondary_handler,
+    InterruptVector::STACK_SEGMENT_FAULT
+);
+interrupt_with_error_code!(
+    general_protection,
+    general_protection_secondary_handler,
+    InterruptVector::GENERAL_PROTECTION
+);
+interrupt_with_error_code!(page_fault, page_fault_secondary_handler, InterruptVector::PAGE_FAULT);
+interrupt!(
+    x87_floating_point,
+    x87_floating_point_secondary_handler,
+    InterruptVector::X87_FLOATING_POINT_ERROR
+);
+interrupt_with_error_code!(
+    alignment_check,
+    alignment_check_secondary_handler,
+    InterruptVector::ALIGNMENT_CHECK
+);
+interrupt!(
+    machine_check,
+    machine_check_secondary_handler,
+    InterruptVector::MACHINE_CHECK
+);
+interrupt!(
+    simd_floating_point,
+    simd_floating_point_secondary_handler,
+    InterruptVector::SIMD_FLOATING_POINT_EXCEPTION
+);
+interrupt!(
+    virtualization,
+    virtualization_secondary_handler,
+    InterruptVector::VIRTUALIZATION_EXCEPTION
+);
@@ -0,0 +1,392 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::fmt;
+use core::ops::Range;
+use core::sync::atomic::{
+    AtomicUsize,
+    Ordering,
+};
+
+use spin::Mutex;
+
+use crate::cpu::LOCAL_APIC;
+use crate::interrupt_with_error_code;
+use crate::interrupts::asm::without_interrupts;
+use crate::interrupts::softirq::run_softirqs;
+use crate::interrupts::stats::VECTOR_IN_ERROR_CODE;
+use crate::interrupts::{
+    ExceptionStackFrameWithErrorCode,
+    InterruptVector,
+};
+
+// Every vector from the first external one up to 255 belongs to the IRQ manager
+pub const IRQ_VECTOR_COUNT: usize = 256 - InterruptVector::FIRST_EXTERNAL;
+// Each entry stub is a `push imm32` followed by a `jmp rel32`
+pub const IRQ_STUB_SIZE: usize = 10;
+// Vectors handed out to GSIs. The ones outside of this range are fixed, like the LAPIC timer, the legacy PIC
+// vectors, the system call vector, the IPI vectors and the spurious vector.
+const DYNAMIC_VECTORS: Range<usize> = 0x40..0xF0;
+
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub enum IrqFlags {}
+
+impl IrqFlags {
+    // The handler is willing to share its vector with other handlers that are as well
+    pub const SHARED: usize = 1;
+}
+
+// What a handler says about an interrupt. On a shared line every handler runs, and the ones whose device didn't
+// raise it return NotHandled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqReturn {
+    Handled,
+    NotHandled,
+}
+
+// Handlers run with interrupts disabled, but not under the IRQ table lock. They may register handlers, but not free
+// one of their own vector: freeing waits for every handler of the vector that is still running to return.
+pub trait IrqHandler: Send + Sync {
+    fn handle(&self, vector: usize) -> IrqReturn;
+}
+
+impl<F> IrqHandler for F
+where
+    F: Fn(usize) -> IrqReturn + Send + Sync,
+{
+    fn handle(&self, vector: usize) -> IrqReturn {
+        self(vector)
+    }
+}
+
+// The chip that delivers a GSI to the CPU. It decides how the line is routed to a vector and who has to be told
+// that the interrupt was serviced.
+pub trait InterruptController: Send + Sync {
+    fn name(&self) -> &'static str;
+    // Routes the GSI to `vector` on the boot CPU and unmasks it
+    fn route(&self, gsi: u32, vector: usize) -> Result<(), IrqError>;
+    // For controllers that can't deliver a GSI to just any vector
+    fn fixed_vector(&self, _gsi: u32) -> Option<usize> {
+        None
+    }
+    fn mask(&self, gsi: u32);
+    fn end_of_interrupt(&self, gsi: u32, vector: usize);
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum IrqError {
+    InvalidVector,
+    Busy,
+    NoFreeVector,
+    NoController,
+    UnroutableGsi,
+    NotRegistered,
+}
+
+impl fmt::Display for IrqError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidVector => f.write_str("IRQ Error: Vector is reserved for exceptions"),
+            Self::Busy => f.write_str("IRQ Error: Vector is taken by a handler that doesn't share it"),
+            Self::NoFreeVector => f.write_str("IRQ Error: No free vector left to route the GSI to"),
+            Self::NoController => f.write_str("IRQ Error: No interrupt controller is registered"),
+            Self::UnroutableGsi => f.write_str("IRQ Error: The interrupt controller doesn't handle this GSI"),
+            Self::NotRegistered => f.write_str("IRQ Error: No such handler is registered"),
+        }
+    }
+}
+
+// Returned on registration, and needed to free the handler again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqHandle {
+    vector: usize,
+    id: usize,
+}
+
+impl IrqHandle {
+    #[inline]
+    pub fn vector(&self) -> usize {
+        self.vector
+    }
+}
+
+struct IrqAction {
+    id: usize,
+    name: &'static str,
+    flags: usize,
+    handler: Box<dyn IrqHandler>,
+}
+
+impl IrqAction {
+    #[inline]
+    fn is_shared(&self) -> bool {
+        self.flags & IrqFlags::SHARED != 0
+    }
+}
+
+// The GSI a vector is routed from, and the controller that routed it
+#[derive(Clone, Copy)]
+struct GsiRoute {
+    gsi: u32,
+    controller: &'static dyn InterruptController,
+}
+
+// The handlers of a line. It's never changed in place, registering or freeing a handler installs a new one, so
+// the dispatcher can run a snapshot of it without holding the IRQ table lock.
+type IrqActions = Arc<[Arc<IrqAction>]>;
+
+struct IrqLine {
+    actions: Option<IrqActions>,
+    gsi_route: Option<GsiRoute>,
+}
+
+impl IrqLine {
+    const EMPTY: IrqLine = IrqLine {
+        actions: None,
+        gsi_route: None,
+    };
+}
+
+// The actions with `action` added to them. Allocates, so it's called without the IRQ table locked.
+fn with_action(actions: Option<IrqActions>, action: IrqAction) -> Result<IrqActions, IrqError> {
+    let actions = actions.as_deref().unwrap_or_default();
+    if !actions.is_empty() && !(action.is_shared() && actions.iter().all(|action| action.is_shared())) {
+        return Err(IrqError::Busy);
+    }
+    Ok(actions.iter().cloned().chain([Arc::new(action)]).collect())
+}
+
+struct IrqTable {
+    lines: [IrqLine; IRQ_VECTOR_COUNT],
+}
+
+impl IrqTable {
+    fn line(&mut self, vector: usize) -> &mut IrqLine {
+        &mut self.lines[vector - InterruptVector::FIRST_EXTERNAL]
+    }
+
+    fn gsi_vector(&self, gsi: u32) -> Option<usize> {
+        self.lines
+            .iter()
+            .position(|line| line.gsi_route.is_some_and(|gsi_route| gsi_route.gsi == gsi))
+            .map(|index| index + InterruptVector::FIRST_EXTERNAL)
+    }
+
+    fn is_vector_free(&self, vector: usize) -> bool {
+        let line = &self.lines[vector - InterruptVector::FIRST_EXTERNAL];
+        line.actions.is_none() && line.gsi_route.is_none()
+    }
+
+    fn free_dynamic_vector(&self) -> Option<usize> {
+        DYNAMIC_VECTORS.into_iter().find(|&vector| self.is_vector_free(vector))
+    }
+}
+
+// Only ever locked with interrupts disabled, the dispatcher takes it from interrupt context. Nothing allocates or
+// waits on another CPU while holding it.
+static IRQ_TABLE: Mutex<IrqTable> = Mutex::new(IrqTable {
+    lines: [IrqLine::EMPTY; IRQ_VECTOR_COUNT],
+});
+// Serializes registering and freeing handlers, which build the new actions of a line outside of the table lock
+static IRQ_REGISTRATION: Mutex<()> = Mutex::new(());
+static INTERRUPT_CONTROLLER: Mutex<Option<&'static dyn InterruptController>> = Mutex::new(None);
+static NEXT_ACTION_ID: AtomicUsize = AtomicUsize::new(0);
+
+// GSIs registered from now on are routed through `controller`. Lines that are already routed stay with the
+// controller that routed them.
+pub fn set_interrupt_controller(controller: &'static dyn InterruptController) {
+    *INTERRUPT_CONTROLLER.lock() = Some(controller);
+    log::info!("Routing GSIs through the {}", controller.name());
+}
+
+pub fn interrupt_controller() -> Option<&'static dyn InterruptController> {
+    *INTERRUPT_CONTROLLER.lock()
+}
+
+fn line_actions(vector: usize) -> Option<IrqActions> {
+    without_interrupts(|| IRQ_TABLE.lock().line(vector).actions.clone())
+}
+
+// Installs the new actions of a line, masking its GSI if none are left. The previous actions are dropped here once
+// no dispatcher runs them anymore, a dispatcher must never be the one to free them: the heap can't be used from
+// interrupt context.
+fn set_line_actions(vector: usize, actions: Option<IrqActions>) {
+    let previous = without_interrupts(|| {
+        let mut irq_table = IRQ_TABLE.lock();
+        let line = irq_table.line(vector);
+        if actions.is_none() {
+            if let Some(gsi_route) = line.gsi_route.take() {
+                gsi_route.controller.mask(gsi_route.gsi);
+            }
+        }
+        core::mem::replace(&mut line.actions, actions)
+    });
+    if let Some(previous) = previous {
+        while Arc::strong_count(&previous) > 1 {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+fn new_action(name: &'static str, flags: usize, handler: Box<dyn IrqHandler>) -> IrqAction {
+    IrqAction {
+        id: NEXT_ACTION_ID.fetch_add(1, Ordering::Relaxed),
+        name,
+        flags,
+        handler,
+    }
+}
+
+// Registers a handler for an interrupt that is delivered straight to a vector, like the LAPIC timer or an MSI
+pub fn request_irq(
+    vector: usize,
+    name: &'static str,
+    flags: usize,
+    handler: Box<dyn IrqHandler>,
+) -> Result<IrqHandle, IrqError> {
+    if !(InterruptVector::FIRST_EXTERNAL..256).contains(&vector) {
+        return Err(IrqError::InvalidVector);
+    }
+    let action = new_action(name, flags, handler);
+    let id = action.id;
+    let _registration = IRQ_REGISTRATION.lock();
+    let actions = with_action(line_actions(vector), action)?;
+    set_line_actions(vector, Some(actions));
+    log::info!("Registered IRQ handler {} on vector {:#X}", name, vector);
+    Ok(IrqHandle { vector, id })
+}
+
+// Registers a handler for a GSI. The first handler of a GSI gets it a free vector and has the interrupt
+// controller route it there, later ones are chained onto that vector if everyone involved shares it.
+pub fn request_gsi(
+    gsi: u32,
+    name: &'static str,
+    flags: usize,
+    handler: Box<dyn IrqHandler>,
+) -> Result<IrqHandle, IrqError> {
+    let action = new_action(name, flags, handler);
+    let id = action.id;
+    let _registration = IRQ_REGISTRATION.lock();
+    let vector = match gsi_vector(gsi) {
+        Some(vector) => {
+            let actions = with_action(line_actions(vector), action)?;
+            set_line_actions(vector, Some(actions));
+            vector
+        }
+        None => {
+            let mut actions = Some(with_action(None, action)?);
+            // Dropped outside of the lock if the GSI can't be routed
+            without_interrupts(|| {
+                let mut irq_table = IRQ_TABLE.lock();
+                let controller = interrupt_controller().ok_or(IrqError::NoController)?;
+                let vector = match controller.fixed_vector(gsi) {
+                    Some(vector) if irq_table.is_vector_free(vector) => vector,
+                    Some(_) => return Err(IrqError::Busy),
+                    None => irq_table.free_dynamic_vector().ok_or(IrqError::NoFreeVector)?,
+                };
+                controller.route(gsi, vector)?;
+                let line = irq_table.line(vector);
+                line.gsi_route = Some(GsiRoute { gsi, controller });
+                line.actions = actions.take();
+                Ok(vector)
+            })?
+        }
+    };
+    log::info!("Registered IRQ handler {} on GSI {} (vector {:#X})", name, gsi, vector);
+    Ok(IrqHandle { vector, id })
+}
+
+// Unregisters a handler. A GSI is masked and its vector freed once its last handler is gone.
+pub fn free_irq(irq_handle: IrqHandle) -> Result<(), IrqError> {
+    let _registration = IRQ_REGISTRATION.lock();
+    let actions = line_actions(irq_handle.vector).ok_or(IrqError::NotRegistered)?;
+    let action = actions
+        .iter()
+        .find(|action| action.id == irq_handle.id)
+        .cloned()
+        .ok_or(IrqError::NotRegistered)?;
+    let remaining: IrqActions = actions
+        .iter()
+        .filter(|action| action.id != irq_handle.id)
+        .cloned()
+        .collect();
+    // The snapshot would keep the line's current actions alive, which are only dropped once nothing holds them
+    drop(actions);
+    set_line_actions(irq_handle.vector, (!remaining.is_empty()).then_some(remaining));
+    log::info!("Freed IRQ handler {} on vector {:#X}", action.name, irq_handle.vector);
+    Ok(())
+}
+
+// The vector a GSI is routed to, if anything registered for it
+pub fn gsi_vector(gsi: u32) -> Option<usize> {
+    without_interrupts(|| IRQ_TABLE.lock().gsi_vector(gsi))
+}
+
+fn dispatch_irq(vector: usize) {
+    // The LAPIC doesn't expect an EOI for spurious interrupts, there was nothing to service
+    if vector == InterruptVector::APIC_SPURIOUS {
+        log::warn!("Spurious interrupt received");
+        return;
+    }
+    let (actions, gsi_route) = {
+        let mut irq_table = IRQ_TABLE.lock();
+        let line = irq_table.line(vector);
+        (line.actions.clone(), line.gsi_route)
+    };
+    // Every handler of a shared line runs, more than one device can have raised it
+    let mut handled = false;
+    for action in actions.iter().flat_map(|actions| actions.iter()) {
+        if action.handler.handle(vector) == IrqReturn::Handled {
+            handled = true;
+        }
+    }
+    if !handled {
+        log::warn!("Nothing handled the IRQ on vector {:#X}", vector);
+    }
+    // Never the last reference, whoever replaced the actions waits for this
+    drop(actions);
+    match gsi_route {
+        Some(gsi_route) => gsi_route.controller.end_of_interrupt(gsi_route.gsi, vector),
+        None => {
+            if let Some(local_apic) = LOCAL_APIC.get() {
+                local_apic.signal_end_of_interrupt()
+            }
+        }
+    }
+}
+
+// The stubs push their vector where exceptions have their error code, and share one entry from there on
+#[no_mangle]
+pub extern "C" fn irq_secondary_handler(exception_stack_frame: &mut ExceptionStackFrameWithErrorCode) {
+    dispatch_irq(exception_stack_frame.error_code);
+    run_softirqs();
+}
+
+interrupt_with_error_code!(irq_entry, irq_secondary_handler, VECTOR_IN_ERROR_CODE);
+
+// One fixed size entry stub per IRQ vector, so the IDT entry of a vector is at a known offset. The instructions
+// are spelled out as bytes since the assembler would otherwise pick the shorter `push imm8` for some vectors.
+#[naked]
+pub unsafe extern "C" fn irq_stubs() {
+    core::arch::asm!(
+        ".set irq_stub_vector, {first_vector}",
+        ".rept {vector_count}",
+        // push imm32
+        ".byte 0x68",
+        ".long irq_stub_vector",
+        // jmp rel32
+        ".byte 0xE9",
+        ".long {irq_entry} - . - 4",
+        ".set irq_stub_vector, irq_stub_vector + 1",
+        ".endr",
+        first_vector = const InterruptVector::FIRST_EXTERNAL,
+        vector_count = const IRQ_VECTOR_COUNT,
+        irq_entry = sym irq_entry,
+        options(noreturn)
+    )
+}
+
+#[inline]
+pub fn irq_stub_address(vector: usize) -> usize {
+    irq_stubs as usize + (vector - InterruptVector::FIRST_EXTERNAL) * IRQ_STUB_SIZE
+}
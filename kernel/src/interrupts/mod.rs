@@ -1,6 +1,11 @@
 pub mod asm;
+pub mod exception;
 pub mod handlers;
 pub mod idt;
+pub mod irq;
+pub mod softirq;
+pub mod stats;
+pub mod workqueue;
 
 use core::fmt;
 
@@ -11,6 +16,7 @@ use idt::{
     GateOptions,
     InterruptDescriptorTable,
 };
+use irq::irq_stub_address;
 use lazy_static::lazy_static;
 
 use crate::mmu::address::VirtualAddress;
@@ -82,10 +88,43 @@ impl InterruptVector {
     pub const SIMD_FLOATING_POINT_EXCEPTION: usize = 0x13;
     pub const VIRTUALIZATION_EXCEPTION: usize = 0x14;
     // Vectors #21-31 are reserved, and #32-255 are reserved for user defined interrupts
-    // TODO: define IRQ numbers here
     pub const APIC_TIMER: usize = 0x20;
+    // The 8259 PICs are remapped to the 16 vectors starting here
+    pub const LEGACY_PIC_BASE: usize = 0x30;
+    // Another CPU has functions queued for this one to run
+    pub const CPU_CALL: usize = 0xF0;
     pub const APIC_SPURIOUS: usize = 0xFF;
     pub const SYSCALL: usize = 0x80;
+
+    // Vectors below this one are reserved for exceptions
+    pub const FIRST_EXTERNAL: usize = 0x20;
+
+    pub fn name(vector: usize) -> &'static str {
+        match vector {
+            Self::DIVIDE_ERROR => "Divide Error (#DE)",
+            Self::DEBUG_EXCEPTION => "Debug Exception (#DB)",
+            Self::NMI => "Non-Maskable Interrupt",
+            Self::BREAKPOINT => "Breakpoint (#BP)",
+            Self::OVERFLOW => "Overflow (#OF)",
+            Self::BOUND_RANGE_EXCEEDED => "Bound Range Exceeded (#BR)",
+            Self::INVALID_OPCODE => "Invalid Opcode (#UD)",
+            Self::DEVICE_NOT_AVAILABLE => "Device Not Available (#NM)",
+            Self::DOUBLE_FAULT => "Double Fault (#DF)",
+            Self::COPROCESSOR_SEGMENT_OVERRUN => "Coprocessor Segment Overrun",
+            Self::INVALID_TSS => "Invalid TSS (#TS)",
+            Self::SEGMENT_NOT_PRESENT => "Segment Not Present (#NP)",
+            Self::STACK_SEGMENT_FAULT => "Stack Segment Fault (#SS)",
+            Self::GENERAL_PROTECTION => "General Protection (#GP)",
+            Self::PAGE_FAULT => "Page Fault (#PF)",
+            Self::X87_FLOATING_POINT_ERROR => "x87 Floating-Point Error (#MF)",
+            Self::ALIGNMENT_CHECK => "Alignment Check (#AC)",
+            Self::MACHINE_CHECK => "Machine Check (#MC)",
+            Self::SIMD_FLOATING_POINT_EXCEPTION => "SIMD Floating-Point Exception (#XM)",
+            Self::VIRTUALIZATION_EXCEPTION => "Virtualization Exception (#VE)",
+            vector if vector < Self::FIRST_EXTERNAL => "Reserved Exception",
+            _ => "External Interrupt",
+        }
+    }
 }
 
 lazy_static! {
@@ -93,42 +132,66 @@ lazy_static! {
         let mut idt = InterruptDescriptorTable::new();
 
         // Gate descriptors - Exceptions
-        let mut div_by_zero_gate_desc = GateDescriptor::new(GateOptions::exception_gate_options());
-        div_by_zero_gate_desc.set_handler_address(VirtualAddress::new(divide_by_zero as usize));
-
-        let df_gate_opts = GateOptions::exception_gate_options().set_stack_index(DOUBLE_FAULT_STACK_TABLE_INDEX);
-        let mut df_gate_desc = GateDescriptor::new(df_gate_opts);
-        df_gate_desc.set_handler_address(VirtualAddress::new(double_fault as usize));
-
-        let pf_gate_opts = GateOptions::exception_gate_options().set_stack_index(PAGE_FAULT_STACK_TABLE_INDEX);
-        let mut pf_gate_desc = GateDescriptor::new(pf_gate_opts);
-        pf_gate_desc.set_handler_address(VirtualAddress::new(page_fault as usize));
-
-
-        // Gate descriptors - IRQs
-        let mut lapic_timer_irq_gate_desc= GateDescriptor::new(GateOptions::trap_gate_options());
-        lapic_timer_irq_gate_desc.set_handler_address(VirtualAddress::new(lapic_timer_interrupt as usize));
-
-        let mut lapic_spurious_irq_gate_desc = GateDescriptor::new(GateOptions::trap_gate_options());
-        lapic_spurious_irq_gate_desc.set_handler_address(VirtualAddress::new(lapic_spurious_interrupt as usize));
+        // Handlers that can't trust the interrupted stack get one of their own. int3 and into are allowed from
+        // user code.
+        let exception_gate_opts = GateOptions::exception_gate_options();
+        let user_gate_opts = exception_gate_opts.dpl_3();
+        let nmi_gate_opts = exception_gate_opts.set_stack_index(NMI_STACK_TABLE_INDEX);
+        let df_gate_opts = exception_gate_opts.set_stack_index(DOUBLE_FAULT_STACK_TABLE_INDEX);
+        let pf_gate_opts = exception_gate_opts.set_stack_index(PAGE_FAULT_STACK_TABLE_INDEX);
+        let mc_gate_opts = exception_gate_opts.set_stack_index(MACHINE_CHECK_STACK_TABLE_INDEX);
+        let exceptions: [(usize, unsafe extern "C" fn(), GateOptions); 20] = [
+            (InterruptVector::DIVIDE_ERROR, divide_by_zero, exception_gate_opts),
+            (InterruptVector::DEBUG_EXCEPTION, debug_exception, exception_gate_opts),
+            (InterruptVector::NMI, nmi, nmi_gate_opts),
+            (InterruptVector::BREAKPOINT, breakpoint, user_gate_opts),
+            (InterruptVector::OVERFLOW, overflow, user_gate_opts),
+            (InterruptVector::BOUND_RANGE_EXCEEDED, bound_range_exceeded, exception_gate_opts),
+            (InterruptVector::INVALID_OPCODE, invalid_opcode, exception_gate_opts),
+            (InterruptVector::DEVICE_NOT_AVAILABLE, device_not_available, exception_gate_opts),
+            (InterruptVector::DOUBLE_FAULT, double_fault, df_gate_opts),
+            (InterruptVector::COPROCESSOR_SEGMENT_OVERRUN, coprocessor_segment_overrun, exception_gate_opts),
+            (InterruptVector::INVALID_TSS, invalid_tss, exception_gate_opts),
+            (InterruptVector::SEGMENT_NOT_PRESENT, segment_not_present, exception_gate_opts),
+            (InterruptVector::STACK_SEGMENT_FAULT, stack_segment_fault, exception_gate_opts),
+            (InterruptVector::GENERAL_PROTECTION, general_protection, exception_gate_opts),
+            (InterruptVector::PAGE_FAULT, page_fault, pf_gate_opts),
+            (InterruptVector::X87_FLOATING_POINT_ERROR, x87_floating_point, exception_gate_opts),
+            (InterruptVector::ALIGNMENT_CHECK, alignment_check, exception_gate_opts),
+            (InterruptVector::MACHINE_CHECK, machine_check, mc_gate_opts),
+            (InterruptVector::SIMD_FLOATING_POINT_EXCEPTION, simd_floating_point, exception_gate_opts),
+            (InterruptVector::VIRTUALIZATION_EXCEPTION, virtualization, exception_gate_opts),
+        ];
 
         // Exceptions
-        idt.descriptor_table[InterruptVector::DIVIDE_ERROR] = div_by_zero_gate_desc;
-        idt.descriptor_table[InterruptVector::DOUBLE_FAULT] = df_gate_desc;
-        idt.descriptor_table[InterruptVector::PAGE_FAULT] = pf_gate_desc;
+        for (vector, handler, gate_options) in exceptions {
+            let mut gate_desc = GateDescriptor::new(gate_options);
+            gate_desc.set_handler_address(VirtualAddress::new(handler as usize));
+            idt.descriptor_table[vector] = gate_desc;
+        }
 
         // IRQs
-        idt.descriptor_table[InterruptVector::APIC_TIMER] = lapic_timer_irq_gate_desc;
-        idt.descriptor_table[InterruptVector::APIC_SPURIOUS] = lapic_spurious_irq_gate_desc;
+        // Every external vector enters the IRQ manager through its stub. Interrupt gates keep the dispatcher from
+        // being interrupted while it holds the IRQ table.
+        for vector in InterruptVector::FIRST_EXTERNAL..256 {
+            let mut gate_desc = GateDescriptor::new(GateOptions::exception_gate_options());
+            gate_desc.set_handler_address(VirtualAddress::new(irq_stub_address(vector)));
+            idt.descriptor_table[vector] = gate_desc;
+        }
 
         idt
     };
 }
 
+// Every CPU uses the same IDT, the APs only have to load it
+pub fn load_idt() {
+    unsafe { InterruptDescriptorTable::load_idt(&IDT.pointer()) };
+}
+
 pub fn init_idt() {
+    load_idt();
+    log::info!("IDT initalized and loaded");
     unsafe {
-        InterruptDescriptorTable::load_idt(&IDT.pointer());
-        log::info!("IDT initalized and loaded");
         enable_interrupts();
         log::info!("CPU interrupts enabled");
     }
@@ -0,0 +1,194 @@
+use core::ptr;
+use core::sync::atomic::{
+    AtomicBool,
+    AtomicPtr,
+    AtomicUsize,
+    Ordering,
+};
+
+use spin::Mutex;
+
+use crate::cpu::{
+    current_cpu_index,
+    MAX_CPUS,
+};
+use crate::interrupts::asm::{
+    disable_interrupts,
+    enable_interrupts,
+    without_interrupts,
+};
+
+// Softirqs still pending after this many passes are left for the next IRQ exit, so a flood of them can't keep
+// the interrupted code from ever running again
+const MAX_SOFTIRQ_PASSES: usize = 10;
+
+// Bottom halves of interrupt handlers. Hard handlers raise them and return, they run once the IRQ is
+// acknowledged, with interrupts enabled. The number is the priority, lower ones run first.
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub enum SoftIrq {}
+
+impl SoftIrq {
+    pub const HI_TASKLET: usize = 0;
+    pub const TIMER: usize = 1;
+    pub const TASKLET: usize = 2;
+    // Functions other CPUs asked this one to run
+    pub const CPU_CALL: usize = 3;
+    pub const COUNT: usize = 8;
+}
+
+type SoftIrqHandlers = [Option<fn()>; SoftIrq::COUNT];
+
+static SOFTIRQ_HANDLERS: Mutex<SoftIrqHandlers> = Mutex::new([None; SoftIrq::COUNT]);
+// A bit per raised softirq, for each CPU
+static PENDING_SOFTIRQS: [AtomicUsize; MAX_CPUS] = [const { AtomicUsize::new(0) }; MAX_CPUS];
+// Keeps an IRQ that arrives while softirqs run from starting another round of them on the same stack
+static IN_SOFTIRQ: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+
+// Softirqs are fixed at compile time, this only installs the handler of one
+pub fn register_softirq(softirq: usize, handler: fn()) {
+    assert!(softirq < SoftIrq::COUNT, "Softirq {} doesn't exist!", softirq);
+    without_interrupts(|| SOFTIRQ_HANDLERS.lock()[softirq] = Some(handler));
+}
+
+// Marks a softirq pending on this CPU. Safe to call from hard IRQ context.
+#[inline]
+pub fn raise_softirq(softirq: usize) {
+    PENDING_SOFTIRQS[current_cpu_index()].fetch_or(1 << softirq, Ordering::AcqRel);
+}
+
+#[inline]
+pub fn softirqs_pending() -> bool {
+    PENDING_SOFTIRQS[current_cpu_index()].load(Ordering::Acquire) != 0
+}
+
+// Runs the pending softirqs of this CPU. Called on IRQ exit with interrupts disabled, and returns with them
+// disabled again.
+pub fn run_softirqs() {
+    let cpu_index = current_cpu_index();
+    if !softirqs_pending() || IN_SOFTIRQ[cpu_index].swap(true, Ordering::Acquire) {
+        return;
+    }
+    let softirq_handlers = *SOFTIRQ_HANDLERS.lock();
+    for _ in 0..MAX_SOFTIRQ_PASSES {
+        let pending = PENDING_SOFTIRQS[cpu_index].swap(0, Ordering::AcqRel);
+        if pending == 0 {
+            break;
+        }
+        unsafe { enable_interrupts() };
+        for (softirq, handler) in softirq_handlers.iter().enumerate() {
+            if pending & (1 << softirq) == 0 {
+                continue;
+            }
+            match handler {
+                Some(handler) => handler(),
+                None => log::warn!("Softirq {} was raised without a handler", softirq),
+            }
+        }
+        unsafe { disable_interrupts() };
+    }
+    IN_SOFTIRQ[cpu_index].store(false, Ordering::Release);
+}
+
+// A function deferred out of a hard IRQ handler. Scheduling one that is already scheduled does nothing, so it
+// runs once no matter how many interrupts asked for it. Tasklets are statics and linked into the queue
+// themselves, scheduling never allocates.
+#[derive(Debug)]
+pub struct Tasklet {
+    pub name: &'static str,
+    func: fn(usize),
+    data: usize,
+    scheduled: AtomicBool,
+    next: AtomicPtr<Tasklet>,
+}
+
+impl Tasklet {
+    pub const fn new(name: &'static str, func: fn(usize), data: usize) -> Self {
+        Tasklet {
+            name,
+            func,
+            data,
+            scheduled: AtomicBool::new(false),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    #[inline]
+    pub fn is_scheduled(&self) -> bool {
+        self.scheduled.load(Ordering::Acquire)
+    }
+}
+
+// Intrusive stack of scheduled tasklets, pushed onto from any context and taken as a whole
+#[derive(Debug)]
+struct TaskletList {
+    head: AtomicPtr<Tasklet>,
+}
+
+impl TaskletList {
+    const fn new() -> Self {
+        TaskletList {
+            head: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    fn push(&self, tasklet: &'static Tasklet) {
+        let tasklet_ptr = tasklet as *const Tasklet as *mut Tasklet;
+        let mut head = self.head.load(Ordering::Acquire);
+        loop {
+            tasklet.next.store(head, Ordering::Relaxed);
+            match self
+                .head
+                .compare_exchange_weak(head, tasklet_ptr, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => return,
+                Err(current_head) => head = current_head,
+            }
+        }
+    }
+
+    // Runs everything on the list in the order it was scheduled. A tasklet may schedule itself again while it
+    // runs, it then lands on the list for the next pass.
+    fn run(&self) {
+        let mut reversed = self.head.swap(ptr::null_mut(), Ordering::AcqRel);
+        let mut tasklet_ptr = ptr::null_mut();
+        while !reversed.is_null() {
+            let tasklet = unsafe { &*reversed };
+            reversed = tasklet.next.swap(tasklet_ptr, Ordering::Relaxed);
+            tasklet_ptr = tasklet as *const Tasklet as *mut Tasklet;
+        }
+        while !tasklet_ptr.is_null() {
+            let tasklet = unsafe { &*tasklet_ptr };
+            tasklet_ptr = tasklet.next.load(Ordering::Relaxed);
+            tasklet.scheduled.store(false, Ordering::Release);
+            (tasklet.func)(tasklet.data);
+        }
+    }
+}
+
+static TASKLETS: [TaskletList; MAX_CPUS] = [const { TaskletList::new() }; MAX_CPUS];
+static HI_TASKLETS: [TaskletList; MAX_CPUS] = [const { TaskletList::new() }; MAX_CPUS];
+
+fn schedule_on(tasklet_lists: &'static [TaskletList; MAX_CPUS], softirq: usize, tasklet: &'static Tasklet) {
+    if tasklet.scheduled.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    tasklet_lists[current_cpu_index()].push(tasklet);
+    raise_softirq(softirq);
+}
+
+// Runs `tasklet` on this CPU once the current IRQ is done
+pub fn tasklet_schedule(tasklet: &'static Tasklet) {
+    schedule_on(&TASKLETS, SoftIrq::TASKLET, tasklet);
+}
+
+// Same, but ahead of the timer and the normal tasklets
+pub fn tasklet_hi_schedule(tasklet: &'static Tasklet) {
+    schedule_on(&HI_TASKLETS, SoftIrq::HI_TASKLET, tasklet);
+}
+
+pub fn init_softirqs() {
+    register_softirq(SoftIrq::HI_TASKLET, || HI_TASKLETS[current_cpu_index()].run());
+    register_softirq(SoftIrq::TASKLET, || TASKLETS[current_cpu_index()].run());
+    log::info!("Softirqs and tasklets initialized");
+}
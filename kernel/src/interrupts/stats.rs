@@ -0,0 +1,223 @@
+use core::fmt;
+use core::sync::atomic::{
+    AtomicBool,
+    AtomicU64,
+    Ordering,
+};
+
+use crate::cpu::{
+    current_cpu_index,
+    read_tsc,
+    MAX_CPUS,
+};
+use crate::interrupts::softirq::{
+    tasklet_schedule,
+    Tasklet,
+};
+use crate::interrupts::{
+    ExceptionStackFrame,
+    ExceptionStackFrameWithErrorCode,
+    InterruptVector,
+};
+
+// Passed by trampolines whose vector is pushed in place of an error code, like the IRQ entry
+pub const VECTOR_IN_ERROR_CODE: usize = usize::MAX;
+
+const VECTOR_COUNT: usize = 256;
+// Handler durations are counted in buckets of powers of two cycles, the last one holds everything longer
+pub const HISTOGRAM_BUCKETS: usize = 32;
+// A vector firing this often within one storm window is reported as a storm
+const STORM_THRESHOLD: u64 = 100_000;
+// Around a tenth of a second at a few GHz. The TSC frequency isn't known, so the window is only a rough one.
+const STORM_WINDOW_CYCLES: u64 = 1 << 28;
+
+static INTERRUPT_COUNTS: [[AtomicU64; VECTOR_COUNT]; MAX_CPUS] =
+    [const { [const { AtomicU64::new(0) }; VECTOR_COUNT] }; MAX_CPUS];
+static LATENCY_HISTOGRAMS: [[AtomicU64; HISTOGRAM_BUCKETS]; VECTOR_COUNT] =
+    [const { [const { AtomicU64::new(0) }; HISTOGRAM_BUCKETS] }; VECTOR_COUNT];
+static TOTAL_CYCLES: [AtomicU64; VECTOR_COUNT] = [const { AtomicU64::new(0) }; VECTOR_COUNT];
+static MAX_CYCLES: [AtomicU64; VECTOR_COUNT] = [const { AtomicU64::new(0) }; VECTOR_COUNT];
+
+// Interrupts of a vector counted since `start`, across all CPUs
+struct StormWindow {
+    start: AtomicU64,
+    count: AtomicU64,
+    storms: AtomicU64,
+    // Set when a storm is detected, until the tasklet reported it
+    unreported: AtomicBool,
+}
+
+impl StormWindow {
+    const fn new() -> Self {
+        StormWindow {
+            start: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+            storms: AtomicU64::new(0),
+            unreported: AtomicBool::new(false),
+        }
+    }
+}
+
+static STORM_WINDOWS: [StormWindow; VECTOR_COUNT] = [const { StormWindow::new() }; VECTOR_COUNT];
+// Logging from the hard handler could deadlock on the logger, storms are reported from a tasklet
+static STORM_TASKLET: Tasklet = Tasklet::new("interrupt-storm", report_storms, 0);
+
+// Called by the `interrupt!` trampolines around the secondary handler
+pub extern "C" fn trace_interrupt(
+    exception_stack_frame: &mut ExceptionStackFrame,
+    vector: usize,
+    secondary_handler: extern "C" fn(&mut ExceptionStackFrame),
+) {
+    let start = record_entry(vector);
+    secondary_handler(exception_stack_frame);
+    record_exit(vector, start);
+}
+
+// Called by the `interrupt_with_error_code!` trampolines around the secondary handler
+pub extern "C" fn trace_interrupt_with_error_code(
+    exception_stack_frame: &mut ExceptionStackFrameWithErrorCode,
+    vector: usize,
+    secondary_handler: extern "C" fn(&mut ExceptionStackFrameWithErrorCode),
+) {
+    let vector = match vector {
+        VECTOR_IN_ERROR_CODE => exception_stack_frame.error_code,
+        vector => vector,
+    };
+    let start = record_entry(vector);
+    secondary_handler(exception_stack_frame);
+    record_exit(vector, start);
+}
+
+fn record_entry(vector: usize) -> u64 {
+    INTERRUPT_COUNTS[current_cpu_index()][vector].fetch_add(1, Ordering::Relaxed);
+    let now = read_tsc();
+    check_for_storm(vector, now);
+    now
+}
+
+// Handlers that never return, like fatal exceptions, only get counted
+fn record_exit(vector: usize, start: u64) {
+    let cycles = read_tsc().saturating_sub(start);
+    let bucket = ((u64::BITS - cycles.leading_zeros()) as usize).min(HISTOGRAM_BUCKETS - 1);
+    LATENCY_HISTOGRAMS[vector][bucket].fetch_add(1, Ordering::Relaxed);
+    TOTAL_CYCLES[vector].fetch_add(cycles, Ordering::Relaxed);
+    MAX_CYCLES[vector].fetch_max(cycles, Ordering::Relaxed);
+}
+
+// The window is shared by all CPUs and reset without a lock, which can lose a few counts. Good enough to tell a
+// storm apart from normal load.
+fn check_for_storm(vector: usize, now: u64) {
+    let storm_window = &STORM_WINDOWS[vector];
+    if now.wrapping_sub(storm_window.start.load(Ordering::Relaxed)) > STORM_WINDOW_CYCLES {
+        storm_window.start.store(now, Ordering::Relaxed);
+        storm_window.count.store(1, Ordering::Relaxed);
+        return;
+    }
+    if storm_window.count.fetch_add(1, Ordering::Relaxed) + 1 == STORM_THRESHOLD {
+        storm_window.storms.fetch_add(1, Ordering::Relaxed);
+        storm_window.unreported.store(true, Ordering::Release);
+        tasklet_schedule(&STORM_TASKLET);
+    }
+}
+
+fn report_storms(_: usize) {
+    for (vector, storm_window) in STORM_WINDOWS.iter().enumerate() {
+        if storm_window.unreported.swap(false, Ordering::AcqRel) {
+            log::warn!(
+                "Interrupt storm on vector {:#X} ({}): {} interrupts within {} cycles",
+                vector,
+                InterruptVector::name(vector),
+                STORM_THRESHOLD,
+                STORM_WINDOW_CYCLES
+            );
+        }
+    }
+}
+
+// Whether the vector hit the storm threshold in its current window
+pub fn is_storming(vector: usize) -> bool {
+    let storm_window = &STORM_WINDOWS[vector];
+    read_tsc().wrapping_sub(storm_window.start.load(Ordering::Relaxed)) <= STORM_WINDOW_CYCLES
+        && storm_window.count.load(Ordering::Relaxed) >= STORM_THRESHOLD
+}
+
+#[inline]
+pub fn cpu_interrupt_count(cpu_index: usize, vector: usize) -> u64 {
+    INTERRUPT_COUNTS[cpu_index][vector].load(Ordering::Relaxed)
+}
+
+pub fn interrupt_count(vector: usize) -> u64 {
+    (0..MAX_CPUS)
+        .map(|cpu_index| cpu_interrupt_count(cpu_index, vector))
+        .sum()
+}
+
+// How many times handling the vector took fewer than 2^bucket cycles, and at least half that
+#[inline]
+pub fn latency_histogram_bucket(vector: usize, bucket: usize) -> u64 {
+    LATENCY_HISTOGRAMS[vector][bucket].load(Ordering::Relaxed)
+}
+
+// A snapshot of the counters in a table, one line per vector that fired and one below it with its latency
+// histogram
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptStats;
+
+impl InterruptStats {
+    // Columns are only printed for CPUs up to the last one that took an interrupt
+    fn cpu_count(&self) -> usize {
+        (0..MAX_CPUS)
+            .rev()
+            .find(|&cpu_index| (0..VECTOR_COUNT).any(|vector| cpu_interrupt_count(cpu_index, vector) > 0))
+            .map_or(1, |cpu_index| cpu_index + 1)
+    }
+}
+
+impl fmt::Display for InterruptStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cpu_count = self.cpu_count();
+        f.write_str("Vector")?;
+        for cpu_index in 0..cpu_count {
+            f.write_fmt(format_args!("{:>12}", cpu_index))?;
+        }
+        f.write_fmt(format_args!(
+            "{:>12}{:>12}{:>12}{:>8}  Name",
+            "Total", "Avg cycles", "Max cycles", "Storms"
+        ))?;
+        for vector in 0..VECTOR_COUNT {
+            let total = interrupt_count(vector);
+            if total == 0 {
+                continue;
+            }
+            f.write_fmt(format_args!("\n{:>#6X}", vector))?;
+            for cpu_index in 0..cpu_count {
+                f.write_fmt(format_args!("{:>12}", cpu_interrupt_count(cpu_index, vector)))?;
+            }
+            let completed: u64 = LATENCY_HISTOGRAMS[vector]
+                .iter()
+                .map(|bucket| bucket.load(Ordering::Relaxed))
+                .sum();
+            let average_cycles = TOTAL_CYCLES[vector].load(Ordering::Relaxed) / completed.max(1);
+            f.write_fmt(format_args!(
+                "{:>12}{:>12}{:>12}{:>8}  {}",
+                total,
+                average_cycles,
+                MAX_CYCLES[vector].load(Ordering::Relaxed),
+                STORM_WINDOWS[vector].storms.load(Ordering::Relaxed),
+                InterruptVector::name(vector)
+            ))?;
+            f.write_str("\n      cycles:")?;
+            for bucket in 0..HISTOGRAM_BUCKETS {
+                let count = latency_histogram_bucket(vector, bucket);
+                if count > 0 {
+                    f.write_fmt(format_args!(" <2^{}: {}", bucket, count))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn dump_interrupt_stats() {
+    log::info!("Interrupt statistics:\n{}", InterruptStats);
+}
@@ -0,0 +1,105 @@
+use core::ptr;
+use core::sync::atomic::{
+    AtomicBool,
+    AtomicPtr,
+    Ordering,
+};
+
+// Deferred work that runs in process context, where it may sleep, take mmu locks and allocate. Like tasklets,
+// work items are statics linked into their queue, so queueing one from a hard IRQ handler never allocates.
+#[derive(Debug)]
+pub struct Work {
+    pub name: &'static str,
+    func: fn(usize),
+    data: usize,
+    pending: AtomicBool,
+    next: AtomicPtr<Work>,
+}
+
+impl Work {
+    pub const fn new(name: &'static str, func: fn(usize), data: usize) -> Self {
+        Work {
+            name,
+            func,
+            data,
+            pending: AtomicBool::new(false),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    #[inline]
+    pub fn is_pending(&self) -> bool {
+        self.pending.load(Ordering::Acquire)
+    }
+}
+
+// Work items waiting for a worker. Each queue is meant to be serviced by its own worker threads once there is a
+// scheduler. Until then the CPU drains the system queue whenever it idles.
+#[derive(Debug)]
+pub struct WorkQueue {
+    pub name: &'static str,
+    head: AtomicPtr<Work>,
+}
+
+impl WorkQueue {
+    pub const fn new(name: &'static str) -> Self {
+        WorkQueue {
+            name,
+            head: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    // Returns false if the work was still pending, it then only runs once
+    pub fn queue(&self, work: &'static Work) -> bool {
+        if work.pending.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+        let work_ptr = work as *const Work as *mut Work;
+        let mut head = self.head.load(Ordering::Acquire);
+        loop {
+            work.next.store(head, Ordering::Relaxed);
+            match self
+                .head
+                .compare_exchange_weak(head, work_ptr, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => return true,
+                Err(current_head) => head = current_head,
+            }
+        }
+    }
+
+    #[inline]
+    pub fn has_pending_work(&self) -> bool {
+        !self.head.load(Ordering::Acquire).is_null()
+    }
+
+    // The body of a worker: runs everything queued so far in the order it was queued, and returns how many work
+    // items ran. Must be called from process context, never from an interrupt handler.
+    pub fn run_pending(&self) -> usize {
+        let mut reversed = self.head.swap(ptr::null_mut(), Ordering::AcqRel);
+        let mut work_ptr = ptr::null_mut();
+        while !reversed.is_null() {
+            let work = unsafe { &*reversed };
+            reversed = work.next.swap(work_ptr, Ordering::Relaxed);
+            work_ptr = work as *const Work as *mut Work;
+        }
+        let mut work_count = 0;
+        while !work_ptr.is_null() {
+            let work = unsafe { &*work_ptr };
+            work_ptr = work.next.load(Ordering::Relaxed);
+            // Cleared first, so the work can be queued again while it runs
+            work.pending.store(false, Ordering::Release);
+            (work.func)(work.data);
+            work_count += 1;
+        }
+        work_count
+    }
+}
+
+// For work that doesn't need a queue of its own
+pub static SYSTEM_WORK_QUEUE: WorkQueue = WorkQueue::new("system");
+
+#[inline]
+pub fn schedule_work(work: &'static Work) -> bool {
+    SYSTEM_WORK_QUEUE.queue(work)
+}
@@ -1,9 +1,12 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![feature(naked_functions)]
 #![feature(asm_const)]
 #![feature(allocator_api)]
+#![feature(alloc_error_handler)]
 #![feature(const_mut_refs)]
 
+extern crate alloc;
+
 use bootloader_x86_64_common::logger::LockedLogger;
 use conquer_once::spin::OnceCell;
 
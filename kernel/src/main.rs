@@ -27,7 +27,8 @@ fn kmain(boot_info: &'static mut BootInfo) -> ! {
     kernel::boot::init(boot_info);
     // kernel::cpu::init_cpu_intrinsics();
     log::info!("{}", kernel::cpu::CPU_INFO.get().unwrap());
-    loop {}
+    // The boot CPU services the system work queue from here on
+    kernel::interrupts::exception::idle()
 }
 
 /// This function is called on panic.
@@ -114,6 +114,13 @@ impl VirtualAddress {
         ((self.inner >> 39) & 0x01FF) as usize
     }
 
+    // Level 1 is the PT index, up through level 4 for the PML4 index
+    #[inline]
+    pub fn get_table_index(self, level: usize) -> usize {
+        debug_assert!((1..=4).contains(&level));
+        (self.inner >> (12 + 9 * (level - 1))) & 0x01FF
+    }
+
     #[inline]
     pub fn is_aligned(&self) -> bool {
         self.inner % 4096 == 0
@@ -0,0 +1,248 @@
+use core::fmt;
+use core::sync::atomic::{
+    AtomicUsize,
+    Ordering,
+};
+
+use bootloader_api::info::{
+    MemoryRegionKind,
+    MemoryRegions,
+};
+use conquer_once::spin::OnceCell;
+
+use crate::mmu::address::PhysicalAddress;
+use crate::mmu::alloc::frame::FRAME_ALLOCATOR;
+use crate::mmu::vmm::kernel_image::kernel_image_size;
+use crate::mmu::vmm::Size;
+
+// What the bootloader's memory map says a region is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryClass {
+    Usable,
+    // The kernel image, the bootloader's page tables, the boot info and the kernel stack
+    Bootloader,
+    AcpiReclaimable,
+    AcpiNvs,
+    // UEFI runtime services code and data
+    Firmware,
+    Reserved,
+    Defective,
+}
+
+impl MemoryClass {
+    pub const COUNT: usize = 7;
+
+    pub const ALL: [MemoryClass; Self::COUNT] = [
+        MemoryClass::Usable,
+        MemoryClass::Bootloader,
+        MemoryClass::AcpiReclaimable,
+        MemoryClass::AcpiNvs,
+        MemoryClass::Firmware,
+        MemoryClass::Reserved,
+        MemoryClass::Defective,
+    ];
+
+    // UEFI Specification 2.10 - Section 7.2, ACPI Specification 6.5 - Section 15.3
+    pub fn of(kind: MemoryRegionKind) -> Self {
+        match kind {
+            MemoryRegionKind::Usable => MemoryClass::Usable,
+            MemoryRegionKind::Bootloader => MemoryClass::Bootloader,
+            MemoryRegionKind::UnknownUefi(9) | MemoryRegionKind::UnknownBios(3) => MemoryClass::AcpiReclaimable,
+            MemoryRegionKind::UnknownUefi(10) | MemoryRegionKind::UnknownBios(4) => MemoryClass::AcpiNvs,
+            MemoryRegionKind::UnknownUefi(5) | MemoryRegionKind::UnknownUefi(6) => MemoryClass::Firmware,
+            MemoryRegionKind::UnknownUefi(8) | MemoryRegionKind::UnknownBios(5) => MemoryClass::Defective,
+            _ => MemoryClass::Reserved,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            MemoryClass::Usable => "Usable",
+            MemoryClass::Bootloader => "Bootloader",
+            MemoryClass::AcpiReclaimable => "AcpiReclaimable",
+            MemoryClass::AcpiNvs => "AcpiNvs",
+            MemoryClass::Firmware => "Firmware",
+            MemoryClass::Reserved => "Reserved",
+            MemoryClass::Defective => "Defective",
+        }
+    }
+}
+
+// Who a frame taken from the frame allocator was handed to. Frames shared between several owners are only
+// counted once, against whoever allocated them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameOwner {
+    Heap,
+    PageTable,
+    Vmalloc,
+    Dma,
+    User,
+    // Anything else the kernel backs with frames, like its demand regions
+    Kernel,
+}
+
+impl FrameOwner {
+    pub const COUNT: usize = 6;
+
+    pub const ALL: [FrameOwner; Self::COUNT] = [
+        FrameOwner::Heap,
+        FrameOwner::PageTable,
+        FrameOwner::Vmalloc,
+        FrameOwner::Dma,
+        FrameOwner::User,
+        FrameOwner::Kernel,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FrameOwner::Heap => "Heap",
+            FrameOwner::PageTable => "PageTables",
+            FrameOwner::Vmalloc => "Vmalloc",
+            FrameOwner::Dma => "Dma",
+            FrameOwner::User => "User",
+            FrameOwner::Kernel => "Kernel",
+        }
+    }
+}
+
+// Bytes of each memory class in the bootloader's map, indexed by MemoryClass
+static MEMORY_MAP_TOTALS: OnceCell<[usize; MemoryClass::COUNT]> = OnceCell::uninit();
+
+static OWNED_FRAMES: [AtomicUsize; FrameOwner::COUNT] = [const { AtomicUsize::new(0) }; FrameOwner::COUNT];
+
+// Logs every region of the bootloader's memory map and keeps the totals of each class around
+pub fn init_memory_accounting(memory_regions: &'static MemoryRegions) {
+    let mut totals = [0; MemoryClass::COUNT];
+    for region in memory_regions.iter() {
+        let memory_class = MemoryClass::of(region.kind);
+        totals[memory_class as usize] += (region.end - region.start) as usize;
+        log::debug!(
+            "Memory region {:#X}-{:#X} {} ({:?})",
+            region.start,
+            region.end,
+            memory_class.name(),
+            region.kind
+        );
+    }
+    MEMORY_MAP_TOTALS.init_once(|| totals);
+    for memory_class in MemoryClass::ALL {
+        log::info!(
+            "Memory map: {} - {} KiB",
+            memory_class.name(),
+            totals[memory_class as usize] / 1024
+        );
+    }
+}
+
+// Number of frames needed to back `size` bytes
+#[inline]
+pub fn frame_count(size: usize) -> usize {
+    PhysicalAddress::new(size).align_up(Size::FOUR_KIB).inner / Size::FOUR_KIB
+}
+
+// Records that `count` frames were allocated on behalf of `owner`
+#[inline]
+pub fn charge_frames(owner: FrameOwner, count: usize) {
+    OWNED_FRAMES[owner as usize].fetch_add(count, Ordering::Relaxed);
+}
+
+// Records that `count` frames charged to `owner` went back to the frame allocator
+#[inline]
+pub fn uncharge_frames(owner: FrameOwner, count: usize) {
+    let previous = OWNED_FRAMES[owner as usize].fetch_sub(count, Ordering::Relaxed);
+    debug_assert!(previous >= count, "{} frames uncharged more than once!", owner.name());
+}
+
+#[inline]
+pub fn owned_frames(owner: FrameOwner) -> usize {
+    OWNED_FRAMES[owner as usize].load(Ordering::Relaxed)
+}
+
+// A snapshot of where physical memory went. All sizes are in bytes.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryInfo {
+    // Usable memory according to the memory map
+    pub total: usize,
+    // Managed by the frame allocator. Usable memory outside of it holds the frame descriptor table
+    pub managed: usize,
+    pub free: usize,
+    pub kernel_image: usize,
+    pub memory_map: [usize; MemoryClass::COUNT],
+    pub owned: [usize; FrameOwner::COUNT],
+}
+
+impl MemoryInfo {
+    #[inline]
+    pub fn memory_class(&self, memory_class: MemoryClass) -> usize {
+        self.memory_map[memory_class as usize]
+    }
+
+    #[inline]
+    pub fn owned_by(&self, owner: FrameOwner) -> usize {
+        self.owned[owner as usize]
+    }
+
+    #[inline]
+    pub fn used(&self) -> usize {
+        self.managed - self.free
+    }
+
+    // Allocated frames nobody was charged for
+    pub fn unaccounted(&self) -> usize {
+        self.used().saturating_sub(self.owned.iter().sum())
+    }
+
+    // Name and size of every line of the report, in the order /proc/meminfo would list them
+    pub fn entries(&self) -> impl Iterator<Item = (&'static str, usize)> + '_ {
+        let totals = [
+            ("MemTotal", self.total),
+            ("MemFree", self.free),
+            ("MemUsed", self.used()),
+        ];
+        let owners = FrameOwner::ALL
+            .into_iter()
+            .map(|owner| (owner.name(), self.owned_by(owner)));
+        let kernel = [
+            ("Unaccounted", self.unaccounted()),
+            ("AllocatorOverhead", self.total - self.managed),
+            ("KernelImage", self.kernel_image),
+        ];
+        let memory_map = MemoryClass::ALL
+            .into_iter()
+            .filter(|memory_class| *memory_class != MemoryClass::Usable)
+            .map(|memory_class| (memory_class.name(), self.memory_class(memory_class)));
+        totals.into_iter().chain(owners).chain(kernel).chain(memory_map)
+    }
+}
+
+impl fmt::Display for MemoryInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, size) in self.entries() {
+            // Values are right aligned in one column, like /proc/meminfo
+            writeln!(f, "{}:{:>width$} kB", name, size / 1024, width = 24 - name.len())?;
+        }
+        Ok(())
+    }
+}
+
+pub fn memory_info() -> MemoryInfo {
+    let memory_map = *MEMORY_MAP_TOTALS.get().expect("Memory accounting not initialized!");
+    let (managed, free) = {
+        let frame_allocator = FRAME_ALLOCATOR.lock();
+        (frame_allocator.total_frames(), frame_allocator.free_frames())
+    };
+    MemoryInfo {
+        total: memory_map[MemoryClass::Usable as usize],
+        managed: managed * Size::FOUR_KIB,
+        free: free * Size::FOUR_KIB,
+        kernel_image: kernel_image_size(),
+        memory_map,
+        owned: FrameOwner::ALL.map(|owner| owned_frames(owner) * Size::FOUR_KIB),
+    }
+}
+
+pub fn log_memory_info() {
+    for (name, size) in memory_info().entries() {
+        log::info!("{}: {} KiB", name, size / 1024);
+    }
+}
@@ -11,8 +11,11 @@ use crate::mmu::vmm::page::{
     VirtualPage,
     VirtualPageRange,
 };
-use crate::mmu::vmm::page_table::MappedPageTable;
-use crate::mmu::vmm::page_table::PageTable;
+use crate::mmu::vmm::page_table::{
+    MapToError,
+    MappedPageTable,
+    PageTable,
+};
 use crate::mmu::vmm::page_table_entry::PageTableEntryFlags;
 use crate::mmu::vmm::Size;
 
@@ -29,7 +32,7 @@ impl FrameAllocator for BootFrameAllocator {
         self.index += 1;
         frame
     }
-    fn deallocate_frame(&self, _physical_frame: PhysicalFrame) {
+    fn deallocate_frame(&mut self, _physical_frame: PhysicalFrame) {
         panic!("Attempted to dealloc!");
     }
 }
@@ -53,17 +56,23 @@ impl BootFrameAllocator {
             .map(|frame_start_address| PhysicalFrame::from_raw_address_aligned(frame_start_address as usize))
     }
 
-    // This is intended to be used exclusively in a kernel context, shortly after booting.
-    pub unsafe fn allocate_region(&mut self, start_addr: VirtualAddress, end_addr: VirtualAddress) {
+    // This is intended to be used exclusively in a kernel context, shortly after booting. Pages mapped before
+    // running out of frames stay mapped, the boot allocator can't take frames back.
+    pub unsafe fn allocate_region(
+        &mut self,
+        start_addr: VirtualAddress,
+        end_addr: VirtualAddress,
+    ) -> Result<(), MapToError> {
         let start_page = VirtualPage::from_address_aligned(start_addr);
         let end_page = VirtualPage::from_address_aligned(end_addr);
         let page_range = VirtualPageRange::range_inclusive(start_page, end_page);
         let mut active_pml4 = MappedPageTable::new(VirtualAddress::kernel_base(), PageTable::get_active_pml4());
-        page_range.for_each(|page| {
-            let allocated_frame = self.allocate_frame().unwrap();
-            let entry_flags = PageTableEntryFlags::PRESENT & PageTableEntryFlags::WRITE_ACCESS;
-            let table_flags = entry_flags;
-            active_pml4.map_to(page, allocated_frame, entry_flags, table_flags, true, self)
-        })
+        let entry_flags = PageTableEntryFlags::PRESENT | PageTableEntryFlags::WRITE_ACCESS;
+        let table_flags = entry_flags;
+        for page in page_range {
+            let allocated_frame = self.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+            active_pml4.map_to(page, allocated_frame, entry_flags, table_flags, self)?;
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,531 @@
+use bootloader_api::info::{
+    MemoryRegionKind,
+    MemoryRegions,
+};
+
+use crate::mmu::address::{
+    PhysicalAddress,
+    VirtualAddress,
+};
+use crate::mmu::alloc::frame::FrameAllocator;
+use crate::mmu::vmm::frame::PhysicalFrame;
+use crate::mmu::vmm::Size;
+
+// Blocks range from a single 4 KiB frame (order 0) up to 4 MiB (order 10)
+pub const MAX_ORDER: usize = 11;
+
+// Marks the end of a free list. Free list links are frame indices, not pointers
+const NIL: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub enum FrameDescriptorFlags {}
+
+impl FrameDescriptorFlags {
+    // The frame is the first frame of a block sitting on one of the free lists
+    pub const FREE: u8 = 1;
+    // The frame is not managed by the allocator (memory holes, firmware, the descriptor table itself)
+    pub const RESERVED: u8 = 1 << 1;
+}
+
+// One of these exists for every frame between the lowest and highest usable physical address.
+// The free lists are threaded through the descriptors, so freed memory is never written to.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct FrameDescriptor {
+    next: u32,
+    prev: u32,
+    order: u8,
+    flags: u8,
+    // Number of owners of an allocated block. Only kept in the block's first descriptor
+    ref_count: u16,
+}
+
+impl FrameDescriptor {
+    const fn reserved() -> Self {
+        FrameDescriptor {
+            next: NIL,
+            prev: NIL,
+            order: 0,
+            flags: FrameDescriptorFlags::RESERVED,
+            ref_count: 0,
+        }
+    }
+
+    #[inline]
+    fn is_free(&self) -> bool {
+        self.flags & FrameDescriptorFlags::FREE == FrameDescriptorFlags::FREE
+    }
+
+    #[inline]
+    fn is_reserved(&self) -> bool {
+        self.flags & FrameDescriptorFlags::RESERVED == FrameDescriptorFlags::RESERVED
+    }
+}
+
+#[derive(Debug)]
+pub struct BuddyFrameAllocator {
+    free_lists: [u32; MAX_ORDER],
+    descriptors: &'static mut [FrameDescriptor],
+    // Frame number of descriptors[0]. Aligned to the largest block size so buddies can be found with an XOR
+    base_frame_number: usize,
+    total_frames: usize,
+    free_frames: usize,
+}
+
+#[inline]
+fn frame_number(frame: PhysicalFrame) -> usize {
+    frame.start_address() / Size::FOUR_KIB
+}
+
+#[inline]
+fn frame_from_number(frame_number: usize) -> PhysicalFrame {
+    PhysicalFrame::from_raw_address_aligned(frame_number * Size::FOUR_KIB)
+}
+
+impl BuddyFrameAllocator {
+    pub const fn new() -> Self {
+        BuddyFrameAllocator {
+            free_lists: [NIL; MAX_ORDER],
+            descriptors: &mut [],
+            base_frame_number: 0,
+            total_frames: 0,
+            free_frames: 0,
+        }
+    }
+
+    // Reads the bootloader memory map, carves the descriptor table out of the first usable region large
+    // enough to hold it, and hands every other usable frame to the free lists.
+    pub unsafe fn init(&mut self, memory_regions: &'static MemoryRegions) {
+        let usable_regions = || {
+            memory_regions
+                .iter()
+                .filter(|memory_region| memory_region.kind == MemoryRegionKind::Usable)
+        };
+        let lowest_address = usable_regions().map(|region| region.start).min().unwrap() as usize;
+        let highest_address = usable_regions().map(|region| region.end).max().unwrap() as usize;
+        let max_block_frames = 1 << (MAX_ORDER - 1);
+        let base_frame_number = (lowest_address / Size::FOUR_KIB) & !(max_block_frames - 1);
+        let end_frame_number = PhysicalAddress::new(highest_address).align_up(Size::FOUR_KIB).inner / Size::FOUR_KIB;
+        let frame_count = end_frame_number - base_frame_number;
+        assert!(
+            frame_count < NIL as usize,
+            "Physical memory too large for the frame descriptor table!"
+        );
+
+        let table_size = frame_count * core::mem::size_of::<FrameDescriptor>();
+        let table_region = usable_regions()
+            .find(|region| (region.end - region.start) as usize >= table_size)
+            .expect("No usable region large enough for the frame descriptor table!");
+        let table_start = PhysicalAddress::new(table_region.start as usize).align_up(Size::FOUR_KIB);
+        let table_end = (table_start + table_size).align_up(Size::FOUR_KIB);
+        let table_virtual_address = VirtualAddress::with_kernel_base_offset(table_start.inner);
+        let table_ptr = table_virtual_address.inner as *mut FrameDescriptor;
+        for index in 0..frame_count {
+            table_ptr.add(index).write(FrameDescriptor::reserved());
+        }
+        self.descriptors = core::slice::from_raw_parts_mut(table_ptr, frame_count);
+        self.base_frame_number = base_frame_number;
+        self.free_lists = [NIL; MAX_ORDER];
+        self.total_frames = 0;
+        self.free_frames = 0;
+
+        for region in usable_regions() {
+            let mut start = PhysicalAddress::new(region.start as usize).align_up(Size::FOUR_KIB);
+            let end = PhysicalAddress::new(region.end as usize).align_down(Size::FOUR_KIB);
+            // Skip over the descriptor table if it lives in this region
+            if start >= table_start && start < table_end {
+                start = table_end;
+            }
+            if start < end {
+                self.add_free_range(start.inner / Size::FOUR_KIB, end.inner / Size::FOUR_KIB);
+            }
+        }
+        log::info!(
+            "Buddy frame allocator initialized: {} frames free, descriptor table at {:#X}",
+            self.free_frames,
+            table_start.inner
+        );
+    }
+
+    // Splits [start, end) into the largest naturally aligned blocks that fit and frees each one
+    fn add_free_range(&mut self, mut start: usize, end: usize) {
+        while start < end {
+            let mut order = MAX_ORDER - 1;
+            while order > 0 && (start % (1 << order) != 0 || start + (1 << order) > end) {
+                order -= 1;
+            }
+            self.total_frames += 1 << order;
+            self.free_block(start, order);
+            start += 1 << order;
+        }
+    }
+
+    #[inline]
+    fn index(&self, frame_number: usize) -> Option<usize> {
+        frame_number
+            .checked_sub(self.base_frame_number)
+            .filter(|index| *index < self.descriptors.len())
+    }
+
+    fn push_free(&mut self, index: usize, order: usize) {
+        let head = self.free_lists[order];
+        if head != NIL {
+            self.descriptors[head as usize].prev = index as u32;
+        }
+        let descriptor = &mut self.descriptors[index];
+        descriptor.next = head;
+        descriptor.prev = NIL;
+        descriptor.order = order as u8;
+        descriptor.flags = FrameDescriptorFlags::FREE;
+        descriptor.ref_count = 0;
+        self.free_lists[order] = index as u32;
+    }
+
+    fn remove_free(&mut self, index: usize) {
+        let FrameDescriptor { next, prev, order, .. } = self.descriptors[index];
+        match prev {
+            NIL => self.free_lists[order as usize] = next,
+            prev => self.descriptors[prev as usize].next = next,
+        }
+        if next != NIL {
+            self.descriptors[next as usize].prev = prev;
+        }
+        let descriptor = &mut self.descriptors[index];
+        descriptor.next = NIL;
+        descriptor.prev = NIL;
+        descriptor.flags &= !FrameDescriptorFlags::FREE;
+    }
+
+    fn free_block(&mut self, mut frame_number: usize, mut order: usize) {
+        self.free_frames += 1 << order;
+        while order < MAX_ORDER - 1 {
+            let buddy_frame_number = frame_number ^ (1 << order);
+            let buddy_index = match self.index(buddy_frame_number) {
+                Some(buddy_index) => buddy_index,
+                None => break,
+            };
+            let buddy = self.descriptors[buddy_index];
+            if !buddy.is_free() || buddy.order as usize != order {
+                break;
+            }
+            self.remove_free(buddy_index);
+            frame_number = frame_number.min(buddy_frame_number);
+            order += 1;
+        }
+        let index = self.index(frame_number).unwrap();
+        self.push_free(index, order);
+    }
+
+    // Descriptor indices of the blocks on the free list of the given order
+    fn free_list(&self, order: usize) -> impl Iterator<Item = usize> + '_ {
+        let head = Some(self.free_lists[order]).filter(|&head| head != NIL);
+        core::iter::successors(head, |&index| {
+            Some(self.descriptors[index as usize].next).filter(|&next| next != NIL)
+        })
+        .map(|index| index as usize)
+    }
+
+    // Allocates 2^order physically contiguous frames, aligned to their size
+    pub fn allocate_order(&mut self, order: usize) -> Option<PhysicalFrame> {
+        if order >= MAX_ORDER {
+            return None;
+        }
+        let current_order = (order..MAX_ORDER).find(|&order| self.free_lists[order] != NIL)?;
+        let index = self.free_lists[current_order] as usize;
+        Some(self.take_block(index, current_order, order))
+    }
+
+    // Like allocate_order, but the block has to end at or below `address_limit`. For devices that can only
+    // address part of physical memory.
+    pub fn allocate_order_below(&mut self, order: usize, address_limit: usize) -> Option<PhysicalFrame> {
+        if order >= MAX_ORDER {
+            return None;
+        }
+        // Splitting keeps the lowest part of a block, so only the start of the block it's cut from matters
+        let highest_frame_number = (address_limit / Size::FOUR_KIB).checked_sub(1 << order)?;
+        let (current_order, index) = (order..MAX_ORDER).find_map(|current_order| {
+            self.free_list(current_order)
+                .find(|&index| self.base_frame_number + index <= highest_frame_number)
+                .map(|index| (current_order, index))
+        })?;
+        Some(self.take_block(index, current_order, order))
+    }
+
+    // Takes the free block at `index` off its free list and splits it down to the requested order
+    fn take_block(&mut self, index: usize, mut current_order: usize, order: usize) -> PhysicalFrame {
+        self.remove_free(index);
+        // Hand the upper halves back until the block is the requested size
+        while current_order > order {
+            current_order -= 1;
+            self.push_free(index + (1 << current_order), current_order);
+        }
+        let descriptor = &mut self.descriptors[index];
+        descriptor.order = order as u8;
+        descriptor.flags = 0;
+        descriptor.ref_count = 1;
+        self.free_frames -= 1 << order;
+        frame_from_number(self.base_frame_number + index)
+    }
+
+    pub fn deallocate_order(&mut self, frame: PhysicalFrame, order: usize) {
+        let frame_number = frame_number(frame);
+        let index = self
+            .index(frame_number)
+            .expect("Attempted to free a frame outside of managed memory!");
+        let descriptor = self.descriptors[index];
+        assert!(!descriptor.is_reserved(), "Attempted to free a reserved frame!");
+        assert!(
+            !descriptor.is_free(),
+            "Double free of frame: {:#X}",
+            frame.start_address()
+        );
+        assert!(
+            descriptor.ref_count <= 1,
+            "Freed frame {:#X} that is still shared!",
+            frame.start_address()
+        );
+        debug_assert_eq!(descriptor.order as usize, order);
+        self.free_block(frame_number, order);
+    }
+
+    // The descriptor of an allocated block, looked up by its first frame
+    fn allocated_descriptor(&mut self, frame: PhysicalFrame) -> &mut FrameDescriptor {
+        let index = self
+            .index(frame_number(frame))
+            .expect("Attempted to share a frame outside of managed memory!");
+        let descriptor = &mut self.descriptors[index];
+        assert!(
+            !descriptor.is_free() && !descriptor.is_reserved() && descriptor.ref_count > 0,
+            "Frame {:#X} is not allocated!",
+            frame.start_address()
+        );
+        descriptor
+    }
+
+    // Adds an owner to an allocated frame. It's only freed again once every owner released it.
+    pub fn share_frame(&mut self, frame: PhysicalFrame) {
+        let descriptor = self.allocated_descriptor(frame);
+        descriptor.ref_count = descriptor
+            .ref_count
+            .checked_add(1)
+            .expect("Frame reference count overflow!");
+    }
+
+    // Drops one owner of the frame, freeing it if that was the last one. Returns whether the frame was freed.
+    pub fn release_frame(&mut self, frame: PhysicalFrame) -> bool {
+        let descriptor = self.allocated_descriptor(frame);
+        descriptor.ref_count -= 1;
+        if descriptor.ref_count > 0 {
+            return false;
+        }
+        let order = descriptor.order as usize;
+        self.free_block(frame_number(frame), order);
+        true
+    }
+
+    // 0 for frames that are free or not managed by the allocator
+    pub fn ref_count(&self, frame: PhysicalFrame) -> usize {
+        self.index(frame_number(frame))
+            .map_or(0, |index| self.descriptors[index].ref_count as usize)
+    }
+
+    #[inline]
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    #[inline]
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+}
+
+impl FrameAllocator for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysicalFrame> {
+        self.allocate_order(0)
+    }
+
+    fn deallocate_frame(&mut self, physical_frame: PhysicalFrame) {
+        self.deallocate_order(physical_frame, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Aligned to the largest block size, like the real base frame
+    const BASE_FRAME_NUMBER: usize = 1 << (MAX_ORDER - 1);
+    const MAX_BLOCK_FRAMES: usize = 1 << (MAX_ORDER - 1);
+
+    // The allocator never touches the frames it hands out, so only the descriptor table needs real memory
+    fn test_allocator(frame_count: usize, free_start: usize, free_end: usize) -> BuddyFrameAllocator {
+        let mut allocator = BuddyFrameAllocator::new();
+        allocator.descriptors = vec![FrameDescriptor::reserved(); frame_count].leak();
+        allocator.base_frame_number = BASE_FRAME_NUMBER;
+        allocator.add_free_range(BASE_FRAME_NUMBER + free_start, BASE_FRAME_NUMBER + free_end);
+        allocator
+    }
+
+    // Descriptor indices of the blocks on the free list of the given order
+    fn free_list(allocator: &BuddyFrameAllocator, order: usize) -> Vec<usize> {
+        let mut indices = Vec::new();
+        let mut index = allocator.free_lists[order];
+        while index != NIL {
+            indices.push(index as usize);
+            index = allocator.descriptors[index as usize].next;
+        }
+        indices
+    }
+
+    fn free_list_lengths(allocator: &BuddyFrameAllocator) -> [usize; MAX_ORDER] {
+        core::array::from_fn(|order| free_list(allocator, order).len())
+    }
+
+    fn frame_index(frame: PhysicalFrame) -> usize {
+        frame_number(frame) - BASE_FRAME_NUMBER
+    }
+
+    #[test]
+    fn unaligned_range_is_split_into_aligned_blocks() {
+        let allocator = test_allocator(32, 3, 17);
+        // 3, 4-7, 8-15 and 16
+        let mut expected = [0; MAX_ORDER];
+        expected[0] = 2;
+        expected[2] = 1;
+        expected[3] = 1;
+        assert_eq!(free_list_lengths(&allocator), expected);
+        assert_eq!(allocator.free_frames(), 14);
+        assert_eq!(allocator.total_frames(), 14);
+    }
+
+    #[test]
+    fn allocating_a_frame_splits_the_largest_block() {
+        let mut allocator = test_allocator(MAX_BLOCK_FRAMES, 0, MAX_BLOCK_FRAMES);
+        let frame = allocator.allocate_order(0).unwrap();
+        assert_eq!(frame_index(frame), 0);
+        // One upper half left over on every order below the largest
+        let mut expected = [1; MAX_ORDER];
+        expected[MAX_ORDER - 1] = 0;
+        assert_eq!(free_list_lengths(&allocator), expected);
+        assert_eq!(allocator.free_frames(), MAX_BLOCK_FRAMES - 1);
+        allocator.deallocate_order(frame, 0);
+        let mut expected = [0; MAX_ORDER];
+        expected[MAX_ORDER - 1] = 1;
+        assert_eq!(free_list_lengths(&allocator), expected);
+        assert_eq!(allocator.free_frames(), MAX_BLOCK_FRAMES);
+    }
+
+    #[test]
+    fn blocks_are_aligned_to_their_order() {
+        let mut allocator = test_allocator(MAX_BLOCK_FRAMES, 0, MAX_BLOCK_FRAMES);
+        let blocks: Vec<(PhysicalFrame, usize)> = [0, 3, 1, 5, 2, 0, 4]
+            .into_iter()
+            .map(|order| (allocator.allocate_order(order).unwrap(), order))
+            .collect();
+        for (frame, order) in blocks.iter() {
+            assert_eq!(frame_index(*frame) % (1 << order), 0);
+        }
+        for (frame, order) in blocks.iter() {
+            allocator.deallocate_order(*frame, *order);
+        }
+        assert_eq!(free_list(&allocator, MAX_ORDER - 1).len(), 1);
+        assert_eq!(allocator.free_frames(), MAX_BLOCK_FRAMES);
+    }
+
+    #[test]
+    fn buddies_merge_only_once_both_are_free() {
+        let mut allocator = test_allocator(16, 0, 16);
+        let a = allocator.allocate_order(0).unwrap();
+        let b = allocator.allocate_order(0).unwrap();
+        let c = allocator.allocate_order(1).unwrap();
+        assert_eq!(frame_index(a) ^ 1, frame_index(b));
+        allocator.deallocate_order(a, 0);
+        // b is still allocated, so a stays on its own
+        assert_eq!(free_list(&allocator, 0), [0]);
+        allocator.deallocate_order(b, 0);
+        assert_eq!(free_list(&allocator, 0).len(), 0);
+        // a and b merged into an order 1 block, which waits for c
+        assert_eq!(free_list(&allocator, 1), [0]);
+        allocator.deallocate_order(c, 1);
+        assert_eq!(free_list(&allocator, 1).len(), 0);
+        assert_eq!(free_list(&allocator, 4), [0]);
+    }
+
+    #[test]
+    fn blocks_do_not_merge_past_the_largest_order() {
+        let mut allocator = test_allocator(2 * MAX_BLOCK_FRAMES, 0, 2 * MAX_BLOCK_FRAMES);
+        assert_eq!(free_list(&allocator, MAX_ORDER - 1).len(), 2);
+        let first = allocator.allocate_order(MAX_ORDER - 1).unwrap();
+        let second = allocator.allocate_order(MAX_ORDER - 1).unwrap();
+        assert!(allocator.allocate_order(MAX_ORDER - 1).is_none());
+        assert!(allocator.allocate_order(0).is_none());
+        assert!(allocator.allocate_order(MAX_ORDER).is_none());
+        allocator.deallocate_order(first, MAX_ORDER - 1);
+        allocator.deallocate_order(second, MAX_ORDER - 1);
+        assert_eq!(free_list(&allocator, MAX_ORDER - 1).len(), 2);
+        assert_eq!(allocator.free_frames(), 2 * MAX_BLOCK_FRAMES);
+    }
+
+    #[test]
+    fn reserved_frames_stop_merging() {
+        // Frame 0 is never handed to the allocator, so frame 1 has no free buddy
+        let mut allocator = test_allocator(4, 1, 4);
+        let frame = allocator.allocate_order(0).unwrap();
+        assert_eq!(frame_index(frame), 1);
+        allocator.deallocate_order(frame, 0);
+        assert_eq!(free_list(&allocator, 0), [1]);
+        assert_eq!(free_list(&allocator, 1), [2]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn double_free_is_caught() {
+        let mut allocator = test_allocator(2, 0, 2);
+        let frame = allocator.allocate_order(0).unwrap();
+        let _other = allocator.allocate_order(0).unwrap();
+        allocator.deallocate_order(frame, 0);
+        allocator.deallocate_order(frame, 0);
+    }
+
+    #[test]
+    fn shared_frames_are_freed_by_their_last_owner() {
+        let mut allocator = test_allocator(2, 0, 2);
+        let frame = allocator.allocate_order(0).unwrap();
+        assert_eq!(allocator.ref_count(frame), 1);
+        allocator.share_frame(frame);
+        allocator.share_frame(frame);
+        assert_eq!(allocator.ref_count(frame), 3);
+        assert!(!allocator.release_frame(frame));
+        assert!(!allocator.release_frame(frame));
+        assert_eq!(allocator.free_frames(), 1);
+        assert!(allocator.release_frame(frame));
+        assert_eq!(allocator.ref_count(frame), 0);
+        assert_eq!(allocator.free_frames(), 2);
+        assert_eq!(free_list(&allocator, 1).len(), 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn freeing_a_shared_frame_is_caught() {
+        let mut allocator = test_allocator(2, 0, 2);
+        let frame = allocator.allocate_order(0).unwrap();
+        allocator.share_frame(frame);
+        allocator.deallocate_order(frame, 0);
+    }
+
+    #[test]
+    fn allocation_below_a_limit() {
+        let mut allocator = test_allocator(64, 0, 64);
+        // Takes the lowest block out of the way so the limit has to be checked
+        let low = allocator.allocate_order(4).unwrap();
+        let limit = (BASE_FRAME_NUMBER + 32) * Size::FOUR_KIB;
+        let frame = allocator.allocate_order_below(3, limit).unwrap();
+        assert!(frame.start_address() + 8 * Size::FOUR_KIB <= limit);
+        assert!(allocator.allocate_order_below(5, limit).is_none());
+        allocator.deallocate_order(frame, 3);
+        allocator.deallocate_order(low, 4);
+        assert_eq!(allocator.free_frames(), 64);
+    }
+}
@@ -1,31 +1,74 @@
-use bootloader_api::BootInfo;
+use bootloader_api::info::MemoryRegions;
 
-use self::boot::BootFrameAllocator;
+use self::accounting::{
+    charge_frames,
+    frame_count,
+    uncharge_frames,
+    FrameOwner,
+};
+use self::buddy::BuddyFrameAllocator;
 use crate::mmu::address::VirtualAddress;
+use crate::mmu::alloc::heap::Locked;
 use crate::mmu::vmm::frame::PhysicalFrame;
-use crate::mmu::vmm::page_table::PageTable;
+use crate::mmu::vmm::page::{
+    VirtualPage,
+    VirtualPageRange,
+};
+use crate::mmu::vmm::page_table::{
+    MapToError,
+    MappedPageTable,
+    PageTable,
+    UnmapError,
+};
+use crate::mmu::vmm::page_table_entry::PageTableEntryFlags;
+use crate::mmu::vmm::tlb::TlbFlush;
+use crate::mmu::vmm::Size;
 
+pub mod accounting;
 pub mod boot;
 pub mod buddy;
 
 pub trait FrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysicalFrame>;
-    fn deallocate_frame(&self, physical_frame: PhysicalFrame);
+    fn deallocate_frame(&mut self, physical_frame: PhysicalFrame);
 }
 
-pub(super) fn map_frames(boot_info: &'static BootInfo, start: usize, size: usize) {
-    log::info!("Mapping initial kernel heap frames");
-    let mut boot_allocator = BootFrameAllocator::new(&boot_info.memory_regions);
-    let start_addr = VirtualAddress::with_kernel_base_offset(start);
-    let end_addr = start_addr + size;
-    unsafe { boot_allocator.allocate_region(start_addr, end_addr) }
-    log::info!("Kernel heap frames mapped");
-    unsafe {
-        let pml4 = PageTable::get_active_pml4();
-        for entry in pml4.iter_mut() {
-            if entry.inner != 0 {
-                log::info!("{:#X}", entry.inner);
-            };
-        }
-    }
+pub static FRAME_ALLOCATOR: Locked<BuddyFrameAllocator> = Locked::new(BuddyFrameAllocator::new());
+
+pub fn init_frame_allocator(memory_regions: &'static MemoryRegions) {
+    log::info!("Initializing buddy frame allocator");
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    unsafe { frame_allocator.init(memory_regions) }
+}
+
+// Backs [start, start + size) of the kernel heap with newly allocated frames. Nothing stays mapped if this fails,
+// but the frames of the failed attempt are only freed once `tlb_flush` is flushed.
+pub(super) fn map_frames(start: VirtualAddress, size: usize, tlb_flush: &mut TlbFlush) -> Result<(), MapToError> {
+    let mut active_pml4 = MappedPageTable::new(VirtualAddress::kernel_base(), unsafe { PageTable::get_active_pml4() });
+    let table_flags = PageTableEntryFlags::PRESENT | PageTableEntryFlags::WRITE_ACCESS;
+    let entry_flags = table_flags | PageTableEntryFlags::no_execute();
+    active_pml4.map_range(
+        page_range(start, size),
+        entry_flags,
+        table_flags,
+        &mut *FRAME_ALLOCATOR.lock(),
+        tlb_flush,
+    )?;
+    charge_frames(FrameOwner::Heap, frame_count(size));
+    Ok(())
+}
+
+// Unmaps [start, start + size) and returns the frames behind it to the frame allocator once `tlb_flush` is
+// flushed. The heap calls this with its lock held, so the flush is left to it.
+pub(super) fn unmap_frames(start: VirtualAddress, size: usize, tlb_flush: &mut TlbFlush) -> Result<(), UnmapError> {
+    let mut active_pml4 = MappedPageTable::new(VirtualAddress::kernel_base(), unsafe { PageTable::get_active_pml4() });
+    active_pml4.unmap_range(page_range(start, size), tlb_flush)?;
+    uncharge_frames(FrameOwner::Heap, frame_count(size));
+    Ok(())
+}
+
+fn page_range(start: VirtualAddress, size: usize) -> VirtualPageRange {
+    let start_page = VirtualPage::from_address_aligned(start);
+    let end_page = VirtualPage::from_address_aligned((start + size).align_up(Size::FOUR_KIB));
+    VirtualPageRange::range_inclusive(start_page, end_page)
 }
@@ -0,0 +1,327 @@
+use core::alloc::{
+    GlobalAlloc,
+    Layout,
+};
+use core::arch::asm;
+
+use spin::Mutex;
+
+use super::slab::SlabAllocator;
+use super::{
+    Locked,
+    HEAP_ALLOCATOR,
+};
+
+// Guard bytes on either side of every allocation. Any write into them is reported when the allocation is freed
+const REDZONE_SIZE: usize = 16;
+const REDZONE_BYTE: u8 = 0xFB;
+// Fresh allocations are filled with this, so reads of uninitialized memory stand out
+const ALLOC_POISON: u8 = 0xCD;
+// Freed allocations are filled with this, so use after free stands out
+const FREE_POISON: u8 = 0xDD;
+const LIVE_MAGIC: usize = 0xA110_CA7E_D0A1_1F3E;
+const FREED_MAGIC: usize = 0xF4EE_DF4E_EDF4_EED0;
+
+// Return addresses recorded per allocation, innermost first
+const BACKTRACE_DEPTH: usize = 6;
+// Frame pointers further apart than this are assumed to be garbage and end the stack walk
+const MAX_FRAME_SIZE: usize = 1 << 20;
+// Must be a power of two
+const MAX_TRACKED_ALLOCATIONS: usize = 4096;
+
+// The fallback heap keeps its free list node in the first words of a freed block. The header is placed past them
+// so a double free still finds the freed magic.
+const INNER_RESERVED_SIZE: usize = 2 * core::mem::size_of::<usize>();
+
+// Sits right in front of the front redzone
+#[repr(C)]
+struct AllocationHeader {
+    magic: usize,
+    size: usize,
+}
+
+const HEADER_SIZE: usize = core::mem::size_of::<AllocationHeader>();
+
+#[derive(Debug, Clone, Copy)]
+struct AllocationRecord {
+    address: usize,
+    size: usize,
+    callers: [usize; BACKTRACE_DEPTH],
+}
+
+impl AllocationRecord {
+    const EMPTY: AllocationRecord = AllocationRecord {
+        address: 0,
+        size: 0,
+        callers: [0; BACKTRACE_DEPTH],
+    };
+
+    #[inline]
+    fn is_empty(&self) -> bool {
+        self.address == 0
+    }
+}
+
+// Open addressing hash table of the live allocations, keyed by address. It lives in static memory since the
+// heap can't be used to track itself.
+struct AllocationTable {
+    records: [AllocationRecord; MAX_TRACKED_ALLOCATIONS],
+    live_allocations: usize,
+    live_bytes: usize,
+    peak_bytes: usize,
+    total_allocations: usize,
+    total_frees: usize,
+    // Live allocations that didn't fit in the table. While this is non-zero, frees of unknown pointers can't be
+    // reported as invalid
+    untracked_allocations: usize,
+    untracked_bytes: usize,
+}
+
+#[inline]
+fn home_slot(address: usize) -> usize {
+    (address >> 4).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> (usize::BITS - MAX_TRACKED_ALLOCATIONS.trailing_zeros())
+}
+
+impl AllocationTable {
+    const fn new() -> Self {
+        AllocationTable {
+            records: [AllocationRecord::EMPTY; MAX_TRACKED_ALLOCATIONS],
+            live_allocations: 0,
+            live_bytes: 0,
+            peak_bytes: 0,
+            total_allocations: 0,
+            total_frees: 0,
+            untracked_allocations: 0,
+            untracked_bytes: 0,
+        }
+    }
+
+    fn insert(&mut self, record: AllocationRecord) {
+        self.total_allocations += 1;
+        self.live_bytes += record.size;
+        self.peak_bytes = self.peak_bytes.max(self.live_bytes);
+        if self.live_allocations == MAX_TRACKED_ALLOCATIONS - 1 {
+            self.untracked_allocations += 1;
+            self.untracked_bytes += record.size;
+            return;
+        }
+        let mut slot = home_slot(record.address);
+        while !self.records[slot].is_empty() {
+            slot = (slot + 1) & (MAX_TRACKED_ALLOCATIONS - 1);
+        }
+        self.records[slot] = record;
+        self.live_allocations += 1;
+    }
+
+    fn find(&self, address: usize) -> Option<usize> {
+        let mut slot = home_slot(address);
+        while !self.records[slot].is_empty() {
+            if self.records[slot].address == address {
+                return Some(slot);
+            }
+            slot = (slot + 1) & (MAX_TRACKED_ALLOCATIONS - 1);
+        }
+        None
+    }
+
+    // Backward shift deletion, so lookups never need tombstones
+    fn remove(&mut self, address: usize) -> Option<AllocationRecord> {
+        let mut hole = self.find(address)?;
+        let record = self.records[hole];
+        let mut slot = hole;
+        loop {
+            slot = (slot + 1) & (MAX_TRACKED_ALLOCATIONS - 1);
+            if self.records[slot].is_empty() {
+                break;
+            }
+            let home = home_slot(self.records[slot].address);
+            // The record can move into the hole unless its home slot lies cyclically in (hole, slot]
+            let stays = match hole <= slot {
+                true => hole < home && home <= slot,
+                false => hole < home || home <= slot,
+            };
+            if !stays {
+                self.records[hole] = self.records[slot];
+                hole = slot;
+            }
+        }
+        self.records[hole] = AllocationRecord::EMPTY;
+        self.live_allocations -= 1;
+        self.total_frees += 1;
+        self.live_bytes -= record.size;
+        Some(record)
+    }
+
+    // Accounts for the free of an allocation that was never recorded. Returns false if there are none left, in
+    // which case the pointer can't have come from the heap.
+    fn remove_untracked(&mut self, size: usize) -> bool {
+        if self.untracked_allocations == 0 {
+            return false;
+        }
+        self.untracked_allocations -= 1;
+        self.untracked_bytes -= size;
+        self.total_frees += 1;
+        self.live_bytes -= size;
+        true
+    }
+}
+
+// Walks the frame pointer chain. Needs the kernel built with frame pointers, which the heap-debug profile turns on
+#[inline(always)]
+fn capture_callers() -> [usize; BACKTRACE_DEPTH] {
+    let mut callers = [0; BACKTRACE_DEPTH];
+    let mut frame_pointer: usize;
+    unsafe { asm!("mov {}, rbp", out(reg) frame_pointer, options(nomem, nostack, preserves_flags)) };
+    for caller in callers.iter_mut() {
+        if frame_pointer == 0 || frame_pointer % 8 != 0 {
+            break;
+        }
+        let (next_frame_pointer, return_address) =
+            unsafe { (*(frame_pointer as *const usize), *((frame_pointer + 8) as *const usize)) };
+        *caller = return_address;
+        // Stack frames only ever get older going up the stack
+        if next_frame_pointer <= frame_pointer || next_frame_pointer - frame_pointer > MAX_FRAME_SIZE {
+            break;
+        }
+        frame_pointer = next_frame_pointer;
+    }
+    callers
+}
+
+#[inline]
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+unsafe fn is_redzone_intact(start: *const u8) -> bool {
+    (0..REDZONE_SIZE).all(|offset| *start.add(offset) == REDZONE_BYTE)
+}
+
+// Wraps the kernel's allocator. Each allocation is laid out as
+// [reserved][padding][header][front redzone][allocation][back redzone]
+pub struct DebugAllocator<A: GlobalAlloc + 'static> {
+    inner: &'static A,
+    table: Mutex<AllocationTable>,
+}
+
+impl<A: GlobalAlloc + 'static> DebugAllocator<A> {
+    pub const fn new(inner: &'static A) -> Self {
+        DebugAllocator {
+            inner,
+            table: Mutex::new(AllocationTable::new()),
+        }
+    }
+
+    // The layout handed to the inner allocator, and the offset of the caller's allocation within it
+    fn outer_layout(layout: Layout) -> (Layout, usize) {
+        let align = layout.align().max(core::mem::align_of::<AllocationHeader>());
+        let front = align_up(INNER_RESERVED_SIZE + HEADER_SIZE + REDZONE_SIZE, align);
+        let outer_size = front + layout.size() + REDZONE_SIZE;
+        (Layout::from_size_align(outer_size, align).unwrap(), front)
+    }
+
+    // Logs before panicking, since the panic handler doesn't print anything
+    fn report(ptr: *mut u8, message: &str, record: Option<&AllocationRecord>) -> ! {
+        log::error!("Heap corruption at {:#X}: {}", ptr as usize, message);
+        if let Some(record) = record {
+            log::error!("Allocated with size {} from {:#X?}", record.size, record.callers);
+        }
+        panic!("Heap corruption at {:#X}: {}", ptr as usize, message);
+    }
+
+    pub fn dump_live_allocations(&self) {
+        let table = self.table.lock();
+        log::info!(
+            "Live heap allocations: {} ({} bytes, peak {} bytes), {} allocations, {} frees, {} untracked ({} bytes)",
+            table.live_allocations,
+            table.live_bytes,
+            table.peak_bytes,
+            table.total_allocations,
+            table.total_frees,
+            table.untracked_allocations,
+            table.untracked_bytes
+        );
+        for record in table.records.iter().filter(|record| !record.is_empty()) {
+            log::info!(
+                "{:#X} size: {} callers: {:#X?}",
+                record.address,
+                record.size,
+                record.callers
+            );
+        }
+    }
+}
+
+unsafe impl<A: GlobalAlloc + 'static> GlobalAlloc for DebugAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (outer_layout, front) = Self::outer_layout(layout);
+        let base = self.inner.alloc(outer_layout);
+        if base.is_null() {
+            return base;
+        }
+        let ptr = base.add(front);
+        let header = ptr.sub(REDZONE_SIZE + HEADER_SIZE) as *mut AllocationHeader;
+        header.write(AllocationHeader {
+            magic: LIVE_MAGIC,
+            size: layout.size(),
+        });
+        core::ptr::write_bytes(ptr.sub(REDZONE_SIZE), REDZONE_BYTE, REDZONE_SIZE);
+        core::ptr::write_bytes(ptr, ALLOC_POISON, layout.size());
+        core::ptr::write_bytes(ptr.add(layout.size()), REDZONE_BYTE, REDZONE_SIZE);
+        self.table.lock().insert(AllocationRecord {
+            address: ptr as usize,
+            size: layout.size(),
+            callers: capture_callers(),
+        });
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (outer_layout, front) = Self::outer_layout(layout);
+        let header = ptr.sub(REDZONE_SIZE + HEADER_SIZE) as *mut AllocationHeader;
+        let record = {
+            let mut table = self.table.lock();
+            let record = table.remove(ptr as usize);
+            // Only a pointer that still looks live can be one of the untracked allocations
+            let is_untracked =
+                record.is_none() && (*header).magic == LIVE_MAGIC && table.remove_untracked((*header).size);
+            if record.is_none() && !is_untracked {
+                drop(table);
+                match (*header).magic {
+                    FREED_MAGIC => Self::report(ptr, "double free", None),
+                    _ => Self::report(ptr, "free of a pointer that was never allocated", None),
+                }
+            }
+            record
+        };
+        match (*header).magic {
+            LIVE_MAGIC => (),
+            FREED_MAGIC => Self::report(ptr, "double free", record.as_ref()),
+            _ => Self::report(ptr, "allocation header overwritten", record.as_ref()),
+        }
+        if (*header).size != layout.size() {
+            Self::report(
+                ptr,
+                "freed with a different size than it was allocated with",
+                record.as_ref(),
+            );
+        }
+        if !is_redzone_intact(ptr.sub(REDZONE_SIZE)) {
+            Self::report(ptr, "write in front of the allocation", record.as_ref());
+        }
+        if !is_redzone_intact(ptr.add(layout.size())) {
+            Self::report(ptr, "write past the end of the allocation", record.as_ref());
+        }
+        core::ptr::write_bytes(ptr, FREE_POISON, layout.size());
+        (*header).magic = FREED_MAGIC;
+        self.inner.dealloc(ptr.sub(front), outer_layout);
+    }
+}
+
+#[cfg_attr(not(test), global_allocator)]
+static DEBUG_HEAP_ALLOCATOR: DebugAllocator<Locked<SlabAllocator>> = DebugAllocator::new(&HEAP_ALLOCATOR);
+
+// Logs every allocation that hasn't been freed yet, along with the return addresses that led to it
+pub fn dump_live_allocations() {
+    DEBUG_HEAP_ALLOCATOR.dump_live_allocations();
+}
@@ -0,0 +1,99 @@
+use core::alloc::Layout;
+
+use super::linked_list::{
+    FitPolicy,
+    LinkedListAllocator,
+    MIN_REGION_SIZE,
+};
+use super::region::HeapRegion;
+use crate::mmu::vmm::Size;
+
+// A linked list allocator over the kernel heap region, which maps more of the region when it runs dry and unmaps
+// the free memory at its end when asked to shrink.
+pub struct GrowableHeap {
+    allocator: LinkedListAllocator,
+    region: HeapRegion,
+}
+
+impl GrowableHeap {
+    pub const fn new() -> Self {
+        GrowableHeap {
+            allocator: LinkedListAllocator::new(),
+            region: HeapRegion::empty(),
+        }
+    }
+
+    pub unsafe fn init(&mut self, region: HeapRegion) {
+        self.allocator.init(region.start(), region.size());
+        self.region = region;
+    }
+
+    #[inline]
+    pub fn region(&self) -> &HeapRegion {
+        &self.region
+    }
+
+    #[inline]
+    pub fn region_mut(&mut self) -> &mut HeapRegion {
+        &mut self.region
+    }
+
+    #[inline]
+    pub fn set_fit_policy(&mut self, policy: FitPolicy) {
+        self.allocator.set_policy(policy);
+    }
+
+    // Whether the pointer lies in the mapped part of the heap
+    #[inline]
+    pub fn contains(&self, ptr: *mut u8) -> bool {
+        (self.region.start().inner..self.region.end().inner).contains(&(ptr as usize))
+    }
+
+    // Grows the heap when the allocator runs dry, then retries. Returns null only once the heap limit is reached
+    // or physical memory runs out.
+    pub unsafe fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let ptr = self.allocator.allocate(layout);
+        if !ptr.is_null() {
+            return ptr;
+        }
+        // Enough for the allocation at its worst alignment, plus room for the leftover to form a free region
+        let required_size = layout.size() + layout.align() + MIN_REGION_SIZE;
+        match self.region.grow(required_size) {
+            Some((growth_start, growth_size)) => {
+                self.allocator.extend(growth_start, growth_size);
+                self.allocator.allocate(layout)
+            }
+            None => ptr,
+        }
+    }
+
+    // The pointer has to come from this heap
+    #[inline]
+    pub unsafe fn free(&mut self, ptr: *mut u8, layout: Layout) {
+        self.allocator.free(ptr, layout);
+    }
+
+    // Unmaps the free memory at the end of the heap, down to its initial size. Returns the number of bytes given
+    // back to the frame allocator.
+    pub fn shrink(&mut self) -> usize {
+        let heap_end = self.region.end();
+        let region_start = match self.allocator.take_region_ending_at(heap_end) {
+            Some(region_start) => region_start,
+            None => return 0,
+        };
+        let mut new_end = region_start.align_up(Size::FOUR_KIB).max(self.region.shrink_floor());
+        // The part of the region in front of the new end stays free, so it has to be able to hold a list node
+        let leftover = new_end.inner - region_start.inner;
+        if leftover > 0 && leftover < MIN_REGION_SIZE {
+            new_end = new_end + Size::FOUR_KIB;
+        }
+        if new_end >= heap_end {
+            unsafe { self.allocator.extend(region_start, heap_end.inner - region_start.inner) };
+            return 0;
+        }
+        if new_end > region_start {
+            unsafe { self.allocator.extend(region_start, new_end.inner - region_start.inner) };
+        }
+        self.region.shrink(new_end)
+    }
+}
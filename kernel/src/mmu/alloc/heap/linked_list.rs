@@ -3,6 +3,17 @@ use core::ptr;
 
 use crate::mmu::address::VirtualAddress;
 
+// Free regions smaller than this can't hold their own list node
+pub const MIN_REGION_SIZE: usize = core::mem::size_of::<ListNode>();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FitPolicy {
+    // Takes the lowest addressed region the allocation fits in
+    FirstFit,
+    // Takes the smallest region the allocation fits in, which leaves the large regions intact for longer
+    BestFit,
+}
+
 struct ListNode {
     size: usize,
     next: Option<&'static mut ListNode>,
@@ -43,81 +54,177 @@ fn size_align(layout: Layout) -> (usize, usize) {
     (size, layout.align())
 }
 
+// Keeps the free regions of the heap in a list sorted by address. Freed regions are merged with their neighbours,
+// so the list only ever holds regions that are separated by allocated memory.
 pub struct LinkedListAllocator {
     head: ListNode,
+    policy: FitPolicy,
 }
 
 impl LinkedListAllocator {
     pub const fn new() -> Self {
+        Self::with_policy(FitPolicy::FirstFit)
+    }
+
+    pub const fn with_policy(policy: FitPolicy) -> Self {
         Self {
             head: ListNode::empty(),
+            policy,
         }
     }
 
+    #[inline]
+    pub fn policy(&self) -> FitPolicy {
+        self.policy
+    }
+
+    #[inline]
+    pub fn set_policy(&mut self, policy: FitPolicy) {
+        self.policy = policy;
+    }
+
     pub unsafe fn init(&mut self, heap_start: VirtualAddress, size: usize) {
         self.add_free_region(heap_start, size);
     }
 
+    // Hands newly mapped memory to the allocator
+    pub unsafe fn extend(&mut self, start: VirtualAddress, size: usize) {
+        self.add_free_region(start, size);
+    }
+
+    // Removes the free region ending exactly at `end`, if there is one, and returns its start address
+    pub fn take_region_ending_at(&mut self, end: VirtualAddress) -> Option<VirtualAddress> {
+        let region_start = self.regions().find(|region| region.end_addr() == end)?.start_addr();
+        self.unlink_region(region_start);
+        Some(region_start)
+    }
+
+    fn regions(&self) -> impl Iterator<Item = &ListNode> {
+        let mut current = self.head.next.as_deref();
+        core::iter::from_fn(move || {
+            let region = current?;
+            current = region.next.as_deref();
+            Some(region)
+        })
+    }
+
+    pub fn free_bytes(&self) -> usize {
+        self.regions().map(|region| region.size).sum()
+    }
+
+    pub fn region_count(&self) -> usize {
+        self.regions().count()
+    }
+
+    // Inserts the region in address order, merging it with the free regions directly before and after it
     unsafe fn add_free_region(&mut self, addr: VirtualAddress, size: usize) {
-        log::info!(
+        log::trace!(
             "Adding free region\nStart: {:#X}, End: {:#X}",
             addr.inner,
             (addr.inner + size)
         );
         check_region(addr, size);
+        let head_ptr = &self.head as *const ListNode;
+        let mut current = &mut self.head;
+        while current.next.as_ref().is_some_and(|next| next.start_addr() < addr) {
+            current = current.next.as_mut().unwrap();
+        }
+        let is_head = ptr::eq(current, head_ptr);
+        assert!(
+            is_head || current.end_addr() <= addr,
+            "Freed region {:#X} overlaps a free region!",
+            addr.inner
+        );
+
+        let mut size = size;
+        if let Some(next) = current.next.as_mut() {
+            assert!(
+                addr.inner + size <= next.start_addr().inner,
+                "Freed region {:#X} overlaps a free region!",
+                addr.inner
+            );
+            if addr.inner + size == next.start_addr().inner {
+                size += next.size;
+                current.next = next.next.take();
+            }
+        }
+        if !is_head && current.end_addr() == addr {
+            current.size += size;
+            return;
+        }
         let mut node = ListNode::new(size);
-        node.next = self.head.next.take();
+        node.next = current.next.take();
         let ptr = addr.inner as *mut ListNode;
         ptr.write(node);
-        self.head.next = Some(&mut *ptr);
-    }
-
-    fn alloc_from_region(region: &ListNode, size: usize, align: usize) -> Result<VirtualAddress, ()> {
-        let alloc_start = region.start_addr().align_up(align);
-        let alloc_end = alloc_start.add_checked(size); // panics if the checked add fails
-        let region_end = region.end_addr();
-        match alloc_end < region_end {
-            true => {
-                let excess = (region_end - alloc_end).inner;
-                if excess > 0 && excess < core::mem::size_of::<ListNode>() {
-                    Err(())
-                } else {
-                    Ok(alloc_start)
-                }
-            }
-            false => Err(()),
-        }
+        current.next = Some(&mut *ptr);
     }
 
-    fn find_region(&mut self, size: usize, align: usize) -> Option<(&'static ListNode, VirtualAddress)> {
+    fn unlink_region(&mut self, region_start: VirtualAddress) {
         let mut current = &mut self.head;
-        // Dear future self,
-        // ref keyword: https://doc.rust-lang.org/std/keyword.ref.html
-        while let Some(ref mut region) = current.next {
-            if let Ok(alloc_start) = Self::alloc_from_region(&region, size, align) {
-                let next = region.next.take();
-                let node = current.next.take().unwrap();
-                current.next = next;
-                return Some((node, alloc_start));
-            } else {
-                current = current.next.as_mut().unwrap();
-            }
+        while current
+            .next
+            .as_ref()
+            .is_some_and(|next| next.start_addr() != region_start)
+        {
+            current = current.next.as_mut().unwrap();
+        }
+        let region = current.next.take().expect("Region is not on the free list!");
+        current.next = region.next.take();
+    }
+
+    // Where an allocation would start within the region. Anything left over on either side of it has to be
+    // large enough to go back on the free list.
+    fn alloc_from_region(region: &ListNode, size: usize, align: usize) -> Option<VirtualAddress> {
+        let region_start = region.start_addr().inner;
+        let region_end = region.end_addr().inner;
+        let mut alloc_start = region.start_addr().align_up(align).inner;
+        let front = alloc_start - region_start;
+        if front > 0 && front < MIN_REGION_SIZE {
+            alloc_start = VirtualAddress::new(region_start + MIN_REGION_SIZE)
+                .align_up(align)
+                .inner;
+        }
+        let alloc_end = alloc_start.checked_add(size)?;
+        if alloc_end > region_end {
+            return None;
+        }
+        let back = region_end - alloc_end;
+        match back > 0 && back < MIN_REGION_SIZE {
+            true => None,
+            false => Some(VirtualAddress::new(alloc_start)),
+        }
+    }
+
+    // Picks a region according to the fit policy. Returns the region's start and size, and where the
+    // allocation starts in it.
+    fn find_region(&self, size: usize, align: usize) -> Option<(VirtualAddress, usize, VirtualAddress)> {
+        let mut candidates = self.regions().filter_map(|region| {
+            Self::alloc_from_region(region, size, align)
+                .map(|alloc_start| (region.start_addr(), region.size, alloc_start))
+        });
+        match self.policy {
+            FitPolicy::FirstFit => candidates.next(),
+            FitPolicy::BestFit => candidates.min_by_key(|&(_, region_size, _)| region_size),
         }
-        None
     }
 
-    pub unsafe fn alloc_first_fit(&mut self, layout: Layout) -> *mut u8 {
+    pub unsafe fn allocate(&mut self, layout: Layout) -> *mut u8 {
         let (size, align) = size_align(layout);
-        if let Some((region, alloc_start)) = self.find_region(size, align) {
-            let alloc_end = alloc_start.add_checked(size);
-            let excess = (region.end_addr() - alloc_end).inner;
-            if excess > 0 {
-                self.add_free_region(alloc_end, size);
-            }
-            alloc_start.inner as *mut u8
-        } else {
-            ptr::null_mut()
+        let (region_start, region_size, alloc_start) = match self.find_region(size, align) {
+            Some(region) => region,
+            None => return ptr::null_mut(),
+        };
+        self.unlink_region(region_start);
+        let front = alloc_start.inner - region_start.inner;
+        if front > 0 {
+            self.add_free_region(region_start, front);
         }
+        let alloc_end = alloc_start.add_checked(size);
+        let back = region_start.inner + region_size - alloc_end.inner;
+        if back > 0 {
+            self.add_free_region(alloc_end, back);
+        }
+        alloc_start.inner as *mut u8
     }
 
     pub unsafe fn free(&mut self, ptr: *mut u8, layout: Layout) {
@@ -126,3 +233,171 @@ impl LinkedListAllocator {
         self.add_free_region(region_start, size);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::{
+        alloc,
+        dealloc,
+    };
+
+    use super::*;
+
+    const HEAP_SIZE: usize = 1 << 16;
+
+    // A page aligned chunk of host memory for the allocator to manage
+    struct TestHeap {
+        start: *mut u8,
+        allocator: LinkedListAllocator,
+    }
+
+    impl TestHeap {
+        fn new(size: usize, policy: FitPolicy) -> Self {
+            let start = unsafe { alloc(Layout::from_size_align(HEAP_SIZE, 4096).unwrap()) };
+            let mut allocator = LinkedListAllocator::with_policy(policy);
+            unsafe { allocator.init(VirtualAddress::new(start as usize), size) };
+            TestHeap { start, allocator }
+        }
+
+        fn alloc(&mut self, size: usize) -> *mut u8 {
+            unsafe { self.allocator.allocate(Layout::from_size_align(size, 8).unwrap()) }
+        }
+
+        fn free(&mut self, ptr: *mut u8, size: usize) {
+            unsafe { self.allocator.free(ptr, Layout::from_size_align(size, 8).unwrap()) }
+        }
+    }
+
+    impl Drop for TestHeap {
+        fn drop(&mut self) {
+            unsafe { dealloc(self.start, Layout::from_size_align(HEAP_SIZE, 4096).unwrap()) }
+        }
+    }
+
+    #[test]
+    fn exact_fit() {
+        let mut heap = TestHeap::new(64, FitPolicy::FirstFit);
+        let ptr = heap.alloc(64);
+        assert_eq!(ptr, heap.start);
+        assert_eq!(heap.allocator.region_count(), 0);
+        assert!(heap.alloc(8).is_null());
+        heap.free(ptr, 64);
+        assert_eq!(heap.allocator.free_bytes(), 64);
+    }
+
+    #[test]
+    fn split_leaves_the_remainder_free() {
+        let mut heap = TestHeap::new(1024, FitPolicy::FirstFit);
+        let ptr = heap.alloc(100);
+        assert_eq!(ptr, heap.start);
+        // The size is padded to the list node alignment
+        assert_eq!(heap.allocator.free_bytes(), 1024 - 104);
+        assert_eq!(heap.allocator.region_count(), 1);
+        let next = heap.alloc(920);
+        assert_eq!(next, unsafe { heap.start.add(104) });
+        assert_eq!(heap.allocator.free_bytes(), 0);
+    }
+
+    #[test]
+    fn free_merges_with_both_neighbours() {
+        let mut heap = TestHeap::new(HEAP_SIZE, FitPolicy::FirstFit);
+        let a = heap.alloc(128);
+        let b = heap.alloc(128);
+        let c = heap.alloc(128);
+        heap.free(a, 128);
+        heap.free(c, 128);
+        // a on its own, c merged into the rest of the heap
+        assert_eq!(heap.allocator.region_count(), 2);
+        heap.free(b, 128);
+        assert_eq!(heap.allocator.region_count(), 1);
+        assert_eq!(heap.allocator.free_bytes(), HEAP_SIZE);
+        assert_eq!(heap.alloc(HEAP_SIZE), heap.start);
+    }
+
+    #[test]
+    fn fragmentation_recovers_after_freeing_everything() {
+        let mut heap = TestHeap::new(4096, FitPolicy::FirstFit);
+        let blocks: Vec<*mut u8> = (0..64).map(|_| heap.alloc(64)).collect();
+        assert!(blocks.iter().all(|block| !block.is_null()));
+        assert!(heap.alloc(64).is_null());
+        for block in blocks.iter().step_by(2) {
+            heap.free(*block, 64);
+        }
+        assert_eq!(heap.allocator.region_count(), 32);
+        assert_eq!(heap.allocator.free_bytes(), 2048);
+        // Half the heap is free, but no two free blocks are adjacent
+        assert!(heap.alloc(128).is_null());
+        for block in blocks.iter().skip(1).step_by(2) {
+            heap.free(*block, 64);
+        }
+        assert_eq!(heap.allocator.region_count(), 1);
+        assert_eq!(heap.alloc(4096), heap.start);
+    }
+
+    #[test]
+    fn free_in_reverse_order_merges() {
+        let mut heap = TestHeap::new(4096, FitPolicy::FirstFit);
+        let blocks: Vec<*mut u8> = (0..16).map(|_| heap.alloc(256)).collect();
+        for block in blocks.iter().rev() {
+            heap.free(*block, 256);
+            assert_eq!(heap.allocator.region_count(), 1);
+        }
+        assert_eq!(heap.allocator.free_bytes(), 4096);
+    }
+
+    // Leaves a 256 byte hole followed by a 64 byte hole, both surrounded by allocations
+    fn make_holes(heap: &mut TestHeap) -> (*mut u8, *mut u8) {
+        let large_hole = heap.alloc(256);
+        let _ = heap.alloc(64);
+        let small_hole = heap.alloc(64);
+        let _ = heap.alloc(64);
+        heap.free(large_hole, 256);
+        heap.free(small_hole, 64);
+        (large_hole, small_hole)
+    }
+
+    #[test]
+    fn first_fit_takes_the_lowest_region() {
+        let mut heap = TestHeap::new(HEAP_SIZE, FitPolicy::FirstFit);
+        let (large_hole, _) = make_holes(&mut heap);
+        assert_eq!(heap.alloc(64), large_hole);
+    }
+
+    #[test]
+    fn best_fit_takes_the_smallest_region() {
+        let mut heap = TestHeap::new(HEAP_SIZE, FitPolicy::BestFit);
+        let (large_hole, small_hole) = make_holes(&mut heap);
+        assert_eq!(heap.alloc(64), small_hole);
+        assert_eq!(heap.alloc(256), large_hole);
+    }
+
+    #[test]
+    fn aligned_allocation_returns_the_padding() {
+        let mut heap = TestHeap::new(HEAP_SIZE, FitPolicy::FirstFit);
+        let _ = heap.alloc(32);
+        let ptr = unsafe { heap.allocator.allocate(Layout::from_size_align(64, 256).unwrap()) };
+        assert_eq!(ptr as usize % 256, 0);
+        // The size is padded up to the alignment, the gap in front of the allocation goes back on the list
+        assert_eq!(heap.allocator.free_bytes(), HEAP_SIZE - 32 - 256);
+        assert_eq!(heap.allocator.region_count(), 2);
+    }
+
+    #[test]
+    fn take_region_ending_at_the_end() {
+        let mut heap = TestHeap::new(4096, FitPolicy::FirstFit);
+        let _ = heap.alloc(1024);
+        let end = VirtualAddress::new(heap.start as usize + 4096);
+        let region_start = heap.allocator.take_region_ending_at(end);
+        assert_eq!(region_start, Some(VirtualAddress::new(heap.start as usize + 1024)));
+        assert_eq!(heap.allocator.free_bytes(), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn double_free_is_caught() {
+        let mut heap = TestHeap::new(4096, FitPolicy::FirstFit);
+        let ptr = heap.alloc(64);
+        heap.free(ptr, 64);
+        heap.free(ptr, 64);
+    }
+}
@@ -1,31 +1,129 @@
-use self::fixed_sized_block::BlockAllocator;
+use core::ops::{
+    Deref,
+    DerefMut,
+};
+use core::sync::atomic::{
+    AtomicUsize,
+    Ordering,
+};
+
+use self::linked_list::FitPolicy;
+use self::region::HeapRegion;
+use self::slab::SlabAllocator;
+use crate::cpu::current_cpu_index;
 use crate::mmu::address::VirtualAddress;
 
-pub mod fixed_sized_block;
+#[cfg(feature = "heap-debug")]
+pub mod debug;
+pub mod growable;
 pub mod linked_list;
+pub mod region;
+pub mod slab;
+
+const NO_OWNER: usize = usize::MAX;
 
 pub struct Locked<Alloc> {
     inner: spin::Mutex<Alloc>,
+    // The CPU holding the lock, only kept track of in debug builds
+    owner: AtomicUsize,
 }
 
 impl<Alloc> Locked<Alloc> {
     pub const fn new(inner: Alloc) -> Self {
         Self {
             inner: spin::Mutex::new(inner),
+            owner: AtomicUsize::new(NO_OWNER),
+        }
+    }
+
+    pub fn lock(&self) -> LockedGuard<'_, Alloc> {
+        let guard = self.inner.lock();
+        if cfg!(debug_assertions) {
+            self.owner.store(current_cpu_index(), Ordering::Relaxed);
         }
+        LockedGuard { locked: self, guard }
     }
 
-    pub fn lock(&self) -> spin::MutexGuard<Alloc> {
-        self.inner.lock()
+    // Whether the running CPU holds the lock. Always false in release builds, it's meant for debug assertions
+    pub fn is_held_here(&self) -> bool {
+        cfg!(debug_assertions) && self.owner.load(Ordering::Relaxed) == current_cpu_index()
     }
 }
 
-#[global_allocator]
-static HEAP_ALLOCATOR: Locked<BlockAllocator> = Locked::new(BlockAllocator::new());
+pub struct LockedGuard<'a, Alloc> {
+    locked: &'a Locked<Alloc>,
+    guard: spin::MutexGuard<'a, Alloc>,
+}
+
+impl<Alloc> Deref for LockedGuard<'_, Alloc> {
+    type Target = Alloc;
+
+    fn deref(&self) -> &Alloc {
+        &self.guard
+    }
+}
+
+impl<Alloc> DerefMut for LockedGuard<'_, Alloc> {
+    fn deref_mut(&mut self) -> &mut Alloc {
+        &mut self.guard
+    }
+}
+
+impl<Alloc> Drop for LockedGuard<'_, Alloc> {
+    // Runs before the guard itself is dropped, while the lock is still held
+    fn drop(&mut self) {
+        if cfg!(debug_assertions) {
+            self.locked.owner.store(NO_OWNER, Ordering::Relaxed);
+        }
+    }
+}
 
-pub(super) fn init_allocator(start: usize, size: usize) {
+// Host-side unit tests run on top of the system allocator. With heap debugging enabled, the global allocator is
+// the debug wrapper around this one instead
+#[cfg_attr(all(not(test), not(feature = "heap-debug")), global_allocator)]
+static HEAP_ALLOCATOR: Locked<SlabAllocator> = Locked::new(SlabAllocator::new());
+
+pub(super) fn init_allocator(start: VirtualAddress, initial_size: usize, max_size: usize) {
     log::info!("Initializing kernel heap allocator");
-    let start_addr = VirtualAddress::with_kernel_base_offset(start);
+    let heap = HeapRegion::new(start, initial_size, max_size);
     let mut allocator = HEAP_ALLOCATOR.lock();
-    unsafe { allocator.init(start_addr, size) }
+    unsafe { allocator.init(heap) }
+}
+
+// Changes how far the kernel heap is allowed to grow. It can't be set below the heap's current size
+pub fn set_kernel_heap_limit(max_size: usize) {
+    HEAP_ALLOCATOR.lock().heap_mut().set_max_size(max_size);
+}
+
+pub fn set_kernel_heap_fit_policy(policy: FitPolicy) {
+    HEAP_ALLOCATOR.lock().set_fit_policy(policy);
+}
+
+// Returns the mapped and maximum size of the kernel heap, in bytes
+pub fn kernel_heap_size() -> (usize, usize) {
+    let allocator = HEAP_ALLOCATOR.lock();
+    (allocator.heap().size(), allocator.heap().max_size())
+}
+
+// Gives unused memory at the end of the kernel heap back to the frame allocator
+pub fn shrink_kernel_heap() -> usize {
+    HEAP_ALLOCATOR.with_heap(|allocator| allocator.shrink())
+}
+
+// Whether the running CPU holds the kernel heap lock, for debug assertions
+pub fn is_heap_lock_held_here() -> bool {
+    HEAP_ALLOCATOR.is_held_here()
+}
+
+// Hands the empty slabs of every cache back to the frame allocator. Returns the number of frames released
+pub fn reclaim_slabs() -> usize {
+    HEAP_ALLOCATOR.lock().reclaim()
+}
+
+pub fn dump_slab_stats() {
+    let allocator = HEAP_ALLOCATOR.lock();
+    log::info!("Slab caches:");
+    for cache in allocator.caches() {
+        log::info!("{}", cache);
+    }
 }
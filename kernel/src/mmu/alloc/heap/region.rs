@@ -0,0 +1,125 @@
+use crate::mmu::address::VirtualAddress;
+use crate::mmu::alloc::frame::{
+    map_frames,
+    unmap_frames,
+};
+use crate::mmu::vmm::tlb::TlbFlush;
+use crate::mmu::vmm::Size;
+
+// The heap never grows by less than this, so a run of small allocations doesn't map one page at a time
+const MIN_GROWTH_SIZE: usize = 1 << 18; // 256 KiB
+
+// The virtual window the kernel heap lives in. Only [start, end) is backed by frames, the rest of the window up
+// to `limit` is mapped as the heap grows.
+#[derive(Debug)]
+pub struct HeapRegion {
+    start: VirtualAddress,
+    end: VirtualAddress,
+    limit: VirtualAddress,
+    // The heap is never shrunk below its initial size
+    initial_end: VirtualAddress,
+    // Invalidations of pages that were unmapped with the heap locked. The heap flushes them once it's unlocked,
+    // shooting them down with the lock held would deadlock with CPUs waiting for it with interrupts disabled.
+    tlb_flush: TlbFlush,
+}
+
+impl HeapRegion {
+    pub const fn empty() -> Self {
+        HeapRegion {
+            start: VirtualAddress::zero(),
+            end: VirtualAddress::zero(),
+            limit: VirtualAddress::zero(),
+            initial_end: VirtualAddress::zero(),
+            tlb_flush: TlbFlush::new(),
+        }
+    }
+
+    // Maps the initial part of the heap. Both sizes are rounded up to whole pages
+    pub fn new(start: VirtualAddress, initial_size: usize, max_size: usize) -> Self {
+        assert!(start.is_aligned(), "Kernel heap start must be page aligned!");
+        let initial_size = align_up(initial_size);
+        let max_size = align_up(max_size).max(initial_size);
+        let mut tlb_flush = TlbFlush::new();
+        map_frames(start, initial_size, &mut tlb_flush).expect("Failed to map the initial kernel heap");
+        HeapRegion {
+            start,
+            end: start + initial_size,
+            limit: start + max_size,
+            initial_end: start + initial_size,
+            tlb_flush,
+        }
+    }
+
+    #[inline]
+    pub fn start(&self) -> VirtualAddress {
+        self.start
+    }
+
+    #[inline]
+    pub fn end(&self) -> VirtualAddress {
+        self.end
+    }
+
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.end.inner - self.start.inner
+    }
+
+    #[inline]
+    pub fn max_size(&self) -> usize {
+        self.limit.inner - self.start.inner
+    }
+
+    // The limit can't be lowered below the part of the heap that's already mapped
+    pub fn set_max_size(&mut self, max_size: usize) {
+        let max_size = align_up(max_size).max(self.size());
+        self.limit = self.start + max_size;
+    }
+
+    // Maps at least `size` more bytes at the end of the heap. Returns the newly mapped range, or None if the
+    // limit would be exceeded or there aren't enough free frames.
+    pub fn grow(&mut self, size: usize) -> Option<(VirtualAddress, usize)> {
+        let remaining = self.limit.inner - self.end.inner;
+        let required = align_up(size);
+        if required > remaining {
+            return None;
+        }
+        let growth_size = align_up(required.max(MIN_GROWTH_SIZE)).min(remaining);
+        let growth_start = self.end;
+        map_frames(growth_start, growth_size, &mut self.tlb_flush).ok()?;
+        self.end = growth_start + growth_size;
+        log::info!("Kernel heap grown to {} KiB", self.size() / 1024);
+        Some((growth_start, growth_size))
+    }
+
+    // Gives the pages of [new_end, end) back to the frame allocator. The caller has to make sure nothing in that
+    // range is still in use. Returns the number of bytes released.
+    pub fn shrink(&mut self, new_end: VirtualAddress) -> usize {
+        let new_end = new_end.align_up(Size::FOUR_KIB).max(self.initial_end);
+        if new_end >= self.end {
+            return 0;
+        }
+        let released = self.end.inner - new_end.inner;
+        unmap_frames(new_end, released, &mut self.tlb_flush).expect("Kernel heap pages went missing");
+        self.end = new_end;
+        log::info!("Kernel heap shrunk to {} KiB", self.size() / 1024);
+        released
+    }
+
+    // Hands over the pending invalidations, to be flushed once the heap is unlocked
+    #[inline]
+    pub fn take_tlb_flush(&mut self) -> TlbFlush {
+        core::mem::replace(&mut self.tlb_flush, TlbFlush::new())
+    }
+
+    // The lowest address the heap can be shrunk to
+    #[inline]
+    pub fn shrink_floor(&self) -> VirtualAddress {
+        self.initial_end
+    }
+}
+
+#[inline]
+fn align_up(size: usize) -> usize {
+    (size + Size::FOUR_KIB - 1) & !(Size::FOUR_KIB - 1)
+}
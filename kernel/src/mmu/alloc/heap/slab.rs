@@ -0,0 +1,593 @@
+use core::alloc::{
+    GlobalAlloc,
+    Layout,
+};
+use core::fmt;
+use core::marker::PhantomData;
+use core::ptr::{
+    self,
+    NonNull,
+};
+
+use super::growable::GrowableHeap;
+use super::linked_list::FitPolicy;
+use super::region::HeapRegion;
+use super::{
+    Locked,
+    HEAP_ALLOCATOR,
+};
+use crate::mmu::alloc::frame::accounting::{
+    charge_frames,
+    frame_count,
+    uncharge_frames,
+    FrameOwner,
+};
+use crate::mmu::alloc::frame::FRAME_ALLOCATOR;
+use crate::mmu::alloc::pressure::relieve_memory_pressure;
+use crate::mmu::vmm::frame::PhysicalFrame;
+use crate::mmu::vmm::Size;
+use crate::mmu::KERNEL_BASE_ADDRESS;
+
+// Object sizes of the general purpose caches. Allocations larger than the last one go to the fallback allocator
+const KMALLOC_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048, 4096];
+const KMALLOC_NAMES: &[&str] = &[
+    "kmalloc-8",
+    "kmalloc-16",
+    "kmalloc-32",
+    "kmalloc-64",
+    "kmalloc-128",
+    "kmalloc-256",
+    "kmalloc-512",
+    "kmalloc-1k",
+    "kmalloc-2k",
+    "kmalloc-4k",
+];
+
+// Includes the general purpose caches
+pub const MAX_CACHES: usize = 32;
+// Slabs are 2^order frames, allocated straight from the buddy allocator
+const MAX_SLAB_ORDER: usize = 3;
+// The slab order is raised until at least this many objects fit, as long as MAX_SLAB_ORDER allows it
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+// Empty slabs kept around per cache. Any more are given back to the frame allocator as soon as they empty out
+const MAX_EMPTY_SLABS: usize = 1;
+// Terminates a slab's free list
+const FREE_END: u16 = u16::MAX;
+
+// Identifies a cache within the slab allocator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheId(usize);
+
+#[derive(Debug, Clone, Copy)]
+pub enum SlabError {
+    ObjectTooLarge,
+    InvalidAlignment,
+    CacheTableFull,
+}
+
+impl fmt::Display for SlabError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ObjectTooLarge => f.write_str("Slab Error: Object doesn't fit in the largest slab"),
+            Self::InvalidAlignment => {
+                f.write_str("Slab Error: Alignment is not a power of two or exceeds the slab size")
+            }
+            Self::CacheTableFull => f.write_str("Slab Error: No free cache slots"),
+        }
+    }
+}
+
+// Sits at the start of every slab. It is followed by the slab's free list, one u16 per object holding the index
+// of the next free object, and then the objects themselves. Keeping the free list out of the objects means a
+// free object is never written to, so it stays in the state the cache's constructor left it in.
+#[repr(C)]
+struct SlabHeader {
+    next: *mut SlabHeader,
+    prev: *mut SlabHeader,
+    free_head: u16,
+    in_use: u16,
+}
+
+impl SlabHeader {
+    #[inline]
+    fn free_indices(&mut self) -> *mut u16 {
+        unsafe { (self as *mut SlabHeader).add(1) as *mut u16 }
+    }
+
+    #[inline]
+    fn is_full(&self) -> bool {
+        self.free_head == FREE_END
+    }
+}
+
+// Intrusive doubly linked list of slabs, threaded through their headers
+#[derive(Debug)]
+struct SlabList {
+    head: *mut SlabHeader,
+    len: usize,
+}
+
+// The slabs are only ever reached through the allocator that owns the list
+unsafe impl Send for SlabList {}
+
+impl SlabList {
+    const fn new() -> Self {
+        SlabList {
+            head: ptr::null_mut(),
+            len: 0,
+        }
+    }
+
+    unsafe fn push(&mut self, slab: *mut SlabHeader) {
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = self.head;
+        if !self.head.is_null() {
+            (*self.head).prev = slab;
+        }
+        self.head = slab;
+        self.len += 1;
+    }
+
+    unsafe fn remove(&mut self, slab: *mut SlabHeader) {
+        let SlabHeader { next, prev, .. } = *slab;
+        match prev.is_null() {
+            true => self.head = next,
+            false => (*prev).next = next,
+        }
+        if !next.is_null() {
+            (*next).prev = prev;
+        }
+        (*slab).next = ptr::null_mut();
+        (*slab).prev = ptr::null_mut();
+        self.len -= 1;
+    }
+
+    unsafe fn pop(&mut self) -> Option<*mut SlabHeader> {
+        let slab = self.head;
+        match slab.is_null() {
+            true => None,
+            false => {
+                self.remove(slab);
+                Some(slab)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SlabCacheStats {
+    pub allocations: usize,
+    pub frees: usize,
+    pub failed_allocations: usize,
+    pub active_objects: usize,
+    pub slabs_allocated: usize,
+    pub slabs_freed: usize,
+}
+
+// A pool of equally sized objects carved out of slabs. Slabs with free objects sit on the partial list, fully
+// used ones on the full list and unused ones on the empty list, until they're reclaimed.
+#[derive(Debug)]
+pub struct SlabCache {
+    name: &'static str,
+    // Object size rounded up to the alignment, i.e. the distance between two objects
+    stride: usize,
+    order: usize,
+    objects_per_slab: usize,
+    objects_offset: usize,
+    // Runs once on every object when its slab is created. Objects have to be freed in their constructed state
+    ctor: Option<fn(*mut u8)>,
+    partial: SlabList,
+    full: SlabList,
+    empty: SlabList,
+    stats: SlabCacheStats,
+}
+
+impl fmt::Display for SlabCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_fmt(format_args!(
+            "{:<16} size: {:>5} per slab: {:>4} active: {:>6}/{:<6} slabs: {}/{}/{} (partial/full/empty) allocs: {} frees: {} failed: {}",
+            self.name,
+            self.stride,
+            self.objects_per_slab,
+            self.stats.active_objects,
+            self.total_objects(),
+            self.partial.len,
+            self.full.len,
+            self.empty.len,
+            self.stats.allocations,
+            self.stats.frees,
+            self.stats.failed_allocations,
+        ))
+    }
+}
+
+#[inline]
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+impl SlabCache {
+    pub fn new(name: &'static str, size: usize, align: usize, ctor: Option<fn(*mut u8)>) -> Result<Self, SlabError> {
+        if !align.is_power_of_two() || align > Size::FOUR_KIB << MAX_SLAB_ORDER {
+            return Err(SlabError::InvalidAlignment);
+        }
+        let stride = align_up(size.max(1), align);
+        let mut layout = None;
+        for order in 0..=MAX_SLAB_ORDER {
+            let (objects_per_slab, objects_offset) = Self::slab_layout(stride, align, order);
+            if objects_per_slab > 0 {
+                layout = Some((order, objects_per_slab, objects_offset));
+            }
+            if objects_per_slab >= MIN_OBJECTS_PER_SLAB {
+                break;
+            }
+        }
+        let (order, objects_per_slab, objects_offset) = layout.ok_or(SlabError::ObjectTooLarge)?;
+        Ok(SlabCache {
+            name,
+            stride,
+            order,
+            objects_per_slab,
+            objects_offset,
+            ctor,
+            partial: SlabList::new(),
+            full: SlabList::new(),
+            empty: SlabList::new(),
+            stats: SlabCacheStats::default(),
+        })
+    }
+
+    // How many objects fit in a slab of the given order, and where the first one starts
+    fn slab_layout(stride: usize, align: usize, order: usize) -> (usize, usize) {
+        let slab_size = Size::FOUR_KIB << order;
+        let header_size = core::mem::size_of::<SlabHeader>();
+        let index_size = core::mem::size_of::<u16>();
+        let mut objects_per_slab = ((slab_size - header_size) / (stride + index_size)).min(FREE_END as usize);
+        while objects_per_slab > 0 {
+            let objects_offset = align_up(header_size + objects_per_slab * index_size, align);
+            if objects_offset + objects_per_slab * stride <= slab_size {
+                return (objects_per_slab, objects_offset);
+            }
+            objects_per_slab -= 1;
+        }
+        (0, 0)
+    }
+
+    #[inline]
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    #[inline]
+    pub fn object_size(&self) -> usize {
+        self.stride
+    }
+
+    #[inline]
+    pub fn stats(&self) -> SlabCacheStats {
+        self.stats
+    }
+
+    #[inline]
+    fn slab_size(&self) -> usize {
+        Size::FOUR_KIB << self.order
+    }
+
+    #[inline]
+    fn total_objects(&self) -> usize {
+        (self.partial.len + self.full.len + self.empty.len) * self.objects_per_slab
+    }
+
+    #[inline]
+    fn object_address(&self, slab: *mut SlabHeader, index: usize) -> *mut u8 {
+        unsafe { (slab as *mut u8).add(self.objects_offset + index * self.stride) }
+    }
+
+    // Slabs are naturally aligned buddy blocks, so the slab an object belongs to is found by rounding down
+    #[inline]
+    fn slab_of(&self, object: *mut u8) -> *mut SlabHeader {
+        (object as usize & !(self.slab_size() - 1)) as *mut SlabHeader
+    }
+
+    // Gets a new slab from the frame allocator, links up its free list and constructs its objects
+    unsafe fn create_slab(&mut self) -> Option<*mut SlabHeader> {
+        let frame = FRAME_ALLOCATOR.lock().allocate_order(self.order)?;
+        charge_frames(FrameOwner::Heap, 1 << self.order);
+        let slab = frame.direct_map_address().inner as *mut SlabHeader;
+        slab.write(SlabHeader {
+            next: ptr::null_mut(),
+            prev: ptr::null_mut(),
+            free_head: 0,
+            in_use: 0,
+        });
+        let free_indices = (*slab).free_indices();
+        for index in 0..self.objects_per_slab {
+            let next = match index + 1 < self.objects_per_slab {
+                true => (index + 1) as u16,
+                false => FREE_END,
+            };
+            free_indices.add(index).write(next);
+            if let Some(ctor) = self.ctor {
+                ctor(self.object_address(slab, index));
+            }
+        }
+        self.stats.slabs_allocated += 1;
+        Some(slab)
+    }
+
+    unsafe fn destroy_slab(&mut self, slab: *mut SlabHeader) {
+        let frame = PhysicalFrame::from_raw_address_aligned(slab as usize - KERNEL_BASE_ADDRESS);
+        FRAME_ALLOCATOR.lock().deallocate_order(frame, self.order);
+        uncharge_frames(FrameOwner::Heap, 1 << self.order);
+        self.stats.slabs_freed += 1;
+    }
+
+    pub fn alloc(&mut self) -> Option<NonNull<u8>> {
+        unsafe {
+            let slab = match self.partial.head.is_null() {
+                false => self.partial.head,
+                true => {
+                    let slab = match self.empty.pop() {
+                        Some(slab) => Some(slab),
+                        None => self.create_slab(),
+                    };
+                    let slab = match slab {
+                        Some(slab) => slab,
+                        None => {
+                            self.stats.failed_allocations += 1;
+                            return None;
+                        }
+                    };
+                    self.partial.push(slab);
+                    slab
+                }
+            };
+            let index = (*slab).free_head as usize;
+            (*slab).free_head = *(*slab).free_indices().add(index);
+            (*slab).in_use += 1;
+            if (*slab).is_full() {
+                self.partial.remove(slab);
+                self.full.push(slab);
+            }
+            self.stats.allocations += 1;
+            self.stats.active_objects += 1;
+            NonNull::new(self.object_address(slab, index))
+        }
+    }
+
+    // The object has to come from this cache
+    pub unsafe fn free(&mut self, object: NonNull<u8>) {
+        let object = object.as_ptr();
+        let slab = self.slab_of(object);
+        let offset = object as usize - slab as usize - self.objects_offset;
+        debug_assert_eq!(offset % self.stride, 0, "Freed a pointer into the middle of an object!");
+        let index = offset / self.stride;
+        let was_full = (*slab).is_full();
+        *(*slab).free_indices().add(index) = (*slab).free_head;
+        (*slab).free_head = index as u16;
+        (*slab).in_use -= 1;
+        if was_full {
+            self.full.remove(slab);
+            self.partial.push(slab);
+        }
+        if (*slab).in_use == 0 {
+            self.partial.remove(slab);
+            match self.empty.len < MAX_EMPTY_SLABS {
+                true => self.empty.push(slab),
+                false => self.destroy_slab(slab),
+            }
+        }
+        self.stats.frees += 1;
+        self.stats.active_objects -= 1;
+    }
+
+    // Gives every empty slab back to the frame allocator. Returns the number of frames released
+    pub fn reclaim(&mut self) -> usize {
+        let mut released = 0;
+        unsafe {
+            while let Some(slab) = self.empty.pop() {
+                self.destroy_slab(slab);
+                released += 1 << self.order;
+            }
+        }
+        released
+    }
+}
+
+// Serves small allocations from the general purpose slab caches and everything else from the fallback heap, which
+// grows as needed. Small allocations also go to the fallback heap when their cache can't get a new slab. Also
+// holds the named caches created for specific kernel objects.
+pub struct SlabAllocator {
+    caches: [Option<SlabCache>; MAX_CACHES],
+    fallback_heap: GrowableHeap,
+}
+
+fn get_kmalloc_index(layout: Layout) -> Option<usize> {
+    let required_size = layout.size().max(layout.align());
+    KMALLOC_SIZES.iter().position(|&cache_size| required_size <= cache_size)
+}
+
+impl Locked<SlabAllocator> {
+    // Runs `f` with the heap locked. Invalidations of heap pages it unmapped are carried out once the lock is
+    // released, so other CPUs spinning on it with interrupts disabled can still answer the shootdown.
+    pub fn with_heap<R>(&self, f: impl FnOnce(&mut SlabAllocator) -> R) -> R {
+        let mut allocator = self.lock();
+        let result = f(&mut allocator);
+        let mut tlb_flush = allocator.heap_mut().take_tlb_flush();
+        drop(allocator);
+        tlb_flush.flush();
+        result
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<SlabAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.with_heap(|allocator| allocator.allocate(layout));
+        if !ptr.is_null() {
+            return ptr;
+        }
+        // The shrinkers take the heap lock themselves, so it has to be released before they run
+        match relieve_memory_pressure(frame_count(layout.size())) {
+            true => self.with_heap(|allocator| allocator.allocate(layout)),
+            false => ptr,
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut allocator = self.lock();
+        // Slab objects live in the direct map, so anything inside the heap region came from the fallback heap
+        match allocator.fallback_heap.contains(ptr) {
+            true => allocator.fallback_heap.free(ptr, layout),
+            false => allocator.caches[get_kmalloc_index(layout).unwrap()]
+                .as_mut()
+                .unwrap()
+                .free(NonNull::new_unchecked(ptr)),
+        }
+    }
+}
+
+impl SlabAllocator {
+    pub const fn new() -> Self {
+        const NONE: Option<SlabCache> = None;
+        Self {
+            caches: [NONE; MAX_CACHES],
+            fallback_heap: GrowableHeap::new(),
+        }
+    }
+
+    pub unsafe fn init(&mut self, heap: HeapRegion) {
+        for (index, (&size, &name)) in KMALLOC_SIZES.iter().zip(KMALLOC_NAMES).enumerate() {
+            // Every block is aligned to its size, like a buddy block would be
+            self.caches[index] = Some(SlabCache::new(name, size, size, None).unwrap());
+        }
+        self.fallback_heap.init(heap);
+    }
+
+    #[inline]
+    pub fn heap(&self) -> &HeapRegion {
+        self.fallback_heap.region()
+    }
+
+    #[inline]
+    pub fn heap_mut(&mut self) -> &mut HeapRegion {
+        self.fallback_heap.region_mut()
+    }
+
+    // Sets how the fallback allocator picks a free region for large allocations
+    #[inline]
+    pub fn set_fit_policy(&mut self, policy: FitPolicy) {
+        self.fallback_heap.set_fit_policy(policy);
+    }
+
+    pub fn create_cache(
+        &mut self,
+        name: &'static str,
+        size: usize,
+        align: usize,
+        ctor: Option<fn(*mut u8)>,
+    ) -> Result<CacheId, SlabError> {
+        let cache = SlabCache::new(name, size, align, ctor)?;
+        let (index, slot) = self
+            .caches
+            .iter_mut()
+            .enumerate()
+            .find(|(_, slot)| slot.is_none())
+            .ok_or(SlabError::CacheTableFull)?;
+        *slot = Some(cache);
+        Ok(CacheId(index))
+    }
+
+    // Releases the cache's slot. Fails, handing the id back, if the cache still has live objects
+    pub fn destroy_cache(&mut self, cache_id: CacheId) -> Result<(), CacheId> {
+        let slot = &mut self.caches[cache_id.0];
+        match slot.as_ref().is_some_and(|cache| cache.stats.active_objects == 0) {
+            true => {
+                slot.take().unwrap().reclaim();
+                Ok(())
+            }
+            false => Err(cache_id),
+        }
+    }
+
+    #[inline]
+    pub fn cache(&mut self, cache_id: CacheId) -> &mut SlabCache {
+        self.caches[cache_id.0]
+            .as_mut()
+            .expect("Use of a destroyed slab cache!")
+    }
+
+    pub fn caches(&self) -> impl Iterator<Item = &SlabCache> {
+        self.caches.iter().flatten()
+    }
+
+    unsafe fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        match get_kmalloc_index(layout) {
+            Some(index) => match self.caches[index].as_mut().and_then(|cache| cache.alloc()) {
+                Some(object) => object.as_ptr(),
+                None => self.fallback_heap.allocate(layout),
+            },
+            None => self.fallback_heap.allocate(layout),
+        }
+    }
+
+    // Empties the empty slab lists of every cache. Returns the number of frames released
+    pub fn reclaim(&mut self) -> usize {
+        self.caches.iter_mut().flatten().map(|cache| cache.reclaim()).sum()
+    }
+
+    // Unmaps the free memory at the end of the heap, down to its initial size. Returns the number of bytes given
+    // back to the frame allocator.
+    pub fn shrink(&mut self) -> usize {
+        self.fallback_heap.shrink()
+    }
+}
+
+// Typed handle to a named cache for objects of type T
+#[derive(Debug)]
+pub struct ObjectCache<T> {
+    cache_id: CacheId,
+    object: PhantomData<T>,
+}
+
+impl<T> ObjectCache<T> {
+    // The constructor is run on every object of a new slab, before it's ever handed out
+    pub fn new(name: &'static str, ctor: Option<fn(*mut u8)>) -> Result<Self, SlabError> {
+        let cache_id =
+            HEAP_ALLOCATOR
+                .lock()
+                .create_cache(name, core::mem::size_of::<T>(), core::mem::align_of::<T>(), ctor)?;
+        Ok(ObjectCache {
+            cache_id,
+            object: PhantomData,
+        })
+    }
+
+    // The object is uninitialized unless the cache has a constructor
+    pub fn alloc(&self) -> Option<NonNull<T>> {
+        HEAP_ALLOCATOR
+            .lock()
+            .cache(self.cache_id)
+            .alloc()
+            .map(|object| object.cast())
+    }
+
+    // The object has to come from this cache and must not be used afterwards
+    pub unsafe fn free(&self, object: NonNull<T>) {
+        HEAP_ALLOCATOR.lock().cache(self.cache_id).free(object.cast());
+    }
+
+    pub fn stats(&self) -> SlabCacheStats {
+        HEAP_ALLOCATOR.lock().cache(self.cache_id).stats()
+    }
+
+    // Fails, handing the cache back, while any of its objects are still allocated
+    pub fn destroy(self) -> Result<(), Self> {
+        HEAP_ALLOCATOR
+            .lock()
+            .destroy_cache(self.cache_id)
+            .map_err(|cache_id| ObjectCache {
+                cache_id,
+                object: PhantomData,
+            })
+    }
+}
@@ -1,15 +1,21 @@
-use bootloader_api::BootInfo;
-
-use self::frame::map_frames;
 use self::heap::init_allocator;
+use crate::mmu::address::VirtualAddress;
+use crate::mmu::vmm::Size;
 
 pub mod frame;
 pub mod heap;
+pub mod oom;
+pub mod pressure;
 
 pub const KERNEL_HEAP_START: usize = 0xD_EADB_EEF0;
-pub const KERNEL_HEAP_SIZE: usize = 1 << 21; // 1 MB
+// Mapped up front. Everything past this is mapped the first time the heap runs out
+pub const KERNEL_HEAP_INITIAL_SIZE: usize = 1 << 21; // 2 MiB
+
+// Default ceiling for heap growth. Can be changed at runtime with heap::set_kernel_heap_limit
+pub const KERNEL_HEAP_MAX_SIZE: usize = 1 << 30; // 1 GiB
 
-pub fn init_kheap(boot_info: &'static BootInfo) {
-    map_frames(boot_info, KERNEL_HEAP_START, KERNEL_HEAP_SIZE);
-    init_allocator(KERNEL_HEAP_START, KERNEL_HEAP_SIZE);
+pub fn init_kheap() {
+    // The start is rounded up to a page boundary so that every byte of the heap sits on a mapped page
+    let heap_start = VirtualAddress::with_kernel_base_offset(KERNEL_HEAP_START).align_up(Size::FOUR_KIB);
+    init_allocator(heap_start, KERNEL_HEAP_INITIAL_SIZE, KERNEL_HEAP_MAX_SIZE);
 }
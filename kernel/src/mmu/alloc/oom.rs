@@ -0,0 +1,109 @@
+use core::alloc::Layout;
+
+use spin::Mutex;
+
+use crate::mmu::alloc::frame::accounting::log_memory_info;
+use crate::mmu::alloc::heap::dump_slab_stats;
+use crate::mmu::vmm::Size;
+use crate::process::PROCESS_TABLE;
+
+// Only one CPU picks a victim at a time. Whoever finds it taken lets the kill in progress free memory instead.
+static OOM_KILLER: Mutex<()> = Mutex::new(());
+
+// Called once reclaiming failed to free anything. Logs where memory went and kills the user process with the
+// most resident pages. Returns whether memory was freed by it.
+//
+// The process whose address space is loaded may be the one allocating, so it can't be torn down from under the
+// allocation. It's only marked killed, and the page fault path terminates it once it has unwound.
+//
+// Locks are only ever tried here: running out of memory while the process table or a victim's address space is
+// locked must not deadlock, so those are skipped.
+pub fn out_of_memory(wanted_frames: usize) -> bool {
+    let _oom_killer = match OOM_KILLER.try_lock() {
+        Some(oom_killer) => oom_killer,
+        None => return false,
+    };
+    log::error!("Out of memory: failed to allocate {} frames", wanted_frames);
+    log_memory_info();
+    dump_slab_stats();
+
+    let victim = {
+        let mut process_table = match PROCESS_TABLE.try_lock() {
+            Some(process_table) => process_table,
+            None => {
+                log::error!("OOM killer: process table is busy, nothing killed");
+                return false;
+            }
+        };
+        let victim = process_table
+            .values()
+            .filter_map(|process| {
+                let address_space = process.address_space().try_lock()?;
+                // Already on its way out
+                if address_space.is_killed() {
+                    return None;
+                }
+                let resident_pages = address_space.resident_pages();
+                (resident_pages > 0).then_some((process.process_id(), resident_pages))
+            })
+            .max_by_key(|&(_, resident_pages)| resident_pages);
+        let (process_id, resident_pages) = match victim {
+            Some(victim) => victim,
+            None => {
+                log::error!("OOM killer: no process to kill");
+                return false;
+            }
+        };
+        let mut address_space = match process_table[&process_id].address_space().try_lock() {
+            Some(address_space) => address_space,
+            None => {
+                log::error!(
+                    "OOM killer: address space of process {} is busy, nothing killed",
+                    process_id
+                );
+                return false;
+            }
+        };
+        if address_space.is_active() {
+            address_space.mark_killed();
+            log::error!(
+                "OOM killer: killing process {} ({} KiB resident) once its page fault unwinds",
+                process_id,
+                resident_pages * Size::FOUR_KIB / 1024
+            );
+            return false;
+        }
+        drop(address_space);
+        process_table
+            .remove(&process_id)
+            .map(|process| (process, resident_pages))
+    };
+    match victim {
+        Some((process, resident_pages)) => {
+            log::error!(
+                "OOM killer: killing process {} ({} KiB resident)",
+                process.process_id(),
+                resident_pages * Size::FOUR_KIB / 1024
+            );
+            process.terminate();
+            true
+        }
+        None => {
+            log::error!("OOM killer: no process to kill");
+            false
+        }
+    }
+}
+
+// The kernel heap already reclaimed and ran the OOM killer before giving up on the allocation. Nothing is left to
+// try, so this only reports what happened.
+#[cfg_attr(not(test), alloc_error_handler)]
+pub fn alloc_error(layout: Layout) -> ! {
+    log::error!(
+        "Kernel heap allocation of {} bytes (align {}) failed",
+        layout.size(),
+        layout.align()
+    );
+    log_memory_info();
+    panic!("Out of memory");
+}
@@ -0,0 +1,132 @@
+use core::fmt;
+
+use spin::Mutex;
+
+use crate::mmu::alloc::frame::accounting::frame_count;
+use crate::mmu::alloc::heap::{
+    reclaim_slabs,
+    shrink_kernel_heap,
+};
+use crate::mmu::alloc::oom::out_of_memory;
+
+// Shrinkers are kept in a fixed table, running them must never depend on the heap having room
+const MAX_SHRINKERS: usize = 16;
+
+// Allocations of more frames than this are likely to fail from fragmentation alone, and aren't worth killing a
+// process over
+pub const COSTLY_FRAME_COUNT: usize = 8;
+
+// A cache that can give memory back when the system runs low. `shrink` is asked for a number of frames and
+// returns how many it actually released. It's called without any mmu locks held.
+#[derive(Debug, Clone, Copy)]
+pub struct Shrinker {
+    pub name: &'static str,
+    pub shrink: fn(usize) -> usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ShrinkerError {
+    DuplicateName,
+    ShrinkerTableFull,
+}
+
+impl fmt::Display for ShrinkerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DuplicateName => f.write_str("Shrinker Error: A shrinker with this name is already registered"),
+            Self::ShrinkerTableFull => f.write_str("Shrinker Error: No free slot in the shrinker table"),
+        }
+    }
+}
+
+static SHRINKERS: Mutex<[Option<Shrinker>; MAX_SHRINKERS]> = Mutex::new([None; MAX_SHRINKERS]);
+
+pub fn register_shrinker(shrinker: Shrinker) -> Result<(), ShrinkerError> {
+    let mut shrinkers = SHRINKERS.lock();
+    if shrinkers
+        .iter()
+        .flatten()
+        .any(|existing| existing.name == shrinker.name)
+    {
+        return Err(ShrinkerError::DuplicateName);
+    }
+    let free_slot = shrinkers
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .ok_or(ShrinkerError::ShrinkerTableFull)?;
+    *free_slot = Some(shrinker);
+    Ok(())
+}
+
+pub fn unregister_shrinker(name: &str) -> Option<Shrinker> {
+    let mut shrinkers = SHRINKERS.lock();
+    let slot = shrinkers
+        .iter_mut()
+        .find(|slot| slot.is_some_and(|shrinker| shrinker.name == name))?;
+    slot.take()
+}
+
+// Registers the shrinkers of the kernel heap. Empty slabs go first, they are the cheapest to give back.
+pub fn init_shrinkers() {
+    let kernel_shrinkers = [
+        Shrinker {
+            name: "slab",
+            shrink: |_| reclaim_slabs(),
+        },
+        Shrinker {
+            name: "kernel-heap",
+            shrink: |_| frame_count(shrink_kernel_heap()),
+        },
+    ];
+    for shrinker in kernel_shrinkers {
+        register_shrinker(shrinker).expect("Failed to register a kernel shrinker");
+    }
+}
+
+// Runs the shrinkers in registration order until `wanted_frames` were released. Returns the number of frames
+// that actually were.
+pub fn shrink_caches(wanted_frames: usize) -> usize {
+    // Copied out so shrinkers are free to register or unregister others
+    let shrinkers = *SHRINKERS.lock();
+    let mut released = 0;
+    for shrinker in shrinkers.iter().flatten() {
+        if released >= wanted_frames {
+            break;
+        }
+        let shrinker_released = (shrinker.shrink)(wanted_frames - released);
+        if shrinker_released > 0 {
+            log::debug!("Shrinker {} released {} frames", shrinker.name, shrinker_released);
+        }
+        released += shrinker_released;
+    }
+    released
+}
+
+// Tries to make room for an allocation of `wanted_frames` that just failed: the shrinkers run first, the OOM
+// killer only if they came up empty and the allocation is small. Returns whether retrying is worth it.
+pub fn relieve_memory_pressure(wanted_frames: usize) -> bool {
+    if shrink_caches(wanted_frames) > 0 {
+        return true;
+    }
+    wanted_frames <= COSTLY_FRAME_COUNT && out_of_memory(wanted_frames)
+}
+
+// Errors that can mean physical memory ran out
+pub trait AllocationFailure {
+    fn is_out_of_memory(&self) -> bool;
+}
+
+// Runs `allocate`, and if it fails for lack of memory, relieves the pressure and runs it once more. Must not be
+// called with any mmu locks held, the shrinkers and the OOM killer need them.
+pub fn retry_on_pressure<T, E: AllocationFailure>(
+    wanted_frames: usize,
+    mut allocate: impl FnMut() -> Result<T, E>,
+) -> Result<T, E> {
+    match allocate() {
+        Err(error) if error.is_out_of_memory() => match relieve_memory_pressure(wanted_frames) {
+            true => allocate(),
+            false => Err(error),
+        },
+        result => result,
+    }
+}
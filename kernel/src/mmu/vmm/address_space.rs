@@ -0,0 +1,512 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::fmt;
+use core::sync::atomic::{
+    AtomicUsize,
+    Ordering,
+};
+
+use conquer_once::spin::OnceCell;
+use spin::Mutex;
+
+use crate::cpu::cpu_set::CpuSet;
+use crate::cpu::smp::lock_answering_calls;
+use crate::cpu::{
+    current_cpu_index,
+    MAX_CPUS,
+};
+use crate::mmu::address::{
+    PhysicalAddress,
+    VirtualAddress,
+};
+use crate::mmu::alloc::frame::accounting::{
+    charge_frames,
+    uncharge_frames,
+    FrameOwner,
+};
+use crate::mmu::alloc::frame::{
+    FrameAllocator,
+    FRAME_ALLOCATOR,
+};
+use crate::mmu::alloc::pressure::AllocationFailure;
+use crate::mmu::vmm::asm::get_raw_pml4_ptr;
+use crate::mmu::vmm::fault::{
+    back_with_zeroed_frame,
+    break_copy_on_write,
+    PageFault,
+    PageFaultError,
+};
+use crate::mmu::vmm::frame::PhysicalFrame;
+use crate::mmu::vmm::page_table::{
+    MappedPageTable,
+    PageTable,
+    KERNEL_PML4_START_INDEX,
+};
+use crate::mmu::vmm::page_table_entry::{
+    PageTableEntryFlags,
+    PHYSICAL_ADDRESS_MASK,
+};
+use crate::mmu::vmm::tlb::{
+    allocate_pcid,
+    free_pcid,
+    load_address_space,
+    TlbFlush,
+    KERNEL_PCID,
+};
+use crate::mmu::vmm::vma::{
+    VirtualMemoryArea,
+    VmaBacking,
+    VmaPermissions,
+};
+use crate::mmu::vmm::{
+    PageSize,
+    Size4KiB,
+};
+use crate::util::bits::is_bit_set;
+
+const USER_TABLE_FLAGS: usize =
+    PageTableEntryFlags::PRESENT | PageTableEntryFlags::WRITE_ACCESS | PageTableEntryFlags::USER_ACCESS;
+
+// The page tables the kernel booted with. Every address space starts out with a copy of its kernel entries.
+struct KernelAddressSpace {
+    pml4_frame: PhysicalFrame,
+    // PML4 entries in the lower half that the bootloader already used (kernel image, boot stack, boot info).
+    // They are shared the same way the upper half is, so user areas can't be placed in them.
+    shared_lower_entries: [bool; KERNEL_PML4_START_INDEX],
+}
+
+static KERNEL_ADDRESS_SPACE: OnceCell<KernelAddressSpace> = OnceCell::uninit();
+
+// The user address space each CPU has loaded, if any
+static ACTIVE_ADDRESS_SPACES: [Mutex<Option<Arc<Mutex<AddressSpace>>>>; MAX_CPUS] =
+    [const { Mutex::new(None) }; MAX_CPUS];
+// The PML4 each CPU has loaded, for finding the CPUs user mappings have to be shot down on without locking them
+static LOADED_PML4S: [AtomicUsize; MAX_CPUS] = [const { AtomicUsize::new(0) }; MAX_CPUS];
+
+// Gives every unused kernel half PML4 entry an empty PDPT. Address spaces copy the kernel half when they are
+// created, so the PML4 entries have to be fixed from here on for later kernel mappings to show up in all of them.
+pub fn init_kernel_address_space() {
+    let pml4 = unsafe { PageTable::get_active_pml4() };
+    let raw_pml4_address = unsafe { get_raw_pml4_ptr() } & PHYSICAL_ADDRESS_MASK;
+    let mut shared_lower_entries = [false; KERNEL_PML4_START_INDEX];
+    for (index, shared) in shared_lower_entries.iter_mut().enumerate() {
+        *shared = !pml4[index].is_unused();
+    }
+
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    for index in KERNEL_PML4_START_INDEX..512 {
+        let entry = &mut pml4[index];
+        if entry.is_unused() {
+            let frame = frame_allocator
+                .allocate_frame()
+                .expect("Failed to allocate a kernel PDPT");
+            charge_frames(FrameOwner::PageTable, 1);
+            frame.zero();
+            entry.set_frame_addr(frame);
+            entry.set_flags(PageTableEntryFlags::PRESENT | PageTableEntryFlags::WRITE_ACCESS);
+        }
+    }
+    KERNEL_ADDRESS_SPACE.get_or_init(move || KernelAddressSpace {
+        pml4_frame: PhysicalFrame::from_raw_address_aligned(raw_pml4_address),
+        shared_lower_entries,
+    });
+}
+
+// The page tables the kernel booted with, which every CPU starts out on
+pub fn kernel_pml4_frame() -> PhysicalFrame {
+    KERNEL_ADDRESS_SPACE.get().unwrap().pml4_frame
+}
+
+// Whether the address belongs to the per-process part of the address space
+pub fn is_user_address(virtual_address: VirtualAddress) -> bool {
+    let pml4_index = virtual_address.get_pml4_index();
+    pml4_index < KERNEL_PML4_START_INDEX
+        && KERNEL_ADDRESS_SPACE
+            .get()
+            .is_some_and(|kernel_address_space| !kernel_address_space.shared_lower_entries[pml4_index])
+}
+
+fn is_user_range(start: VirtualAddress, end: VirtualAddress) -> bool {
+    let last_address = VirtualAddress::new(end.inner - 1);
+    (start.get_pml4_index()..=last_address.get_pml4_index())
+        .all(|pml4_index| is_user_address(VirtualAddress::new(pml4_index << 39)))
+}
+
+// The address space loaded on this CPU
+pub fn active_address_space() -> Option<Arc<Mutex<AddressSpace>>> {
+    ACTIVE_ADDRESS_SPACES[current_cpu_index()].lock().clone()
+}
+
+// CPUs that have the page tables rooted at the frame loaded. A CPU may be switching away while this runs.
+fn loaded_cpus(pml4_frame: PhysicalFrame) -> CpuSet {
+    let mut cpus = CpuSet::empty();
+    for (cpu_index, loaded_pml4) in LOADED_PML4S.iter().enumerate() {
+        if loaded_pml4.load(Ordering::Acquire) == pml4_frame.start_address() {
+            cpus.insert(cpu_index);
+        }
+    }
+    cpus
+}
+
+// Loads the address space into CR3 and makes it the one user page faults on this CPU are resolved against.
+// The caller has to make sure nothing still relies on the user mappings of the previous address space.
+pub unsafe fn switch_to(address_space: Arc<Mutex<AddressSpace>>) {
+    let cpu_index = current_cpu_index();
+    let mut active_address_space = ACTIVE_ADDRESS_SPACES[cpu_index].lock();
+    {
+        // Whoever holds it may be shooting down its mappings and waiting for this CPU
+        let mut next = lock_answering_calls(&address_space);
+        // Address spaces without a PCID of their own share the kernel's, whose entries can't be trusted
+        let flush = next.stale_cpus.contains(cpu_index) || next.pcid == KERNEL_PCID;
+        load_address_space(next.pml4_frame.start_address(), next.pcid, flush);
+        LOADED_PML4S[cpu_index].store(next.pml4_frame.start_address(), Ordering::Release);
+        next.stale_cpus.remove(cpu_index);
+    }
+    let previous = active_address_space.replace(address_space);
+    // The previous address space may be dropped here, which takes the frame allocator lock
+    drop(active_address_space);
+    drop(previous);
+}
+
+// Goes back to the kernel's own page tables, leaving no user address space active
+pub unsafe fn switch_to_kernel() {
+    let kernel_address_space = KERNEL_ADDRESS_SPACE.get().unwrap();
+    let cpu_index = current_cpu_index();
+    let mut active_address_space = ACTIVE_ADDRESS_SPACES[cpu_index].lock();
+    // User address spaces without a PCID may have left entries behind under the kernel's
+    load_address_space(kernel_address_space.pml4_frame.start_address(), KERNEL_PCID, true);
+    LOADED_PML4S[cpu_index].store(kernel_address_space.pml4_frame.start_address(), Ordering::Release);
+    let previous = active_address_space.take();
+    drop(active_address_space);
+    drop(previous);
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum AddressSpaceError {
+    OutOfMemory,
+    UnalignedArea,
+    InvalidRange,
+    OverlappingArea,
+    AreaNotFound,
+}
+
+impl fmt::Display for AddressSpaceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutOfMemory => f.write_str("Address Space Error: Out of physical memory"),
+            Self::UnalignedArea => f.write_str("Address Space Error: Area is not page aligned"),
+            Self::InvalidRange => f.write_str("Address Space Error: Area is empty or outside of user memory"),
+            Self::OverlappingArea => f.write_str("Address Space Error: Overlaps an existing area"),
+            Self::AreaNotFound => f.write_str("Address Space Error: No area starts at the given address"),
+        }
+    }
+}
+
+impl AllocationFailure for AddressSpaceError {
+    fn is_out_of_memory(&self) -> bool {
+        matches!(self, Self::OutOfMemory)
+    }
+}
+
+// An isolated set of user mappings. The upper (kernel) half of its PML4 points at the same tables as every other
+// address space, the lower half is private and described by its areas, keyed by their start address.
+#[derive(Debug)]
+pub struct AddressSpace {
+    pml4_frame: PhysicalFrame,
+    areas: BTreeMap<VirtualAddress, VirtualMemoryArea>,
+    pcid: u16,
+    // CPUs that may still cache mappings that changed under the PCID, because they didn't have the address space
+    // loaded when it happened. Each of them flushes the PCID the next time it switches to the address space.
+    stale_cpus: CpuSet,
+    // Set when the OOM killer picks the process while this address space is loaded. The process is torn down
+    // once the path that ran out of memory has unwound, nothing may be mapped into it until then.
+    killed: bool,
+}
+
+impl AddressSpace {
+    pub fn new() -> Result<Self, AddressSpaceError> {
+        let kernel_address_space = KERNEL_ADDRESS_SPACE
+            .get()
+            .expect("Kernel address space not initialized!");
+        let pml4_frame = FRAME_ALLOCATOR
+            .lock()
+            .allocate_frame()
+            .ok_or(AddressSpaceError::OutOfMemory)?;
+        charge_frames(FrameOwner::PageTable, 1);
+        let kernel_pml4 = kernel_address_space
+            .pml4_frame
+            .frame_to_page_table(VirtualAddress::kernel_base());
+        let pml4 = pml4_frame.frame_to_page_table(VirtualAddress::kernel_base());
+        pml4.set_empty();
+        for (index, &shared) in kernel_address_space.shared_lower_entries.iter().enumerate() {
+            if shared {
+                pml4[index] = kernel_pml4[index];
+            }
+        }
+        for index in KERNEL_PML4_START_INDEX..512 {
+            pml4[index] = kernel_pml4[index];
+        }
+        Ok(AddressSpace {
+            pml4_frame,
+            areas: BTreeMap::new(),
+            pcid: allocate_pcid().unwrap_or(KERNEL_PCID),
+            // A recycled PCID can still tag entries of its previous owner, on any CPU
+            stale_cpus: CpuSet::from_bits(usize::MAX),
+            killed: false,
+        })
+    }
+
+    #[inline]
+    pub fn pml4_frame(&self) -> PhysicalFrame {
+        self.pml4_frame
+    }
+
+    #[inline]
+    pub fn pcid(&self) -> u16 {
+        self.pcid
+    }
+
+    // Whether this CPU has the address space loaded
+    #[inline]
+    pub fn is_active(&self) -> bool {
+        let raw_pml4_address = unsafe { get_raw_pml4_ptr() } & PHYSICAL_ADDRESS_MASK;
+        raw_pml4_address == self.pml4_frame.start_address()
+    }
+
+    #[inline]
+    pub fn is_killed(&self) -> bool {
+        self.killed
+    }
+
+    #[inline]
+    pub fn mark_killed(&mut self) {
+        self.killed = true;
+    }
+
+    fn page_table(&self) -> MappedPageTable {
+        let offset = VirtualAddress::kernel_base();
+        MappedPageTable::new(offset, self.pml4_frame.frame_to_page_table(offset))
+    }
+
+    // The batch is carried out on the CPUs that have the address space loaded. The others are left to flush the
+    // PCID the next time they switch to it.
+    fn finish_tlb_flush(&mut self, tlb_flush: &mut TlbFlush) {
+        if tlb_flush.is_empty() {
+            tlb_flush.discard();
+            return;
+        }
+        let loaded_cpus = loaded_cpus(self.pml4_frame);
+        let missed_cpus = tlb_flush.flush_loaded(self.pml4_frame.start_address(), loaded_cpus);
+        self.stale_cpus = CpuSet::from_bits(self.stale_cpus.bits() | !loaded_cpus.bits() | missed_cpus.bits());
+    }
+
+    // Pages of the areas owning their frames that are backed right now, shared ones included. Walks the page
+    // tables, so it's meant for rare callers like the OOM killer.
+    pub fn resident_pages(&self) -> usize {
+        let page_table = self.page_table();
+        self.areas
+            .values()
+            .filter(|area| area.owns_frames())
+            .flat_map(|area| area.pages())
+            .filter(|&page| page_table.get_mapping(page).is_some())
+            .count()
+    }
+
+    pub fn areas(&self) -> impl Iterator<Item = &VirtualMemoryArea> {
+        self.areas.values()
+    }
+
+    pub fn find_area(&self, address: VirtualAddress) -> Option<&VirtualMemoryArea> {
+        self.areas
+            .range(..=address)
+            .next_back()
+            .map(|(_, area)| area)
+            .filter(|area| area.contains(address))
+    }
+
+    // Adds the area to the address space. Anonymous and stack areas are backed lazily by the page fault handler,
+    // physical areas are mapped straight away.
+    pub fn map_area(&mut self, area: VirtualMemoryArea) -> Result<(), AddressSpaceError> {
+        if !area.start.is_aligned() || !area.end.is_aligned() {
+            return Err(AddressSpaceError::UnalignedArea);
+        }
+        if area.start >= area.end || !is_user_range(area.start, area.end) {
+            return Err(AddressSpaceError::InvalidRange);
+        }
+        // Areas never overlap each other, so only the last one starting below the new end can collide with it
+        if self
+            .areas
+            .range(..area.end)
+            .next_back()
+            .is_some_and(|(_, existing)| existing.overlaps(&area))
+        {
+            return Err(AddressSpaceError::OverlappingArea);
+        }
+        if let VmaBacking::Physical(physical_address) = area.backing {
+            self.map_physical(&area, physical_address)?;
+        }
+        self.areas.insert(area.start, area);
+        Ok(())
+    }
+
+    fn map_physical(
+        &mut self,
+        area: &VirtualMemoryArea,
+        physical_address: PhysicalAddress,
+    ) -> Result<(), AddressSpaceError> {
+        if physical_address.inner % Size4KiB::SIZE != 0 {
+            return Err(AddressSpaceError::UnalignedArea);
+        }
+        let mut page_table = self.page_table();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        for page in area.pages() {
+            let frame = PhysicalFrame::from_address_aligned(physical_address + (page.offset.inner - area.start.inner));
+            let result = page_table.map_to(page, frame, area.entry_flags(), USER_TABLE_FLAGS, &mut *frame_allocator);
+            if result.is_err() {
+                let mut tlb_flush = TlbFlush::new();
+                for mapped_page in area.pages().take_while(|mapped_page| *mapped_page < page) {
+                    let _ = page_table.unmap(mapped_page, &mut tlb_flush);
+                }
+                drop(frame_allocator);
+                self.finish_tlb_flush(&mut tlb_flush);
+                return Err(AddressSpaceError::OutOfMemory);
+            }
+        }
+        Ok(())
+    }
+
+    // Removes the area starting at `start`, unmapping its pages and freeing the frames it owns
+    pub fn unmap_area(&mut self, start: VirtualAddress) -> Result<VirtualMemoryArea, AddressSpaceError> {
+        let area = self.areas.remove(&start).ok_or(AddressSpaceError::AreaNotFound)?;
+        self.unmap_pages(&area);
+        Ok(area)
+    }
+
+    fn unmap_pages(&mut self, area: &VirtualMemoryArea) {
+        let mut page_table = self.page_table();
+        let mut tlb_flush = TlbFlush::new();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        for page in area.pages() {
+            // Lazily backed areas usually have pages that were never touched
+            if let Ok(frame) = page_table.unmap(page, &mut tlb_flush) {
+                if !area.owns_frames() {
+                    continue;
+                }
+                // Frames shared copy-on-write stay around until their last owner lets go of them
+                match frame_allocator.ref_count(frame) {
+                    1 => {
+                        tlb_flush.free_after_flush(frame);
+                        uncharge_frames(FrameOwner::User, 1);
+                    }
+                    _ => {
+                        frame_allocator.release_frame(frame);
+                    }
+                }
+            }
+        }
+        drop(frame_allocator);
+        self.finish_tlb_flush(&mut tlb_flush);
+    }
+
+    // Called for faults on user addresses while this address space is active
+    pub fn handle_page_fault(&mut self, page_fault: &PageFault) -> Result<(), PageFaultError> {
+        if self.killed {
+            return Err(PageFaultError::OutOfMemory);
+        }
+        let area = *self
+            .find_area(page_fault.address)
+            .ok_or(PageFaultError::UnreservedAddress)?;
+        if !area.permits(page_fault) {
+            return Err(PageFaultError::AccessNotPermitted);
+        }
+        if page_fault.is_present() {
+            if !page_fault.is_write() {
+                return Err(PageFaultError::ProtectionViolation);
+            }
+            let mut tlb_flush = TlbFlush::new();
+            let result = break_copy_on_write(
+                &mut self.page_table(),
+                page_fault.address,
+                area.entry_flags(),
+                &mut tlb_flush,
+            );
+            self.finish_tlb_flush(&mut tlb_flush);
+            return result;
+        }
+        match area.backing {
+            VmaBacking::Anonymous | VmaBacking::Stack => back_with_zeroed_frame(
+                &mut self.page_table(),
+                page_fault.address,
+                area.entry_flags(),
+                FrameOwner::User,
+            ),
+            // Physical areas are mapped in full when they are added, a missing page means it was never part of them
+            VmaBacking::Physical(_) => Err(PageFaultError::UnreservedAddress),
+        }
+    }
+
+    // Builds a copy of the address space with the same areas. Pages that were already touched are shared
+    // copy-on-write between the two, untouched pages stay lazily backed in both.
+    pub fn try_clone(&mut self) -> Result<Self, AddressSpaceError> {
+        let mut clone = AddressSpace::new()?;
+        let mut tlb_flush = TlbFlush::new();
+        let result = self.share_pages_with(&mut clone, &mut tlb_flush);
+        // Pages that were made copy-on-write before a failure stay that way, which is harmless
+        self.finish_tlb_flush(&mut tlb_flush);
+        result.map(|()| clone)
+    }
+
+    fn share_pages_with(&self, clone: &mut AddressSpace, tlb_flush: &mut TlbFlush) -> Result<(), AddressSpaceError> {
+        let mut source_page_table = self.page_table();
+        for area in self.areas.values() {
+            clone.map_area(*area)?;
+            if !area.owns_frames() {
+                continue;
+            }
+            // Both sides only get to read the frame until one of them writes to it
+            let shared_flags = match is_bit_set(area.permissions, VmaPermissions::WRITE) {
+                true => (area.entry_flags() & !PageTableEntryFlags::WRITE_ACCESS) | PageTableEntryFlags::COPY_ON_WRITE,
+                false => area.entry_flags(),
+            };
+            let mut clone_page_table = clone.page_table();
+            let mut frame_allocator = FRAME_ALLOCATOR.lock();
+            for page in area.pages() {
+                let frame = match source_page_table.get_mapping(page) {
+                    Some((frame, _)) => frame,
+                    None => continue,
+                };
+                source_page_table
+                    .update_flags(page, shared_flags, tlb_flush)
+                    .expect("Mapped page vanished while sharing it!");
+                frame_allocator.share_frame(frame);
+                if clone_page_table
+                    .map_to(page, frame, shared_flags, USER_TABLE_FLAGS, &mut *frame_allocator)
+                    .is_err()
+                {
+                    frame_allocator.release_frame(frame);
+                    return Err(AddressSpaceError::OutOfMemory);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        debug_assert!(
+            loaded_cpus(self.pml4_frame).is_empty(),
+            "Dropped an address space that is still loaded!"
+        );
+        // Unmapping every page also frees the user page tables once they are empty
+        let areas = core::mem::take(&mut self.areas);
+        for area in areas.values() {
+            self.unmap_pages(area);
+        }
+        FRAME_ALLOCATOR.lock().deallocate_frame(self.pml4_frame);
+        uncharge_frames(FrameOwner::PageTable, 1);
+        if self.pcid != KERNEL_PCID {
+            free_pcid(self.pcid);
+        }
+    }
+}
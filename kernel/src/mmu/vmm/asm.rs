@@ -7,13 +7,37 @@ pub unsafe fn get_raw_pml4_ptr() -> usize {
     raw_pml4_address
 }
 
+// Switches to the address space rooted at the given PML4. Flushes every non-global TLB entry
 #[inline]
-pub unsafe fn flush(address: usize) {
+pub unsafe fn write_cr3(raw_pml4_address: usize) {
+    asm!("mov cr3, {}", in(reg) raw_pml4_address, options(nostack, preserves_flags));
+}
+
+// Holds the linear address that caused the most recent page fault
+#[inline]
+pub unsafe fn read_cr2() -> usize {
+    let faulting_address: usize;
+    asm!("mov {}, cr2", out(reg) faulting_address, options(nomem, nostack, preserves_flags));
+    faulting_address
+}
+
+// Invalidates the TLB entries for the page containing the address in the current PCID, along with any global
+// entry for it
+#[inline]
+pub unsafe fn invlpg(address: usize) {
     asm!("invlpg [{}]", in(reg) address, options(nostack, preserves_flags));
 }
 
-pub unsafe fn flush_all() {
+// Invalidates TLB entries by PCID. What the descriptor's PCID and address mean depends on the invalidation type
+#[inline]
+pub unsafe fn invpcid(invalidation_type: usize, pcid: usize, address: usize) {
+    let descriptor: [usize; 2] = [pcid, address];
+    asm!("invpcid {}, [{}]", in(reg) invalidation_type, in(reg) &descriptor, options(nostack, preserves_flags));
+}
+
+// Reloading CR3 with its own value flushes every non-global entry of the current PCID
+pub unsafe fn reload_cr3() {
     let raw_cr3_value: usize;
-    asm!("mov {}, cr3 ", out(reg) raw_cr3_value, options(nostack, preserves_flags));
+    asm!("mov {}, cr3", out(reg) raw_cr3_value, options(nomem, nostack, preserves_flags));
     asm!("mov cr3, {}", in(reg) raw_cr3_value, options(nostack, preserves_flags));
 }
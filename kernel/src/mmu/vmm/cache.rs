@@ -0,0 +1,288 @@
+use core::arch::asm;
+use core::fmt;
+use core::sync::atomic::{
+    AtomicBool,
+    Ordering,
+};
+
+use crate::cpu::msr::{
+    read_msr_value,
+    write_msr_value,
+    IA32_MTRRCAP,
+    IA32_MTRR_DEF_TYPE,
+    IA32_MTRR_PHYSBASE0,
+    IA32_MTRR_PHYSMASK0,
+    IA32_PAT,
+};
+use crate::cpu::CPU_INFO;
+use crate::mmu::address::{
+    PhysicalAddress,
+    VirtualAddress,
+};
+use crate::mmu::vmm::page::{
+    VirtualPage,
+    VirtualPageRange,
+};
+use crate::mmu::vmm::page_table::{
+    FlagUpdateError,
+    MappedPageTable,
+    PageTable,
+};
+use crate::mmu::vmm::page_table_entry::PageTableEntryFlags;
+use crate::mmu::vmm::tlb::{
+    flush_all_contexts,
+    TlbFlush,
+};
+use crate::mmu::vmm::{
+    PageSize,
+    Size,
+    Size4KiB,
+};
+use crate::util::bits::is_bit_set;
+
+// Intel Manual - Section 12.12
+// Encodings shared by the PAT and the MTRRs. UC- only exists in the PAT
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub enum MemoryType {}
+
+impl MemoryType {
+    pub const UNCACHEABLE: usize = 0;
+    pub const WRITE_COMBINING: usize = 1;
+    pub const WRITE_THROUGH: usize = 4;
+    pub const WRITE_PROTECTED: usize = 5;
+    pub const WRITE_BACK: usize = 6;
+    pub const UNCACHED_MINUS: usize = 7;
+
+    pub fn name(memory_type: usize) -> &'static str {
+        match memory_type {
+            Self::UNCACHEABLE => "UC",
+            Self::WRITE_COMBINING => "WC",
+            Self::WRITE_THROUGH => "WT",
+            Self::WRITE_PROTECTED => "WP",
+            Self::WRITE_BACK => "WB",
+            Self::UNCACHED_MINUS => "UC-",
+            _ => "Reserved",
+        }
+    }
+}
+
+// The layout programmed into IA32_PAT, indexed by PAT:PCD:PWT. The first four entries match the power-on
+// defaults, so entries written before the PAT is set up keep their meaning.
+const PAT_ENTRIES: [usize; 8] = [
+    MemoryType::WRITE_BACK,
+    MemoryType::WRITE_THROUGH,
+    MemoryType::UNCACHED_MINUS,
+    MemoryType::UNCACHEABLE,
+    MemoryType::WRITE_COMBINING,
+    MemoryType::WRITE_PROTECTED,
+    MemoryType::UNCACHED_MINUS,
+    MemoryType::UNCACHEABLE,
+];
+
+static PAT_ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub enum MtrrFlags {}
+
+impl MtrrFlags {
+    // IA32_MTRRCAP
+    pub const VARIABLE_COUNT_MASK: usize = 0xFF;
+    pub const FIXED_RANGES_SUPPORTED: usize = 1 << 8;
+    pub const WRITE_COMBINING_SUPPORTED: usize = 1 << 10;
+    // IA32_MTRR_DEF_TYPE
+    pub const DEFAULT_TYPE_MASK: usize = 0xFF;
+    pub const FIXED_RANGES_ENABLED: usize = 1 << 10;
+    pub const MTRRS_ENABLED: usize = 1 << 11;
+    // IA32_MTRR_PHYSMASKn
+    pub const RANGE_VALID: usize = 1 << 11;
+}
+
+// Memory type of a mapping, as requested by whoever maps it. The effective type also depends on the MTRRs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    WriteBack,
+    WriteThrough,
+    // UC-. Can still be turned into write combining by the MTRRs
+    UncachedMinus,
+    Uncached,
+    WriteCombining,
+    WriteProtected,
+}
+
+impl CacheMode {
+    pub fn memory_type(self) -> usize {
+        match self {
+            CacheMode::WriteBack => MemoryType::WRITE_BACK,
+            CacheMode::WriteThrough => MemoryType::WRITE_THROUGH,
+            CacheMode::UncachedMinus => MemoryType::UNCACHED_MINUS,
+            CacheMode::Uncached => MemoryType::UNCACHEABLE,
+            CacheMode::WriteCombining => MemoryType::WRITE_COMBINING,
+            CacheMode::WriteProtected => MemoryType::WRITE_PROTECTED,
+        }
+    }
+
+    // Without the PAT only the default entries exist. Falls back to the closest type that is never cached more
+    // aggressively than asked for.
+    fn pat_index(self) -> usize {
+        let cache_mode = match (is_pat_enabled(), self) {
+            (false, CacheMode::WriteCombining) => CacheMode::UncachedMinus,
+            (false, CacheMode::WriteProtected) => CacheMode::Uncached,
+            (_, cache_mode) => cache_mode,
+        };
+        PAT_ENTRIES
+            .iter()
+            .position(|&memory_type| memory_type == cache_mode.memory_type())
+            .unwrap()
+    }
+
+    // The PWT, PCD and PAT bits selecting this cache mode in an entry mapping a page of size S
+    pub fn entry_flags<S: PageSize>(self) -> usize {
+        let pat_index = self.pat_index();
+        let mut entry_flags = 0;
+        if is_bit_set(pat_index, 1) {
+            entry_flags |= PageTableEntryFlags::WRITE_THROUGH;
+        }
+        if is_bit_set(pat_index, 1 << 1) {
+            entry_flags |= PageTableEntryFlags::CACHE_DISABLED;
+        }
+        if is_bit_set(pat_index, 1 << 2) {
+            entry_flags |= pat_flag::<S>();
+        }
+        entry_flags
+    }
+}
+
+#[inline]
+fn pat_flag<S: PageSize>() -> usize {
+    match S::LEVEL {
+        1 => PageTableEntryFlags::PAT_4KIB,
+        _ => PageTableEntryFlags::PAT_HUGE,
+    }
+}
+
+// Every flag that takes part in selecting the memory type of a page of size S
+#[inline]
+pub fn cache_flags<S: PageSize>() -> usize {
+    PageTableEntryFlags::WRITE_THROUGH | PageTableEntryFlags::CACHE_DISABLED | pat_flag::<S>()
+}
+
+#[inline]
+pub fn is_pat_enabled() -> bool {
+    PAT_ENABLED.load(Ordering::Acquire)
+}
+
+#[inline]
+unsafe fn wbinvd() {
+    asm!("wbinvd", options(nostack, preserves_flags));
+}
+
+// Loads PAT_ENTRIES into IA32_PAT. Every CPU has its own copy of the MSR, so this has to run on each of them
+// before they use any of the upper four entries.
+pub fn init_pat() {
+    let cpu_info = CPU_INFO.get().unwrap();
+    if !cpu_info.pat_enabled {
+        log::warn!("PAT not supported, write combining and write protected mappings fall back to uncached");
+        return;
+    }
+    let pat_value = PAT_ENTRIES
+        .iter()
+        .enumerate()
+        .fold(0, |pat_value, (index, &memory_type)| {
+            pat_value | (memory_type << (index * 8))
+        });
+    // Intel Manual - Section 12.12.4
+    // Lines and translations cached under the old memory types have to go
+    unsafe {
+        wbinvd();
+        write_msr_value(IA32_PAT, pat_value);
+        wbinvd();
+    }
+    flush_all_contexts();
+    PAT_ENABLED.store(true, Ordering::Release);
+    log::info!("PAT programmed: {:#018X}", pat_value);
+}
+
+// Changes the cache mode of the already mapped 4 KiB pages covering [start, start + size), leaving every other
+// flag alone
+pub fn set_cache_mode(start: VirtualAddress, size: usize, cache_mode: CacheMode) -> Result<(), FlagUpdateError> {
+    let mut page_table = MappedPageTable::new(VirtualAddress::kernel_base(), unsafe { PageTable::get_active_pml4() });
+    let mut tlb_flush = TlbFlush::new();
+    let pages = VirtualPageRange::<Size4KiB>::range_inclusive(
+        VirtualPage::from_address_aligned(start),
+        VirtualPage::from_address_aligned((start + size).align_up(Size::FOUR_KIB)),
+    );
+    for page in pages {
+        let (_, entry_flags) = page_table.get_mapping(page).ok_or(FlagUpdateError::PageNotMapped)?;
+        let entry_flags = (entry_flags & !cache_flags::<Size4KiB>()) | cache_mode.entry_flags::<Size4KiB>();
+        page_table.update_flags(page, entry_flags, &mut tlb_flush)?;
+    }
+    tlb_flush.flush();
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MtrrRange {
+    pub base: PhysicalAddress,
+    pub size: usize,
+    pub memory_type: usize,
+}
+
+impl fmt::Display for MtrrRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_fmt(format_args!(
+            "{:#X}-{:#X} {}",
+            self.base.inner,
+            self.base.inner + self.size,
+            MemoryType::name(self.memory_type)
+        ))
+    }
+}
+
+// The enabled variable range MTRRs. Empty if the CPU has no MTRRs
+pub fn variable_mtrr_ranges() -> impl Iterator<Item = MtrrRange> {
+    let cpu_info = CPU_INFO.get().unwrap();
+    let range_count = match cpu_info.mtrr_enabled {
+        true => unsafe { read_msr_value(IA32_MTRRCAP) & MtrrFlags::VARIABLE_COUNT_MASK },
+        false => 0,
+    };
+    let address_mask = ((1 << cpu_info.physical_address_bits) - 1) & !(Size::FOUR_KIB - 1);
+    (0..range_count as u32).filter_map(move |index| {
+        let (base, mask) = unsafe {
+            (
+                read_msr_value(IA32_MTRR_PHYSBASE0 + 2 * index),
+                read_msr_value(IA32_MTRR_PHYSMASK0 + 2 * index),
+            )
+        };
+        if !is_bit_set(mask, MtrrFlags::RANGE_VALID) {
+            return None;
+        }
+        // The mask has every address bit above the range's size set
+        Some(MtrrRange {
+            base: PhysicalAddress::new(base & address_mask),
+            size: (!mask & address_mask) + Size::FOUR_KIB,
+            memory_type: base & 0xFF,
+        })
+    })
+}
+
+pub fn report_mtrrs() {
+    let cpu_info = CPU_INFO.get().unwrap();
+    if !cpu_info.mtrr_enabled {
+        log::info!("MTRRs not supported");
+        return;
+    }
+    let (capabilities, default_type) = unsafe { (read_msr_value(IA32_MTRRCAP), read_msr_value(IA32_MTRR_DEF_TYPE)) };
+    log::info!(
+        "MTRRs enabled - {} Fixed ranges supported - {} enabled - {} WC supported - {} Default type: {}",
+        is_bit_set(default_type, MtrrFlags::MTRRS_ENABLED),
+        is_bit_set(capabilities, MtrrFlags::FIXED_RANGES_SUPPORTED),
+        is_bit_set(default_type, MtrrFlags::FIXED_RANGES_ENABLED),
+        is_bit_set(capabilities, MtrrFlags::WRITE_COMBINING_SUPPORTED),
+        MemoryType::name(default_type & MtrrFlags::DEFAULT_TYPE_MASK)
+    );
+    for range in variable_mtrr_ranges() {
+        log::info!("MTRR: {}", range);
+    }
+}
@@ -0,0 +1,135 @@
+use bootloader_api::info::MemoryRegions;
+
+use crate::cpu::CPU_INFO;
+use crate::mmu::address::{
+    PhysicalAddress,
+    VirtualAddress,
+};
+use crate::mmu::alloc::frame::{
+    FrameAllocator,
+    FRAME_ALLOCATOR,
+};
+use crate::mmu::vmm::cache::{
+    set_cache_mode,
+    CacheMode,
+};
+use crate::mmu::vmm::frame::PhysicalFrame;
+use crate::mmu::vmm::page::VirtualPage;
+use crate::mmu::vmm::page_table::{
+    FlagUpdateError,
+    MapToError,
+    MappedPageTable,
+    PageTable,
+};
+use crate::mmu::vmm::page_table_entry::PageTableEntryFlags;
+use crate::mmu::vmm::tlb::TlbFlush;
+use crate::mmu::vmm::{
+    PageSize,
+    Size,
+    Size1GiB,
+    Size2MiB,
+};
+
+// The bootloader only maps physical memory up to the end of the highest region in its memory map. That leaves
+// out the MMIO hole below 4 GiB where the LAPIC and IOAPIC registers live, so the direct map always covers at
+// least this much.
+pub const DIRECT_MAP_MINIMUM_SIZE: usize = 1 << 32;
+
+fn map_direct<S: PageSize>(
+    active_pml4: &mut MappedPageTable,
+    physical_address: usize,
+    frame_allocator: &mut impl FrameAllocator,
+) -> Result<(), MapToError> {
+    let page = VirtualPage::<S>::from_address_aligned(VirtualAddress::with_kernel_base_offset(physical_address));
+    let frame = PhysicalFrame::<S>::from_address_aligned(PhysicalAddress::new(physical_address));
+    let entry_flags = PageTableEntryFlags::PRESENT
+        | PageTableEntryFlags::WRITE_ACCESS
+        | PageTableEntryFlags::GLOBAL
+        | PageTableEntryFlags::no_execute();
+    let table_flags = PageTableEntryFlags::PRESENT | PageTableEntryFlags::WRITE_ACCESS;
+    active_pml4.map_to(page, frame, entry_flags, table_flags, frame_allocator)
+}
+
+// Fills in the gaps of the physical memory mapping at KERNEL_BASE_ADDRESS with huge pages. 1 GiB pages are
+// used where the CPU supports them and nothing is mapped yet, 2 MiB pages everywhere else. Whatever the
+// bootloader already mapped is left alone.
+pub fn init_direct_map(memory_regions: &'static MemoryRegions) {
+    let highest_address = memory_regions
+        .iter()
+        .map(|memory_region| memory_region.end as usize)
+        .max()
+        .unwrap_or(0);
+    let end = PhysicalAddress::new(highest_address.max(DIRECT_MAP_MINIMUM_SIZE))
+        .align_up(Size::TWO_MB)
+        .inner;
+    let gib_pages_enabled = CPU_INFO.get().is_some_and(|cpu_info| cpu_info.gib_pages_enabled);
+    let mut active_pml4 = MappedPageTable::new(VirtualAddress::kernel_base(), unsafe { PageTable::get_active_pml4() });
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+
+    let mut physical_address = 0;
+    let mut mapped_bytes = 0;
+    while physical_address < end {
+        if gib_pages_enabled && physical_address % Size::ONE_GB == 0 && end - physical_address >= Size::ONE_GB {
+            match map_direct::<Size1GiB>(&mut active_pml4, physical_address, &mut *frame_allocator) {
+                Ok(()) => {
+                    physical_address += Size::ONE_GB;
+                    mapped_bytes += Size::ONE_GB;
+                    continue;
+                }
+                // Part of this gigabyte is already mapped, fill in the rest 2 MiB at a time
+                Err(MapToError::PageAlreadyMapped(_)) => {}
+                Err(map_error) => panic!("{}", map_error),
+            }
+        }
+        match map_direct::<Size2MiB>(&mut active_pml4, physical_address, &mut *frame_allocator) {
+            Ok(()) => mapped_bytes += Size::TWO_MB,
+            Err(MapToError::PageAlreadyMapped(_)) | Err(MapToError::ParentEntryHugePage) => {}
+            Err(map_error) => panic!("{}", map_error),
+        }
+        physical_address += Size::TWO_MB;
+    }
+    log::info!(
+        "Direct map covers {:#X} bytes of physical memory ({:#X} bytes added)",
+        end,
+        mapped_bytes
+    );
+}
+
+// Changes the cache mode of the direct map over [physical_address, physical_address + size). Any other mapping of
+// those frames with a different memory type has to be matched by the direct map, since mixed memory types for the
+// same memory are undefined. Huge pages covering the range are split first, so the rest of them keep their type.
+pub fn set_direct_map_cache_mode(
+    physical_address: PhysicalAddress,
+    size: usize,
+    cache_mode: CacheMode,
+) -> Result<(), FlagUpdateError> {
+    let start = physical_address.align_down(Size::FOUR_KIB);
+    let end = (physical_address + size).align_up(Size::FOUR_KIB);
+    {
+        let mut active_pml4 =
+            MappedPageTable::new(VirtualAddress::kernel_base(), unsafe { PageTable::get_active_pml4() });
+        // Declared first so the frame allocator is unlocked before it's flushed, on errors too
+        let mut tlb_flush = TlbFlush::new();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let mut address = start.align_down(Size::TWO_MB).inner;
+        while address < end.inner {
+            let virtual_address = VirtualAddress::with_kernel_base_offset(address);
+            let gib_page = VirtualPage::<Size1GiB>::from_address_aligned(virtual_address);
+            if active_pml4.get_mapping(gib_page).is_some() {
+                active_pml4.split_huge_page(gib_page, &mut *frame_allocator, &mut tlb_flush)?;
+            }
+            let mib_page = VirtualPage::<Size2MiB>::from_address_aligned(virtual_address);
+            if active_pml4.get_mapping(mib_page).is_some() {
+                active_pml4.split_huge_page(mib_page, &mut *frame_allocator, &mut tlb_flush)?;
+            }
+            address += Size::TWO_MB;
+        }
+        drop(frame_allocator);
+        tlb_flush.flush();
+    }
+    set_cache_mode(
+        VirtualAddress::with_kernel_base_offset(start.inner),
+        end.inner - start.inner,
+        cache_mode,
+    )
+}
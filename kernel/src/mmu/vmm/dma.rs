@@ -0,0 +1,210 @@
+use core::fmt;
+
+use crate::cpu::CPU_INFO;
+use crate::mmu::address::{
+    PhysicalAddress,
+    VirtualAddress,
+};
+use crate::mmu::alloc::frame::accounting::{
+    charge_frames,
+    frame_count,
+    uncharge_frames,
+    FrameOwner,
+};
+use crate::mmu::alloc::frame::buddy::MAX_ORDER;
+use crate::mmu::alloc::frame::FRAME_ALLOCATOR;
+use crate::mmu::alloc::pressure::{
+    retry_on_pressure,
+    AllocationFailure,
+};
+use crate::mmu::vmm::cache::CacheMode;
+use crate::mmu::vmm::direct_map::set_direct_map_cache_mode;
+use crate::mmu::vmm::frame::PhysicalFrame;
+use crate::mmu::vmm::page_table::FlagUpdateError;
+use crate::mmu::vmm::vmalloc::{
+    ioremap,
+    IoMapping,
+    VmapError,
+};
+use crate::mmu::vmm::Size;
+
+// Highest physical address, exclusive, a device is able to reach
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub enum DmaLimit {}
+
+impl DmaLimit {
+    // ISA DMA controllers only have 24 address lines
+    pub const ISA: usize = 1 << 24;
+    pub const BITS_32: usize = 1 << 32;
+    pub const NONE: usize = usize::MAX;
+}
+
+// How the CPU sees the buffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaMapping {
+    // Device accesses snoop the CPU caches on x86, so the buffer is used through the write back direct map
+    Coherent,
+    // For devices that don't snoop, or when writes have to reach memory in program order. Mapped separately, and
+    // the direct map alias is made uncached as well while the buffer is alive.
+    Uncached,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum DmaError {
+    InvalidSize,
+    InvalidAlignment,
+    TooLarge,
+    OutOfMemory,
+    Mapping(VmapError),
+    DirectMap(FlagUpdateError),
+}
+
+impl fmt::Display for DmaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidSize => f.write_str("DMA Error: Buffer size is zero"),
+            Self::InvalidAlignment => f.write_str("DMA Error: Alignment is not a power of two"),
+            Self::TooLarge => f.write_str("DMA Error: Buffer is larger than the largest contiguous block"),
+            Self::OutOfMemory => f.write_str("DMA Error: No free contiguous block below the address limit"),
+            Self::Mapping(vmap_error) => f.write_fmt(format_args!("DMA Error: {}", vmap_error)),
+            Self::DirectMap(flag_update_error) => f.write_fmt(format_args!("DMA Error: {}", flag_update_error)),
+        }
+    }
+}
+
+impl AllocationFailure for DmaError {
+    fn is_out_of_memory(&self) -> bool {
+        match self {
+            Self::OutOfMemory => true,
+            Self::Mapping(vmap_error) => vmap_error.is_out_of_memory(),
+            Self::DirectMap(flag_update_error) => flag_update_error.is_out_of_memory(),
+            _ => false,
+        }
+    }
+}
+
+// Physically contiguous, zeroed memory that can be handed to a device. The frames are freed again when this is
+// dropped, so the device must be done with them by then.
+#[derive(Debug)]
+pub struct DmaBuffer {
+    frame: PhysicalFrame,
+    order: usize,
+    size: usize,
+    mapping: DmaMapping,
+    // Only set for uncached buffers
+    io_mapping: Option<IoMapping>,
+}
+
+impl DmaBuffer {
+    // Allocates at least `size` bytes starting at a physical address aligned to `align` and ending at or below
+    // `address_limit`. The frame allocator hands out power of two blocks aligned to their size, so large
+    // alignments cost as much memory as they cover. Reclaims memory and retries once if none is left.
+    pub fn new(size: usize, align: usize, address_limit: usize, mapping: DmaMapping) -> Result<Self, DmaError> {
+        if size == 0 {
+            return Err(DmaError::InvalidSize);
+        }
+        if !align.is_power_of_two() {
+            return Err(DmaError::InvalidAlignment);
+        }
+        let order = frame_count(size.max(align)).next_power_of_two().trailing_zeros() as usize;
+        if order >= MAX_ORDER {
+            return Err(DmaError::TooLarge);
+        }
+        retry_on_pressure(1 << order, || Self::allocate(order, address_limit, mapping))
+    }
+
+    fn allocate(order: usize, address_limit: usize, mapping: DmaMapping) -> Result<Self, DmaError> {
+        let frame = FRAME_ALLOCATOR
+            .lock()
+            .allocate_order_below(order, address_limit)
+            .ok_or(DmaError::OutOfMemory)?;
+        charge_frames(FrameOwner::Dma, 1 << order);
+        let mut dma_buffer = DmaBuffer {
+            frame,
+            order,
+            size: Size::FOUR_KIB << order,
+            mapping,
+            io_mapping: None,
+        };
+        if mapping == DmaMapping::Uncached {
+            // Intel Manual - Section 12.12.4
+            // The direct map stops caching the frames first, then the lines it already cached are written back
+            set_direct_map_cache_mode(dma_buffer.physical_address(), dma_buffer.size, CacheMode::Uncached)
+                .map_err(DmaError::DirectMap)?;
+            dma_buffer.flush_cache_lines();
+            let io_mapping = ioremap(dma_buffer.physical_address(), dma_buffer.size, CacheMode::Uncached)
+                .map_err(DmaError::Mapping)?;
+            dma_buffer.io_mapping = Some(io_mapping);
+        }
+        dma_buffer.as_mut_slice().fill(0);
+        Ok(dma_buffer)
+    }
+
+    #[inline]
+    pub fn address(&self) -> VirtualAddress {
+        match &self.io_mapping {
+            Some(io_mapping) => io_mapping.address(),
+            None => self.frame.direct_map_address(),
+        }
+    }
+
+    // The address to program into the device
+    #[inline]
+    pub fn physical_address(&self) -> PhysicalAddress {
+        PhysicalAddress::new(self.frame.start_address())
+    }
+
+    // The allocated size, which can be larger than what was asked for
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    #[inline]
+    pub fn mapping(&self) -> DmaMapping {
+        self.mapping
+    }
+
+    #[inline]
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.address().inner as *mut u8
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.as_ptr(), self.size) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.as_ptr(), self.size) }
+    }
+
+    // Writes back and invalidates every cache line of the buffer in the direct map
+    fn flush_cache_lines(&self) {
+        let cache_line_size = CPU_INFO.get().unwrap().cache_line_size;
+        let start = self.frame.direct_map_address().inner;
+        for line in (start..start + self.size).step_by(cache_line_size) {
+            unsafe { core::arch::x86_64::_mm_clflush(line as *const u8) };
+        }
+        unsafe { core::arch::x86_64::_mm_mfence() };
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        drop(self.io_mapping.take());
+        if self.mapping == DmaMapping::Uncached {
+            if let Err(flag_update_error) =
+                set_direct_map_cache_mode(self.physical_address(), self.size, CacheMode::WriteBack)
+            {
+                log::warn!(
+                    "Failed to restore the direct map of DMA buffer {:#X}: {}",
+                    self.frame.start_address(),
+                    flag_update_error
+                );
+            }
+        }
+        FRAME_ALLOCATOR.lock().deallocate_order(self.frame, self.order);
+        uncharge_frames(FrameOwner::Dma, 1 << self.order);
+    }
+}
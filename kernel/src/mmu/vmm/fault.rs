@@ -0,0 +1,368 @@
+use core::fmt;
+
+use spin::Mutex;
+
+use crate::cpu::smp::lock_answering_calls;
+use crate::mmu::address::VirtualAddress;
+use crate::mmu::alloc::frame::accounting::{
+    charge_frames,
+    uncharge_frames,
+    FrameOwner,
+};
+use crate::mmu::alloc::frame::{
+    FrameAllocator,
+    FRAME_ALLOCATOR,
+};
+use crate::mmu::alloc::pressure::{
+    relieve_memory_pressure,
+    AllocationFailure,
+};
+use crate::mmu::vmm::address_space::{
+    active_address_space,
+    is_user_address,
+};
+use crate::mmu::vmm::asm::read_cr2;
+use crate::mmu::vmm::page::VirtualPage;
+use crate::mmu::vmm::page_table::{
+    MapToError,
+    MappedPageTable,
+    PageTable,
+};
+use crate::mmu::vmm::page_table_entry::PageTableEntryFlags;
+use crate::mmu::vmm::tlb::TlbFlush;
+use crate::mmu::vmm::{
+    PageSize,
+    Size4KiB,
+};
+use crate::util::bits::is_bit_set;
+
+// Demand regions are kept in a fixed table so the fault handler never has to touch the heap,
+// which is itself one of the regions.
+const MAX_DEMAND_REGIONS: usize = 32;
+
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub enum PageFaultErrorCode {}
+
+impl PageFaultErrorCode {
+    // Intel Manual - Section 4.7
+    // If clear, the page wasn't present. If set, the access violated the page's permissions
+    pub const PROTECTION_VIOLATION: usize = 1;
+    pub const CAUSED_BY_WRITE: usize = 1 << 1;
+    pub const USER_MODE: usize = 1 << 2;
+    // A reserved bit was set in one of the paging structures
+    pub const MALFORMED_TABLE: usize = 1 << 3;
+    pub const INSTRUCTION_FETCH: usize = 1 << 4;
+    pub const PROTECTION_KEY: usize = 1 << 5;
+    pub const SHADOW_STACK: usize = 1 << 6;
+    pub const SGX: usize = 1 << 15;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PageFault {
+    pub address: VirtualAddress,
+    pub error_code: usize,
+    pub instruction_pointer: usize,
+}
+
+impl fmt::Display for PageFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let page_state = match self.is_present() {
+            true => "present",
+            false => "non-present",
+        };
+        let access = match (self.is_instruction_fetch(), self.is_write()) {
+            (true, _) => "instruction fetch",
+            (false, true) => "write",
+            (false, false) => "read",
+        };
+        let mode = match self.is_user() {
+            true => "user",
+            false => "kernel",
+        };
+        f.write_fmt(format_args!(
+            "Faulting Address: {:#X} RIP: {:#X} Error Code: {:#X}\n{} of {} page from {} mode",
+            self.address.inner, self.instruction_pointer, self.error_code, access, page_state, mode
+        ))?;
+        if self.is_reserved_bit_violation() {
+            f.write_str(", reserved bit set in paging structures")?;
+        }
+        Ok(())
+    }
+}
+
+impl PageFault {
+    // Must be called from the page fault handler, before anything else can fault and overwrite CR2
+    pub fn read(error_code: usize, instruction_pointer: usize) -> Self {
+        let address = VirtualAddress::new(unsafe { read_cr2() });
+        PageFault {
+            address,
+            error_code,
+            instruction_pointer,
+        }
+    }
+
+    #[inline]
+    pub fn is_present(&self) -> bool {
+        is_bit_set(self.error_code, PageFaultErrorCode::PROTECTION_VIOLATION)
+    }
+
+    #[inline]
+    pub fn is_write(&self) -> bool {
+        is_bit_set(self.error_code, PageFaultErrorCode::CAUSED_BY_WRITE)
+    }
+
+    #[inline]
+    pub fn is_user(&self) -> bool {
+        is_bit_set(self.error_code, PageFaultErrorCode::USER_MODE)
+    }
+
+    #[inline]
+    pub fn is_reserved_bit_violation(&self) -> bool {
+        is_bit_set(self.error_code, PageFaultErrorCode::MALFORMED_TABLE)
+    }
+
+    #[inline]
+    pub fn is_instruction_fetch(&self) -> bool {
+        is_bit_set(self.error_code, PageFaultErrorCode::INSTRUCTION_FETCH)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DemandRegionKind {
+    KernelHeap,
+    Stack,
+    Anonymous,
+}
+
+// A range of virtual memory that has been reserved but isn't backed yet. Pages are given a zeroed frame the
+// first time they're touched.
+#[derive(Debug, Clone, Copy)]
+pub struct DemandRegion {
+    pub start: VirtualAddress,
+    pub end: VirtualAddress,
+    pub entry_flags: usize,
+    pub kind: DemandRegionKind,
+}
+
+impl DemandRegion {
+    pub fn new(start: VirtualAddress, size: usize, entry_flags: usize, kind: DemandRegionKind) -> Self {
+        DemandRegion {
+            start,
+            end: start + size,
+            entry_flags,
+            kind,
+        }
+    }
+
+    #[inline]
+    pub fn contains(&self, address: VirtualAddress) -> bool {
+        self.start <= address && address < self.end
+    }
+
+    #[inline]
+    fn overlaps(&self, other: &DemandRegion) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum DemandRegionError {
+    OverlappingRegion,
+    RegionTableFull,
+}
+
+impl fmt::Display for DemandRegionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OverlappingRegion => f.write_str("Demand Region Error: Overlaps an existing region"),
+            Self::RegionTableFull => f.write_str("Demand Region Error: No free region slots"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum PageFaultError {
+    MalformedPageTable,
+    ProtectionViolation,
+    UnreservedAddress,
+    AccessNotPermitted,
+    OutOfMemory,
+}
+
+impl fmt::Display for PageFaultError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MalformedPageTable => f.write_str("Page Fault Error: Reserved bit set in a page table entry"),
+            Self::ProtectionViolation => f.write_str("Page Fault Error: Access violates the page's protection"),
+            Self::UnreservedAddress => f.write_str("Page Fault Error: Address is not part of any reserved region"),
+            Self::AccessNotPermitted => f.write_str("Page Fault Error: Access not permitted by the region"),
+            Self::OutOfMemory => f.write_str("Page Fault Error: Out of physical memory"),
+        }
+    }
+}
+
+impl AllocationFailure for PageFaultError {
+    fn is_out_of_memory(&self) -> bool {
+        matches!(self, Self::OutOfMemory)
+    }
+}
+
+static DEMAND_REGIONS: Mutex<[Option<DemandRegion>; MAX_DEMAND_REGIONS]> = Mutex::new([None; MAX_DEMAND_REGIONS]);
+
+pub fn reserve_demand_region(region: DemandRegion) -> Result<(), DemandRegionError> {
+    let mut demand_regions = DEMAND_REGIONS.lock();
+    if demand_regions
+        .iter()
+        .flatten()
+        .any(|existing| existing.overlaps(&region))
+    {
+        return Err(DemandRegionError::OverlappingRegion);
+    }
+    let free_slot = demand_regions
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .ok_or(DemandRegionError::RegionTableFull)?;
+    *free_slot = Some(region);
+    Ok(())
+}
+
+// Pages of the region that were already backed stay mapped. Tearing those down is up to the caller.
+pub fn release_demand_region(start: VirtualAddress) -> Option<DemandRegion> {
+    let mut demand_regions = DEMAND_REGIONS.lock();
+    let slot = demand_regions
+        .iter_mut()
+        .find(|slot| slot.is_some_and(|region| region.start == start))?;
+    slot.take()
+}
+
+fn find_demand_region(address: VirtualAddress) -> Option<DemandRegion> {
+    DEMAND_REGIONS
+        .lock()
+        .iter()
+        .flatten()
+        .find(|region| region.contains(address))
+        .copied()
+}
+
+// Gives the page containing `address` a zeroed frame in the given page table, charged to `owner`
+pub(crate) fn back_with_zeroed_frame(
+    page_table: &mut MappedPageTable,
+    address: VirtualAddress,
+    entry_flags: usize,
+    owner: FrameOwner,
+) -> Result<(), PageFaultError> {
+    let page = VirtualPage::from_address_aligned(address);
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let frame = frame_allocator.allocate_frame().ok_or(PageFaultError::OutOfMemory)?;
+    frame.zero();
+    let table_flags = PageTableEntryFlags::PRESENT
+        | PageTableEntryFlags::WRITE_ACCESS
+        | (entry_flags & PageTableEntryFlags::USER_ACCESS);
+    match page_table.map_to(page, frame, entry_flags, table_flags, &mut *frame_allocator) {
+        Ok(()) => {
+            charge_frames(owner, 1);
+            Ok(())
+        }
+        // The page was backed between the fault and now. The access can simply be retried
+        Err(MapToError::PageAlreadyMapped(_)) => {
+            frame_allocator.deallocate_frame(frame);
+            Ok(())
+        }
+        Err(_) => {
+            frame_allocator.deallocate_frame(frame);
+            Err(PageFaultError::OutOfMemory)
+        }
+    }
+}
+
+// Gives the faulting page a private, writable copy of the frame it shares with other mappings. The last owner
+// of a frame doesn't need a copy and simply gets write access back. `entry_flags` are the flags the page has
+// once it's no longer shared.
+pub(crate) fn break_copy_on_write(
+    page_table: &mut MappedPageTable,
+    address: VirtualAddress,
+    entry_flags: usize,
+    tlb_flush: &mut TlbFlush,
+) -> Result<(), PageFaultError> {
+    let page = VirtualPage::<Size4KiB>::from_address_aligned(address);
+    let (shared_frame, shared_flags) = page_table
+        .get_mapping(page)
+        .ok_or(PageFaultError::ProtectionViolation)?;
+    if !is_bit_set(shared_flags, PageTableEntryFlags::COPY_ON_WRITE) {
+        return Err(PageFaultError::ProtectionViolation);
+    }
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    if frame_allocator.ref_count(shared_frame) == 1 {
+        page_table
+            .update_flags(page, entry_flags, tlb_flush)
+            .map_err(|_| PageFaultError::ProtectionViolation)?;
+        return Ok(());
+    }
+    let frame = frame_allocator.allocate_frame().ok_or(PageFaultError::OutOfMemory)?;
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            shared_frame.direct_map_address().inner as *const u8,
+            frame.direct_map_address().inner as *mut u8,
+            Size4KiB::SIZE,
+        );
+    }
+    match page_table.remap(page, frame, entry_flags, tlb_flush) {
+        Ok(_) => {
+            charge_frames(FrameOwner::User, 1);
+            if frame_allocator.release_frame(shared_frame) {
+                uncharge_frames(FrameOwner::User, 1);
+            }
+            Ok(())
+        }
+        Err(_) => {
+            frame_allocator.deallocate_frame(frame);
+            Err(PageFaultError::ProtectionViolation)
+        }
+    }
+}
+
+pub(crate) fn access_permitted(page_fault: &PageFault, entry_flags: usize) -> bool {
+    let write_permitted = !page_fault.is_write() || is_bit_set(entry_flags, PageTableEntryFlags::WRITE_ACCESS);
+    let user_permitted = !page_fault.is_user() || is_bit_set(entry_flags, PageTableEntryFlags::USER_ACCESS);
+    write_permitted && user_permitted
+}
+
+// Resolves the fault if it hit a reserved but unbacked page, or a write to a copy-on-write page. Anything else is
+// a genuine invalid access and is handed back to the caller to deal with. User addresses are looked up in the
+// active address space, kernel addresses in the kernel's demand regions.
+pub fn handle_page_fault(page_fault: &PageFault) -> Result<(), PageFaultError> {
+    if page_fault.is_reserved_bit_violation() {
+        return Err(PageFaultError::MalformedPageTable);
+    }
+    if is_user_address(page_fault.address) {
+        let address_space = active_address_space().ok_or(PageFaultError::UnreservedAddress)?;
+        // Taken with interrupts disabled, while the CPU holding it may be waiting for this one to drop stale
+        // entries. It's unlocked while memory is reclaimed, the OOM killer has to be able to look at it.
+        match lock_answering_calls(&address_space).handle_page_fault(page_fault) {
+            Err(page_fault_error) if page_fault_error.is_out_of_memory() => {}
+            result => return result,
+        }
+        // If the OOM killer picked the faulting process, the retry fails and the process is torn down once the
+        // handler gets to it
+        return match relieve_memory_pressure(1) {
+            true => lock_answering_calls(&address_space).handle_page_fault(page_fault),
+            false => Err(PageFaultError::OutOfMemory),
+        };
+    }
+    if page_fault.is_present() {
+        return Err(PageFaultError::ProtectionViolation);
+    }
+
+    let region = find_demand_region(page_fault.address).ok_or(PageFaultError::UnreservedAddress)?;
+    if !access_permitted(page_fault, region.entry_flags) {
+        return Err(PageFaultError::AccessNotPermitted);
+    }
+    let mut active_pml4 = MappedPageTable::new(VirtualAddress::kernel_base(), unsafe { PageTable::get_active_pml4() });
+    back_with_zeroed_frame(
+        &mut active_pml4,
+        page_fault.address,
+        region.entry_flags,
+        FrameOwner::Kernel,
+    )
+}
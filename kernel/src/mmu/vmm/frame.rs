@@ -1,49 +1,57 @@
+use core::marker::PhantomData;
+
 use crate::mmu::address::{
     PhysicalAddress,
     VirtualAddress,
 };
 use crate::mmu::vmm::page_table::PageTable;
-use crate::mmu::vmm::Size;
+use crate::mmu::vmm::{
+    PageSize,
+    Size4KiB,
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 #[repr(C)]
-pub struct PhysicalFrame {
+pub struct PhysicalFrame<S: PageSize = Size4KiB> {
     pub offset: PhysicalAddress,
+    size: PhantomData<S>,
 }
 
-impl core::ops::Add<usize> for PhysicalFrame {
+impl<S: PageSize> core::ops::Add<usize> for PhysicalFrame<S> {
     type Output = Self;
 
     fn add(self, rhs: usize) -> Self::Output {
-        PhysicalFrame::from_address_aligned(self.offset + (rhs * Size::FOUR_KIB))
+        PhysicalFrame::from_address_aligned(self.offset + (rhs * S::SIZE))
     }
 }
 
-impl core::ops::AddAssign<usize> for PhysicalFrame {
+impl<S: PageSize> core::ops::AddAssign<usize> for PhysicalFrame<S> {
     fn add_assign(&mut self, rhs: usize) {
         *self = *self + rhs;
     }
 }
 
-impl PhysicalFrame {
+impl<S: PageSize> PhysicalFrame<S> {
     pub fn from_raw_address_aligned(raw_physical_address: usize) -> Self {
-        let aligned_raw_address = !(Size::FOUR_KIB - 1) & raw_physical_address;
+        let aligned_raw_address = !(S::SIZE - 1) & raw_physical_address;
         PhysicalFrame {
             offset: PhysicalAddress::new(aligned_raw_address),
+            size: PhantomData,
         }
     }
 
     // yields the frame that contains the physical address
     pub fn from_address_aligned(physical_address: PhysicalAddress) -> Self {
-        let aligned_address = physical_address.align_down(Size::FOUR_KIB);
+        let aligned_address = physical_address.align_down(S::SIZE);
         PhysicalFrame {
             offset: aligned_address,
+            size: PhantomData,
         }
     }
 
-    pub fn frame_to_table_ptr(&self, offset: VirtualAddress) -> PageTable {
-        let raw_virtual_address = offset.inner + self.offset.inner;
-        unsafe { *(raw_virtual_address as *const PageTable) }
+    #[inline]
+    pub fn size(&self) -> usize {
+        S::SIZE
     }
 
     pub fn start_address(&self) -> usize {
@@ -51,14 +59,32 @@ impl PhysicalFrame {
     }
 }
 
+impl PhysicalFrame {
+    // Where the frame can be reached through the kernel's direct map of physical memory
+    #[inline]
+    pub fn direct_map_address(&self) -> VirtualAddress {
+        VirtualAddress::with_kernel_base_offset(self.start_address())
+    }
+
+    pub fn zero(&self) {
+        unsafe { core::ptr::write_bytes(self.direct_map_address().inner as *mut u8, 0, Size4KiB::SIZE) }
+    }
+
+    // The table is reached through the physical memory mapping at `offset`
+    pub fn frame_to_page_table(&self, offset: VirtualAddress) -> &'static mut PageTable {
+        let raw_virtual_address = offset.inner + self.offset.inner;
+        unsafe { &mut *(raw_virtual_address as *mut PageTable) }
+    }
+}
+
 #[derive(Debug)]
-pub struct PhysicalFrameRange {
-    current: PhysicalFrame,
-    end: PhysicalFrame,
+pub struct PhysicalFrameRange<S: PageSize = Size4KiB> {
+    current: PhysicalFrame<S>,
+    end: PhysicalFrame<S>,
 }
 
-impl Iterator for PhysicalFrameRange {
-    type Item = PhysicalFrame;
+impl<S: PageSize> Iterator for PhysicalFrameRange<S> {
+    type Item = PhysicalFrame<S>;
 
     fn next(&mut self) -> Option<Self::Item> {
         match self.current < self.end {
@@ -72,8 +98,8 @@ impl Iterator for PhysicalFrameRange {
     }
 }
 
-impl PhysicalFrameRange {
-    pub fn range_inclusive(start: PhysicalFrame, end: PhysicalFrame) -> Self {
+impl<S: PageSize> PhysicalFrameRange<S> {
+    pub fn range_inclusive(start: PhysicalFrame<S>, end: PhysicalFrame<S>) -> Self {
         PhysicalFrameRange { current: start, end }
     }
 }
@@ -0,0 +1,137 @@
+use crate::mmu::address::VirtualAddress;
+use crate::mmu::vmm::page::{
+    VirtualPage,
+    VirtualPageRange,
+};
+use crate::mmu::vmm::page_table::{
+    MappedPageTable,
+    PageTable,
+};
+use crate::mmu::vmm::page_table_entry::PageTableEntryFlags;
+use crate::mmu::vmm::tlb::TlbFlush;
+use crate::mmu::vmm::{
+    Size,
+    Size4KiB,
+};
+use crate::util::bits::is_bit_set;
+
+// ELF-64 Object File Format, Version 1.5 Draft 2
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 1 << 1;
+
+extern "C" {
+    // Defined by the linker at the ELF header, which is part of the first loaded segment
+    static __ehdr_start: u8;
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct ElfHeader {
+    ident: [u8; 16],
+    elf_type: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    program_header_offset: u64,
+    section_header_offset: u64,
+    flags: u32,
+    header_size: u16,
+    program_header_size: u16,
+    program_header_count: u16,
+    section_header_size: u16,
+    section_header_count: u16,
+    section_name_index: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct ProgramHeader {
+    segment_type: u32,
+    flags: u32,
+    offset: u64,
+    virtual_address: u64,
+    physical_address: u64,
+    file_size: u64,
+    memory_size: u64,
+    align: u64,
+}
+
+// Each loadable segment groups the sections that share its permissions
+fn program_headers() -> impl Iterator<Item = ProgramHeader> {
+    let image_start = core::ptr::addr_of!(__ehdr_start);
+    let elf_header = unsafe { (image_start as *const ElfHeader).read_unaligned() };
+    assert_eq!(&elf_header.ident[..4], b"\x7FELF", "Kernel image has no ELF header!");
+    let program_header_table = unsafe { image_start.add(elf_header.program_header_offset as usize) };
+    let program_header_size = elf_header.program_header_size as usize;
+    (0..elf_header.program_header_count as usize).map(move |index| unsafe {
+        (program_header_table.add(index * program_header_size) as *const ProgramHeader).read_unaligned()
+    })
+}
+
+// Bytes of memory taken up by the loaded segments
+pub fn kernel_image_size() -> usize {
+    program_headers()
+        .filter(|header| header.segment_type == PT_LOAD)
+        .map(|header| {
+            let start = VirtualAddress::new(header.virtual_address as usize);
+            let end = (start + header.memory_size as usize).align_up(Size::FOUR_KIB);
+            end.inner - start.align_down(Size::FOUR_KIB).inner
+        })
+        .sum()
+}
+
+// Remaps every page of the kernel image with the permissions of the segment it belongs to: code is read-only and
+// executable, everything else is non-executable and only writable if the segment is. Segments that are both
+// writable and executable are refused.
+pub fn protect_kernel_image() {
+    let loaded_segments = || program_headers().filter(|header| header.segment_type == PT_LOAD);
+    // The kernel is position independent, so its link addresses are offset from where it actually runs
+    let image_start = core::ptr::addr_of!(__ehdr_start) as usize;
+    let first_segment = loaded_segments()
+        .find(|header| header.offset == 0)
+        .expect("ELF header is not part of a loaded segment!");
+    let load_offset = image_start - first_segment.virtual_address as usize;
+
+    let mut active_pml4 = MappedPageTable::new(VirtualAddress::kernel_base(), unsafe { PageTable::get_active_pml4() });
+    let mut tlb_flush = TlbFlush::new();
+    for segment in loaded_segments() {
+        let is_writable = is_bit_set(segment.flags as usize, PF_W as usize);
+        let is_executable = is_bit_set(segment.flags as usize, PF_X as usize);
+        assert!(
+            !(is_writable && is_executable),
+            "Kernel segment at {:#X} is both writable and executable!",
+            segment.virtual_address
+        );
+        let mut entry_flags = PageTableEntryFlags::PRESENT | PageTableEntryFlags::GLOBAL;
+        if is_writable {
+            entry_flags |= PageTableEntryFlags::WRITE_ACCESS;
+        }
+        if !is_executable {
+            entry_flags |= PageTableEntryFlags::no_execute();
+        }
+        let start = VirtualAddress::new(load_offset + segment.virtual_address as usize);
+        let end = (start + segment.memory_size as usize).align_up(Size::FOUR_KIB);
+        let pages = VirtualPageRange::<Size4KiB>::range_inclusive(
+            VirtualPage::from_address_aligned(start),
+            VirtualPage::from_address_aligned(end),
+        );
+        for page in pages {
+            if let Err(flag_update_error) = active_pml4.update_flags(page, entry_flags, &mut tlb_flush) {
+                log::warn!(
+                    "Failed to protect kernel page {:#X}: {}",
+                    page.offset.inner,
+                    flag_update_error
+                );
+            }
+        }
+        log::info!(
+            "Kernel segment {:#X}-{:#X} mapped {}{}",
+            start.inner,
+            end.inner,
+            if is_writable { "RW" } else { "R" },
+            if is_executable { "X" } else { "" }
+        );
+    }
+    tlb_flush.flush();
+}
@@ -1,8 +1,17 @@
+pub mod address_space;
 pub mod asm;
+pub mod cache;
+pub mod direct_map;
+pub mod dma;
+pub mod fault;
 pub mod frame;
+pub mod kernel_image;
 pub mod page;
 pub mod page_table;
 pub mod page_table_entry;
+pub mod tlb;
+pub mod vma;
+pub mod vmalloc;
 
 #[derive(Debug, Clone, Copy)]
 #[non_exhaustive]
@@ -13,3 +22,34 @@ impl Size {
     pub const TWO_MB: usize = (1 << 20) * 2;
     pub const ONE_GB: usize = 1 << 30;
 }
+
+// Implemented by the marker types below so pages and frames can be generic over the size they cover.
+// LEVEL is the paging level the mapping terminates at: PT (1), PD (2) or PDPT (3).
+pub trait PageSize: Copy + Eq + Ord + core::fmt::Debug {
+    const SIZE: usize;
+    const LEVEL: usize;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Size4KiB {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Size2MiB {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Size1GiB {}
+
+impl PageSize for Size4KiB {
+    const SIZE: usize = Size::FOUR_KIB;
+    const LEVEL: usize = 1;
+}
+
+impl PageSize for Size2MiB {
+    const SIZE: usize = Size::TWO_MB;
+    const LEVEL: usize = 2;
+}
+
+impl PageSize for Size1GiB {
+    const SIZE: usize = Size::ONE_GB;
+    const LEVEL: usize = 3;
+}
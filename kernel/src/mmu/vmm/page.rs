@@ -1,47 +1,63 @@
+use core::marker::PhantomData;
+
 use crate::mmu::address::VirtualAddress;
-use crate::mmu::vmm::asm::flush;
-use crate::mmu::vmm::Size;
+use crate::mmu::vmm::{
+    PageSize,
+    Size4KiB,
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 #[repr(transparent)]
-pub struct VirtualPage {
+pub struct VirtualPage<S: PageSize = Size4KiB> {
     pub offset: VirtualAddress,
+    size: PhantomData<S>,
 }
 
-impl core::ops::Add<usize> for VirtualPage {
+impl<S: PageSize> core::ops::Add<usize> for VirtualPage<S> {
     type Output = Self;
 
     fn add(self, rhs: usize) -> Self::Output {
-        VirtualPage::from_address_aligned(self.offset + (rhs * Size::FOUR_KIB))
+        VirtualPage::from_address_aligned(self.offset + (rhs * S::SIZE))
     }
 }
 
-impl core::ops::AddAssign<usize> for VirtualPage {
+impl<S: PageSize> core::ops::AddAssign<usize> for VirtualPage<S> {
     fn add_assign(&mut self, rhs: usize) {
         *self = *self + rhs;
     }
 }
 
-impl VirtualPage {
+impl<S: PageSize> VirtualPage<S> {
     pub fn from_address_aligned(virtual_address: VirtualAddress) -> Self {
-        let aligned_address = virtual_address.align_down(Size::FOUR_KIB);
+        let aligned_address = virtual_address.align_down(S::SIZE);
         VirtualPage {
             offset: aligned_address,
+            size: PhantomData,
+        }
+    }
+
+    // Only succeeds if the address is already aligned to the page size
+    pub fn from_start_address(virtual_address: VirtualAddress) -> Option<Self> {
+        match virtual_address.inner % S::SIZE {
+            0 => Some(Self::from_address_aligned(virtual_address)),
+            _ => None,
         }
     }
 
-    pub fn flush_from_tlb(&self) {
-        unsafe { flush(self.offset.inner) }
+    #[inline]
+    pub fn size(&self) -> usize {
+        S::SIZE
     }
 }
 
-pub struct VirtualPageRange {
-    current: VirtualPage,
-    end: VirtualPage,
+#[derive(Debug, Clone, Copy)]
+pub struct VirtualPageRange<S: PageSize = Size4KiB> {
+    current: VirtualPage<S>,
+    end: VirtualPage<S>,
 }
 
-impl Iterator for VirtualPageRange {
-    type Item = VirtualPage;
+impl<S: PageSize> Iterator for VirtualPageRange<S> {
+    type Item = VirtualPage<S>;
 
     fn next(&mut self) -> Option<Self::Item> {
         match self.current < self.end {
@@ -55,8 +71,12 @@ impl Iterator for VirtualPageRange {
     }
 }
 
-impl VirtualPageRange {
-    pub fn range_inclusive(start: VirtualPage, end: VirtualPage) -> Self {
+impl<S: PageSize> VirtualPageRange<S> {
+    pub fn range_inclusive(start: VirtualPage<S>, end: VirtualPage<S>) -> Self {
         VirtualPageRange { current: start, end }
     }
+
+    pub fn start(&self) -> VirtualPage<S> {
+        self.current
+    }
 }
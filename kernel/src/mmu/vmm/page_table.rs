@@ -1,16 +1,34 @@
-use super::asm::flush;
 use super::asm::get_raw_pml4_ptr;
 use super::frame::PhysicalFrame;
-use super::page::VirtualPage;
+use super::page::{
+    VirtualPage,
+    VirtualPageRange,
+};
 use super::page_table_entry::{
     PageTableEntry,
+    PageTableEntryFlags,
     PHYSICAL_ADDRESS_MASK,
 };
 use crate::mmu::address::{
     PhysicalAddress,
     VirtualAddress,
 };
+use crate::mmu::alloc::frame::accounting::{
+    charge_frames,
+    uncharge_frames,
+    FrameOwner,
+};
 use crate::mmu::alloc::frame::FrameAllocator;
+use crate::mmu::alloc::pressure::AllocationFailure;
+use crate::mmu::vmm::tlb::TlbFlush;
+use crate::mmu::vmm::{
+    PageSize,
+    Size,
+};
+
+// PML4 entries from this index upwards map the kernel half of the address space. The tables they point to
+// are shared, so they are never reclaimed.
+pub const KERNEL_PML4_START_INDEX: usize = 256;
 
 #[derive(Debug, Clone, Copy)]
 #[repr(C, align(4096))]
@@ -18,6 +36,20 @@ pub struct PageTable {
     inner: [PageTableEntry; 512],
 }
 
+impl core::ops::Index<usize> for PageTable {
+    type Output = PageTableEntry;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.inner[index]
+    }
+}
+
+impl core::ops::IndexMut<usize> for PageTable {
+    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
+        &mut self.inner[index]
+    }
+}
+
 impl PageTable {
     pub fn empty() -> Self {
         PageTable {
@@ -37,48 +69,110 @@ impl PageTable {
         }
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.inner.iter().all(|entry| entry.is_unused())
+    }
+
     pub unsafe fn get_active_pml4() -> &'static mut Self {
         let raw_pml4_address = get_raw_pml4_ptr();
         let addr = PhysicalAddress::new(PHYSICAL_ADDRESS_MASK & raw_pml4_address);
         let frame = PhysicalFrame::from_address_aligned(addr);
-        let virt_addr = VirtualAddress::with_kernel_base_offset(frame.start_address());
-        let table_ptr = virt_addr.inner as *mut PageTable;
-        &mut *table_ptr
+        frame.frame_to_page_table(VirtualAddress::kernel_base())
     }
+}
 
-    fn next_table_opt(offset: VirtualAddress, entry: PageTableEntry) -> Option<Self> {
-        match entry.get_frame() {
-            Some(frame) => Some(frame.frame_to_page_table(offset)),
-            None => None,
+#[derive(Debug, Clone, Copy)]
+pub enum MapToError {
+    FrameAllocationFailed,
+    ParentEntryHugePage,
+    PageAlreadyMapped(PhysicalAddress),
+}
+
+impl core::fmt::Display for MapToError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::FrameAllocationFailed => f.write_str("Map Error: Failed to allocate a frame for a page table"),
+            Self::ParentEntryHugePage => f.write_str("Map Error: Parent entry maps a huge page"),
+            Self::PageAlreadyMapped(address) => f.write_fmt(format_args!(
+                "Map Error: Page is already mapped to {:#X}",
+                address.inner
+            )),
         }
     }
+}
 
-    pub fn get_next_page_table(
-        &self,
-        offset: VirtualAddress,
-        mut entry: PageTableEntry,
-        flags: usize,
-        frame_allocator: &mut impl FrameAllocator,
-    ) -> Self {
-        let created: bool;
-        let physical_frame: PhysicalFrame;
-        match entry.is_unused() {
-            true => {
-                physical_frame = frame_allocator.allocate_frame().unwrap();
-                entry.set_frame_addr(physical_frame);
-                created = true;
-            }
-            false => {
-                entry.set_flags(flags);
-                physical_frame = entry.get_frame().unwrap();
-                created = false;
+impl AllocationFailure for MapToError {
+    fn is_out_of_memory(&self) -> bool {
+        matches!(self, Self::FrameAllocationFailed)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum UnmapError {
+    PageNotMapped,
+    ParentEntryHugePage,
+    PageSizeMismatch,
+}
+
+impl core::fmt::Display for UnmapError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::PageNotMapped => f.write_str("Unmap Error: Page is not mapped"),
+            Self::ParentEntryHugePage => f.write_str("Unmap Error: Parent entry maps a huge page"),
+            Self::PageSizeMismatch => f.write_str("Unmap Error: Entry maps a page of a different size"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum FlagUpdateError {
+    PageNotMapped,
+    ParentEntryHugePage,
+    PageSizeMismatch,
+    FrameAllocationFailed,
+}
+
+impl core::fmt::Display for FlagUpdateError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::PageNotMapped => f.write_str("Flag Update Error: Page is not mapped"),
+            Self::ParentEntryHugePage => f.write_str("Flag Update Error: Parent entry maps a huge page"),
+            Self::PageSizeMismatch => f.write_str("Flag Update Error: Entry maps a page of a different size"),
+            Self::FrameAllocationFailed => {
+                f.write_str("Flag Update Error: Failed to allocate a frame for a page table")
             }
         }
-        let mut page_table = physical_frame.frame_to_page_table(offset);
-        if created {
-            page_table.set_empty();
+    }
+}
+
+impl AllocationFailure for FlagUpdateError {
+    fn is_out_of_memory(&self) -> bool {
+        matches!(self, Self::FrameAllocationFailed)
+    }
+}
+
+// Internal error for walking down existing tables. Converted into the public error types above
+#[derive(Debug, Clone, Copy)]
+enum WalkError {
+    NotMapped,
+    HugePage,
+}
+
+impl From<WalkError> for UnmapError {
+    fn from(walk_error: WalkError) -> Self {
+        match walk_error {
+            WalkError::NotMapped => UnmapError::PageNotMapped,
+            WalkError::HugePage => UnmapError::ParentEntryHugePage,
+        }
+    }
+}
+
+impl From<WalkError> for FlagUpdateError {
+    fn from(walk_error: WalkError) -> Self {
+        match walk_error {
+            WalkError::NotMapped => FlagUpdateError::PageNotMapped,
+            WalkError::HugePage => FlagUpdateError::ParentEntryHugePage,
         }
-        page_table
     }
 }
 
@@ -93,74 +187,297 @@ impl MappedPageTable {
         Self { page_table, offset }
     }
 
+    fn next_table(offset: VirtualAddress, entry: &PageTableEntry) -> Result<&'static mut PageTable, WalkError> {
+        if entry.is_flag_set(PageTableEntryFlags::LARGE_PAGE_SIZE) {
+            return Err(WalkError::HugePage);
+        }
+        match entry.get_frame() {
+            Some(frame) => Ok(frame.frame_to_page_table(offset)),
+            None => Err(WalkError::NotMapped),
+        }
+    }
+
+    fn next_table_or_create(
+        offset: VirtualAddress,
+        entry: &mut PageTableEntry,
+        table_flags: usize,
+        frame_allocator: &mut impl FrameAllocator,
+    ) -> Result<&'static mut PageTable, MapToError> {
+        if entry.is_unused() {
+            let physical_frame = frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            charge_frames(FrameOwner::PageTable, 1);
+            let page_table = physical_frame.frame_to_page_table(offset);
+            page_table.set_empty();
+            entry.set_frame_addr(physical_frame);
+            entry.set_flags(table_flags);
+            Ok(page_table)
+        } else {
+            let page_table = Self::next_table(offset, entry).map_err(|_| MapToError::ParentEntryHugePage)?;
+            // Intermediate entries have to be at least as permissive as any of the entries below them
+            entry.set_flags(table_flags);
+            Ok(page_table)
+        }
+    }
+
+    // Walks down to the entry that maps the address at `level` (1 for PT entries, up to 3 for PDPT entries)
+    fn walk_to_entry(
+        &self,
+        virtual_address: VirtualAddress,
+        level: usize,
+    ) -> Result<&'static mut PageTableEntry, WalkError> {
+        let offset = self.offset;
+        let pml4_entry = &self.page_table.inner[virtual_address.get_pml4_index()];
+        let mut page_table = Self::next_table(offset, pml4_entry)?;
+        for parent_level in (level + 1..4).rev() {
+            let entry = &page_table.inner[virtual_address.get_table_index(parent_level)];
+            page_table = Self::next_table(offset, entry)?;
+        }
+        Ok(&mut page_table.inner[virtual_address.get_table_index(level)])
+    }
+
+    #[inline]
+    fn set_leaf_flags<S: PageSize>(entry: &mut PageTableEntry, entry_flags: usize) {
+        match S::LEVEL {
+            1 => entry.replace_flags(entry_flags),
+            _ => entry.replace_huge_page_flags(entry_flags | PageTableEntryFlags::LARGE_PAGE_SIZE),
+        }
+    }
+
+    // Handles huge pages at any level, including the ones the bootloader set up
     pub fn translate_virtual_address(&self, virtual_address: VirtualAddress) -> Option<PhysicalAddress> {
-        let pml4_index = virtual_address.get_pml4_index();
-        let pml4_entry = self.page_table.inner[pml4_index];
-        let pdpt_option = PageTable::next_table_opt(self.offset, pml4_entry);
-        match pdpt_option {
-            Some(pdpt) => {
-                let pdpt_index = virtual_address.get_pdpt_index();
-                let pdpt_entry = pdpt.inner[pdpt_index];
-                let pd_option = PageTable::next_table_opt(self.offset, pdpt_entry);
-                match pd_option {
-                    Some(pd) => {
-                        let pd_index = virtual_address.get_pd_index();
-                        let pd_entry = pd.inner[pd_index];
-                        let pt_option = PageTable::next_table_opt(self.offset, pd_entry);
-                        match pt_option {
-                            Some(pt) => {
-                                let pt_index = virtual_address.get_pt_index();
-                                let pt_entry = pt.inner[pt_index];
-                                let physical_frame_option = pt_entry.get_frame();
-                                match physical_frame_option {
-                                    Some(physical_frame) => Some(PhysicalAddress::new(
-                                        physical_frame.start_address() + virtual_address.get_page_offset(),
-                                    )),
-                                    None => None,
-                                }
-                            }
-                            None => None,
-                        }
-                    }
-                    None => None,
-                }
+        let offset = self.offset;
+        let pml4_entry = &self.page_table.inner[virtual_address.get_pml4_index()];
+        let mut page_table = Self::next_table(offset, pml4_entry).ok()?;
+        for level in (1..4).rev() {
+            let entry = &page_table.inner[virtual_address.get_table_index(level)];
+            if !entry.is_flag_set(PageTableEntryFlags::PRESENT) {
+                return None;
             }
-            None => None,
+            if level == 1 || entry.is_flag_set(PageTableEntryFlags::LARGE_PAGE_SIZE) {
+                let page_size = Size::FOUR_KIB << (9 * (level - 1));
+                let page_start = entry.get_physical_addr().align_down(page_size);
+                return Some(page_start + (virtual_address.inner & (page_size - 1)));
+            }
+            page_table = Self::next_table(offset, entry).ok()?;
         }
+        unreachable!()
     }
 
-    pub fn map_to(
+    // Only ever fills in non-present entries, which the TLB doesn't cache, so there is nothing to invalidate
+    pub fn map_to<S: PageSize>(
         &mut self,
-        page: VirtualPage,
-        frame: PhysicalFrame,
+        page: VirtualPage<S>,
+        frame: PhysicalFrame<S>,
         entry_flags: usize,
         table_flags: usize,
-        should_flush_page: bool,
         frame_allocator: &mut impl FrameAllocator,
-    ) {
+    ) -> Result<(), MapToError> {
         let virtual_address = page.offset;
-        let pml4 = &mut self.page_table;
-        let pml4_entry = pml4.inner[virtual_address.get_pml4_index()];
-        let pdpt = pml4.get_next_page_table(self.offset, pml4_entry, table_flags, frame_allocator);
-        let pdpt_entry = pdpt.inner[virtual_address.get_pdpt_index()];
-        let pd = pdpt.get_next_page_table(self.offset, pdpt_entry, table_flags, frame_allocator);
-        let pd_entry = pd.inner[virtual_address.get_pd_index()];
-        let mut pt = pd.get_next_page_table(self.offset, pd_entry, table_flags, frame_allocator);
-        let mut pt_entry = pt.inner[virtual_address.get_pt_index()];
-
-        match pt_entry.is_unused() {
-            true => {
-                pt_entry.set_frame_addr(frame);
-                pt_entry.set_flags(entry_flags);
-                // update the page table.
-                pt.inner[virtual_address.get_pt_index()] = pt_entry;
-                if should_flush_page {
-                    unsafe {
-                        flush(virtual_address.inner);
-                    }
+        let offset = self.offset;
+        let pml4_entry = &mut self.page_table.inner[virtual_address.get_pml4_index()];
+        let mut page_table = Self::next_table_or_create(offset, pml4_entry, table_flags, frame_allocator)?;
+        for parent_level in (S::LEVEL + 1..4).rev() {
+            let entry = &mut page_table.inner[virtual_address.get_table_index(parent_level)];
+            page_table = Self::next_table_or_create(offset, entry, table_flags, frame_allocator)?;
+        }
+        let entry = &mut page_table.inner[virtual_address.get_table_index(S::LEVEL)];
+
+        if entry.is_flag_set(PageTableEntryFlags::PRESENT) {
+            return Err(MapToError::PageAlreadyMapped(entry.get_physical_addr()));
+        }
+        entry.set_frame_addr(frame);
+        Self::set_leaf_flags::<S>(entry, entry_flags);
+        Ok(())
+    }
+
+    // Removes the mapping for the page and hands back the frame it pointed to. The frame itself is not freed,
+    // but any page tables left empty by the unmap are, once `tlb_flush` is flushed. The old mapping stays usable
+    // through the TLB until then.
+    pub fn unmap<S: PageSize>(
+        &mut self,
+        page: VirtualPage<S>,
+        tlb_flush: &mut TlbFlush,
+    ) -> Result<PhysicalFrame<S>, UnmapError> {
+        let entry = self.walk_to_entry(page.offset, S::LEVEL)?;
+        if S::LEVEL > 1 && !entry.is_unused() && !entry.is_flag_set(PageTableEntryFlags::LARGE_PAGE_SIZE) {
+            return Err(UnmapError::PageSizeMismatch);
+        }
+        let frame = entry.get_sized_frame::<S>().ok_or(UnmapError::PageNotMapped)?;
+        entry.set_unused();
+        tlb_flush.add(page);
+        self.reclaim_tables(page.offset, S::LEVEL, tlb_flush);
+        Ok(frame)
+    }
+
+    // Frees the tables above a removed entry that were left empty, bottom-up, stopping at the first one that
+    // still has live entries. PDPTs in the kernel half are shared between address spaces and are never freed.
+    fn reclaim_tables(&mut self, virtual_address: VirtualAddress, level: usize, tlb_flush: &mut TlbFlush) {
+        let offset = self.offset;
+        for table_level in level..3 {
+            let parent_entry = match self.walk_to_entry(virtual_address, table_level + 1) {
+                Ok(parent_entry) => parent_entry,
+                Err(_) => return,
+            };
+            match Self::next_table(offset, parent_entry) {
+                Ok(page_table) if page_table.is_empty() => {
+                    let table_frame = parent_entry.get_frame().unwrap();
+                    parent_entry.set_unused();
+                    tlb_flush.free_after_flush(table_frame);
+                    uncharge_frames(FrameOwner::PageTable, 1);
+                }
+                _ => return,
+            }
+        }
+        let pml4_index = virtual_address.get_pml4_index();
+        if pml4_index >= KERNEL_PML4_START_INDEX {
+            return;
+        }
+        let pml4_entry = &mut self.page_table.inner[pml4_index];
+        if let Ok(pdpt) = Self::next_table(offset, pml4_entry) {
+            if pdpt.is_empty() {
+                let table_frame = pml4_entry.get_frame().unwrap();
+                pml4_entry.set_unused();
+                tlb_flush.free_after_flush(table_frame);
+                uncharge_frames(FrameOwner::PageTable, 1);
+            }
+        }
+    }
+
+    // Replaces the flags of an existing mapping, keeping the frame it points to
+    pub fn update_flags<S: PageSize>(
+        &mut self,
+        page: VirtualPage<S>,
+        entry_flags: usize,
+        tlb_flush: &mut TlbFlush,
+    ) -> Result<(), FlagUpdateError> {
+        let entry = self.walk_to_entry(page.offset, S::LEVEL)?;
+        if !entry.is_flag_set(PageTableEntryFlags::PRESENT) {
+            return Err(FlagUpdateError::PageNotMapped);
+        }
+        if S::LEVEL > 1 && !entry.is_flag_set(PageTableEntryFlags::LARGE_PAGE_SIZE) {
+            return Err(FlagUpdateError::PageSizeMismatch);
+        }
+        Self::set_leaf_flags::<S>(entry, entry_flags);
+        tlb_flush.add(page);
+        Ok(())
+    }
+
+    // The frame and flags of the page's mapping, if it's mapped with a page of that size
+    pub fn get_mapping<S: PageSize>(&self, page: VirtualPage<S>) -> Option<(PhysicalFrame<S>, usize)> {
+        let entry = self.walk_to_entry(page.offset, S::LEVEL).ok()?;
+        if S::LEVEL > 1 && !entry.is_flag_set(PageTableEntryFlags::LARGE_PAGE_SIZE) {
+            return None;
+        }
+        let entry_flags = match S::LEVEL {
+            1 => entry.flags(),
+            _ => entry.flags() | (entry.inner & PageTableEntryFlags::PAT_HUGE),
+        };
+        Some((entry.get_sized_frame::<S>()?, entry_flags))
+    }
+
+    // Replaces the huge page mapping with a table of pages one size down, which map the same memory with the same
+    // flags. Lets part of a huge page be remapped with other flags.
+    pub fn split_huge_page<S: PageSize>(
+        &mut self,
+        page: VirtualPage<S>,
+        frame_allocator: &mut impl FrameAllocator,
+        tlb_flush: &mut TlbFlush,
+    ) -> Result<(), FlagUpdateError> {
+        assert!(S::LEVEL > 1, "Only huge pages can be split!");
+        let entry = self.walk_to_entry(page.offset, S::LEVEL)?;
+        if !entry.is_flag_set(PageTableEntryFlags::PRESENT) {
+            return Err(FlagUpdateError::PageNotMapped);
+        }
+        if !entry.is_flag_set(PageTableEntryFlags::LARGE_PAGE_SIZE) {
+            return Err(FlagUpdateError::PageSizeMismatch);
+        }
+        let huge_page_flags = entry.flags() | (entry.inner & PageTableEntryFlags::PAT_HUGE);
+        // The PAT bit moves down to bit 7 once the pages are 4 KiB
+        let entry_flags = match S::LEVEL - 1 {
+            1 => {
+                let entry_flags =
+                    huge_page_flags & !(PageTableEntryFlags::LARGE_PAGE_SIZE | PageTableEntryFlags::PAT_HUGE);
+                match huge_page_flags & PageTableEntryFlags::PAT_HUGE != 0 {
+                    true => entry_flags | PageTableEntryFlags::PAT_4KIB,
+                    false => entry_flags,
                 }
             }
-            false => panic!("Page is already mapped!"),
+            _ => huge_page_flags,
+        };
+        let table_frame = frame_allocator
+            .allocate_frame()
+            .ok_or(FlagUpdateError::FrameAllocationFailed)?;
+        charge_frames(FrameOwner::PageTable, 1);
+        let page_table = table_frame.frame_to_page_table(self.offset);
+        let start_address = entry.get_physical_addr().align_down(S::SIZE);
+        let page_size = S::SIZE >> 9;
+        for (index, table_entry) in page_table.inner.iter_mut().enumerate() {
+            *table_entry = PageTableEntry::new(entry_flags, start_address + index * page_size);
+        }
+        // The table entry grants everything the huge page did, so the new entries alone decide the permissions
+        let table_flags = huge_page_flags
+            & (PageTableEntryFlags::PRESENT | PageTableEntryFlags::WRITE_ACCESS | PageTableEntryFlags::USER_ACCESS);
+        *entry = PageTableEntry::new(table_flags, table_frame.offset);
+        tlb_flush.add(page);
+        Ok(())
+    }
+
+    // Points an existing mapping at another frame and hands back the one it pointed to before
+    pub fn remap<S: PageSize>(
+        &mut self,
+        page: VirtualPage<S>,
+        frame: PhysicalFrame<S>,
+        entry_flags: usize,
+        tlb_flush: &mut TlbFlush,
+    ) -> Result<PhysicalFrame<S>, FlagUpdateError> {
+        let entry = self.walk_to_entry(page.offset, S::LEVEL)?;
+        let previous_frame = entry.get_sized_frame::<S>().ok_or(FlagUpdateError::PageNotMapped)?;
+        if S::LEVEL > 1 && !entry.is_flag_set(PageTableEntryFlags::LARGE_PAGE_SIZE) {
+            return Err(FlagUpdateError::PageSizeMismatch);
+        }
+        entry.set_frame_addr(frame);
+        Self::set_leaf_flags::<S>(entry, entry_flags);
+        tlb_flush.add(page);
+        Ok(previous_frame)
+    }
+
+    // Backs every page in the range with a freshly allocated frame. If any page fails to map, the pages mapped
+    // so far are unmapped and their frames freed again before the error is returned.
+    pub fn map_range(
+        &mut self,
+        page_range: VirtualPageRange,
+        entry_flags: usize,
+        table_flags: usize,
+        frame_allocator: &mut impl FrameAllocator,
+        tlb_flush: &mut TlbFlush,
+    ) -> Result<(), MapToError> {
+        let first_page = page_range.start();
+        for page in page_range {
+            let result = match frame_allocator.allocate_frame() {
+                Some(frame) => self
+                    .map_to(page, frame, entry_flags, table_flags, frame_allocator)
+                    .inspect_err(|_| frame_allocator.deallocate_frame(frame)),
+                None => Err(MapToError::FrameAllocationFailed),
+            };
+            if let Err(map_error) = result {
+                let mapped_pages = VirtualPageRange::range_inclusive(first_page, page);
+                // Every page in this range was mapped by the loop above, so this can't fail
+                let _ = self.unmap_range(mapped_pages, tlb_flush);
+                return Err(map_error);
+            }
+        }
+        Ok(())
+    }
+
+    // Unmaps every page in the range and frees the frames backing them once `tlb_flush` is flushed. Stops at the
+    // first unmapped page.
+    pub fn unmap_range(&mut self, page_range: VirtualPageRange, tlb_flush: &mut TlbFlush) -> Result<(), UnmapError> {
+        for page in page_range {
+            let frame = self.unmap(page, tlb_flush)?;
+            tlb_flush.free_after_flush(frame);
         }
+        Ok(())
     }
 }
@@ -1,5 +1,7 @@
+use crate::cpu::control::is_nx_enabled;
 use crate::mmu::address::PhysicalAddress;
 use crate::mmu::vmm::frame::PhysicalFrame;
+use crate::mmu::vmm::PageSize;
 use crate::util::bits::{
     is_bit_set,
     set_bit,
@@ -21,7 +23,25 @@ impl PageTableEntryFlags {
     // Shoutout to the Black-eyed Peas
     pub const DIRTY: usize = 1 << 6;
     pub const LARGE_PAGE_SIZE: usize = 1 << 7;
+    // Third bit of the PAT index in entries mapping 4 KiB pages. The same bit means LARGE_PAGE_SIZE anywhere else
+    pub const PAT_4KIB: usize = 1 << 7;
     pub const GLOBAL: usize = 1 << 8;
+    // Ignored by the CPU. The page is shared read-only and gets copied on the first write to it
+    pub const COPY_ON_WRITE: usize = 1 << 9;
+    // Third bit of the PAT index in entries mapping 2 MiB and 1 GiB pages. Falls inside the address bits of
+    // every other entry
+    pub const PAT_HUGE: usize = 1 << 12;
+    // Reserved unless EFER.NXE is set. Use no_execute() rather than setting it directly
+    pub const NO_EXECUTE: usize = 1 << 63;
+
+    // NO_EXECUTE if the CPU supports it, nothing otherwise
+    #[inline]
+    pub fn no_execute() -> usize {
+        match is_nx_enabled() {
+            true => Self::NO_EXECUTE,
+            false => 0,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -33,7 +53,7 @@ pub struct PageTableEntry {
 impl PageTableEntry {
     pub fn new(flags: usize, physical_address: PhysicalAddress) -> Self {
         PageTableEntry {
-            inner: (flags & physical_address.inner),
+            inner: (flags | physical_address.inner),
         }
     }
 
@@ -53,7 +73,7 @@ impl PageTableEntry {
 
     #[inline]
     pub fn flags(&self) -> usize {
-        self.inner & 0xFF
+        self.inner & !PHYSICAL_ADDRESS_MASK
     }
 
     #[inline]
@@ -71,7 +91,29 @@ impl PageTableEntry {
     }
 
     #[inline]
-    fn get_physical_addr(&self) -> PhysicalAddress {
+    pub fn replace_flags(&mut self, entry_flags: usize) {
+        self.inner = (self.inner & PHYSICAL_ADDRESS_MASK) | (entry_flags & !PHYSICAL_ADDRESS_MASK)
+    }
+
+    // Like replace_flags, for entries mapping huge pages, whose flags include PAT_HUGE
+    #[inline]
+    pub fn replace_huge_page_flags(&mut self, entry_flags: usize) {
+        let address_mask = PHYSICAL_ADDRESS_MASK & !PageTableEntryFlags::PAT_HUGE;
+        self.inner = (self.inner & address_mask) | (entry_flags & !address_mask)
+    }
+
+    #[inline]
+    pub fn is_copy_on_write(&self) -> bool {
+        self.is_flag_set(PageTableEntryFlags::COPY_ON_WRITE)
+    }
+
+    #[inline]
+    pub fn is_executable(&self) -> bool {
+        !self.is_flag_set(PageTableEntryFlags::NO_EXECUTE)
+    }
+
+    #[inline]
+    pub fn get_physical_addr(&self) -> PhysicalAddress {
         PhysicalAddress::new(self.inner & PHYSICAL_ADDRESS_MASK)
     }
 
@@ -82,7 +124,15 @@ impl PageTableEntry {
         }
     }
 
-    pub fn set_frame_addr(&mut self, physical_frame: PhysicalFrame) {
-        self.inner &= physical_frame.start_address()
+    // Huge page entries keep the PAT bit inside the 4 KiB address bits, so they're aligned to their own size
+    pub fn get_sized_frame<S: PageSize>(&self) -> Option<PhysicalFrame<S>> {
+        match self.is_flag_set(PageTableEntryFlags::PRESENT) {
+            true => Some(PhysicalFrame::from_address_aligned(self.get_physical_addr())),
+            false => None,
+        }
+    }
+
+    pub fn set_frame_addr<S: PageSize>(&mut self, physical_frame: PhysicalFrame<S>) {
+        self.inner = (self.inner & !PHYSICAL_ADDRESS_MASK) | physical_frame.start_address()
     }
 }
@@ -0,0 +1,357 @@
+use core::sync::atomic::{
+    AtomicBool,
+    AtomicUsize,
+    Ordering,
+};
+
+use spin::Mutex;
+
+use crate::cpu::control::{
+    read_cr4,
+    write_cr4,
+    Cr4Flags,
+};
+use crate::cpu::cpu_set::CpuSet;
+use crate::cpu::smp::{
+    online_cpus,
+    run_on_cpus,
+};
+use crate::cpu::{
+    current_cpu_index,
+    CPU_INFO,
+};
+use crate::mmu::address::VirtualAddress;
+use crate::mmu::alloc::frame::{
+    FrameAllocator,
+    FRAME_ALLOCATOR,
+};
+use crate::mmu::alloc::heap::is_heap_lock_held_here;
+use crate::mmu::vmm::address_space::is_user_address;
+use crate::mmu::vmm::asm::{
+    get_raw_pml4_ptr,
+    invlpg,
+    invpcid,
+    reload_cr3,
+    write_cr3,
+};
+use crate::mmu::vmm::frame::PhysicalFrame;
+use crate::mmu::vmm::page::VirtualPage;
+use crate::mmu::vmm::page_table_entry::PHYSICAL_ADDRESS_MASK;
+use crate::mmu::vmm::PageSize;
+
+// Past this many pages, dropping the whole TLB is cheaper than invalidating the pages one by one
+pub const FULL_FLUSH_THRESHOLD: usize = 32;
+
+// The kernel's own page tables use PCID 0, as does every address space that couldn't get a PCID of its own
+pub const KERNEL_PCID: u16 = 0;
+const MAX_PCIDS: usize = 1 << 12;
+const PCID_MASK: usize = MAX_PCIDS - 1;
+// Set on a CR3 write to keep the TLB entries already tagged with the new PCID
+const CR3_NO_FLUSH: usize = 1 << 63;
+
+static PCID_ENABLED: AtomicBool = AtomicBool::new(false);
+static INVPCID_ENABLED: AtomicBool = AtomicBool::new(false);
+
+// One bit per PCID, set while it's handed out
+static PCID_MAP: Mutex<[u64; MAX_PCIDS / 64]> = Mutex::new({
+    let mut pcid_map = [0; MAX_PCIDS / 64];
+    pcid_map[0] = 1 << KERNEL_PCID;
+    pcid_map
+});
+
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub enum InvpcidType {}
+
+impl InvpcidType {
+    pub const INDIVIDUAL_ADDRESS: usize = 0;
+    pub const SINGLE_CONTEXT: usize = 1;
+    pub const ALL_CONTEXTS_INCLUDING_GLOBAL: usize = 2;
+    pub const ALL_CONTEXTS: usize = 3;
+}
+
+// Enables global pages, and PCIDs when CPUInfo reports them
+pub fn init_tlb() {
+    let cpu_info = CPU_INFO.get().unwrap();
+    // CR4.PCIDE can only be set while CR3 selects PCID 0
+    let pcid_enabled = cpu_info.pcid_enabled && unsafe { get_raw_pml4_ptr() } & PCID_MASK == 0;
+    unsafe {
+        let mut cr4 = read_cr4() | Cr4Flags::PAGE_GLOBAL_ENABLE;
+        if pcid_enabled {
+            cr4 |= Cr4Flags::PCID_ENABLE;
+        }
+        write_cr4(cr4);
+    }
+    PCID_ENABLED.store(pcid_enabled, Ordering::Release);
+    INVPCID_ENABLED.store(pcid_enabled && cpu_info.invpcid_enabled, Ordering::Release);
+    log::info!(
+        "TLB: PCID - {} INVPCID - {}",
+        pcid_enabled,
+        pcid_enabled && cpu_info.invpcid_enabled
+    );
+}
+
+#[inline]
+pub fn is_pcid_enabled() -> bool {
+    PCID_ENABLED.load(Ordering::Acquire)
+}
+
+#[inline]
+pub fn is_invpcid_enabled() -> bool {
+    INVPCID_ENABLED.load(Ordering::Acquire)
+}
+
+// Returns None once all of them are in use, or if PCIDs aren't enabled at all
+pub fn allocate_pcid() -> Option<u16> {
+    if !is_pcid_enabled() {
+        return None;
+    }
+    let mut pcid_map = PCID_MAP.lock();
+    let (index, word) = pcid_map.iter_mut().enumerate().find(|(_, word)| **word != u64::MAX)?;
+    let bit = word.trailing_ones() as usize;
+    *word |= 1 << bit;
+    Some((index * 64 + bit) as u16)
+}
+
+// The PCID may still tag stale entries. Whoever gets it next has to flush it before first use.
+pub fn free_pcid(pcid: u16) {
+    let pcid = pcid as usize;
+    assert!(
+        pcid != KERNEL_PCID as usize && pcid < MAX_PCIDS,
+        "Invalid PCID {}!",
+        pcid
+    );
+    PCID_MAP.lock()[pcid / 64] &= !(1 << (pcid % 64));
+}
+
+#[inline]
+pub fn current_pcid() -> u16 {
+    (unsafe { get_raw_pml4_ptr() } & PCID_MASK) as u16
+}
+
+// Loads the page tables rooted at the given PML4 under `pcid`. Unless `flush` is set, entries left over from the
+// last time the PCID was active are kept. Without PCIDs every load flushes.
+pub unsafe fn load_address_space(raw_pml4_address: usize, pcid: u16, flush: bool) {
+    let cr3 = match (is_pcid_enabled(), flush) {
+        (true, true) => raw_pml4_address | pcid as usize,
+        (true, false) => raw_pml4_address | pcid as usize | CR3_NO_FLUSH,
+        (false, _) => raw_pml4_address,
+    };
+    write_cr3(cr3);
+}
+
+// Drops every non-global entry of the current address space
+pub fn flush_current_context() {
+    unsafe {
+        match is_invpcid_enabled() {
+            true => invpcid(InvpcidType::SINGLE_CONTEXT, current_pcid() as usize, 0),
+            false => reload_cr3(),
+        }
+    }
+}
+
+// Drops every entry of every PCID, global ones included
+pub fn flush_all_contexts() {
+    unsafe {
+        match is_invpcid_enabled() {
+            true => invpcid(InvpcidType::ALL_CONTEXTS_INCLUDING_GLOBAL, 0, 0),
+            // Any write to CR4 that changes PGE flushes the whole TLB
+            false => {
+                let cr4 = read_cr4();
+                write_cr4(cr4 ^ Cr4Flags::PAGE_GLOBAL_ENABLE);
+                write_cr4(cr4);
+            }
+        }
+    }
+}
+
+// Mappings outside of the per-process part of the address space are shared by every address space, so with PCIDs
+// they can be cached under any of them. invlpg only reaches the current one.
+#[inline]
+fn needs_all_contexts(address: usize) -> bool {
+    is_pcid_enabled() && !is_user_address(VirtualAddress::new(address))
+}
+
+// Mappings outside of the per-process part of the address space can be cached by every CPU, not just the one that
+// changed them
+#[inline]
+fn is_shared(address: usize) -> bool {
+    !is_user_address(VirtualAddress::new(address))
+}
+
+fn invalidate(pages: &[usize], all_contexts: bool, overflowed: bool) {
+    match (all_contexts, overflowed) {
+        (true, _) => flush_all_contexts(),
+        (false, true) => flush_current_context(),
+        (false, false) => {
+            for &address in pages.iter() {
+                unsafe { invlpg(address) };
+            }
+        }
+    }
+}
+
+// Runs `func` on the CPUs of the set other than this one and waits for them to be done
+fn run_on_other_cpus(cpus: CpuSet, func: impl Fn(usize) + Sync) {
+    // The other CPUs may be waiting for these with interrupts disabled, they would never answer
+    debug_assert!(
+        !FRAME_ALLOCATOR.is_held_here() && !is_heap_lock_held_here(),
+        "TLB shootdown with an allocator lock held"
+    );
+    let mut other_cpus = cpus;
+    other_cpus.remove(current_cpu_index());
+    if other_cpus.is_empty() {
+        return;
+    }
+    run_on_cpus(other_cpus, func).expect("Failed to shoot down TLB entries on the other CPUs");
+}
+
+// Has every other online CPU drop its entries for the pages as well, and waits for them to be done. The frames
+// behind the old mappings can only be reused after that.
+fn shoot_down(pages: &[usize], all_contexts: bool, overflowed: bool) {
+    run_on_other_cpus(online_cpus(), |_| invalidate(pages, all_contexts, overflowed));
+}
+
+pub fn flush_page<S: PageSize>(page: VirtualPage<S>) {
+    let address = page.offset.inner;
+    let all_contexts = needs_all_contexts(address);
+    invalidate(&[address], all_contexts, false);
+    if is_shared(address) {
+        shoot_down(&[address], all_contexts, false);
+    }
+}
+
+// Collects the pages whose mappings changed and invalidates them in one go. Once more than FULL_FLUSH_THRESHOLD
+// pages were added, the whole TLB is flushed instead. Any invalidations still pending when the batch is dropped
+// are carried out then.
+//
+// `flush` acts on the page tables loaded on this CPU. User address spaces use `flush_loaded` to reach every CPU
+// that has them loaded, and have the others flush their PCID the next time they load it.
+//
+// Frames that were mapped by the changed entries, page tables included, are only freed after the invalidations,
+// once no TLB or paging structure cache can reach them anymore. Flushing takes the frame allocator's lock then.
+#[derive(Debug)]
+pub struct TlbFlush {
+    pages: [usize; FULL_FLUSH_THRESHOLD],
+    page_count: usize,
+    overflowed: bool,
+    all_contexts: bool,
+    shared: bool,
+    // Physical address of the last frame waiting to be freed. Each frame holds the address of the one before it
+    // in its first word, which a stale walk of a freed page table sees as a non-present entry.
+    freed_frames: usize,
+    freed_frame_count: usize,
+}
+
+impl TlbFlush {
+    pub const fn new() -> Self {
+        TlbFlush {
+            pages: [0; FULL_FLUSH_THRESHOLD],
+            page_count: 0,
+            overflowed: false,
+            all_contexts: false,
+            shared: false,
+            freed_frames: 0,
+            freed_frame_count: 0,
+        }
+    }
+
+    // A huge page only ever takes up a single TLB entry
+    pub fn add<S: PageSize>(&mut self, page: VirtualPage<S>) {
+        self.all_contexts |= needs_all_contexts(page.offset.inner);
+        self.shared |= is_shared(page.offset.inner);
+        match self.page_count < FULL_FLUSH_THRESHOLD {
+            true => {
+                self.pages[self.page_count] = page.offset.inner;
+                self.page_count += 1;
+            }
+            false => self.overflowed = true,
+        }
+    }
+
+    // Hands the frame back to the frame allocator once the batch is flushed. The frame must no longer be mapped
+    // by any page table, only the pages added to the batch may still reach it through the TLB.
+    pub fn free_after_flush(&mut self, frame: PhysicalFrame) {
+        unsafe { (frame.direct_map_address().inner as *mut usize).write(self.freed_frames) };
+        self.freed_frames = frame.start_address();
+        self.freed_frame_count += 1;
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.page_count == 0
+    }
+
+    pub fn flush(&mut self) {
+        if !self.is_empty() {
+            let pages = &self.pages[..self.page_count];
+            // Reloading CR3 leaves global entries behind, and kernel mappings are global
+            let all_contexts = self.all_contexts || (self.shared && self.overflowed);
+            invalidate(pages, all_contexts, self.overflowed);
+            if self.shared {
+                shoot_down(pages, all_contexts, self.overflowed);
+            }
+        }
+        self.discard();
+    }
+
+    // For batches of user mappings, which only the CPUs that have the page tables rooted at `raw_pml4_address`
+    // loaded can use. Invalidates them on each CPU of `cpus` that still has the page tables loaded once it's
+    // reached, and returns the ones that had switched away by then. Those may still cache the old mappings under
+    // the PCID.
+    pub fn flush_loaded(&mut self, raw_pml4_address: usize, cpus: CpuSet) -> CpuSet {
+        let missed_cpus = AtomicUsize::new(0);
+        if !self.is_empty() {
+            let pages = &self.pages[..self.page_count];
+            let overflowed = self.overflowed;
+            let all_contexts = self.all_contexts || (self.shared && overflowed);
+            let invalidate_if_loaded =
+                |cpu_index: usize| match unsafe { get_raw_pml4_ptr() } & PHYSICAL_ADDRESS_MASK == raw_pml4_address {
+                    true => invalidate(pages, all_contexts, overflowed),
+                    false => {
+                        missed_cpus.fetch_or(1 << cpu_index, Ordering::Relaxed);
+                    }
+                };
+            if cpus.contains(current_cpu_index()) {
+                invalidate_if_loaded(current_cpu_index());
+            }
+            run_on_other_cpus(cpus, invalidate_if_loaded);
+            if self.shared {
+                shoot_down(pages, all_contexts, overflowed);
+            }
+        }
+        self.discard();
+        CpuSet::from_bits(missed_cpus.into_inner())
+    }
+
+    // Forgets the pending invalidations without carrying them out, for page tables no TLB has entries of. The
+    // frames waiting on them are freed right away.
+    pub fn discard(&mut self) {
+        self.page_count = 0;
+        self.overflowed = false;
+        self.all_contexts = false;
+        self.shared = false;
+        self.free_frames();
+    }
+
+    fn free_frames(&mut self) {
+        if self.freed_frame_count == 0 {
+            return;
+        }
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let mut frame_address = self.freed_frames;
+        for _ in 0..self.freed_frame_count {
+            let frame = PhysicalFrame::from_raw_address_aligned(frame_address);
+            frame_address = unsafe { (frame.direct_map_address().inner as *const usize).read() };
+            frame_allocator.deallocate_frame(frame);
+        }
+        self.freed_frames = 0;
+        self.freed_frame_count = 0;
+    }
+}
+
+impl Drop for TlbFlush {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
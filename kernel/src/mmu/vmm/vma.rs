@@ -0,0 +1,120 @@
+use core::fmt;
+
+use crate::mmu::address::{
+    PhysicalAddress,
+    VirtualAddress,
+};
+use crate::mmu::vmm::fault::PageFault;
+use crate::mmu::vmm::page::{
+    VirtualPage,
+    VirtualPageRange,
+};
+use crate::mmu::vmm::page_table_entry::PageTableEntryFlags;
+use crate::util::bits::is_bit_set;
+
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub enum VmaPermissions {}
+
+impl VmaPermissions {
+    pub const READ: usize = 1;
+    pub const WRITE: usize = 1 << 1;
+    pub const EXECUTE: usize = 1 << 2;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaBacking {
+    // Zero-filled on first touch. The frames belong to the address space
+    Anonymous,
+    // Same as anonymous memory, kept separate so stacks can be told apart from the rest of the user's memory
+    Stack,
+    // A fixed range of physical memory starting at the given address. The frames aren't owned by the address
+    // space and are never freed by it
+    Physical(PhysicalAddress),
+}
+
+// A page aligned range of user virtual memory, [start, end), with uniform permissions and backing
+#[derive(Debug, Clone, Copy)]
+pub struct VirtualMemoryArea {
+    pub start: VirtualAddress,
+    pub end: VirtualAddress,
+    pub permissions: usize,
+    pub backing: VmaBacking,
+}
+
+impl fmt::Display for VirtualMemoryArea {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let permission_char = |permission, c| match is_bit_set(self.permissions, permission) {
+            true => c,
+            false => '-',
+        };
+        f.write_fmt(format_args!(
+            "{:#X}-{:#X} {}{}{} {:?}",
+            self.start.inner,
+            self.end.inner,
+            permission_char(VmaPermissions::READ, 'r'),
+            permission_char(VmaPermissions::WRITE, 'w'),
+            permission_char(VmaPermissions::EXECUTE, 'x'),
+            self.backing
+        ))
+    }
+}
+
+impl VirtualMemoryArea {
+    pub fn new(start: VirtualAddress, size: usize, permissions: usize, backing: VmaBacking) -> Self {
+        VirtualMemoryArea {
+            start,
+            end: start + size,
+            permissions,
+            backing,
+        }
+    }
+
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.end.inner - self.start.inner
+    }
+
+    #[inline]
+    pub fn contains(&self, address: VirtualAddress) -> bool {
+        self.start <= address && address < self.end
+    }
+
+    #[inline]
+    pub fn overlaps(&self, other: &VirtualMemoryArea) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+
+    // Whether the frames backing the area are allocated by, and returned to, the frame allocator
+    #[inline]
+    pub fn owns_frames(&self) -> bool {
+        !matches!(self.backing, VmaBacking::Physical(_))
+    }
+
+    pub fn pages(&self) -> VirtualPageRange {
+        VirtualPageRange::range_inclusive(
+            VirtualPage::from_address_aligned(self.start),
+            VirtualPage::from_address_aligned(self.end),
+        )
+    }
+
+    pub fn entry_flags(&self) -> usize {
+        let mut entry_flags = PageTableEntryFlags::PRESENT | PageTableEntryFlags::USER_ACCESS;
+        if is_bit_set(self.permissions, VmaPermissions::WRITE) {
+            entry_flags |= PageTableEntryFlags::WRITE_ACCESS;
+        }
+        if !is_bit_set(self.permissions, VmaPermissions::EXECUTE) {
+            entry_flags |= PageTableEntryFlags::no_execute();
+        }
+        entry_flags
+    }
+
+    pub fn permits(&self, page_fault: &PageFault) -> bool {
+        let required_permission = match (page_fault.is_instruction_fetch(), page_fault.is_write()) {
+            (true, _) => VmaPermissions::EXECUTE,
+            (false, true) => VmaPermissions::WRITE,
+            (false, false) => VmaPermissions::READ,
+        };
+        is_bit_set(self.permissions, required_permission)
+    }
+}
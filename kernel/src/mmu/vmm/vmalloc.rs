@@ -0,0 +1,355 @@
+use alloc::collections::BTreeMap;
+use core::fmt;
+
+use spin::Mutex;
+
+use crate::mmu::address::{
+    PhysicalAddress,
+    VirtualAddress,
+};
+use crate::mmu::alloc::frame::accounting::{
+    charge_frames,
+    frame_count,
+    uncharge_frames,
+    FrameOwner,
+};
+use crate::mmu::alloc::frame::FRAME_ALLOCATOR;
+use crate::mmu::alloc::pressure::{
+    retry_on_pressure,
+    AllocationFailure,
+};
+use crate::mmu::vmm::cache::CacheMode;
+use crate::mmu::vmm::frame::PhysicalFrame;
+use crate::mmu::vmm::page::{
+    VirtualPage,
+    VirtualPageRange,
+};
+use crate::mmu::vmm::page_table::{
+    MappedPageTable,
+    PageTable,
+};
+use crate::mmu::vmm::page_table_entry::PageTableEntryFlags;
+use crate::mmu::vmm::tlb::TlbFlush;
+use crate::mmu::vmm::{
+    Size,
+    Size4KiB,
+};
+
+// vmalloc and ioremap mappings are placed in here, well clear of the direct map and the heap
+pub const KERNEL_VMAP_START: usize = 0xFFFF_C900_0000_0000;
+pub const KERNEL_VMAP_SIZE: usize = 1 << 40;
+// Every range is followed by an unmapped page, so running off the end faults instead of hitting the next mapping
+const GUARD_SIZE: usize = Size::FOUR_KIB;
+
+const KERNEL_TABLE_FLAGS: usize = PageTableEntryFlags::PRESENT | PageTableEntryFlags::WRITE_ACCESS;
+
+static KERNEL_VMAP_RANGES: Mutex<VirtualRangeAllocator> =
+    Mutex::new(VirtualRangeAllocator::new(KERNEL_VMAP_START, KERNEL_VMAP_SIZE));
+
+#[derive(Debug, Clone, Copy)]
+pub enum VmapError {
+    InvalidSize,
+    OutOfVirtualMemory,
+    OutOfMemory,
+}
+
+impl fmt::Display for VmapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidSize => f.write_str("Vmap Error: Mapping size is zero"),
+            Self::OutOfVirtualMemory => f.write_str("Vmap Error: No free kernel virtual range is large enough"),
+            Self::OutOfMemory => f.write_str("Vmap Error: Out of physical memory"),
+        }
+    }
+}
+
+impl AllocationFailure for VmapError {
+    fn is_out_of_memory(&self) -> bool {
+        matches!(self, Self::OutOfMemory)
+    }
+}
+
+// Hands out ranges of a fixed window of virtual addresses. Everything from `frontier` up is untouched, freed
+// ranges below it are kept address ordered so neighbours can be merged.
+#[derive(Debug)]
+pub struct VirtualRangeAllocator {
+    // Start address to size
+    free_ranges: BTreeMap<usize, usize>,
+    frontier: usize,
+    end: usize,
+}
+
+#[inline]
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+impl VirtualRangeAllocator {
+    pub const fn new(start: usize, size: usize) -> Self {
+        VirtualRangeAllocator {
+            free_ranges: BTreeMap::new(),
+            frontier: start,
+            end: start + size,
+        }
+    }
+
+    // First fit over the freed ranges, falling back to the untouched part of the window
+    pub fn allocate(&mut self, size: usize, align: usize) -> Option<usize> {
+        let fit = self.free_ranges.iter().find_map(|(&start, &range_size)| {
+            let aligned_start = align_up(start, align);
+            (aligned_start + size <= start + range_size).then_some((start, range_size, aligned_start))
+        });
+        if let Some((start, range_size, aligned_start)) = fit {
+            self.free_ranges.remove(&start);
+            if aligned_start > start {
+                self.free_ranges.insert(start, aligned_start - start);
+            }
+            let allocation_end = aligned_start + size;
+            if allocation_end < start + range_size {
+                self.free_ranges
+                    .insert(allocation_end, start + range_size - allocation_end);
+            }
+            return Some(aligned_start);
+        }
+        let aligned_start = align_up(self.frontier, align);
+        if aligned_start.checked_add(size)? > self.end {
+            return None;
+        }
+        if aligned_start > self.frontier {
+            self.free_ranges.insert(self.frontier, aligned_start - self.frontier);
+        }
+        self.frontier = aligned_start + size;
+        Some(aligned_start)
+    }
+
+    pub fn deallocate(&mut self, mut start: usize, mut size: usize) {
+        if let Some(next_size) = self.free_ranges.remove(&(start + size)) {
+            size += next_size;
+        }
+        if let Some((&previous_start, &previous_size)) = self.free_ranges.range(..start).next_back() {
+            if previous_start + previous_size == start {
+                self.free_ranges.remove(&previous_start);
+                start = previous_start;
+                size += previous_size;
+            }
+        }
+        match start + size == self.frontier {
+            true => self.frontier = start,
+            false => {
+                self.free_ranges.insert(start, size);
+            }
+        }
+    }
+}
+
+fn page_range(start: VirtualAddress, size: usize) -> VirtualPageRange {
+    VirtualPageRange::range_inclusive(
+        VirtualPage::from_address_aligned(start),
+        VirtualPage::from_address_aligned(start + size),
+    )
+}
+
+fn active_page_table() -> MappedPageTable {
+    MappedPageTable::new(VirtualAddress::kernel_base(), unsafe { PageTable::get_active_pml4() })
+}
+
+// Reserves a page aligned range with a guard page behind it
+fn reserve_range(size: usize) -> Result<VirtualAddress, VmapError> {
+    KERNEL_VMAP_RANGES
+        .lock()
+        .allocate(size + GUARD_SIZE, Size::FOUR_KIB)
+        .map(VirtualAddress::new)
+        .ok_or(VmapError::OutOfVirtualMemory)
+}
+
+fn release_range(start: VirtualAddress, size: usize) {
+    KERNEL_VMAP_RANGES.lock().deallocate(start.inner, size + GUARD_SIZE);
+}
+
+// Removes the mappings of [start, start + size) without touching the frames behind them
+fn unmap_borrowed(start: VirtualAddress, size: usize) {
+    let mut page_table = active_page_table();
+    let mut tlb_flush = TlbFlush::new();
+    for page in page_range(start, size) {
+        let _ = page_table.unmap(page, &mut tlb_flush);
+    }
+    tlb_flush.flush();
+}
+
+// A device's registers or memory, mapped with the cache mode it was asked for. The mapping is torn down when
+// this is dropped.
+#[derive(Debug)]
+pub struct IoMapping {
+    // Page aligned start of the mapping, the device memory begins `offset` bytes into it
+    start: VirtualAddress,
+    mapped_size: usize,
+    offset: usize,
+    size: usize,
+    physical_address: PhysicalAddress,
+    cache_mode: CacheMode,
+}
+
+impl IoMapping {
+    #[inline]
+    pub fn address(&self) -> VirtualAddress {
+        self.start + self.offset
+    }
+
+    #[inline]
+    pub fn physical_address(&self) -> PhysicalAddress {
+        self.physical_address
+    }
+
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    #[inline]
+    pub fn cache_mode(&self) -> CacheMode {
+        self.cache_mode
+    }
+
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        assert!(
+            offset + core::mem::size_of::<T>() <= self.size,
+            "Read at offset {:#X} is past the end of the mapping!",
+            offset
+        );
+        unsafe { core::ptr::read_volatile((self.address().inner + offset) as *const T) }
+    }
+
+    pub fn write<T: Copy>(&self, offset: usize, value: T) {
+        assert!(
+            offset + core::mem::size_of::<T>() <= self.size,
+            "Write at offset {:#X} is past the end of the mapping!",
+            offset
+        );
+        unsafe { core::ptr::write_volatile((self.address().inner + offset) as *mut T, value) }
+    }
+}
+
+impl Drop for IoMapping {
+    fn drop(&mut self) {
+        unmap_borrowed(self.start, self.mapped_size);
+        release_range(self.start, self.mapped_size);
+    }
+}
+
+// Maps [physical_address, physical_address + size) into the kernel's virtual range. Neither end has to be page
+// aligned. The memory is never executable.
+pub fn ioremap(physical_address: PhysicalAddress, size: usize, cache_mode: CacheMode) -> Result<IoMapping, VmapError> {
+    if size == 0 {
+        return Err(VmapError::InvalidSize);
+    }
+    let offset = physical_address.inner % Size::FOUR_KIB;
+    let physical_start = physical_address.align_down(Size::FOUR_KIB);
+    let mapped_size = align_up(offset + size, Size::FOUR_KIB);
+    let start = reserve_range(mapped_size)?;
+    let entry_flags = PageTableEntryFlags::PRESENT
+        | PageTableEntryFlags::WRITE_ACCESS
+        | PageTableEntryFlags::GLOBAL
+        | PageTableEntryFlags::no_execute()
+        | cache_mode.entry_flags::<Size4KiB>();
+
+    let mut page_table = active_page_table();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    for (index, page) in page_range(start, mapped_size).enumerate() {
+        let frame = PhysicalFrame::from_address_aligned(physical_start + index * Size::FOUR_KIB);
+        if page_table
+            .map_to(page, frame, entry_flags, KERNEL_TABLE_FLAGS, &mut *frame_allocator)
+            .is_err()
+        {
+            drop(frame_allocator);
+            unmap_borrowed(start, index * Size::FOUR_KIB);
+            release_range(start, mapped_size);
+            return Err(VmapError::OutOfMemory);
+        }
+    }
+    Ok(IoMapping {
+        start,
+        mapped_size,
+        offset,
+        size,
+        physical_address,
+        cache_mode,
+    })
+}
+
+// Virtually contiguous kernel memory backed by frames that don't have to be physically contiguous. The memory
+// is zeroed, and unmapped and freed again when this is dropped.
+#[derive(Debug)]
+pub struct VmallocBuffer {
+    start: VirtualAddress,
+    size: usize,
+}
+
+impl VmallocBuffer {
+    #[inline]
+    pub fn address(&self) -> VirtualAddress {
+        self.start
+    }
+
+    // Always a multiple of the page size
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    #[inline]
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.start.inner as *mut u8
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.as_ptr(), self.size) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.as_ptr(), self.size) }
+    }
+}
+
+impl Drop for VmallocBuffer {
+    fn drop(&mut self) {
+        let mut tlb_flush = TlbFlush::new();
+        // The whole range was mapped by vmalloc, so this can't fail
+        let _ = active_page_table().unmap_range(page_range(self.start, self.size), &mut tlb_flush);
+        tlb_flush.flush();
+        uncharge_frames(FrameOwner::Vmalloc, frame_count(self.size));
+        release_range(self.start, self.size);
+    }
+}
+
+// Reclaims memory and retries once if physical memory runs out
+pub fn vmalloc(size: usize) -> Result<VmallocBuffer, VmapError> {
+    if size == 0 {
+        return Err(VmapError::InvalidSize);
+    }
+    let size = align_up(size, Size::FOUR_KIB);
+    retry_on_pressure(frame_count(size), || try_vmalloc(size))
+}
+
+fn try_vmalloc(size: usize) -> Result<VmallocBuffer, VmapError> {
+    let start = reserve_range(size)?;
+    let entry_flags = PageTableEntryFlags::PRESENT
+        | PageTableEntryFlags::WRITE_ACCESS
+        | PageTableEntryFlags::GLOBAL
+        | PageTableEntryFlags::no_execute();
+    let mut tlb_flush = TlbFlush::new();
+    let result = active_page_table().map_range(
+        page_range(start, size),
+        entry_flags,
+        KERNEL_TABLE_FLAGS,
+        &mut *FRAME_ALLOCATOR.lock(),
+        &mut tlb_flush,
+    );
+    tlb_flush.flush();
+    if result.is_err() {
+        release_range(start, size);
+        return Err(VmapError::OutOfMemory);
+    }
+    charge_frames(FrameOwner::Vmalloc, frame_count(size));
+    unsafe { core::ptr::write_bytes(start.inner as *mut u8, 0, size) };
+    Ok(VmallocBuffer { start, size })
+}
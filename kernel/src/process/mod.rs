@@ -1,4 +1,18 @@
-use crate::mmu::address::VirtualAddress;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+
+use spin::Mutex;
+
+use crate::cpu::fpu::{
+    FpuState,
+    FpuSwitching,
+};
+use crate::cpu::smp::lock_answering_calls;
+use crate::mmu::vmm::address_space::{
+    active_address_space,
+    switch_to_kernel,
+    AddressSpace,
+};
 
 pub mod file;
 pub mod kernel;
@@ -7,6 +21,9 @@ pub mod process;
 // FIXME
 const MAX_THREADS: usize = 1;
 
+// Every live process, keyed by its process ID
+pub static PROCESS_TABLE: Mutex<BTreeMap<usize, Process>> = Mutex::new(BTreeMap::new());
+
 #[derive(Debug, Clone, Copy)]
 #[repr(C)]
 pub struct RegisterState {
@@ -59,16 +76,18 @@ pub enum ThreadState {
     Sleeping,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug)]
 #[repr(C)]
 pub struct Thread {
     register_state: RegisterState,
     program_counter: usize, // RIP
     stack_pointer: usize,   // RSP
     state: ThreadState,
+    // Allocated on the thread's first use of the x87 or SIMD registers, none until then
+    fpu_state: Option<FpuState>,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug)]
 #[repr(C)]
 pub struct Process {
     process_id: usize,
@@ -77,7 +96,118 @@ pub struct Process {
     scheduling_priority: usize,
     ticks_left: usize,
     cpu_time_used: usize,
-    text_seg_addr: VirtualAddress,
-    data_seg_addr: VirtualAddress,
-    bss_seg_addr: VirtualAddress,
+    // Shared with the scheduler, which switches to it whenever one of the process's threads runs
+    address_space: Arc<Mutex<AddressSpace>>,
+}
+
+impl Thread {
+    pub const fn new() -> Self {
+        Thread {
+            register_state: RegisterState {
+                r15: 0,
+                r14: 0,
+                r13: 0,
+                r12: 0,
+                r11: 0,
+                r10: 0,
+                r9: 0,
+                r8: 0,
+                rbp: 0,
+                rdi: 0,
+                rsi: 0,
+                rdx: 0,
+                rcx: 0,
+                rbx: 0,
+                rax: 0,
+            },
+            program_counter: 0,
+            stack_pointer: 0,
+            state: ThreadState::Ready,
+            fpu_state: None,
+        }
+    }
+
+    #[inline]
+    pub fn fpu_state(&self) -> Option<&FpuState> {
+        self.fpu_state.as_ref()
+    }
+
+    // What the scheduler passes to switch_fpu when switching away from the thread
+    #[inline]
+    pub fn fpu_state_mut(&mut self) -> &mut Option<FpuState> {
+        &mut self.fpu_state
+    }
+
+    // Gives the thread an FPU state if it has none yet, and switches it with `switching` from then on
+    pub fn enable_fpu(&mut self, switching: FpuSwitching) -> &mut FpuState {
+        let fpu_state = self.fpu_state.get_or_insert_with(|| FpuState::new(switching));
+        fpu_state.set_switching(switching);
+        fpu_state
+    }
+}
+
+impl Process {
+    pub fn new(process_id: usize, parent_pid: Option<usize>, address_space: AddressSpace) -> Self {
+        Process {
+            process_id,
+            parent_pid,
+            thread_table: [const { Thread::new() }; MAX_THREADS],
+            scheduling_priority: 0,
+            ticks_left: 0,
+            cpu_time_used: 0,
+            address_space: Arc::new(Mutex::new(address_space)),
+        }
+    }
+
+    #[inline]
+    pub fn process_id(&self) -> usize {
+        self.process_id
+    }
+
+    #[inline]
+    pub fn parent_pid(&self) -> Option<usize> {
+        self.parent_pid
+    }
+
+    #[inline]
+    pub fn address_space(&self) -> &Arc<Mutex<AddressSpace>> {
+        &self.address_space
+    }
+
+    // Tears the process down. Its memory is freed once nothing else holds on to the address space, the kernel's
+    // page tables are loaded first if it's the active one.
+    pub fn terminate(self) {
+        // Terminated from fault handlers too, with interrupts disabled
+        if lock_answering_calls(&self.address_space).is_active() {
+            unsafe { switch_to_kernel() };
+        }
+        log::info!("Process {} terminated", self.process_id);
+    }
+}
+
+pub fn add_process(process: Process) {
+    let previous = PROCESS_TABLE.lock().insert(process.process_id, process);
+    assert!(previous.is_none(), "Process ID reused while the process is alive!");
+}
+
+// The process whose address space is loaded, if any
+pub fn current_process_id() -> Option<usize> {
+    let active_address_space = active_address_space()?;
+    PROCESS_TABLE
+        .lock()
+        .values()
+        .find(|process| Arc::ptr_eq(process.address_space(), &active_address_space))
+        .map(|process| process.process_id())
+}
+
+// Removes the process from the process table and terminates it. Returns false if there is no such process
+pub fn kill_process(process_id: usize) -> bool {
+    let process = PROCESS_TABLE.lock().remove(&process_id);
+    match process {
+        Some(process) => {
+            process.terminate();
+            true
+        }
+        None => false,
+    }
 }
@@ -2,8 +2,12 @@ pub mod asm;
 pub mod gdt;
 pub mod tss;
 
+use alloc::boxed::Box;
+use core::ptr::addr_of;
+
 use lazy_static::lazy_static;
 
+use crate::mmu::vmm::vmalloc::vmalloc;
 use crate::segmentation::asm::*;
 use crate::segmentation::gdt::{
     GlobalDescriptorTable,
@@ -25,21 +29,28 @@ pub struct GDTWithSegmentSelectors {
 lazy_static! {
     pub static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
-        unsafe { tss.init_interrupt_stack_table(DOUBLE_FAULT_STACK_TABLE_INDEX, DOUBLE_FAULT_STACK) }
-        unsafe { tss.init_interrupt_stack_table(PAGE_FAULT_STACK_TABLE_INDEX, PAGE_FAULT_STACK) }
+        tss.init_interrupt_stack_table(DOUBLE_FAULT_STACK_TABLE_INDEX, addr_of!(DOUBLE_FAULT_STACK));
+        tss.init_interrupt_stack_table(PAGE_FAULT_STACK_TABLE_INDEX, addr_of!(PAGE_FAULT_STACK));
+        tss.init_interrupt_stack_table(NMI_STACK_TABLE_INDEX, addr_of!(NMI_STACK));
+        tss.init_interrupt_stack_table(MACHINE_CHECK_STACK_TABLE_INDEX, addr_of!(MACHINE_CHECK_STACK));
         tss
     };
 }
 
 lazy_static! {
-    pub static ref GDT: GDTWithSegmentSelectors = {
+    pub static ref GDT: GDTWithSegmentSelectors = GDTWithSegmentSelectors::new(&TSS);
+}
+
+impl GDTWithSegmentSelectors {
+    // Every CPU gets the same layout, so the selectors are the same everywhere. Only the TSS differs.
+    fn new(tss: &'static TaskStateSegment) -> Self {
         let mut gdt = GlobalDescriptorTable::new();
         let null_segment_selector = gdt.get_entry(0);
         let kernel_code_selector = gdt.set_entry(1, SegmentDescriptor::kernel_code_segment_descriptor(), DPL_0);
         let kernel_data_selector = gdt.set_entry(2, SegmentDescriptor::kernel_data_segment_descriptor(), DPL_0);
         let user_code_selector = gdt.set_entry(3, SegmentDescriptor::user_code_segment_descriptor(), DPL_3);
         let user_data_selector = gdt.set_entry(4, SegmentDescriptor::user_data_segment_descriptor(), DPL_3);
-        let (tss_system_segment_low, tss_system_segment_high) = SegmentDescriptor::tss_system_segment(&TSS);
+        let (tss_system_segment_low, tss_system_segment_high) = SegmentDescriptor::tss_system_segment(tss);
         let tss_selector = gdt.set_entry(5, tss_system_segment_low, DPL_0);
         let _ = gdt.set_entry(6, tss_system_segment_high, DPL_0); // Ignore the high segment
         let selectors = Selectors {
@@ -48,23 +59,50 @@ lazy_static! {
             kernel_data_selector,
             user_code_selector,
             user_data_selector,
-            tss_selector
+            tss_selector,
         };
         GDTWithSegmentSelectors { table: gdt, selectors }
+    }
 
-    };
+    unsafe fn load(&self) {
+        GlobalDescriptorTable::load_gdt(&self.table.address());
+        set_cs(self.selectors.kernel_code_selector);
+        set_ss(self.selectors.kernel_data_selector);
+        set_ds(self.selectors.kernel_data_selector);
+        set_es(self.selectors.kernel_data_selector);
+        set_fs(self.selectors.kernel_data_selector);
+        set_gs(self.selectors.kernel_data_selector);
+        load_task_register(self.selectors.tss_selector);
+    }
+}
+
+// An interrupt stack of its own for an AP, with a guard page below the next vmalloc range
+fn allocate_interrupt_stack() -> *const [u8; STACK_SIZE] {
+    let stack = vmalloc(STACK_SIZE).expect("Failed to allocate an interrupt stack");
+    let stack_ptr = stack.as_ptr() as *const [u8; STACK_SIZE];
+    // The CPU keeps using it until the machine goes down
+    core::mem::forget(stack);
+    stack_ptr
 }
 
 pub fn init_gdt() {
-    unsafe {
-        GlobalDescriptorTable::load_gdt(&GDT.table.address());
-        set_cs(GDT.selectors.kernel_code_selector);
-        set_ss(GDT.selectors.kernel_data_selector);
-        set_ds(GDT.selectors.kernel_data_selector);
-        set_es(GDT.selectors.kernel_data_selector);
-        set_fs(GDT.selectors.kernel_data_selector);
-        set_gs(GDT.selectors.kernel_data_selector);
-        load_task_register(GDT.selectors.tss_selector);
-    }
+    unsafe { GDT.load() };
     log::info!("Loaded GDT and segment registers");
 }
+
+// The CPU marks the TSS it loaded busy, so each AP needs a TSS and with it a GDT of its own. Its interrupt stacks
+// can't be shared either, two CPUs taking an NMI at once would run on the same one.
+pub fn init_ap_gdt() {
+    let mut tss = TaskStateSegment::new();
+    for stack_table_index in [
+        DOUBLE_FAULT_STACK_TABLE_INDEX,
+        PAGE_FAULT_STACK_TABLE_INDEX,
+        NMI_STACK_TABLE_INDEX,
+        MACHINE_CHECK_STACK_TABLE_INDEX,
+    ] {
+        tss.init_interrupt_stack_table(stack_table_index, allocate_interrupt_stack());
+    }
+    let tss: &'static TaskStateSegment = Box::leak(Box::new(tss));
+    let gdt: &'static GDTWithSegmentSelectors = Box::leak(Box::new(GDTWithSegmentSelectors::new(tss)));
+    unsafe { gdt.load() };
+}
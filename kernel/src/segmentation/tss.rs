@@ -2,16 +2,22 @@ use core::mem::size_of;
 
 use crate::mmu::address::VirtualAddress;
 
-const STACK_SIZE: usize = 4096 * 5;
+pub const STACK_SIZE: usize = 4096 * 5;
 
 pub const PRIVILEGE_LEVEL_ZERO_STACK_TABLE_INDEX: usize = 0x00;
 pub const PRIVILEGE_LEVEL_THREE_STACK_TABLE_INDEX: usize = 0x02;
 
 pub const DOUBLE_FAULT_STACK_TABLE_INDEX: usize = 0x00;
 pub const PAGE_FAULT_STACK_TABLE_INDEX: usize = 0x01;
+// NMIs and machine checks can arrive at any point, including right after a syscall entry before the kernel stack
+// is set up, so they always get a known good stack of their own
+pub const NMI_STACK_TABLE_INDEX: usize = 0x02;
+pub const MACHINE_CHECK_STACK_TABLE_INDEX: usize = 0x03;
 
 pub static mut DOUBLE_FAULT_STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
 pub static mut PAGE_FAULT_STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+pub static mut NMI_STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+pub static mut MACHINE_CHECK_STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
 
 pub enum StackTableType {
     Privilege,
@@ -47,14 +53,15 @@ impl TaskStateSegment {
         VirtualAddress::new(self as *const _ as usize)
     }
 
-    pub fn init_interrupt_stack_table(&mut self, stack_table_index: usize, stack: [u8; STACK_SIZE]) {
-        let stack_ptr: usize = &stack as *const _ as usize + STACK_SIZE;
+    // The stack is taken by pointer, a copy of it would only live as long as this call
+    pub fn init_interrupt_stack_table(&mut self, stack_table_index: usize, stack: *const [u8; STACK_SIZE]) {
+        let stack_ptr: usize = stack as usize + STACK_SIZE;
         let canonical_stack_ptr = VirtualAddress::new(stack_ptr);
         self.interrupt_stack_table[stack_table_index] = canonical_stack_ptr;
     }
 
-    pub fn init_priviledge_stack_table(&mut self, stack_table_index: usize, stack: [u8; STACK_SIZE]) {
-        let stack_ptr: usize = (&stack as *const _ as usize + STACK_SIZE) as usize;
+    pub fn init_priviledge_stack_table(&mut self, stack_table_index: usize, stack: *const [u8; STACK_SIZE]) {
+        let stack_ptr: usize = stack as usize + STACK_SIZE;
         let canonical_stack_ptr = VirtualAddress::new(stack_ptr);
         self.privilege_stack_table[stack_table_index] = canonical_stack_ptr;
     }
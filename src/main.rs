@@ -1,4 +1,7 @@
-use std::env::current_exe;
+use std::env::{
+    current_exe,
+    var,
+};
 use std::fs::copy;
 use std::process::{
     exit,
@@ -7,6 +10,9 @@ use std::process::{
 
 use ovmf_prebuilt::ovmf_pure_efi;
 
+// Number of CPUs QEMU emulates, unless RUNIX_CPUS says otherwise
+const DEFAULT_CPU_COUNT: &str = "4";
+
 fn main() {
     let current_exe = current_exe().unwrap();
 
@@ -14,11 +20,14 @@ fn main() {
     copy(env!("UEFI_IMAGE"), &uefi_target).unwrap();
     println!("UEFI disk image at {}", uefi_target.display());
 
+    let cpu_count = var("RUNIX_CPUS").unwrap_or_else(|_| DEFAULT_CPU_COUNT.to_string());
     let mut qemu = Command::new("qemu-system-x86_64");
     qemu.arg("-drive")
         .arg(format!("format=raw,file={}", env!("UEFI_IMAGE")))
         .arg("-bios")
         .arg(ovmf_pure_efi())
+        .arg("-smp")
+        .arg(cpu_count)
         .arg("-serial")
         .arg("file:serial_output.log")
         .arg("-d")